@@ -0,0 +1,232 @@
+//! Criterion benches for the lookup hot paths
+//!
+//! Runs over a synthetic fixture generated at startup (a 20k-word lexicon
+//! with Zipf-like frequencies, a top-20 bigram model and a trigram cache),
+//! so it needs none of the real model files. `benchmark_engine` remains the
+//! end-to-end check against the real Vietnamese models.
+//!
+//! Usage:
+//!   cargo bench --bench lookup
+//!   cargo bench --bench lookup -- suggest_next
+
+use combined2fst::bigram::{write_bigram, EdgeValues};
+use combined2fst::builder::IndexEntry;
+use combined2fst::lexicon::{search_fst_fuzzy_prefix, write_lexicon};
+use combined2fst::{BigramModel, ImeEngine, TrigramCache, Vocab};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use fst::Map;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufWriter;
+
+const VOCAB_SIZE: usize = 20_000;
+const TOP_N: usize = 20;
+const TRIGRAM_PAIRS: usize = 50_000;
+const SYLLABLES: &[&str] = &[
+    "ka", "lo", "mi", "tan", "re", "so", "vu", "den", "pa", "ri", "ne", "go", "ba", "shi", "tor",
+    "lin", "ve", "mu", "dar", "eth",
+];
+
+struct Fixture {
+    lexicon: Map<Vec<u8>>,
+    bigram: BigramModel,
+    trigram: TrigramCache,
+    engine: ImeEngine,
+    /// Frequent words first, as a corpus would weight them
+    words: Vec<String>,
+    /// Trigram pairs present in the cache
+    pairs: Vec<(u32, u32)>,
+}
+
+/// Draw a rank in 0..n skewed towards 0, roughly Zipf
+fn zipf_rank(rng: &mut StdRng, n: usize) -> usize {
+    let u: f64 = rng.gen_range(0.0..1.0);
+    ((n as f64).powf(u) - 1.0) as usize
+}
+
+fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}_bench_{}", std::process::id(), name));
+    path.to_string_lossy().into_owned()
+}
+
+fn fixture() -> Fixture {
+    let mut rng = StdRng::seed_from_u64(42);
+
+    // Lexicon: distinct pseudo-words, earlier ones more probable
+    let mut words: Vec<String> = Vec::with_capacity(VOCAB_SIZE);
+    let mut seen = std::collections::HashSet::new();
+    while words.len() < VOCAB_SIZE {
+        let syllables = rng.gen_range(1..=4);
+        let word: String = (0..syllables)
+            .map(|_| SYLLABLES[rng.gen_range(0..SYLLABLES.len())])
+            .collect();
+        if seen.insert(word.clone()) {
+            words.push(word);
+        }
+    }
+    let probs: BTreeMap<String, u8> = words
+        .iter()
+        .enumerate()
+        .map(|(rank, w)| (w.clone(), (255.0 / (1.0 + rank as f64).sqrt()) as u8))
+        .collect();
+    let mut fst_bytes = Vec::new();
+    let mut vocab_bytes = Vec::new();
+    write_lexicon(&probs, &mut fst_bytes, Some(&mut vocab_bytes)).unwrap();
+    let lexicon = Map::new(fst_bytes).unwrap();
+    let vocab = Vocab::from_text(std::str::from_utf8(&vocab_bytes).unwrap());
+    let id_of: HashMap<String, u32> = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, w)| (w.to_string(), id as u32))
+        .collect();
+    let ranked_ids: Vec<u32> = words.iter().map(|w| id_of[w]).collect();
+
+    // Bigrams: every word gets a full row of Zipf-drawn successors
+    let mut index: Vec<IndexEntry> = vec![(0, 0, 0); VOCAB_SIZE];
+    let mut edges = Vec::with_capacity(VOCAB_SIZE * TOP_N);
+    for entry in index.iter_mut() {
+        let offset = (edges.len() * 8) as u32;
+        let mut row: Vec<u32> = Vec::with_capacity(TOP_N);
+        while row.len() < TOP_N {
+            let next = ranked_ids[zipf_rank(&mut rng, VOCAB_SIZE)];
+            if !row.contains(&next) {
+                row.push(next);
+            }
+        }
+        for (i, next) in row.into_iter().enumerate() {
+            edges.push((next, 65535 - (i as u16) * 3000));
+        }
+        *entry = (offset, TOP_N as u16, 0);
+    }
+    let bigram_path = temp_path("bigram.bin");
+    let file = BufWriter::new(std::fs::File::create(&bigram_path).unwrap());
+    write_bigram(
+        file,
+        VOCAB_SIZE as u32,
+        vocab.fingerprint(),
+        TOP_N as u32,
+        &index,
+        EdgeValues::Weights(&edges),
+    )
+    .unwrap();
+
+    // Trigrams: pairs drawn from the bigram rows, sorted for the binary search
+    let mut pairs: Vec<(u32, u32)> = (0..TRIGRAM_PAIRS)
+        .map(|_| {
+            let w1 = ranked_ids[zipf_rank(&mut rng, VOCAB_SIZE)];
+            let (offset, _, _) = index[w1 as usize];
+            let w2 = edges[offset as usize / 8 + rng.gen_range(0..TOP_N)].0;
+            (w1, w2)
+        })
+        .collect();
+    pairs.sort_unstable();
+    pairs.dedup();
+    let trigram_path = temp_path("trigram.bin");
+    std::fs::write(&trigram_path, trigram_bytes(&pairs, &mut rng, &ranked_ids)).unwrap();
+
+    let bigram = BigramModel::open(&bigram_path).unwrap();
+    let trigram = TrigramCache::open(&trigram_path).unwrap();
+    let engine = ImeEngine::from_parts(
+        vocab,
+        id_of,
+        BigramModel::open(&bigram_path).unwrap(),
+        Some(TrigramCache::open(&trigram_path).unwrap()),
+    );
+    // The maps stay valid after unlinking
+    let _ = std::fs::remove_file(&bigram_path);
+    let _ = std::fs::remove_file(&trigram_path);
+
+    Fixture {
+        lexicon,
+        bigram,
+        trigram,
+        engine,
+        words,
+        pairs,
+    }
+}
+
+/// A version 1 TRGC file with `TOP_N` edges per pair
+fn trigram_bytes(pairs: &[(u32, u32)], rng: &mut StdRng, ranked_ids: &[u32]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&0x54524743u32.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(TOP_N as u32).to_le_bytes());
+    out.extend_from_slice(&[0u8; 16]);
+    for (i, (w1, w2)) in pairs.iter().enumerate() {
+        out.extend_from_slice(&w1.to_le_bytes());
+        out.extend_from_slice(&w2.to_le_bytes());
+        out.extend_from_slice(&((i * TOP_N * 8) as u32).to_le_bytes());
+        out.extend_from_slice(&(TOP_N as u16).to_le_bytes());
+        out.extend_from_slice(&[0u8; 2]);
+    }
+    for _ in pairs {
+        for i in 0..TOP_N {
+            let next = ranked_ids[zipf_rank(rng, ranked_ids.len())];
+            out.extend_from_slice(&next.to_le_bytes());
+            out.extend_from_slice(&(65535 - (i as u16) * 3000).to_le_bytes());
+            out.extend_from_slice(&[0u8; 2]);
+        }
+    }
+    out
+}
+
+fn bench_lookups(c: &mut Criterion) {
+    let f = fixture();
+    let mut rng = StdRng::seed_from_u64(7);
+    let prevs: Vec<u32> = (0..1024)
+        .map(|_| {
+            f.engine
+                .word_id(&f.words[zipf_rank(&mut rng, VOCAB_SIZE)])
+                .unwrap()
+        })
+        .collect();
+    let pairs: Vec<(u32, u32)> = (0..1024)
+        .map(|_| f.pairs[rng.gen_range(0..f.pairs.len())])
+        .collect();
+    let prefixes: Vec<String> = (0..256)
+        .map(|_| {
+            let word = &f.words[zipf_rank(&mut rng, VOCAB_SIZE)];
+            word.chars().take(4).collect()
+        })
+        .collect();
+    let contexts: Vec<String> = (0..256)
+        .map(|_| {
+            let (w1, w2) = f.pairs[rng.gen_range(0..f.pairs.len())];
+            let vocab = f.engine.vocab();
+            format!("{} {}", vocab.word(w1).unwrap(), vocab.word(w2).unwrap())
+        })
+        .collect();
+
+    let mut i = 0;
+    c.bench_function("bigram_next", |b| {
+        b.iter(|| {
+            i = (i + 1) % prevs.len();
+            black_box(f.bigram.next(black_box(prevs[i]), 10))
+        })
+    });
+    c.bench_function("trigram_lookup", |b| {
+        b.iter(|| {
+            i = (i + 1) % pairs.len();
+            let (w1, w2) = pairs[i];
+            black_box(f.trigram.lookup(black_box(w1), black_box(w2), 10))
+        })
+    });
+    c.bench_function("fuzzy_lookup", |b| {
+        b.iter(|| {
+            i = (i + 1) % prefixes.len();
+            black_box(search_fst_fuzzy_prefix(&f.lexicon, black_box(&prefixes[i]), 1, 10).unwrap())
+        })
+    });
+    c.bench_function("suggest_next", |b| {
+        b.iter(|| {
+            i = (i + 1) % contexts.len();
+            black_box(f.engine.suggest_next(black_box(&contexts[i]), 10))
+        })
+    });
+}
+
+criterion_group!(benches, bench_lookups);
+criterion_main!(benches);
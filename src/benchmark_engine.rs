@@ -1,44 +1,52 @@
 use anyhow::{Context, Result};
-use combined2fst::build_canonical_map;
-use memmap2::Mmap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use combined2fst::{
+    source_label, truncate_display, BigramModel, BoostTable, ImeEngine, Source, MAX_DISPLAY_CHARS,
+};
 use std::time::Instant;
 
+/// `--json` also prints the source breakdown as one line of JSON
+/// (`SourceStats`), for CI checks such as a minimum trigram coverage
 fn main() -> Result<()> {
+    let json = std::env::args().any(|a| a == "--json");
     println!("=== Benchmark: Vietnamese Suggestion Engine ===");
 
     // 1. Load Models
     let start_load = Instant::now();
 
-    println!("Loading Vocabulary & Canonical Map...");
-    // Note: Adjust filenames if needed based on what's available
-    let fst_path = "vi.phrase.fst";
-    let vocab_path = "vi.phrase.vocab.txt";
+    println!("Loading models...");
     let bigram_path = "vi.bigram.bin";
     let trigram_path = "vi.trigram.cache.bin";
+    // The bigram's header says whether its ids are syllables or phrases
+    // (build_vi_bigram --unit); load the vocab they index
+    let unit = BigramModel::open(bigram_path)
+        .context("Failed to open vi.bigram.bin")?
+        .unit();
+    let (fst_path, vocab_path) = unit.vi_paths();
+    println!("Bigram unit: {} ({})", unit.name(), vocab_path);
+
+    let mut engine = ImeEngine::load(fst_path, vocab_path, bigram_path, Some(trigram_path))
+        .context("Failed to load models")?;
+    if !engine.has_trigram() {
+        println!("Warning: No trigram cache found.");
+    }
+    engine.set_boost_table(BoostTable::for_language("vi", ".")?);
 
-    let (_, canonical_map) =
-        build_canonical_map(fst_path, vocab_path).context("Failed to load FST/Vocab map")?;
-
-    let vocab: Vec<String> = BufReader::new(File::open(vocab_path)?)
-        .lines()
-        .collect::<std::io::Result<_>>()?;
-
-    println!("Loading Bigram Model...");
-    let bigram_file = File::open(bigram_path).context("Failed to open bigram")?;
-    let bigram_mmap = unsafe { Mmap::map(&bigram_file)? };
+    println!("Models loaded in {:.2?}", start_load.elapsed());
 
-    println!("Loading Trigram Cache...");
-    let trigram_mmap = match File::open(trigram_path) {
-        Ok(f) => Some(unsafe { Mmap::map(&f)? }),
-        Err(_) => {
-            println!("Warning: No trigram cache found.");
-            None
-        }
+    // Warm the indexes so the first queries don't pay for page faults
+    let report_resident = |label: &str| match engine.resident_estimate() {
+        Some(bytes) => println!("Resident {}: {:.2} MB", label, bytes as f64 / 1_000_000.0),
+        None => println!("Resident {}: unknown on this platform", label),
     };
-
-    println!("Models loaded in {:.2?}", start_load.elapsed());
+    report_resident("after load");
+    let start_prefetch = Instant::now();
+    let prefetched = engine.prefetch(false);
+    println!(
+        "Prefetched {:.2} MB of index in {:.2?}",
+        prefetched as f64 / 1_000_000.0,
+        start_prefetch.elapsed()
+    );
+    report_resident("after prefetch");
 
     // 2. Test Cases
     let test_phrases = vec![
@@ -62,50 +70,11 @@ fn main() -> Result<()> {
     let mut latencies = Vec::new();
 
     for phrase in &test_phrases {
-        let words: Vec<&str> = phrase.split_whitespace().collect();
-        // Simulate typing the last word? Or next word prediction?
-        // Let's assume prediction given the context `phrase`
-
-        let normalized: Vec<String> = words.iter().map(|w| w.to_lowercase()).collect();
-
         let start_predict = Instant::now();
 
-        // Predict logic
-        let mut found_suggestions = Vec::new();
-        let mut source = "None";
-
-        // Try Trigram (Last 2 words)
-        if let Some(tri_mmap) = &trigram_mmap {
-            if normalized.len() >= 2 {
-                let w1 = &normalized[normalized.len() - 2];
-                let w2 = &normalized[normalized.len() - 1]; // Last word is context
-
-                if let (Some(&id1), Some(&id2)) = (canonical_map.get(w1), canonical_map.get(w2)) {
-                    if let Some(results) = lookup_trigram(tri_mmap, id1, id2, &vocab) {
-                        if !results.is_empty() {
-                            found_suggestions = results;
-                            source = "Trigram";
-                        }
-                    }
-                }
-            }
-        }
-
-        // Fallback Bigram (Last 1 word)
-        if found_suggestions.is_empty() {
-            if let Some(last_word) = normalized.last() {
-                if let Some(&id) = canonical_map.get(last_word) {
-                    if let Some(results) = lookup_bigram(bigram_mmap.as_ref(), id, &vocab) {
-                        found_suggestions = results;
-                        source = "Bigram";
-                    }
-                }
-            }
-        }
-
-        if !found_suggestions.is_empty() {
-            apply_gating(&mut found_suggestions);
-        }
+        // Trigram results padded with bigram results, then gated
+        let found_suggestions = engine.suggest_next(phrase, 10);
+        let source = source_label(&found_suggestions);
 
         let duration = start_predict.elapsed();
         latencies.push(duration);
@@ -113,10 +82,10 @@ fn main() -> Result<()> {
         let top_3: Vec<String> = found_suggestions
             .iter()
             .take(3)
-            .map(|(w, _)| w.clone())
+            .map(|s| truncate_display(&s.word, MAX_DISPLAY_CHARS))
             .collect();
         println!(
-            "Input: {:20} | Time: {:<10?} | Source: {:<7} | Top 3: {:?}",
+            "Input: {:20} | Time: {:<10?} | Source: {:<14} | Top 3: {:?}",
             phrase, duration, source, top_3
         );
     }
@@ -132,116 +101,30 @@ fn main() -> Result<()> {
     println!("Avg Latency:       {:.2?}", avg_latency);
     println!("Min Latency:       {:.2?}", min_latency);
     println!("Max Latency:       {:.2?}", max_latency);
+    println!("Decode Errors:     {}", engine.decode_errors());
 
-    Ok(())
-}
-
-fn apply_gating(suggestions: &mut Vec<(String, u16)>) {
-    let boost_words = [
-        "là", "của", "và", "có", "những", "trong", "được", "một", "cho", "với",
-    ];
-    let mut boosted = Vec::new();
-    let mut others = Vec::new();
-    for (w, s) in suggestions.drain(..) {
-        if boost_words.contains(&w.as_str()) {
-            boosted.push((w, s));
-        } else {
-            others.push((w, s));
-        }
+    // Queries each source contributed to; a padded list counts for several
+    let sources = engine.source_stats();
+    println!("\n=== Sources ===");
+    for source in Source::ALL {
+        println!(
+            "{:<18} {:>5.1}% ({}/{})",
+            format!("{}:", source),
+            100.0 * sources.coverage(source),
+            sources.hits(source),
+            sources.queries
+        );
     }
-    suggestions.extend(boosted);
-    suggestions.extend(others);
-}
-
-fn lookup_trigram(data: &[u8], w1: u32, w2: u32, vocab: &[String]) -> Option<Vec<(String, u16)>> {
-    let num_pairs = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
-    let header_size = 32;
-    let mut low = 0;
-    let mut high = num_pairs;
-    while low < high {
-        let mid = low + (high - low) / 2;
-        let entry_offset = header_size + mid * 16;
-        let mw1 = u32::from_le_bytes([
-            data[entry_offset],
-            data[entry_offset + 1],
-            data[entry_offset + 2],
-            data[entry_offset + 3],
-        ]);
-        let mw2 = u32::from_le_bytes([
-            data[entry_offset + 4],
-            data[entry_offset + 5],
-            data[entry_offset + 6],
-            data[entry_offset + 7],
-        ]);
-        match (mw1, mw2).cmp(&(w1, w2)) {
-            std::cmp::Ordering::Equal => {
-                let edges_start_offset = u32::from_le_bytes([
-                    data[entry_offset + 8],
-                    data[entry_offset + 9],
-                    data[entry_offset + 10],
-                    data[entry_offset + 11],
-                ]) as usize;
-                let len =
-                    u16::from_le_bytes([data[entry_offset + 12], data[entry_offset + 13]]) as usize;
-                let edges_base = header_size + num_pairs * 16;
-                let mut results = Vec::new();
-                for i in 0..len {
-                    let off = edges_base + edges_start_offset + i * 8;
-                    let next_id = u32::from_le_bytes([
-                        data[off],
-                        data[off + 1],
-                        data[off + 2],
-                        data[off + 3],
-                    ]);
-                    let weight = u16::from_le_bytes([data[off + 4], data[off + 5]]);
-                    if let Some(w) = vocab.get(next_id as usize) {
-                        results.push((w.clone(), weight));
-                    }
-                }
-                return Some(results);
-            }
-            std::cmp::Ordering::Less => low = mid + 1,
-            std::cmp::Ordering::Greater => high = mid,
-        }
+    println!(
+        "{:<18} {:>5.1}% ({}/{})",
+        "Empty:",
+        100.0 * sources.empty_rate(),
+        sources.empty,
+        sources.queries
+    );
+    if json {
+        println!("{}", serde_json::to_string(&sources)?);
     }
-    None
-}
 
-fn lookup_bigram(data: &[u8], w_id: u32, vocab: &[String]) -> Option<Vec<(String, u16)>> {
-    let vocab_size = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
-    let header_size = 32;
-    let index_offset = header_size + (w_id as usize) * 8;
-    if index_offset
-        .checked_add(8)
-        .map_or(true, |end| end > header_size + vocab_size * 8)
-    {
-        return None;
-    }
-    if index_offset + 6 > data.len() {
-        return None;
-    }
-    let edges_offset = u32::from_le_bytes([
-        data[index_offset],
-        data[index_offset + 1],
-        data[index_offset + 2],
-        data[index_offset + 3],
-    ]) as usize;
-    let len = u16::from_le_bytes([data[index_offset + 4], data[index_offset + 5]]) as usize;
-    if len == 0 {
-        return None;
-    }
-    let edges_base = header_size + vocab_size * 8;
-    let mut results = Vec::new();
-    for i in 0..len {
-        let off = edges_base + edges_offset + i * 8;
-        if off + 6 > data.len() {
-            break;
-        }
-        let next_id = u32::from_le_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]]);
-        let weight = u16::from_le_bytes([data[off + 4], data[off + 5]]);
-        if let Some(w) = vocab.get(next_id as usize) {
-            results.push((w.clone(), weight));
-        }
-    }
-    Some(results)
+    Ok(())
 }
@@ -0,0 +1,1200 @@
+//! Memory-mapped reader for BGRM bigram files
+//!
+//! Layout: header (40 bytes; 32 before version 3) + index (8 bytes per
+//! prev_id) + edges (8 bytes each)
+//!
+//! Index entries are offset (u32), len (u16) and the prev's encoded
+//! `ln(max_count)` (u16, see `builder::encode_log_max`; 0 in older files).
+//!
+//! Version 2 headers record the build's `word_id_bits` (u32 at offset 20)
+//! and `weight_bits` (u32 at offset 24), so a build that truncated ids or
+//! weights is detectable. Version 1 files are read as 32 and 16.
+//!
+//! The header word at offset 28 holds flags from version 2. With
+//! `FLAG_RAW_COUNTS` set, each edge's weight and flags fields together hold
+//! its u32 corpus count (saturated) instead of a 0-65535 weight, and
+//! `weight_bits` is 32. `next` still returns weights, quantized per row on
+//! read; `raw_counts` returns the counts themselves. `FLAG_PHRASE_UNIT`
+//! marks a Vietnamese model whose ids are phrases of `vi.phrase.vocab.txt`
+//! rather than syllables (see `VocabUnit`).
+//!
+//! In weight files each edge's u16 flags field holds `EDGE_FLAG_*` bits:
+//! `EDGE_FLAG_USER` for edges merged in from a user's own typing,
+//! `EDGE_FLAG_CROSS_SENTENCE` for pairs mostly seen across a sentence
+//! boundary, which a suggester may want to weight down. Files written
+//! without flags have 0 there and read as unflagged. `next_flagged` returns
+//! them. Raw-counts files use the field for the count and carry no edge
+//! flags.
+//!
+//! Version 3 appends the `Vocab::fingerprint` of the vocab the model was
+//! built against (u64 at offset 32). Word ids are positions in that vocab,
+//! so a vocab regenerated in another order shifts every id; `ImeEngine::load`
+//! compares fingerprints and refuses such a pairing instead of resolving ids
+//! to the wrong words. Older files carry no fingerprint and aren't checked.
+//!
+//! Reserved space is zero, and readers hold writers to that so a later
+//! version can give it a meaning without old binaries misreading it. Header
+//! bits are checked at open: unknown header flags and nonzero reserved
+//! header bytes (offsets 20-31 of a version 1 header; versions 2 and 3 have
+//! none left) fail with `EngineError`. Edge bits are only checked by
+//! `validate_bigram`, since open doesn't read the edges: in a version 3
+//! weight file, edge flag bits outside `EDGE_FLAGS_KNOWN` count as
+//! `reserved_errors`. A future version that uses any of this space bumps
+//! `VERSION`, so older readers refuse it instead of guessing.
+//!
+//! `BigramModel::from_bytes` reads the same format from any `AsRef<[u8]>`
+//! store (a `Vec<u8>`, or a model embedded with `include_bytes!`) for
+//! platforms where mapping a file isn't an option; rows from `iter` borrow
+//! from that store.
+//!
+//! Pages are faulted in on first access, so the first lookups after `open`
+//! are slower. `prefetch` touches them up front: the index (8 bytes per
+//! word) is cheap to warm, the edges can be most of the file, and warming
+//! them trades load time and resident memory for steady first-query
+//! latency. The OS may still evict warmed pages under memory pressure.
+
+use crate::builder::{
+    decode_log_max, encode_log_max, quantize_weight, CountEntry, EdgeEntry, FlaggedEntry,
+    IndexEntry,
+};
+use crate::error::{EngineError, EngineResult};
+use crate::vocab::Vocab;
+use crate::{Count, Edge, Weight};
+use anyhow::{bail, Result};
+use memmap2::Mmap;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const MAGIC: u32 = 0x4247524D; // "BGRM"
+/// Header size of the current version; see `header_size`
+pub const HEADER_SIZE: usize = 40;
+pub const VERSION: u32 = 3;
+
+/// Widest next_id this reader handles, and the implicit v1 width
+pub const WORD_ID_BITS: u32 = 32;
+/// Widest edge weight this reader handles, and the implicit v1 width
+pub const WEIGHT_BITS: u32 = 16;
+/// Width of an edge's value in a raw-counts file
+pub const RAW_COUNT_BITS: u32 = 32;
+
+/// Most edges one row can hold: the index entry's length is a u16
+pub const MAX_ROW_LEN: usize = u16::MAX as usize;
+
+/// Header flag: edges hold u32 counts rather than quantized weights
+pub const FLAG_RAW_COUNTS: u32 = 1;
+/// Header flag: ids are multi-syllable phrases, not single tokens
+pub const FLAG_PHRASE_UNIT: u32 = 1 << 1;
+
+/// Edge flag: the edge came from user history rather than the corpus
+pub const EDGE_FLAG_USER: u16 = 1;
+/// Edge flag: the pair was mostly seen with a sentence end between the words
+pub const EDGE_FLAG_CROSS_SENTENCE: u16 = 1 << 1;
+/// Every edge flag bit this version defines; the rest are reserved
+pub const EDGE_FLAGS_KNOWN: u16 = EDGE_FLAG_USER | EDGE_FLAG_CROSS_SENTENCE;
+
+/// Header fields of a BGRM file, as checked by `parse_header`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BigramHeader {
+    pub version: u32,
+    pub vocab_size: u32,
+    pub edges_count: u32,
+    pub top_n: u32,
+    pub word_id_bits: u32,
+    pub weight_bits: u32,
+    pub flags: u32,
+    /// Fingerprint of the build's vocab (version 3 and later)
+    pub vocab_hash: Option<u64>,
+}
+
+impl BigramHeader {
+    pub fn raw_counts(&self) -> bool {
+        self.flags & FLAG_RAW_COUNTS != 0
+    }
+
+    pub fn unit(&self) -> VocabUnit {
+        if self.flags & FLAG_PHRASE_UNIT != 0 {
+            VocabUnit::Phrase
+        } else {
+            VocabUnit::Syllable
+        }
+    }
+}
+
+/// What one id of a model stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VocabUnit {
+    /// One whitespace token: a Vietnamese syllable, or an English word
+    #[default]
+    Syllable,
+    /// A Vietnamese word of one or more syllables ("xin chào")
+    Phrase,
+}
+
+impl VocabUnit {
+    /// Parse a `--unit` value
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "syllable" => Some(Self::Syllable),
+            "phrase" => Some(Self::Phrase),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Syllable => "syllable",
+            Self::Phrase => "phrase",
+        }
+    }
+
+    /// The Vietnamese FST and vocab whose ids a model of this unit uses
+    pub fn vi_paths(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Syllable => ("vi.syllable.fst", "vi.syllable.vocab.txt"),
+            Self::Phrase => ("vi.phrase.fst", "vi.phrase.vocab.txt"),
+        }
+    }
+
+    fn header_flag(self) -> u32 {
+        match self {
+            Self::Syllable => 0,
+            Self::Phrase => FLAG_PHRASE_UNIT,
+        }
+    }
+}
+
+/// Bytes of header before the index in a file of `version`
+pub fn header_size(version: u32) -> usize {
+    if version >= 3 {
+        HEADER_SIZE
+    } else {
+        32
+    }
+}
+
+/// Header bytes `version` reserves, as a (start, end) span
+pub fn reserved_header_span(version: u32) -> Option<(usize, usize)> {
+    (version == 1).then_some((20, 32))
+}
+
+/// Parse the header at the start of `data`, checking its length, magic,
+/// version, bit widths and reserved bytes. Returns an error, never panics,
+/// on short or foreign input.
+pub fn parse_header(data: &[u8]) -> EngineResult<BigramHeader> {
+    check_len(data, header_size(1))?;
+    check_magic(data, MAGIC)?;
+    let version = read_u32(data, 4);
+    let flags = read_flags(data, version)?;
+    let max_weight_bits = if flags & FLAG_RAW_COUNTS != 0 {
+        RAW_COUNT_BITS
+    } else {
+        WEIGHT_BITS
+    };
+    let (word_id_bits, weight_bits) = read_bit_widths(data, version, VERSION, 20, max_weight_bits)?;
+    check_len(data, header_size(version))?;
+    check_reserved(data, reserved_header_span(version))?;
+    let vocab_hash = (version >= 3).then(|| read_u64(data, 32));
+    Ok(BigramHeader {
+        version,
+        vocab_size: read_u32(data, 8),
+        edges_count: read_u32(data, 12),
+        top_n: read_u32(data, 16),
+        word_id_bits,
+        weight_bits,
+        flags,
+        vocab_hash,
+    })
+}
+
+/// Parse the header of every file in `paths` without mapping the files,
+/// for tools that audit a directory of models. Each file's length is also
+/// checked against the header, index and edges its header describes. One
+/// result per path, in order, so a missing or corrupt file doesn't stop
+/// the rest.
+pub fn read_headers(paths: &[&str]) -> Vec<EngineResult<BigramHeader>> {
+    paths.iter().map(|path| read_header(path)).collect()
+}
+
+fn read_header(path: &str) -> EngineResult<BigramHeader> {
+    let file = File::open(path).map_err(|e| EngineError::io(path, e))?;
+    let len = file.metadata().map_err(|e| EngineError::io(path, e))?.len();
+    let mut data = Vec::with_capacity(HEADER_SIZE);
+    file.take(HEADER_SIZE as u64)
+        .read_to_end(&mut data)
+        .map_err(|e| EngineError::io(path, e))?;
+
+    let header = parse_header(&data)?;
+    let need = header_size(header.version) as u64
+        + (header.vocab_size as u64 + header.edges_count as u64) * 8;
+    if len < need {
+        return Err(EngineError::Truncated {
+            need: need as usize,
+            have: len as usize,
+        });
+    }
+    Ok(header)
+}
+
+/// Edge values to write: quantized weights (optionally with `EDGE_FLAG_*`
+/// bits), or raw counts
+#[derive(Debug, Clone, Copy)]
+pub enum EdgeValues<'a> {
+    Weights(&'a [EdgeEntry]),
+    Flagged(&'a [FlaggedEntry]),
+    RawCounts(&'a [CountEntry]),
+}
+
+impl EdgeValues<'_> {
+    pub fn len(&self) -> usize {
+        match self {
+            EdgeValues::Weights(edges) => edges.len(),
+            EdgeValues::Flagged(edges) => edges.len(),
+            EdgeValues::RawCounts(edges) => edges.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of edges whose next_id is not below `vocab_size`
+    pub fn count_out_of_range(&self, vocab_size: u32) -> usize {
+        match self {
+            EdgeValues::Weights(edges) => edges.iter().filter(|e| e.0 >= vocab_size).count(),
+            EdgeValues::Flagged(edges) => edges.iter().filter(|e| e.0 >= vocab_size).count(),
+            EdgeValues::RawCounts(edges) => edges.iter().filter(|e| e.0 >= vocab_size).count(),
+        }
+    }
+}
+
+/// Write a version 3 BGRM file: header, `index` (offsets in bytes) and
+/// `edges`. `vocab_hash` is the `Vocab::fingerprint` of the vocab the ids
+/// refer to. Raw counts above `u32::MAX` are saturated. Fails before
+/// writing anything if an edge's next_id is past `vocab_size`, as a stale
+/// vocab or id map would produce: readers would drop those edges. Also
+/// fails if the edge section is too big for the index's u32 byte offsets.
+pub fn write_bigram<W: Write>(
+    out: W,
+    vocab_size: u32,
+    vocab_hash: u64,
+    top_n: u32,
+    index: &[IndexEntry],
+    edges: EdgeValues,
+) -> Result<()> {
+    write_bigram_with_unit(
+        out,
+        VocabUnit::Syllable,
+        vocab_size,
+        vocab_hash,
+        top_n,
+        index,
+        edges,
+    )
+}
+
+/// `write_bigram` for a model whose ids are `unit`s, recorded in the header
+pub fn write_bigram_with_unit<W: Write>(
+    mut out: W,
+    unit: VocabUnit,
+    vocab_size: u32,
+    vocab_hash: u64,
+    top_n: u32,
+    index: &[IndexEntry],
+    edges: EdgeValues,
+) -> Result<()> {
+    let out_of_range = edges.count_out_of_range(vocab_size);
+    if out_of_range > 0 {
+        bail!(
+            "{} of {} edges have a next_id >= vocab_size {}; is the vocab stale?",
+            out_of_range,
+            edges.len(),
+            vocab_size
+        );
+    }
+    if edges.len() > u32::MAX as usize / 8 {
+        bail!(
+            "{} edges don't fit in a BGRM file, whose u32 offsets address at most {}",
+            edges.len(),
+            u32::MAX / 8
+        );
+    }
+
+    let header = BigramHeader {
+        version: VERSION,
+        vocab_size,
+        edges_count: edges.len() as u32,
+        top_n,
+        word_id_bits: WORD_ID_BITS,
+        weight_bits: WEIGHT_BITS,
+        flags: unit.header_flag(),
+        vocab_hash: Some(vocab_hash),
+    };
+    let header = match edges {
+        EdgeValues::Weights(_) | EdgeValues::Flagged(_) => header,
+        EdgeValues::RawCounts(_) => BigramHeader {
+            weight_bits: RAW_COUNT_BITS,
+            flags: header.flags | FLAG_RAW_COUNTS,
+            ..header
+        },
+    };
+    write_header_and_index(&mut out, &header, index)?;
+
+    // Edges (8 bytes per entry)
+    match edges {
+        EdgeValues::Weights(edges) => {
+            for (next_id, weight) in edges {
+                out.write_all(&next_id.to_le_bytes())?;
+                out.write_all(&weight.to_le_bytes())?;
+                out.write_all(&[0u8; 2])?; // flags
+            }
+        }
+        EdgeValues::Flagged(edges) => {
+            for (next_id, weight, flags) in edges {
+                out.write_all(&next_id.to_le_bytes())?;
+                out.write_all(&weight.to_le_bytes())?;
+                out.write_all(&flags.to_le_bytes())?;
+            }
+        }
+        EdgeValues::RawCounts(edges) => {
+            for (next_id, count) in edges {
+                write_raw_count(&mut out, *next_id, *count)?;
+            }
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// The version 3 header (40 bytes) followed by the index (8 bytes per entry)
+fn write_header_and_index<W: Write>(
+    out: &mut W,
+    header: &BigramHeader,
+    index: &[IndexEntry],
+) -> Result<()> {
+    out.write_all(&MAGIC.to_le_bytes())?;
+    out.write_all(&header.version.to_le_bytes())?;
+    out.write_all(&header.vocab_size.to_le_bytes())?;
+    out.write_all(&header.edges_count.to_le_bytes())?;
+    out.write_all(&header.top_n.to_le_bytes())?;
+    out.write_all(&header.word_id_bits.to_le_bytes())?;
+    out.write_all(&header.weight_bits.to_le_bytes())?;
+    out.write_all(&header.flags.to_le_bytes())?;
+    out.write_all(&header.vocab_hash.unwrap_or(0).to_le_bytes())?;
+
+    for (offset, len, log_max) in index {
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&log_max.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// A raw-counts edge: next_id, then the count (saturated) over the weight
+/// and flags fields
+fn write_raw_count<W: Write>(out: &mut W, next_id: u32, count: u64) -> Result<()> {
+    let count = count.min(u32::MAX as u64) as u32;
+    out.write_all(&next_id.to_le_bytes())?;
+    out.write_all(&count.to_le_bytes())?;
+    Ok(())
+}
+
+/// Writes a BGRM file row by row, for builders whose edges don't fit in
+/// memory at once. Each row goes straight to `out` after the space left
+/// for the header and index; only the index (8 bytes per prev) is held
+/// until `finish` seeks back and writes it with the header. Rows may come
+/// in any prev order, and their offsets follow the order they came in:
+/// fed in prev order, the file is byte for byte what `write_bigram` makes
+/// of the same rows.
+pub struct BigramWriter<W: Write + Seek> {
+    out: W,
+    header: BigramHeader,
+    index: Vec<IndexEntry>,
+    edges_count: usize,
+}
+
+impl<W: Write + Seek> BigramWriter<W> {
+    /// Start a file of `vocab_size` rows at the current position of `out`
+    /// (which should be its start). With `raw_counts` rows are stored as
+    /// counts, otherwise quantized to weights per row.
+    pub fn new(
+        mut out: W,
+        unit: VocabUnit,
+        vocab_size: u32,
+        vocab_hash: u64,
+        top_n: u32,
+        raw_counts: bool,
+    ) -> Result<Self> {
+        out.seek(SeekFrom::Start(
+            (HEADER_SIZE + vocab_size as usize * 8) as u64,
+        ))?;
+        let (weight_bits, flags) = if raw_counts {
+            (RAW_COUNT_BITS, FLAG_RAW_COUNTS)
+        } else {
+            (WEIGHT_BITS, 0)
+        };
+        Ok(Self {
+            out,
+            header: BigramHeader {
+                version: VERSION,
+                vocab_size,
+                edges_count: 0,
+                top_n,
+                word_id_bits: WORD_ID_BITS,
+                weight_bits,
+                flags: flags | unit.header_flag(),
+                vocab_hash: Some(vocab_hash),
+            },
+            index: vec![(0, 0, 0); vocab_size as usize],
+            edges_count: 0,
+        })
+    }
+
+    /// Write `prev_id`'s row from its (next_id, count) edges, largest count
+    /// first. Fails, as `write_bigram` does, on a next_id past the vocab or
+    /// once the edges outgrow the u32 offsets; a row already written for
+    /// `prev_id` is replaced in the index but its edges stay in the file.
+    pub fn push_counts(&mut self, prev_id: u32, counts: &[CountEntry]) -> Result<()> {
+        let vocab_size = self.header.vocab_size;
+        if prev_id >= vocab_size {
+            bail!("prev_id {} >= vocab_size {}", prev_id, vocab_size);
+        }
+        if counts.is_empty() {
+            return Ok(());
+        }
+        if counts.len() > MAX_ROW_LEN {
+            bail!("row of {} edges exceeds {}", counts.len(), MAX_ROW_LEN);
+        }
+        let out_of_range = counts.iter().filter(|e| e.0 >= vocab_size).count();
+        if out_of_range > 0 {
+            bail!(
+                "{} of {} edges have a next_id >= vocab_size {}; is the vocab stale?",
+                out_of_range,
+                counts.len(),
+                vocab_size
+            );
+        }
+        let edges_count = self.edges_count + counts.len();
+        if edges_count > u32::MAX as usize / 8 {
+            bail!(
+                "{} edges don't fit in a BGRM file, whose u32 offsets address at most {}",
+                edges_count,
+                u32::MAX / 8
+            );
+        }
+
+        let max_count = counts[0].1;
+        for &(next_id, count) in counts {
+            if self.header.raw_counts() {
+                write_raw_count(&mut self.out, next_id, count)?;
+            } else {
+                self.out.write_all(&next_id.to_le_bytes())?;
+                self.out
+                    .write_all(&quantize_weight(count, max_count).to_le_bytes())?;
+                self.out.write_all(&[0u8; 2])?; // flags
+            }
+        }
+        self.index[prev_id as usize] = (
+            (self.edges_count * 8) as u32,
+            counts.len() as u16,
+            encode_log_max(max_count),
+        );
+        self.edges_count = edges_count;
+        Ok(())
+    }
+
+    pub fn vocab_size(&self) -> u32 {
+        self.header.vocab_size
+    }
+
+    /// Edges written so far
+    pub fn edges_count(&self) -> usize {
+        self.edges_count
+    }
+
+    /// Prevs with a row so far
+    pub fn rows(&self) -> usize {
+        self.index.iter().filter(|e| e.1 > 0).count()
+    }
+
+    /// Write the header and index, flush, and hand back `out`
+    pub fn finish(mut self) -> Result<W> {
+        self.header.edges_count = self.edges_count as u32;
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header_and_index(&mut self.out, &self.header, &self.index)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Coverage and row-length summary of a model, from `BigramModel::stats`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BigramStats {
+    pub vocab_size: usize,
+    pub edges_count: usize,
+    /// Prevs with at least one edge
+    pub non_empty_prevs: usize,
+    /// `non_empty_prevs / vocab_size` (0 for an empty vocab)
+    pub coverage_ratio: f64,
+    /// `len_histogram[n]` = prevs with n edges; rows longer than `top_n`
+    /// land in the last bucket
+    pub len_histogram: Vec<usize>,
+    /// Percentiles of the row length among non-empty prevs (0 if none)
+    pub p10_len: usize,
+    pub median_len: usize,
+    pub p90_len: usize,
+}
+
+impl BigramStats {
+    /// Summarize per-prev row lengths, as read from a BGRM index
+    pub fn from_lens(lens: &[usize], edges_count: usize, top_n: usize) -> Self {
+        let mut len_histogram = vec![0usize; top_n + 1];
+        for &len in lens {
+            len_histogram[len.min(top_n)] += 1;
+        }
+
+        let mut non_empty: Vec<usize> = lens.iter().copied().filter(|&l| l > 0).collect();
+        non_empty.sort_unstable();
+        let percentile = |p: f64| {
+            non_empty
+                .get((non_empty.len() as f64 * p) as usize)
+                .copied()
+                .unwrap_or(0)
+        };
+
+        Self {
+            vocab_size: lens.len(),
+            edges_count,
+            non_empty_prevs: non_empty.len(),
+            coverage_ratio: if lens.is_empty() {
+                0.0
+            } else {
+                non_empty.len() as f64 / lens.len() as f64
+            },
+            p10_len: percentile(0.1),
+            median_len: percentile(0.5),
+            p90_len: percentile(0.9),
+            len_histogram,
+        }
+    }
+
+    /// Summarize an index about to be written, as a builder holds it
+    pub fn from_index(index: &[IndexEntry], top_n: usize) -> Self {
+        let lens: Vec<usize> = index.iter().map(|&(_, len, _)| len as usize).collect();
+        let edges_count = lens.iter().sum();
+        Self::from_lens(&lens, edges_count, top_n)
+    }
+
+    /// Size of a current-version file with this many words and edges
+    pub fn file_size(&self) -> usize {
+        HEADER_SIZE + self.vocab_size * 8 + self.edges_count * 8
+    }
+}
+
+/// One edge record as stored in the file, borrowed from the mapping
+///
+/// Fields are kept as little-endian bytes, so the struct has alignment 1
+/// and a row of the edge section can be viewed as `&[EdgeRecord]` in place.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeRecord {
+    next_id: [u8; 4],
+    weight: [u8; 2],
+    flags: [u8; 2],
+}
+
+impl EdgeRecord {
+    pub fn next_id(&self) -> u32 {
+        u32::from_le_bytes(self.next_id)
+    }
+
+    /// Stored weight (0-65535). Only meaningful when the file doesn't hold
+    /// raw counts; see `count`.
+    pub fn weight(&self) -> Weight {
+        Weight(u16::from_le_bytes(self.weight))
+    }
+
+    /// `EDGE_FLAG_*` bits. Only meaningful when the file doesn't hold raw
+    /// counts, whose high count bytes sit here.
+    pub fn flags(&self) -> u16 {
+        u16::from_le_bytes(self.flags)
+    }
+
+    /// Corpus count of a raw-counts file (weight and flags fields together)
+    pub fn count(&self) -> Count {
+        let [a, b] = self.weight;
+        let [c, d] = self.flags;
+        Count(u32::from_le_bytes([a, b, c, d]) as u64)
+    }
+}
+
+/// View `bytes` (a multiple of 8 long) as edge records
+fn edges_of(bytes: &[u8]) -> &[EdgeRecord] {
+    const _: () =
+        assert!(std::mem::size_of::<EdgeRecord>() == 8 && std::mem::align_of::<EdgeRecord>() == 1);
+    debug_assert_eq!(bytes.len() % 8, 0);
+    // SAFETY: EdgeRecord is repr(C), 8 bytes, alignment 1 and valid for any bytes
+    unsafe { std::slice::from_raw_parts(bytes.as_ptr().cast::<EdgeRecord>(), bytes.len() / 8) }
+}
+
+/// A BGRM model over its bytes: a mapped file by default (`open`), or any
+/// in-memory store such as `Vec<u8>` or an `include_bytes!` slice
+/// (`from_bytes`). Edge rows borrow from the store.
+pub struct BigramModel<D = Mmap> {
+    data: D,
+    vocab_size: usize,
+    edges_count: usize,
+    top_n: usize,
+    version: u32,
+    /// Start of the index: the header size for `version`
+    index_base: usize,
+    word_id_bits: u32,
+    weight_bits: u32,
+    raw_counts: bool,
+    unit: VocabUnit,
+    vocab_hash: Option<u64>,
+}
+
+impl BigramModel {
+    pub fn open(path: &str) -> EngineResult<Self> {
+        Self::from_bytes(map_file(path)?)
+    }
+}
+
+impl<D: AsRef<[u8]>> BigramModel<D> {
+    /// Read a model from bytes already in memory, checked as `open` does
+    pub fn from_bytes(bytes: D) -> EngineResult<Self> {
+        let data = bytes.as_ref();
+
+        let header = parse_header(data)?;
+        let vocab_size = header.vocab_size as usize;
+        let index_base = header_size(header.version);
+        check_len(data, index_base + vocab_size * 8)?;
+
+        Ok(Self {
+            data: bytes,
+            vocab_size,
+            edges_count: header.edges_count as usize,
+            top_n: header.top_n as usize,
+            version: header.version,
+            index_base,
+            word_id_bits: header.word_id_bits,
+            weight_bits: header.weight_bits,
+            raw_counts: header.raw_counts(),
+            unit: header.unit(),
+            vocab_hash: header.vocab_hash,
+        })
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    pub fn edges_count(&self) -> usize {
+        self.edges_count
+    }
+
+    pub fn top_n(&self) -> usize {
+        self.top_n
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Bits of next_id the build kept (32 unless a narrow build said otherwise)
+    pub fn word_id_bits(&self) -> u32 {
+        self.word_id_bits
+    }
+
+    pub fn weight_bits(&self) -> u32 {
+        self.weight_bits
+    }
+
+    /// What the model's ids stand for (see the module docs)
+    pub fn unit(&self) -> VocabUnit {
+        self.unit
+    }
+
+    /// Whether edges store raw counts (see the module docs)
+    pub fn has_raw_counts(&self) -> bool {
+        self.raw_counts
+    }
+
+    /// Fingerprint of the vocab the model was built against; None before
+    /// version 3
+    pub fn vocab_hash(&self) -> Option<u64> {
+        self.vocab_hash
+    }
+
+    /// Check that the model was built against `vocab`: the header's vocab
+    /// size must equal its line count (`EngineError::VocabMismatch`), and
+    /// a recorded fingerprint must equal its own
+    /// (`EngineError::VocabHashMismatch`). Index math goes by the header,
+    /// so a stale vocab would otherwise resolve ids to the wrong words.
+    pub fn check_vocab(&self, vocab: &Vocab) -> EngineResult<()> {
+        if self.vocab_size != vocab.len() {
+            return Err(EngineError::VocabMismatch {
+                model: self.vocab_size,
+                vocab: vocab.len(),
+            });
+        }
+        if let Some(model) = self.vocab_hash {
+            let fingerprint = vocab.fingerprint();
+            if model != fingerprint {
+                return Err(EngineError::VocabHashMismatch {
+                    model,
+                    vocab: fingerprint,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Up to `limit` edges for `prev_id`, in stored (weight-descending)
+    /// order. Empty if `prev_id` has no edges or is outside the index.
+    ///
+    /// The `top_n` the file was built with is a hard ceiling: a larger
+    /// `limit` returns every stored edge and no more.
+    pub fn next(&self, prev_id: u32, limit: usize) -> Vec<Edge> {
+        let data = self.data.as_ref();
+        if !self.raw_counts {
+            return self
+                .row(prev_id, limit)
+                .map(|off| Edge {
+                    next_id: read_u32(data, off),
+                    weight: Weight(read_u16(data, off + 4)),
+                })
+                .collect();
+        }
+
+        let max_count = self
+            .row(prev_id, 1)
+            .next()
+            .map_or(1, |off| read_u32(data, off + 4) as u64);
+        self.row(prev_id, limit)
+            .map(|off| {
+                let count = read_u32(data, off + 4) as u64;
+                Edge {
+                    next_id: read_u32(data, off),
+                    weight: Weight(quantize_weight(count, max_count)),
+                }
+            })
+            .collect()
+    }
+
+    /// The weight of the `prev_id` -> `next_id` edge, as `next` would give
+    /// it; None if the model doesn't store that edge. Rows are ordered by
+    /// weight, not id, so this scans the prev's row once: O(row length),
+    /// at most `top_n`, without allocating.
+    pub fn contains_edge(&self, prev_id: u32, next_id: u32) -> Option<Weight> {
+        let data = self.data.as_ref();
+        let off = self
+            .row(prev_id, usize::MAX)
+            .find(|&off| read_u32(data, off) == next_id)?;
+        if !self.raw_counts {
+            return Some(Weight(read_u16(data, off + 4)));
+        }
+        let max_count = self
+            .row(prev_id, 1)
+            .next()
+            .map_or(1, |first| read_u32(data, first + 4) as u64);
+        let count = read_u32(data, off + 4) as u64;
+        Some(Weight(quantize_weight(count, max_count)))
+    }
+
+    /// Like `next`, with each edge's `EDGE_FLAG_*` bits (0 for raw-counts
+    /// files, which can't carry flags)
+    pub fn next_flagged(&self, prev_id: u32, limit: usize) -> Vec<(Edge, u16)> {
+        if self.raw_counts {
+            return self
+                .next(prev_id, limit)
+                .into_iter()
+                .map(|edge| (edge, 0))
+                .collect();
+        }
+        let data = self.data.as_ref();
+        self.row(prev_id, limit)
+            .map(|off| {
+                let edge = Edge {
+                    next_id: read_u32(data, off),
+                    weight: Weight(read_u16(data, off + 4)),
+                };
+                (edge, read_u16(data, off + 6))
+            })
+            .collect()
+    }
+
+    /// Up to `limit` edges for `prev_id` as (next_id, count), in stored
+    /// (count-descending) order. None unless the file stores raw counts:
+    /// quantized weights can't be turned back into counts.
+    pub fn raw_counts(&self, prev_id: u32, limit: usize) -> Option<Vec<(u32, Count)>> {
+        if !self.raw_counts {
+            return None;
+        }
+        let data = self.data.as_ref();
+        Some(
+            self.row(prev_id, limit)
+                .map(|off| (read_u32(data, off), Count(read_u32(data, off + 4) as u64)))
+                .collect(),
+        )
+    }
+
+    /// Coverage and row-length distribution, read from the index
+    pub fn stats(&self) -> BigramStats {
+        let lens: Vec<usize> = self.row_lens().collect();
+        BigramStats::from_lens(&lens, self.edges_count, self.top_n)
+    }
+
+    /// Words with no edges stored: coverage gaps, in id order. Ids the
+    /// vocab doesn't have are skipped. In a canonical model every case
+    /// variant but the canonical one shows up here, since its edges are
+    /// keyed on the canonical id.
+    pub fn empty_prevs<'a>(&'a self, vocab: &'a Vocab) -> impl Iterator<Item = &'a str> + 'a {
+        self.row_lens()
+            .enumerate()
+            .filter(|&(_, len)| len == 0)
+            .filter_map(|(prev, _)| vocab.word(prev as u32))
+    }
+
+    /// Stored row length of every prev, in id order, from the index
+    fn row_lens(&self) -> impl Iterator<Item = usize> + '_ {
+        let data = self.data.as_ref();
+        (0..self.vocab_size)
+            .map(move |prev| read_u16(data, self.index_base + prev * 8 + 4) as usize)
+    }
+
+    /// Every prev with edges, in id order, with its stored row. Walks the
+    /// index once instead of looking each prev up as `next` does, for
+    /// whole-model passes. Rows running past the end of a truncated file
+    /// are cut short.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &[EdgeRecord])> {
+        let data = self.data.as_ref();
+        let edges_base = self.index_base + self.vocab_size * 8;
+        data[self.index_base..edges_base]
+            .chunks_exact(8)
+            .enumerate()
+            .filter_map(move |(prev, entry)| {
+                let len = read_u16(entry, 4) as usize;
+                let start = edges_base.checked_add(read_u32(entry, 0) as usize)?;
+                let available = data.len().saturating_sub(start) / 8;
+                let len = len.min(available);
+                (len > 0).then(|| (prev as u32, edges_of(&data[start..start + len * 8])))
+            })
+    }
+
+    /// The edges of a row from `iter`, quantizing raw counts per row as
+    /// `next` does
+    pub fn weights<'a>(&self, edges: &'a [EdgeRecord]) -> impl Iterator<Item = Edge> + 'a {
+        let raw_counts = self.raw_counts;
+        let max_count = edges.first().map_or(Count(1), |e| e.count());
+        edges.iter().map(move |e| {
+            let weight = if raw_counts {
+                e.count().quantize(max_count)
+            } else {
+                e.weight()
+            };
+            Edge {
+                next_id: e.next_id(),
+                weight,
+            }
+        })
+    }
+
+    /// Byte offsets of the first `limit` edge records stored for `prev_id`
+    fn row(&self, prev_id: u32, limit: usize) -> impl Iterator<Item = usize> {
+        let data = self.data.as_ref();
+        let prev = prev_id as usize;
+        let (start, len) = if prev < self.vocab_size {
+            let index_offset = self.index_base + prev * 8;
+            let edges_base = self.index_base + self.vocab_size * 8;
+            (
+                edges_base + read_u32(data, index_offset) as usize,
+                (read_u16(data, index_offset + 4) as usize).min(limit),
+            )
+        } else {
+            (0, 0)
+        };
+        let data_len = data.len();
+        (0..len)
+            .map(move |i| start + i * 8)
+            .take_while(move |&off| off + 8 <= data_len)
+    }
+
+    /// Fault in the header and index pages, plus the edges if `include_edges`.
+    /// Returns the number of bytes covered.
+    pub fn prefetch(&self, include_edges: bool) -> usize {
+        let data = self.data.as_ref();
+        let end = if include_edges {
+            data.len()
+        } else {
+            (self.index_base + self.vocab_size * 8).min(data.len())
+        };
+        touch_pages(&data[..end]);
+        end
+    }
+
+    /// Bytes of the file currently resident in memory, where the platform
+    /// can report it (unix `mincore`); None elsewhere, and usually for
+    /// stores that aren't page-aligned mappings
+    pub fn resident_estimate(&self) -> Option<usize> {
+        resident_bytes(self.data.as_ref())
+    }
+
+    /// The prev's stored `ln(max_count)`, or None for files that predate it
+    pub fn log_max(&self, prev_id: u32) -> Option<f64> {
+        let prev = prev_id as usize;
+        if prev >= self.vocab_size {
+            return None;
+        }
+        decode_log_max(read_u16(self.data.as_ref(), self.index_base + prev * 8 + 6))
+    }
+
+    /// Stream the model as JSON Lines, one `{"prev": .., "edges": [..]}` object
+    /// per prev word, keeping edges with weight >= `min_weight`.
+    /// Ids that don't resolve against `vocab` are skipped, as are prevs left
+    /// with no edges. Returns the number of lines written.
+    pub fn export_jsonl<W: Write>(
+        &self,
+        vocab: &Vocab,
+        min_weight: Weight,
+        mut out: W,
+    ) -> Result<usize> {
+        let mut rows = 0;
+        for (prev_id, row) in self.iter() {
+            let Some(prev) = vocab.word(prev_id) else {
+                continue;
+            };
+            let edges: Vec<JsonEdge> = self
+                .weights(row)
+                .filter(|edge| edge.weight >= min_weight)
+                .filter_map(|edge| {
+                    let next = vocab.word(edge.next_id)?;
+                    Some(JsonEdge {
+                        next,
+                        weight: edge.weight.0,
+                    })
+                })
+                .collect();
+            if edges.is_empty() {
+                continue;
+            }
+
+            serde_json::to_writer(&mut out, &JsonRow { prev, edges })?;
+            out.write_all(b"\n")?;
+            rows += 1;
+        }
+        out.flush()?;
+        Ok(rows)
+    }
+
+    /// Graphviz DOT of `prev_word`'s neighbourhood: its top edges, and at
+    /// `depth` 2 each successor's top edges too, labelled with their
+    /// weights. Each word is expanded once, in breadth-first order, so
+    /// cycles ("the" -> "end" -> "the") stop. Ids that don't resolve against
+    /// `vocab` are skipped. A word missing from `vocab` gives an empty graph.
+    pub fn to_dot(&self, prev_word: &str, vocab: &Vocab, depth: usize) -> String {
+        let mut dot = String::from("digraph bigram {\n  rankdir=LR;\n");
+        let Some(start) = vocab.iter().position(|w| w == prev_word) else {
+            dot.push_str("}\n");
+            return dot;
+        };
+        let start = start as u32;
+        dot.push_str(&format!("  {} [shape=box];\n", dot_id(prev_word)));
+
+        let mut seen = vec![start];
+        let mut frontier = vec![start];
+        for _ in 0..depth {
+            let mut next_frontier = Vec::new();
+            for prev in frontier {
+                let from = dot_id(vocab.word(prev).unwrap_or_default());
+                for Edge { next_id, weight } in self.next(prev, usize::MAX) {
+                    let Some(next) = vocab.word(next_id) else {
+                        continue;
+                    };
+                    if !seen.contains(&next_id) {
+                        seen.push(next_id);
+                        next_frontier.push(next_id);
+                        dot.push_str(&format!("  {};\n", dot_id(next)));
+                    }
+                    dot.push_str(&format!(
+                        "  {} -> {} [label=\"{}\"];\n",
+                        from,
+                        dot_id(next),
+                        weight
+                    ));
+                }
+            }
+            frontier = next_frontier;
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Count edges whose next_id is not a valid id for a vocab of `vocab_len` words
+    pub fn count_out_of_range(&self, vocab_len: usize) -> usize {
+        let data = self.data.as_ref();
+        let edges_base = self.index_base + self.vocab_size * 8;
+        (0..self.edges_count)
+            .map(|i| edges_base + i * 8)
+            .take_while(|&off| off + 8 <= data.len())
+            .filter(|&off| read_u32(data, off) as usize >= vocab_len)
+            .count()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRow<'a> {
+    prev: &'a str,
+    edges: Vec<JsonEdge<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonEdge<'a> {
+    next: &'a str,
+    weight: u16,
+}
+
+/// `word` as a quoted DOT identifier
+fn dot_id(word: &str) -> String {
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub(crate) fn read_u32(data: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]])
+}
+
+pub(crate) fn read_u64(data: &[u8], off: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[off..off + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub(crate) fn read_u16(data: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([data[off], data[off + 1]])
+}
+
+/// Bit widths declared by a header: implicit for version 1, read from
+/// `offset` (word_id_bits, then weight_bits) for versions 2 to `latest`.
+/// Widths of 0 or beyond what this reader supports (`max_weight_bits` for
+/// the weight) are rejected.
+pub(crate) fn read_bit_widths(
+    data: &[u8],
+    version: u32,
+    latest: u32,
+    offset: usize,
+    max_weight_bits: u32,
+) -> EngineResult<(u32, u32)> {
+    match version {
+        1 => Ok((WORD_ID_BITS, WEIGHT_BITS)),
+        v if (2..=latest).contains(&v) => {
+            let word_id_bits = read_u32(data, offset);
+            let weight_bits = read_u32(data, offset + 4);
+            if word_id_bits == 0 || word_id_bits > WORD_ID_BITS {
+                return Err(EngineError::UnsupportedField {
+                    field: "word_id_bits",
+                    value: word_id_bits,
+                });
+            }
+            if weight_bits == 0 || weight_bits > max_weight_bits {
+                return Err(EngineError::UnsupportedField {
+                    field: "weight_bits",
+                    value: weight_bits,
+                });
+            }
+            Ok((word_id_bits, weight_bits))
+        }
+        _ => Err(EngineError::UnsupportedVersion(version)),
+    }
+}
+
+/// Header flags of a BGRM file: none for version 1, the u32 at offset 28
+/// from version 2. Unknown bits are rejected.
+pub(crate) fn read_flags(data: &[u8], version: u32) -> EngineResult<u32> {
+    let flags = if version >= 2 { read_u32(data, 28) } else { 0 };
+    if flags & !(FLAG_RAW_COUNTS | FLAG_PHRASE_UNIT) != 0 {
+        return Err(EngineError::UnsupportedField {
+            field: "flags",
+            value: flags,
+        });
+    }
+    Ok(flags)
+}
+
+/// Fail if any byte of the reserved `span` is set
+pub(crate) fn check_reserved(data: &[u8], span: Option<(usize, usize)>) -> EngineResult<()> {
+    let Some((start, end)) = span else {
+        return Ok(());
+    };
+    match data[start..end].iter().position(|&b| b != 0) {
+        Some(i) => Err(EngineError::ReservedNonZero { offset: start + i }),
+        None => Ok(()),
+    }
+}
+
+/// Memory-map a model file
+pub(crate) fn map_file(path: &str) -> EngineResult<Mmap> {
+    let file = File::open(path).map_err(|e| EngineError::io(path, e))?;
+    unsafe { Mmap::map(&file) }.map_err(|e| EngineError::io(path, e))
+}
+
+pub(crate) fn check_len(data: &[u8], need: usize) -> EngineResult<()> {
+    if data.len() < need {
+        return Err(EngineError::Truncated {
+            need,
+            have: data.len(),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_magic(data: &[u8], expected: u32) -> EngineResult<()> {
+    let found = read_u32(data, 0);
+    if found != expected {
+        return Err(EngineError::BadMagic { found, expected });
+    }
+    Ok(())
+}
+
+/// Read one byte per page so the OS maps the whole slice in
+pub(crate) fn touch_pages(data: &[u8]) {
+    let mut sum = 0u8;
+    for i in (0..data.len()).step_by(4096) {
+        sum = sum.wrapping_add(data[i]);
+    }
+    std::hint::black_box(sum);
+}
+
+/// Resident bytes of an mmap'd slice, rounded to whole pages
+#[cfg(unix)]
+pub(crate) fn resident_bytes(data: &[u8]) -> Option<usize> {
+    if data.is_empty() {
+        return Some(0);
+    }
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page <= 0 {
+        return None;
+    }
+    let page = page as usize;
+    let mut vec = vec![0u8; data.len().div_ceil(page)];
+    // The mapping starts on a page boundary, as mincore requires
+    let rc = unsafe {
+        libc::mincore(
+            data.as_ptr() as *mut _,
+            data.len(),
+            vec.as_mut_ptr() as *mut _,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    let resident_pages = vec.iter().filter(|&&v| v & 1 != 0).count();
+    Some((resident_pages * page).min(data.len()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn resident_bytes(_data: &[u8]) -> Option<usize> {
+    None
+}
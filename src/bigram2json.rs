@@ -0,0 +1,50 @@
+//! Export a bigram model as JSON Lines for inspection outside Rust
+//!
+//! Each line: {"prev":"the","edges":[{"next":"same","weight":65535}, ...]}
+//!
+//! Usage: cargo run --release --bin bigram2json -- <bigram.bin> <vocab.txt> [--min-weight W] [--out FILE]
+//! Writes to stdout unless --out is given.
+
+use anyhow::{Context, Result};
+use combined2fst::{BigramModel, Vocab, Weight};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!(
+            "Usage: {} <bigram.bin> <vocab.txt> [--min-weight W] [--out FILE]",
+            args[0]
+        );
+        eprintln!("  --min-weight W : Drop edges with weight below W (default: 0)");
+        eprintln!("  --out FILE     : Write to FILE instead of stdout");
+        std::process::exit(1);
+    }
+
+    let model = BigramModel::open(&args[1])?;
+    let vocab = Vocab::load(&args[2])?;
+    model
+        .check_vocab(&vocab)
+        .with_context(|| format!("{} doesn't match {}", args[1], args[2]))?;
+    let min_weight = match flag_value(&args, "--min-weight") {
+        Some(v) => Weight(v.parse().context("--min-weight must be 0-65535")?),
+        None => Weight(0),
+    };
+
+    let out: Box<dyn Write> = match flag_value(&args, "--out") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let rows = model.export_jsonl(&vocab, min_weight, BufWriter::new(out))?;
+    eprintln!("Exported {} prev words", rows);
+
+    Ok(())
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
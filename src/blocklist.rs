@@ -0,0 +1,83 @@
+//! Words kept out of suggestions at runtime (privacy and safety filtering)
+//!
+//! A `Blocklist` is a set of word ids plus the lowercased words they came
+//! from. `ImeEngine::set_blocklist` makes every suggestion path drop
+//! matching candidates before returning, without rebuilding any model.
+//! Words are resolved against the vocab once, at construction: every id
+//! whose word lowercases to a blocked word is blocked, so "Foo" and "FOO"
+//! go with "foo". The words themselves are kept for candidates that have
+//! no id (shortcut expansions) and for completion lists built outside the
+//! engine, which can filter with `contains_word`.
+//!
+//! `load` reads one word per line; `#` starts a comment.
+
+use crate::vocab::Vocab;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    ids: HashSet<u32>,
+    /// Lowercased
+    words: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Block these ids as they are; no word matching
+    pub fn from_ids(ids: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            ids: ids.into_iter().collect(),
+            words: HashSet::new(),
+        }
+    }
+
+    /// Block `words` and every vocab id that lowercases to one of them
+    pub fn from_words<S: AsRef<str>>(words: impl IntoIterator<Item = S>, vocab: &Vocab) -> Self {
+        let words: HashSet<String> = words
+            .into_iter()
+            .map(|w| w.as_ref().trim().to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect();
+        let ids = vocab
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| words.contains(&w.to_lowercase()))
+            .map(|(id, _)| id as u32)
+            .collect();
+        Self { ids, words }
+    }
+
+    /// Read a word list (see the module docs) and resolve it against `vocab`
+    pub fn load(path: &str, vocab: &Vocab) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        let words = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|w| !w.is_empty());
+        Ok(Self::from_words(words, vocab))
+    }
+
+    pub fn contains_id(&self, id: u32) -> bool {
+        self.ids.contains(&id)
+    }
+
+    /// True when `word` is blocked, ignoring case. A multi-word phrase is
+    /// blocked when any of its words is.
+    pub fn contains_word(&self, word: &str) -> bool {
+        if self.words.is_empty() {
+            return false;
+        }
+        let word = word.to_lowercase();
+        self.words.contains(&word) || word.split_whitespace().any(|w| self.words.contains(w))
+    }
+
+    /// Number of blocked ids
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.words.is_empty()
+    }
+}
@@ -0,0 +1,100 @@
+//! Bloom filter over the canonical map's keys, for fast negative lookups
+//!
+//! `ImeEngine::word_id` runs on every keystroke, and a token that is not a
+//! word still costs a full hash into a map of the whole vocab before it
+//! misses. With `ImeEngine::enable_canonical_bloom` a small bit array is
+//! checked first: a clear bit means the token is definitely not in the map,
+//! so `might_be_word` false is a safe signal to treat it as out of
+//! vocabulary (e.g. to learn it as a user word). Set bits can be false
+//! positives, at the rate the filter was sized for, so the map still has
+//! the final say.
+//!
+//! Sizing: n keys at false-positive rate p take m = -n·ln p / ln²2 bits and
+//! k = m/n·ln 2 hashes; 1% is about 9.6 bits and 7 hashes per key. The k
+//! bit positions come from two 64-bit hashes (Kirsch-Mitzenmacher double
+//! hashing) rather than k separate hash functions.
+
+use crate::sketch::mix64;
+use std::hash::Hasher;
+
+/// False-positive rate `enable_canonical_bloom` is usually called with
+pub const DEFAULT_FP_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter of `num_bits` bits (at least 1) probed `num_hashes`
+    /// times (at least 1) per key
+    pub fn new(num_bits: usize, num_hashes: u32) -> Self {
+        let num_bits = num_bits.max(1);
+        Self {
+            bits: vec![0; num_bits.div_ceil(64)],
+            num_bits: num_bits as u64,
+            num_hashes: num_hashes.max(1),
+        }
+    }
+
+    /// An empty filter sized for `items` keys at `fp_rate` false positives
+    /// (see the module docs). Rates outside (0, 1) are clamped into it.
+    pub fn with_rate(items: usize, fp_rate: f64) -> Self {
+        let p = fp_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(items.max(1) as f64) * p.ln() / (ln2 * ln2)).ceil() as usize;
+        let num_hashes = (num_bits as f64 / items.max(1) as f64 * ln2).round() as u32;
+        Self::new(num_bits, num_hashes)
+    }
+
+    /// A filter holding `keys`, sized for them at `fp_rate`
+    pub fn from_keys<S: AsRef<str>>(keys: impl ExactSizeIterator<Item = S>, fp_rate: f64) -> Self {
+        let mut filter = Self::with_rate(keys.len(), fp_rate);
+        for key in keys {
+            filter.insert(key.as_ref());
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        let (h1, h2) = hash_pair(key);
+        for i in 0..self.num_hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False if `key` was never inserted; true if it was, or as a false
+    /// positive
+    pub fn may_contain(&self, key: &str) -> bool {
+        let (h1, h2) = hash_pair(key);
+        (0..self.num_hashes as u64).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn num_bits(&self) -> usize {
+        self.num_bits as usize
+    }
+
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Memory used by the bit array
+    pub fn size_bytes(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u64>()
+    }
+}
+
+/// The two hashes bit positions are derived from; the second is odd, so
+/// the k probes never repeat a step of 0
+fn hash_pair(key: &str) -> (u64, u64) {
+    let mut hasher = rustc_hash::FxHasher::default();
+    hasher.write(key.as_bytes());
+    let h = hasher.finish();
+    (mix64(h), mix64(h ^ 0x9E37_79B9_7F4A_7C15) | 1)
+}
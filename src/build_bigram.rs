@@ -1,107 +1,222 @@
 //! Production bigram builder for large corpora (OpenSubtitles, Wikipedia)
 //!
 //! Features:
-//! - Sharded processing (RAM-safe for 100M+ bigrams)
+//! - Sharded processing (RAM-safe for 100M+ bigrams); the shard count is
+//!   sized from the corpus file against `--max-ram` unless `--shards` is given
 //! - Canonical lowercase mapping for better coverage  
 //! - Correct binary layout: header + index + edges
 //! - Weight quantization preserved
+//! - `--resume` skips re-sharding after a crash in the reduce phase
+//!   (see `combined2fst::shard` for the manifest check)
+//! - `bigram_shards/` is removed when the build fails, not only when it
+//!   succeeds; with `--resume` it is kept for the next attempt
+//! - Optional `--case-sensitive` mode keyed on exact FST word_ids
+//!   (see `combined2fst::builder` for the lookup implications)
+//! - Lines that aren't valid UTF-8 are decoded lossily and counted;
+//!   `--strict-utf8` fails on them instead (see `combined2fst::corpus`)
+//! - Optional `--rich-tokens` keeps digits and hyphens inside words and
+//!   maps numbers and links to placeholders (see `combined2fst::tokenize`)
+//! - Optional `--pretokenized` trusts the corpus's whitespace split and
+//!   looks tokens up as they are, for pre-segmented text (tokens must
+//!   already match the vocab keys; see `combined2fst::tokenize`)
+//! - `--progress` reports throughput and an ETA while sharding
+//!   (see `combined2fst::progress`)
+//! - Optional `--raw-counts` stores u32 counts instead of quantized weights,
+//!   for research use (see `combined2fst::bigram`)
+//! - Rows are written to disk as each shard is reduced, so only one
+//!   shard's rows are in memory at once (see `combined2fst::bigram::BigramWriter`)
+//! - `--validate` re-reads the finished file and fails the build if any
+//!   format invariant is violated (see `combined2fst::validate`)
 //!
 //! Usage:
-//!   cargo run --release --bin build_bigram_v2 -- <corpus.txt.gz> [--top N] [--shards S]
+//!   cargo run --release --bin build_bigram_v2 -- <corpus.txt.gz> [--top N] [--shards S] [--max-ram SIZE] [--case-sensitive] [--rich-tokens | --pretokenized] [--raw-counts] [--strict-utf8] [--progress] [--resume] [--validate]
 
 use anyhow::{Context, Result};
-use flate2::read::GzDecoder;
+use combined2fst::bigram::{BigramWriter, VocabUnit, HEADER_SIZE};
+use combined2fst::corpus::Utf8Policy;
+use combined2fst::shard::{
+    auto_shard_count, map_hash, parse_size, reduce_shards_into, shard_bigrams, ShardDirGuard,
+    ShardManifest, ShardOptions, DEFAULT_MAX_RAM, MANIFEST_FILE,
+};
+use combined2fst::tokenize;
+use combined2fst::{build_exact_map, validate_bigram_file, FastMap, Vocab};
 use fst::Map;
 use memmap2::Mmap;
-use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, BufWriter};
 use std::path::Path;
 
-// Binary format constants
-const MAGIC: u32 = 0x4247524D; // "BGRM"
-const VERSION: u32 = 1;
-
-/// Header layout (32 bytes)
-#[repr(C, packed)]
-struct Header {
-    magic: u32,         // 0x4247524D "BGRM"
-    version: u32,       // 1
-    vocab_size: u32,    // total entries in index
-    edges_count: u32,   // total edges
-    top_n: u32,         // max edges per prev
-    reserved: [u32; 3], // padding to 32 bytes
-}
-
-/// Index entry (8 bytes per prev_id)
-#[repr(C, packed)]
-struct IndexEntry {
-    offset: u32,   // byte offset into edges section
-    len: u16,      // number of edges for this prev
-    reserved: u16, // padding
-}
-
-/// Edge entry (8 bytes)
-#[repr(C, packed)]
-struct Edge {
-    next_id: u32, // word_id of next word
-    weight: u16,  // quantized weight (0-65535)
-    flags: u16,   // reserved
-}
-
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: {} <input.txt.gz> [--top N] [--shards S]", args[0]);
+        eprintln!(
+            "Usage: {} <input.txt.gz> [--top N] [--shards S] [--max-ram SIZE] [--case-sensitive] [--rich-tokens | --pretokenized] [--raw-counts] [--strict-utf8] [--progress] [--resume]",
+            args[0]
+        );
         eprintln!("  --top N      : Keep top N next words per prev (default: 10)");
-        eprintln!("  --shards S   : Number of shards for RAM control (default: 256)");
+        eprintln!(
+            "  --shards S   : Number of shards (default: sized from the corpus and --max-ram)"
+        );
+        eprintln!("  --max-ram SIZE : Reduce-phase RAM budget, e.g. 512M or 4G (default: 2G)");
+        eprintln!("  --case-sensitive : Keep case variants on separate rows (exact word_ids)");
+        eprintln!("  --rich-tokens : Keep digits and hyphens in words, emit <NUM>/<URL>");
+        eprintln!("  --pretokenized : Look tokens up as split, without normalizing them");
+        eprintln!("  --raw-counts : Store u32 counts instead of quantized weights");
+        eprintln!(
+            "  --strict-utf8 : Fail on lines that aren't valid UTF-8 (default: decode lossily)"
+        );
+        eprintln!("  --progress   : Report lines/s, bigrams/s and an ETA every 10s");
+        eprintln!("  --resume     : Reuse shards from an interrupted run if they match");
+        eprintln!("  --validate   : Check the written file's format invariants");
         std::process::exit(1);
     }
 
     let input_path = &args[1];
     let top_n: usize = parse_arg(&args, "--top").unwrap_or(10);
-    let num_shards: usize = parse_arg(&args, "--shards").unwrap_or(256);
+    let max_ram = match args.iter().position(|a| a == "--max-ram") {
+        Some(i) => parse_size(args.get(i + 1).context("--max-ram needs a size")?)?,
+        None => DEFAULT_MAX_RAM,
+    };
+    let num_shards: usize = match parse_arg(&args, "--shards") {
+        Some(n) => n,
+        None => {
+            let corpus_bytes = std::fs::metadata(input_path)
+                .with_context(|| format!("Failed to stat {}", input_path))?
+                .len();
+            auto_shard_count(input_path, corpus_bytes, max_ram)
+        }
+    };
+    let case_sensitive = args.iter().any(|a| a == "--case-sensitive");
+    let normalizer = tokenize::from_flags(
+        args.iter().any(|a| a == "--rich-tokens"),
+        args.iter().any(|a| a == "--pretokenized"),
+    )?;
+    let raw_counts = args.iter().any(|a| a == "--raw-counts");
+    let utf8 = if args.iter().any(|a| a == "--strict-utf8") {
+        Utf8Policy::Strict
+    } else {
+        Utf8Policy::Lossy
+    };
+    let progress = args.iter().any(|a| a == "--progress");
+    let resume = args.iter().any(|a| a == "--resume");
+    let validate = args.iter().any(|a| a == "--validate");
 
     println!("=== Production Bigram Builder ===");
     println!("Input: {}", input_path);
     println!("Top-N: {}", top_n);
     println!("Shards: {}", num_shards);
+    println!("Max reduce RAM: {} MB", max_ram >> 20);
+    if case_sensitive {
+        println!("Case-sensitive: edges keyed on exact word_ids");
+    }
+    println!("Tokenizer: {}", normalizer.name());
+    if raw_counts {
+        println!("Raw counts: edges store u32 counts, not weights");
+    }
 
-    // Step 1: Build canonical lowercase map
-    println!("\n[1/4] Building canonical lowercase map...");
-    let (vocab_size, canonical_map) = build_canonical_map("en.lex.fst", "en.vocab.txt")?;
-    println!("  Vocab size: {}", vocab_size);
-    println!("  Canonical entries: {}", canonical_map.len());
+    // Step 1: Build canonical lowercase map (or exact map)
+    let (vocab_size, word_ids) = if case_sensitive {
+        println!("\n[1/4] Building exact word map...");
+        let (vocab_size, exact) = build_exact_map("en.lex.fst", "en.vocab.txt")?;
+        (vocab_size, exact.into_iter().collect())
+    } else {
+        println!("\n[1/4] Building canonical lowercase map...");
+        build_canonical_map("en.lex.fst", "en.vocab.txt")?
+    };
+    let vocab_hash = Vocab::load("en.vocab.txt")?.fingerprint();
+    println!(
+        "  Vocab size: {} (fingerprint {:016x})",
+        vocab_size, vocab_hash
+    );
+    println!("  Mapped entries: {}", word_ids.len());
 
-    // Step 2: Shard bigrams to disk
-    println!("\n[2/4] Extracting bigrams to shards...");
+    // Step 2: Shard bigrams to disk (or reuse shards from an interrupted run)
     let shard_dir = Path::new("bigram_shards");
-    std::fs::create_dir_all(shard_dir)?;
-    let total_bigrams = shard_bigrams(input_path, &canonical_map, shard_dir, num_shards)?;
-    println!("  Total bigrams emitted: {}", total_bigrams);
+    let manifest = ShardManifest {
+        corpus: input_path.clone(),
+        map_hash: map_hash(&word_ids),
+        num_shards,
+        case_sensitive,
+        tokenizer: normalizer.name(),
+    };
+    // Removed on any error below unless --resume may want the shards again
+    let mut shard_guard = ShardDirGuard::new(shard_dir);
+    if resume {
+        shard_guard.commit();
+    }
+    let resumed = match ShardManifest::load(shard_dir)? {
+        Some(saved) if resume => {
+            saved
+                .ensure_matches(&manifest)
+                .context("Refusing to resume; rerun without --resume to start over")?;
+            true
+        }
+        Some(_) => false,
+        None => {
+            if resume {
+                println!("No completed shards to resume from; starting over");
+            }
+            false
+        }
+    };
 
-    // Step 3: Reduce shards to top-N per prev
-    println!("\n[3/4] Reducing shards to top-{} per prev...", top_n);
-    let (index, edges) = reduce_shards(shard_dir, num_shards, vocab_size, top_n)?;
+    if resumed {
+        println!(
+            "\n[2/4] Resuming: reusing shards in {}",
+            shard_dir.display()
+        );
+    } else {
+        println!("\n[2/4] Extracting bigrams to shards...");
+        std::fs::create_dir_all(shard_dir)?;
+        // A stale manifest must not vouch for half-written shards
+        let _ = std::fs::remove_file(shard_dir.join(MANIFEST_FILE));
+        let options = ShardOptions {
+            case_sensitive,
+            normalizer: normalizer.as_ref(),
+            utf8,
+            progress,
+        };
+        let stats = shard_bigrams(input_path, &word_ids, &options, shard_dir, num_shards)?;
+        manifest.save(shard_dir)?;
+        println!("  Total bigrams emitted: {}", stats.bigrams);
+        if stats.malformed_lines > 0 {
+            println!(
+                "  Malformed UTF-8 lines (decoded lossily): {}",
+                stats.malformed_lines
+            );
+        }
+    }
+
+    // Steps 3 and 4: reduce shards to top-N per prev, writing each shard's
+    // rows as soon as they are final instead of holding every edge
     println!(
-        "  Unique prev_ids with edges: {}",
-        index.iter().filter(|e| e.len > 0).count()
+        "\n[3/4] Reducing shards to top-{} per prev...\n[4/4] Writing en.bigram.bin as rows are reduced...",
+        top_n
     );
-    println!("  Total edges: {}", edges.len());
-
-    // Step 4: Write binary file
-    println!("\n[4/4] Writing en.bigram.bin...");
-    write_bigram_bin("en.bigram.bin", vocab_size, top_n as u32, &index, &edges)?;
+    let file = BufWriter::new(File::create("en.bigram.bin")?);
+    let mut writer = BigramWriter::new(
+        file,
+        VocabUnit::Syllable,
+        vocab_size,
+        vocab_hash,
+        top_n as u32,
+        raw_counts,
+    )?;
+    reduce_shards_into(shard_dir, num_shards, top_n, max_ram, &mut writer)?;
+    let edges_count = writer.edges_count();
+    println!("  Unique prev_ids with edges: {}", writer.rows());
+    println!("  Total edges: {}", edges_count);
+    writer.finish()?;
 
     // Cleanup shards
-    std::fs::remove_dir_all(shard_dir)?;
+    shard_guard.remove()?;
 
     let file_size = std::fs::metadata("en.bigram.bin")?.len();
     println!(
         "\n✓ en.bigram.bin created ({:.2} MB)",
         file_size as f64 / 1_000_000.0
     );
-    println!("  Header: 32 bytes");
+    println!("  Header: {} bytes", HEADER_SIZE);
     println!(
         "  Index: {} entries × 8 bytes = {} bytes",
         vocab_size,
@@ -109,10 +224,19 @@ fn main() -> Result<()> {
     );
     println!(
         "  Edges: {} entries × 8 bytes = {} bytes",
-        edges.len(),
-        edges.len() * 8
+        edges_count,
+        edges_count * 8
     );
 
+    if validate {
+        println!("\nValidating en.bigram.bin...");
+        let report = validate_bigram_file("en.bigram.bin")?;
+        println!(
+            "  ✓ All invariants hold ({} prevs, {} edges)",
+            report.vocab_size, report.edges_count
+        );
+    }
+
     Ok(())
 }
 
@@ -125,13 +249,13 @@ fn parse_arg(args: &[String], flag: &str) -> Option<usize> {
 
 /// Build canonical lowercase -> best word_id map
 /// "best" = highest prob_q among all case variants
-fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, HashMap<String, u32>)> {
+fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, FastMap<String, u32>)> {
     let file = File::open(fst_path).context("Failed to open FST")?;
     let mmap = unsafe { Mmap::map(&file)? };
     let fst = Map::new(mmap)?;
 
     let vocab_file = BufReader::new(File::open(vocab_path)?);
-    let mut canonical: HashMap<String, (u32, u8)> = HashMap::new(); // lower -> (best_id, best_prob)
+    let mut canonical: FastMap<String, (u32, u8)> = FastMap::default(); // lower -> (best_id, best_prob)
     let mut vocab_size: u32 = 0;
 
     for line in vocab_file.lines() {
@@ -141,7 +265,7 @@ fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, HashMap
         if let Some(v) = fst.get(&word) {
             let word_id = ((v >> 16) & 0xFFFF_FFFF) as u32;
             let prob = (v & 0xFF) as u8;
-            let lower = word.to_lowercase();
+            let lower = tokenize::fold_apostrophes(&word).to_lowercase();
 
             canonical
                 .entry(lower)
@@ -156,225 +280,6 @@ fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, HashMap
     }
 
     // Convert to simple id map
-    let map: HashMap<String, u32> = canonical.into_iter().map(|(k, (id, _))| (k, id)).collect();
+    let map: FastMap<String, u32> = canonical.into_iter().map(|(k, (id, _))| (k, id)).collect();
     Ok((vocab_size, map))
 }
-
-/// Emit bigrams to shard files: shard[prev_id % S] gets (prev_id, next_id)
-fn shard_bigrams(
-    input_path: &str,
-    canonical: &HashMap<String, u32>,
-    shard_dir: &Path,
-    num_shards: usize,
-) -> Result<u64> {
-    // Open shard files
-    let mut shards: Vec<BufWriter<File>> = (0..num_shards)
-        .map(|i| {
-            let path = shard_dir.join(format!("shard_{:03}.bin", i));
-            BufWriter::new(File::create(path).unwrap())
-        })
-        .collect();
-
-    let file = File::open(input_path)?;
-    let reader: Box<dyn BufRead> = if input_path.ends_with(".gz") {
-        Box::new(BufReader::with_capacity(1 << 20, GzDecoder::new(file)))
-    } else {
-        Box::new(BufReader::with_capacity(1 << 20, file))
-    };
-
-    let mut lines_processed = 0u64;
-    let mut bigrams_emitted = 0u64;
-    let mut prev_id: Option<u32> = None;
-
-    for line in reader.lines() {
-        let line = line?;
-        lines_processed += 1;
-
-        if lines_processed % 5_000_000 == 0 {
-            println!(
-                "  {} M lines, {} M bigrams",
-                lines_processed / 1_000_000,
-                bigrams_emitted / 1_000_000
-            );
-        }
-
-        for word in line.split_whitespace() {
-            let normalized = normalize_token(word);
-            if normalized.is_empty() {
-                prev_id = None;
-                continue;
-            }
-
-            if let Some(&word_id) = canonical.get(&normalized) {
-                if let Some(prev) = prev_id {
-                    // Emit to shard
-                    let shard_idx = (prev as usize) % num_shards;
-                    shards[shard_idx].write_all(&prev.to_le_bytes())?;
-                    shards[shard_idx].write_all(&word_id.to_le_bytes())?;
-                    bigrams_emitted += 1;
-                }
-                prev_id = Some(word_id);
-            } else {
-                prev_id = None;
-            }
-        }
-        prev_id = None; // End of line breaks chain
-    }
-
-    // Flush all shards
-    for mut shard in shards {
-        shard.flush()?;
-    }
-
-    Ok(bigrams_emitted)
-}
-
-fn normalize_token(word: &str) -> String {
-    word.to_lowercase()
-        .chars()
-        .filter(|c| c.is_alphabetic() || *c == '\'')
-        .collect()
-}
-
-/// Reduce shards: sort, count, top-N per prev
-fn reduce_shards(
-    shard_dir: &Path,
-    num_shards: usize,
-    vocab_size: u32,
-    top_n: usize,
-) -> Result<(Vec<IndexEntry>, Vec<Edge>)> {
-    // Per-prev aggregation using external sort approach per shard
-    let mut all_edges: Vec<Vec<(u32, u64)>> = vec![Vec::new(); vocab_size as usize];
-
-    for shard_idx in 0..num_shards {
-        let path = shard_dir.join(format!("shard_{:03}.bin", shard_idx));
-        let mut file = File::open(&path)?;
-        let file_len = file.metadata()?.len();
-
-        if file_len == 0 {
-            continue;
-        }
-
-        // Read entire shard into memory (each shard is ~1/256 of data)
-        let mut buf = vec![0u8; file_len as usize];
-        file.read_exact(&mut buf)?;
-
-        // Parse pairs and count
-        let mut shard_counts: HashMap<(u32, u32), u64> = HashMap::new();
-        for chunk in buf.chunks_exact(8) {
-            let prev = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
-            let next = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
-            *shard_counts.entry((prev, next)).or_insert(0) += 1;
-        }
-
-        // Merge into global per-prev lists
-        for ((prev, next), count) in shard_counts {
-            if (prev as usize) < all_edges.len() {
-                all_edges[prev as usize].push((next, count));
-            }
-        }
-
-        if (shard_idx + 1) % 32 == 0 {
-            println!("  Processed {}/{} shards", shard_idx + 1, num_shards);
-        }
-    }
-
-    // Build index and edges arrays
-    let mut index: Vec<IndexEntry> = Vec::with_capacity(vocab_size as usize);
-    let mut edges: Vec<Edge> = Vec::new();
-
-    for edges_for_prev in all_edges {
-        let offset = (edges.len() * 8) as u32;
-
-        if edges_for_prev.is_empty() {
-            index.push(IndexEntry {
-                offset,
-                len: 0,
-                reserved: 0,
-            });
-            continue;
-        }
-
-        // Sort by count descending, take top-N
-        let mut sorted = edges_for_prev;
-        sorted.sort_by(|a, b| b.1.cmp(&a.1));
-        sorted.truncate(top_n);
-
-        // Quantize weights: log-scale to 0-65535
-        let max_count = sorted.first().map(|(_, c)| *c).unwrap_or(1);
-
-        for (next_id, count) in &sorted {
-            let weight = quantize_weight(*count, max_count);
-            edges.push(Edge {
-                next_id: *next_id,
-                weight,
-                flags: 0,
-            });
-        }
-
-        index.push(IndexEntry {
-            offset,
-            len: sorted.len() as u16,
-            reserved: 0,
-        });
-    }
-
-    Ok((index, edges))
-}
-
-/// Quantize count to 16-bit weight using log scale
-fn quantize_weight(count: u64, max_count: u64) -> u16 {
-    if count == 0 || max_count == 0 {
-        return 0;
-    }
-    // Relative weight: (count / max_count) scaled to 0-65535
-    // Use log scale for better distribution
-    let ratio = (count as f64).ln() / (max_count as f64).ln().max(1.0);
-    (ratio.clamp(0.0, 1.0) * 65535.0) as u16
-}
-
-/// Write binary file with header + index + edges
-fn write_bigram_bin(
-    path: &str,
-    vocab_size: u32,
-    top_n: u32,
-    index: &[IndexEntry],
-    edges: &[Edge],
-) -> Result<()> {
-    let mut file = BufWriter::new(File::create(path)?);
-
-    // Write header
-    let header = Header {
-        magic: MAGIC,
-        version: VERSION,
-        vocab_size,
-        edges_count: edges.len() as u32,
-        top_n,
-        reserved: [0; 3],
-    };
-
-    unsafe {
-        let header_bytes = std::slice::from_raw_parts(
-            &header as *const Header as *const u8,
-            std::mem::size_of::<Header>(),
-        );
-        file.write_all(header_bytes)?;
-    }
-
-    // Write index
-    for entry in index {
-        file.write_all(&entry.offset.to_le_bytes())?;
-        file.write_all(&entry.len.to_le_bytes())?;
-        file.write_all(&entry.reserved.to_le_bytes())?;
-    }
-
-    // Write edges
-    for edge in edges {
-        file.write_all(&edge.next_id.to_le_bytes())?;
-        file.write_all(&edge.weight.to_le_bytes())?;
-        file.write_all(&edge.flags.to_le_bytes())?;
-    }
-
-    file.flush()?;
-    Ok(())
-}
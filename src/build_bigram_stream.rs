@@ -1,217 +1,372 @@
 //! Streaming bigram builder - single pass, RAM-constrained
 //!
-//! Keeps only top-N candidates per prev_id in RAM using count-min sketch
-//! for approximate counting + min-heap for top-N tracking.
+//! By default counts are exact per prev_id, pruned to the best 2N once a prev
+//! has more than 100N candidates, so memory grows with prevs × fan-out.
+//! `--sketch width:depth` instead keeps pair counts in a fixed-size count-min
+//! sketch and only a bounded candidate list (≤ 4N) per prev, ranked by sketch
+//! estimates. See `combined2fst::sketch` for the error bounds.
 //!
 //! Trade-off: Less accurate than full count, but fits in memory.
 //!
+//! Multiple corpora can be mixed with `--corpus path:weight`; each bigram
+//! observed in a source adds that source's weight (relative to the lightest
+//! source) to its count, so a small in-domain corpus isn't drowned out.
+//!
+//! Corpora may be gzip (.gz), zstd (.zst), bzip2 (.bz2) or plain text.
+//! Lines that aren't valid UTF-8 are decoded lossily and counted;
+//! `--strict-utf8` fails on them instead (see `combined2fst::corpus`).
+//!
+//! `--case-sensitive` keys edges on exact FST word_ids instead of canonical
+//! lowercase ids (see `combined2fst::builder` for what that means at lookup).
+//!
+//! `--rich-tokens` keeps digits and hyphens inside words and maps numbers
+//! and links to placeholders (see `combined2fst::tokenize`).
+//!
+//! `--pretokenized` trusts the whitespace split of an already-segmented
+//! corpus and looks tokens up unnormalized, so they must already match the
+//! vocab keys (see `combined2fst::tokenize`).
+//!
+//! `--progress` reports throughput and, across all corpora, an ETA (see
+//! `combined2fst::progress`); with `--limit` the ETA is for the full files.
+//!
+//! `--raw-counts` stores each edge's u32 count instead of a quantized weight,
+//! for research use (see `combined2fst::bigram` for the flag readers check).
+//!
+//! `--cross-sentence` flags edges mostly seen across a sentence end
+//! ("end. The") with `EDGE_FLAG_CROSS_SENTENCE`, so a suggester can weight
+//! them down. Raw-counts files have no room for edge flags.
+//!
+//! `--cross-sentence-weight w` (0 < w <= 1) keeps the chain going across
+//! line ends and OOV tokens instead of breaking it there: an edge across
+//! one counts `w` of an edge inside a line, and edges mostly seen that way
+//! are flagged as with `--cross-sentence` (which it implies). For chat logs
+//! and other corpora where a short line often continues the last one.
+//!
+//! `--no-topn` keeps every observed (prev, next) pair instead of the top N,
+//! for research use: rows are as long as the data makes them, up to the
+//! `MAX_ROW_LEN` (65535) a row's u16 length allows, and the header records
+//! that as `top_n`. Nothing is pruned while counting, so memory and the
+//! file grow with the number of distinct pairs; expect both to be many
+//! times a keyboard model's. Not with `--sketch`, which only tracks a
+//! bounded candidate list.
+//!
+//! `--max-edges M` caps the whole file at M edges, for a hard size budget
+//! (8 bytes an edge, plus the index): after the top-N cut, the
+//! lowest-weight edges across all prevs are trimmed until M remain, each
+//! prev keeping its top edge while M allows (see
+//! `combined2fst::builder::cap_total_edges`).
+//!
+//! `--dry-run` runs the counting pass (capped by `--limit` if given) and
+//! prints what the model would hold - edges, coverage, row lengths and the
+//! file size - without writing `en.bigram.bin`.
+//!
+//! `--sample-rate R` (0 < R <= 1) counts only about R of each corpus's
+//! lines, picked by a hash of the line number so every run keeps the same
+//! ones (see `combined2fst::corpus::LineSampler`). For trying out build
+//! parameters on a huge corpus: absolute counts shrink by about R and pairs
+//! rarer than 1/R may vanish, but the top-N rows come out close to a full
+//! build's. `--limit` counts lines read, sampled or not.
+//!
+//! `--validate` re-reads the written file with `validate_bigram` and fails
+//! the build if any format invariant is violated.
+//!
 //! Usage:
 //!   cargo run --release --bin build_bigram_stream -- <corpus.txt.gz> [--top N]
-
-use anyhow::{Context, Result};
-use flate2::read::GzDecoder;
+//!   cargo run --release --bin build_bigram_stream -- --corpus web.gz:1 --corpus chat.txt:20
+
+use anyhow::{bail, Context, Result};
+use combined2fst::bigram::{write_bigram, BigramStats, EdgeValues, MAX_ROW_LEN};
+use combined2fst::builder::{cap_total_edges, quantize_rows, BigramCounter, FlaggedEntry};
+use combined2fst::corpus::{weight_increments, CorpusLines, CorpusSource, LineSampler, Utf8Policy};
+use combined2fst::progress::{file_size, Progress};
+use combined2fst::sketch::CountMinSketch;
+use combined2fst::tokenize::{self, Normalizer};
+use combined2fst::{build_exact_map, open_corpus_counted, validate_bigram_file, FastMap, Vocab};
 use fst::Map;
 use memmap2::Mmap;
-use std::cmp::Reverse;
-use std::collections::{BinaryHeap, HashMap};
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-
-// Binary format constants
-const MAGIC: u32 = 0x4247524D; // "BGRM"
-const VERSION: u32 = 1;
-
-/// TopN tracker using exact counting with pruning
-/// Prunes when entry count exceeds threshold
-struct TopNTracker {
-    counts: HashMap<u32, u64>, // next_id -> count
-    top_n: usize,
-    prune_threshold: usize, // prune when len > this
-}
-
-impl TopNTracker {
-    fn new(top_n: usize) -> Self {
-        Self {
-            counts: HashMap::new(),
-            top_n,
-            prune_threshold: top_n * 100, // keep 100x candidates before pruning
-        }
-    }
-
-    fn add(&mut self, next_id: u32) {
-        *self.counts.entry(next_id).or_insert(0) += 1;
-
-        if self.counts.len() > self.prune_threshold {
-            self.prune();
-        }
-    }
-
-    fn prune(&mut self) {
-        if self.counts.len() <= self.top_n * 2 {
-            return;
-        }
-
-        // Keep top 2*N by count
-        let mut items: Vec<_> = self.counts.drain().collect();
-        items.sort_by(|a, b| b.1.cmp(&a.1));
-        items.truncate(self.top_n * 2);
-
-        self.counts = items.into_iter().collect();
-    }
-
-    fn finalize(mut self) -> Vec<(u32, u64)> {
-        let mut items: Vec<_> = self.counts.into_iter().collect();
-        items.sort_by(|a, b| b.1.cmp(&a.1));
-        items.truncate(self.top_n);
-        items
-    }
-}
+use std::io::{BufRead, BufReader, BufWriter};
 
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: {} <input.txt.gz> [--top N] [--limit M]", args[0]);
+        eprintln!(
+            "Usage: {} [input.txt.gz] [--corpus path:weight]... [--top N] [--limit M] [--sketch W:D] [--case-sensitive] [--rich-tokens | --pretokenized] [--raw-counts] [--no-topn] [--max-edges M] [--cross-sentence] [--strict-utf8] [--sample-rate R] [--progress] [--dry-run]",
+            args[0]
+        );
+        eprintln!("  --corpus P:W : Add corpus P with count multiplier W (repeatable)");
         eprintln!("  --top N    : Keep top N next words per prev (default: 10)");
-        eprintln!("  --limit M  : Process only first M million lines (default: all)");
+        eprintln!("  --limit M  : Process only first M million lines per corpus (default: all)");
+        eprintln!("  --sketch W:D : Count pairs in a W x D count-min sketch (bounded memory)");
+        eprintln!("  --case-sensitive : Keep case variants on separate rows (exact word_ids)");
+        eprintln!("  --rich-tokens : Keep digits and hyphens in words, emit <NUM>/<URL>");
+        eprintln!("  --pretokenized : Look tokens up as split, without normalizing them");
+        eprintln!("  --raw-counts : Store u32 counts instead of quantized weights");
+        eprintln!("  --no-topn  : Keep every observed edge, not just the top N (large!)");
+        eprintln!("  --max-edges M : Trim the weakest edges until at most M remain in total");
+        eprintln!("  --cross-sentence : Flag edges mostly seen across a sentence end");
+        eprintln!(
+            "  --cross-sentence-weight W : Chain across line ends and OOVs at weight W, flagged"
+        );
+        eprintln!(
+            "  --strict-utf8 : Fail on lines that aren't valid UTF-8 (default: decode lossily)"
+        );
+        eprintln!("  --sample-rate R : Count only about R of the lines (deterministic)");
+        eprintln!("  --progress : Report lines/s, bigrams/s and an ETA every 10s");
+        eprintln!("  --dry-run  : Print the model's stats instead of writing it");
+        eprintln!("  --validate : Check the written file's format invariants");
         std::process::exit(1);
     }
 
-    let input_path = &args[1];
-    let top_n: usize = parse_arg(&args, "--top").unwrap_or(10);
+    let sources = parse_sources(&args)?;
+    let no_topn = args.iter().any(|a| a == "--no-topn");
+    let top_n: usize = if no_topn {
+        MAX_ROW_LEN
+    } else {
+        parse_arg(&args, "--top").unwrap_or(10)
+    };
     let limit_m: Option<usize> = parse_arg(&args, "--limit");
+    let max_edges: Option<usize> = parse_arg(&args, "--max-edges");
+    let increments = weight_increments(&sources);
+    let case_sensitive = args.iter().any(|a| a == "--case-sensitive");
+    let normalizer = tokenize::from_flags(
+        args.iter().any(|a| a == "--rich-tokens"),
+        args.iter().any(|a| a == "--pretokenized"),
+    )?;
+    let show_progress = args.iter().any(|a| a == "--progress");
+    let raw_counts = args.iter().any(|a| a == "--raw-counts");
+    let cross_weight: Option<f64> = match args.iter().position(|a| a == "--cross-sentence-weight") {
+        Some(i) => {
+            let w: f64 = args
+                .get(i + 1)
+                .context("--cross-sentence-weight needs a value")?
+                .parse()
+                .context("--cross-sentence-weight must be a number")?;
+            if !(w > 0.0 && w <= 1.0) {
+                bail!("--cross-sentence-weight must be in (0, 1], got {}", w);
+            }
+            Some(w)
+        }
+        None => None,
+    };
+    let cross_sentence = args.iter().any(|a| a == "--cross-sentence") || cross_weight.is_some();
+    let sample_rate: Option<f64> = match args.iter().position(|a| a == "--sample-rate") {
+        Some(i) => Some(
+            args.get(i + 1)
+                .context("--sample-rate needs a value")?
+                .parse()
+                .context("--sample-rate must be a number")?,
+        ),
+        None => None,
+    };
+    let sampler = sample_rate.map(LineSampler::new).transpose()?;
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let validate = args.iter().any(|a| a == "--validate");
+    if raw_counts && cross_sentence {
+        bail!("--cross-sentence needs weight edges; raw-counts files can't carry edge flags");
+    }
+    let utf8 = if args.iter().any(|a| a == "--strict-utf8") {
+        Utf8Policy::Strict
+    } else {
+        Utf8Policy::Lossy
+    };
+    let sketch = match args.iter().position(|a| a == "--sketch") {
+        Some(i) => Some(CountMinSketch::parse(
+            args.get(i + 1).context("--sketch needs a value")?,
+        )?),
+        None => None,
+    };
+    if no_topn && sketch.is_some() {
+        bail!("--no-topn keeps every edge; --sketch only tracks a bounded candidate list");
+    }
 
     println!("=== Streaming Bigram Builder ===");
-    println!("Input: {}", input_path);
-    println!("Top-N: {}", top_n);
+    for (source, inc) in sources.iter().zip(&increments) {
+        println!(
+            "Input: {} (weight {}, increment {})",
+            source.path, source.weight, inc
+        );
+    }
+    if no_topn {
+        println!("Top-N: all observed edges (up to {} per prev)", MAX_ROW_LEN);
+        println!(
+            "  Warning: --no-topn keeps every distinct pair; RAM and en.bigram.bin can be many times a top-N build's"
+        );
+    } else {
+        println!("Top-N: {}", top_n);
+    }
     if let Some(m) = limit_m {
         println!("Limit: {} million lines", m);
     }
+    if case_sensitive {
+        println!("Case-sensitive: edges keyed on exact word_ids");
+    }
+    println!("Tokenizer: {}", normalizer.name());
+    if raw_counts {
+        println!("Raw counts: edges store u32 counts, not weights");
+    }
+    if cross_sentence {
+        println!("Cross-sentence: flagging edges mostly seen across a sentence end");
+    }
+    if let Some(w) = cross_weight {
+        println!(
+            "Cross-boundary weight: {} (chain kept across line ends and OOVs)",
+            w
+        );
+    }
+    if let Some(rate) = sample_rate {
+        println!(
+            "Sample rate: {} (same lines every run; counts shrink by about that)",
+            rate
+        );
+    }
+    if dry_run {
+        println!("Dry run: stats only, en.bigram.bin is not written");
+    }
+    if let Some(sketch) = &sketch {
+        println!(
+            "Sketch: {} x {} ({:.1} MB)",
+            sketch.width(),
+            sketch.depth(),
+            sketch.size_bytes() as f64 / 1_000_000.0
+        );
+    }
 
-    // Step 1: Build canonical lowercase map
-    println!("\n[1/3] Building canonical lowercase map...");
-    let (vocab_size, canonical_map) = build_canonical_map("en.lex.fst", "en.vocab.txt")?;
-    println!("  Vocab size: {}", vocab_size);
-    println!("  Canonical entries: {}", canonical_map.len());
+    // Step 1: Build canonical lowercase map (or exact map)
+    let (vocab_size, word_ids) = if case_sensitive {
+        println!("\n[1/3] Building exact word map...");
+        let (vocab_size, exact) = build_exact_map("en.lex.fst", "en.vocab.txt")?;
+        (vocab_size, exact.into_iter().collect())
+    } else {
+        println!("\n[1/3] Building canonical lowercase map...");
+        build_canonical_map("en.lex.fst", "en.vocab.txt")?
+    };
+    let vocab_hash = Vocab::load("en.vocab.txt")?.fingerprint();
+    println!(
+        "  Vocab size: {} (fingerprint {:016x})",
+        vocab_size, vocab_hash
+    );
+    println!("  Mapped entries: {}", word_ids.len());
 
     // Step 2: Stream through corpus, maintain per-prev TopN trackers
     println!("\n[2/3] Streaming bigrams (single pass)...");
 
     // Per-prev tracking - only allocate when seen
-    let mut trackers: HashMap<u32, TopNTracker> = HashMap::new();
-
-    let file = File::open(input_path)?;
-    let reader: Box<dyn BufRead> = if input_path.ends_with(".gz") {
-        Box::new(BufReader::with_capacity(1 << 20, GzDecoder::new(file)))
-    } else {
-        Box::new(BufReader::with_capacity(1 << 20, file))
-    };
-
+    let mut counter = BigramCounter::new(top_n)
+        .case_sensitive(case_sensitive)
+        .mark_cross_sentence(cross_sentence);
+    if let Some(sketch) = sketch {
+        counter = counter.with_sketch(sketch);
+    }
+    if let Some(w) = cross_weight {
+        counter = counter.cross_boundary_weight(w);
+    }
+    counter = counter.normalizer(normalizer);
     let mut lines_processed = 0u64;
-    let mut bigrams_seen = 0u64;
-    let mut prev_id: Option<u32> = None;
-    let line_limit = limit_m.map(|m| m * 1_000_000);
-
-    for line in reader.lines() {
-        let line = line?;
-        lines_processed += 1;
-
-        if let Some(limit) = line_limit {
-            if lines_processed as usize > limit {
-                break;
-            }
-        }
-
-        if lines_processed % 5_000_000 == 0 {
-            println!(
-                "  {} M lines, {} M bigrams, {} active prevs",
-                lines_processed / 1_000_000,
-                bigrams_seen / 1_000_000,
-                trackers.len()
-            );
+    let mut lines_sampled = 0u64;
+    let mut malformed_lines = 0u64;
+    let line_limit = limit_m.map_or(usize::MAX, |m| m * 1_000_000);
+    let mut progress = show_progress.then(|| {
+        let total: Option<u64> = sources.iter().map(|s| file_size(&s.path)).sum();
+        Progress::new("bigrams").with_total_bytes(total)
+    });
+
+    for (source, &increment) in sources.iter().zip(&increments) {
+        let (corpus, bytes_read) = open_corpus_counted(&source.path)?;
+        if let Some(progress) = progress.as_mut() {
+            progress.track(bytes_read);
         }
-
-        for word in line.split_whitespace() {
-            let normalized = normalize_token(word);
-            if normalized.is_empty() {
-                prev_id = None;
-                continue;
+        let mut lines = CorpusLines::new(corpus, utf8);
+
+        for (line_no, line) in lines.by_ref().take(line_limit).enumerate() {
+            let line = line?;
+
+            lines_processed += 1;
+            if let Some(progress) = progress.as_mut() {
+                progress.tick(lines_processed, counter.bigrams_seen);
+            } else if lines_processed.is_multiple_of(5_000_000) {
+                println!(
+                    "  {} M lines, {} M bigrams, {} active prevs",
+                    lines_processed / 1_000_000,
+                    counter.bigrams_seen / 1_000_000,
+                    counter.trackers.len()
+                );
             }
 
-            if let Some(&word_id) = canonical_map.get(&normalized) {
-                if let Some(prev) = prev_id {
-                    trackers
-                        .entry(prev)
-                        .or_insert_with(|| TopNTracker::new(top_n))
-                        .add(word_id);
-                    bigrams_seen += 1;
-                }
-                prev_id = Some(word_id);
-            } else {
-                prev_id = None;
+            if sampler.is_some_and(|s| !s.keeps(line_no as u64)) {
+                // The lines on either side of a skipped one aren't adjacent
+                counter.break_chain();
+                continue;
             }
+            lines_sampled += 1;
+            counter.feed_line(&line, &word_ids, increment);
         }
-        prev_id = None;
+        malformed_lines += lines.malformed();
+        counter.break_chain();
     }
 
     println!(
         "\n  Total: {} lines, {} bigrams",
-        lines_processed, bigrams_seen
+        lines_processed, counter.bigrams_seen
     );
-    println!("  Unique prev_ids tracked: {}", trackers.len());
+    if sampler.is_some() {
+        println!("  Sampled: {} of {} lines", lines_sampled, lines_processed);
+    }
+    if malformed_lines > 0 {
+        println!(
+            "  Malformed UTF-8 lines (decoded lossily): {}",
+            malformed_lines
+        );
+    }
+    println!("  Unique prev_ids tracked: {}", counter.trackers.len());
 
     // Step 3: Finalize and write binary file
+    if dry_run {
+        println!("\n[3/3] Finalizing (dry run)...");
+        let (index, counts) = counter.into_counts(vocab_size as usize);
+        let index = match max_edges {
+            Some(max_edges) => cap_total_edges(&index, &counts, max_edges).0,
+            None => index,
+        };
+        print_stats(&BigramStats::from_index(&index, top_n));
+        return Ok(());
+    }
     println!("\n[3/3] Finalizing and writing en.bigram.bin...");
 
     // Build index and edges
-    let mut index: Vec<(u32, u16)> = vec![(0, 0); vocab_size as usize]; // (offset, len)
-    let mut edges: Vec<(u32, u16)> = Vec::new(); // (next_id, weight)
-
-    for (prev_id, tracker) in trackers {
-        let top_items = tracker.finalize();
-        if top_items.is_empty() {
-            continue;
-        }
-
-        let offset = edges.len() as u32;
-        let max_count = top_items.first().map(|(_, c)| *c).unwrap_or(1);
-
-        for (next_id, count) in top_items {
-            let weight = quantize_weight(count, max_count);
-            edges.push((next_id, weight));
-        }
-
-        if (prev_id as usize) < index.len() {
-            let len = (edges.len() as u32 - offset) as u16;
-            index[prev_id as usize] = (offset * 8, len); // offset in bytes
+    let (index, counts, flags) = counter.into_flagged(vocab_size as usize);
+    let (index, counts, flags) = match max_edges {
+        Some(max_edges) => {
+            let (capped, kept) = cap_total_edges(&index, &counts, max_edges);
+            println!(
+                "  Trimmed {} edges to fit --max-edges {}",
+                counts.len() - kept.len(),
+                max_edges
+            );
+            let counts = kept.iter().map(|&i| counts[i]).collect();
+            let flags = kept.iter().map(|&i| flags[i]).collect();
+            (capped, counts, flags)
         }
-    }
+        None => (index, counts, flags),
+    };
+    let weights = (!raw_counts).then(|| quantize_rows(&index, &counts));
+    let flagged: Option<Vec<FlaggedEntry>> =
+        weights.as_ref().filter(|_| cross_sentence).map(|weights| {
+            weights
+                .iter()
+                .zip(&flags)
+                .map(|(&(next_id, weight), &flags)| (next_id, weight, flags))
+                .collect()
+        });
+    let edges = match (&flagged, &weights) {
+        (Some(flagged), _) => EdgeValues::Flagged(flagged),
+        (None, Some(weights)) => EdgeValues::Weights(weights),
+        (None, None) => EdgeValues::RawCounts(&counts),
+    };
 
     // Write file
-    let mut file = BufWriter::new(File::create("en.bigram.bin")?);
-
-    // Header (32 bytes)
-    file.write_all(&MAGIC.to_le_bytes())?;
-    file.write_all(&VERSION.to_le_bytes())?;
-    file.write_all(&vocab_size.to_le_bytes())?;
-    file.write_all(&(edges.len() as u32).to_le_bytes())?;
-    file.write_all(&(top_n as u32).to_le_bytes())?;
-    file.write_all(&[0u8; 12])?; // reserved
-
-    // Index (8 bytes per entry)
-    for (offset, len) in &index {
-        file.write_all(&offset.to_le_bytes())?;
-        file.write_all(&len.to_le_bytes())?;
-        file.write_all(&[0u8; 2])?; // reserved
-    }
-
-    // Edges (8 bytes per entry)
-    for (next_id, weight) in &edges {
-        file.write_all(&next_id.to_le_bytes())?;
-        file.write_all(&weight.to_le_bytes())?;
-        file.write_all(&[0u8; 2])?; // flags
-    }
-
-    file.flush()?;
+    let file = BufWriter::new(File::create("en.bigram.bin")?);
+    write_bigram(file, vocab_size, vocab_hash, top_n as u32, &index, edges)?;
 
     let file_size = std::fs::metadata("en.bigram.bin")?.len();
     println!(
@@ -220,13 +375,89 @@ fn main() -> Result<()> {
     );
     println!(
         "  Vocab entries with bigrams: {}",
-        index.iter().filter(|(_, len)| *len > 0).count()
+        index.iter().filter(|(_, len, _)| *len > 0).count()
     );
     println!("  Total edges: {}", edges.len());
+    if no_topn {
+        let capped = index
+            .iter()
+            .filter(|&&(_, len, _)| len as usize == MAX_ROW_LEN)
+            .count();
+        if capped > 0 {
+            println!(
+                "  Warning: {} prevs hit the {}-edge row cap; only their top edges are kept",
+                capped, MAX_ROW_LEN
+            );
+        }
+    }
+    if cross_sentence {
+        println!(
+            "  Cross-sentence edges: {}",
+            flags.iter().filter(|&&f| f != 0).count()
+        );
+    }
+
+    if validate {
+        println!("\nValidating en.bigram.bin...");
+        let report = validate_bigram_file("en.bigram.bin")?;
+        println!(
+            "  ✓ All invariants hold ({} prevs, {} edges)",
+            report.vocab_size, report.edges_count
+        );
+    }
 
     Ok(())
 }
 
+/// What a dry run would have written
+fn print_stats(stats: &BigramStats) {
+    println!("  Total edges: {}", stats.edges_count);
+    println!(
+        "  Vocab entries with bigrams: {}/{} ({:.1}%)",
+        stats.non_empty_prevs,
+        stats.vocab_size,
+        stats.coverage_ratio * 100.0
+    );
+    println!(
+        "  Edges per prev (P10/median/P90): {}/{}/{}",
+        stats.p10_len, stats.median_len, stats.p90_len
+    );
+    println!("  Length histogram:");
+    for (len, count) in stats.len_histogram.iter().enumerate() {
+        if *count > 0 && len > 0 {
+            println!("    len={:2}: {}", len, count);
+        }
+    }
+    println!(
+        "  Projected en.bigram.bin: {:.2} MB",
+        stats.file_size() as f64 / 1_000_000.0
+    );
+}
+
+/// Collect `--corpus path:weight` specs; a leading positional path counts as weight 1
+fn parse_sources(args: &[String]) -> Result<Vec<CorpusSource>> {
+    let mut sources = Vec::new();
+    if !args[1].starts_with("--") {
+        sources.push(CorpusSource {
+            path: args[1].clone(),
+            weight: 1.0,
+        });
+    }
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--corpus" {
+            let spec = args.get(i + 1).context("--corpus needs a value")?;
+            sources.push(CorpusSource::parse(spec)?);
+            i += 1;
+        }
+        i += 1;
+    }
+    if sources.is_empty() {
+        bail!("no input corpus given");
+    }
+    Ok(sources)
+}
+
 fn parse_arg(args: &[String], flag: &str) -> Option<usize> {
     args.iter()
         .position(|a| a == flag)
@@ -234,13 +465,13 @@ fn parse_arg(args: &[String], flag: &str) -> Option<usize> {
         .and_then(|s| s.parse().ok())
 }
 
-fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, HashMap<String, u32>)> {
+fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, FastMap<String, u32>)> {
     let file = File::open(fst_path).context("Failed to open FST")?;
     let mmap = unsafe { Mmap::map(&file)? };
     let fst = Map::new(mmap)?;
 
     let vocab_file = BufReader::new(File::open(vocab_path)?);
-    let mut canonical: HashMap<String, (u32, u8)> = HashMap::new();
+    let mut canonical: FastMap<String, (u32, u8)> = FastMap::default();
     let mut vocab_size: u32 = 0;
 
     for line in vocab_file.lines() {
@@ -250,7 +481,7 @@ fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, HashMap
         if let Some(v) = fst.get(&word) {
             let word_id = ((v >> 16) & 0xFFFF_FFFF) as u32;
             let prob = (v & 0xFF) as u8;
-            let lower = word.to_lowercase();
+            let lower = tokenize::fold_apostrophes(&word).to_lowercase();
 
             canonical
                 .entry(lower)
@@ -264,21 +495,6 @@ fn build_canonical_map(fst_path: &str, vocab_path: &str) -> Result<(u32, HashMap
         }
     }
 
-    let map: HashMap<String, u32> = canonical.into_iter().map(|(k, (id, _))| (k, id)).collect();
+    let map: FastMap<String, u32> = canonical.into_iter().map(|(k, (id, _))| (k, id)).collect();
     Ok((vocab_size, map))
 }
-
-fn normalize_token(word: &str) -> String {
-    word.to_lowercase()
-        .chars()
-        .filter(|c| c.is_alphabetic() || *c == '\'')
-        .collect()
-}
-
-fn quantize_weight(count: u64, max_count: u64) -> u16 {
-    if count == 0 || max_count == 0 {
-        return 0;
-    }
-    let ratio = (count as f64).ln() / (max_count as f64).ln().max(1.0);
-    (ratio.clamp(0.0, 1.0) * 65535.0) as u16
-}
@@ -0,0 +1,90 @@
+//! Build the English lexicon FST from a plain frequency list
+//!
+//! Input lines are `word<TAB>count` (gzip, zstd, bzip2 or plain text);
+//! `#` comments and lines that don't parse are skipped, and a word listed
+//! twice has its counts summed. Counts are quantized to the prob byte on a
+//! log scale against the largest count, and the FST and vocab are written
+//! with the same packing as `combined2fst` (see `combined2fst::lexicon`).
+//!
+//! `--keep-ids <old.vocab.txt>` keeps the ids of an earlier build, so
+//! bigram and trigram models built against it stay valid for the words it
+//! knew; new words get ids after them.
+//!
+//! Usage: cargo run --release --bin build_en_fst -- <words.tsv> [out.lex.fst] [out.vocab.txt] [--keep-ids old.vocab.txt]
+
+use anyhow::{Context, Result};
+use combined2fst::corpus::{CorpusLines, Utf8Policy};
+use combined2fst::lexicon::{
+    assign_ids, load_previous_vocab, parse_frequency_line, quantize_prob, write_lexicon_with_ids,
+};
+use combined2fst::open_corpus;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+
+fn main() -> Result<()> {
+    let mut args: Vec<String> = std::env::args().collect();
+    let keep_ids = match args.iter().position(|a| a == "--keep-ids") {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..i + 2).nth(1).unwrap()),
+        _ => None,
+    };
+    if args.len() < 2 {
+        eprintln!(
+            "Usage: {} <words.tsv> [out.lex.fst] [out.vocab.txt] [--keep-ids old.vocab.txt]",
+            args[0]
+        );
+        eprintln!("  Input lines: word<TAB>count");
+        eprintln!("  --keep-ids : Words of the old vocab keep their ids; new words are appended");
+        eprintln!("  Defaults: en.lex.fst, en.vocab.txt");
+        std::process::exit(1);
+    }
+
+    let input_path = &args[1];
+    let out_fst = args.get(2).map_or("en.lex.fst", |s| s.as_str());
+    let out_vocab = args.get(3).map_or("en.vocab.txt", |s| s.as_str());
+
+    println!("=== English Lexicon FST Builder ===");
+    println!("Input: {}", input_path);
+
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    let mut skipped = 0u64;
+    for line in CorpusLines::new(open_corpus(input_path)?, Utf8Policy::Strict) {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_frequency_line(&line) {
+            Some((word, count)) => *counts.entry(word.to_string()).or_insert(0) += count,
+            None => skipped += 1,
+        }
+    }
+    println!("  Words: {}", counts.len());
+    if skipped > 0 {
+        println!("  Skipped {} lines without word<TAB>count", skipped);
+    }
+
+    let max_count = counts.values().copied().max().unwrap_or(0);
+    let words: BTreeMap<String, u8> = counts
+        .into_iter()
+        .map(|(word, count)| (word, quantize_prob(count, max_count)))
+        .collect();
+
+    // Read before the output is created: it may be the same file
+    let previous = keep_ids.as_deref().map(load_previous_vocab).transpose()?;
+    let ids = assign_ids(&words, previous.as_ref());
+    if let Some(previous) = &previous {
+        println!(
+            "  Kept {} ids, appended {} new words",
+            previous.len(),
+            ids.len() - previous.len()
+        );
+    }
+
+    let fst = BufWriter::new(File::create(out_fst).with_context(|| format!("create {}", out_fst))?);
+    let vocab =
+        BufWriter::new(File::create(out_vocab).with_context(|| format!("create {}", out_vocab))?);
+    write_lexicon_with_ids(&words, &ids, fst, Some(vocab))?;
+
+    println!("\n✓ {} and {} created", out_fst, out_vocab);
+    Ok(())
+}
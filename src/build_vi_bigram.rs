@@ -1,21 +1,40 @@
-//! Vietnamese bigram builder using syllable-based approach
+//! Vietnamese bigram builder, over syllables or over phrases
 //!
-//! Usage: cargo run --release --bin build_vi_bigram -- <corpus.txt.gz> [--top N]
-
-use anyhow::{Context, Result};
-use flate2::read::GzDecoder;
+//! `--unit syllable` (the default) keys edges on `vi.syllable.fst` ids, one
+//! per whitespace token. `--unit phrase` keys them on `vi.phrase.fst` ids
+//! instead: each line is split into phrases by longest match against the
+//! phrase vocab ("xin chào các bạn" -> "xin chào", "các bạn"), and a token
+//! no phrase starts with breaks the chain. The unit is recorded in the
+//! header (`bigram::VocabUnit`), so a loader can pick the vocab whose ids
+//! the model uses.
+//!
+//! `--progress` reports throughput and an ETA (see `combined2fst::progress`).
+//!
+//! Syllables are lowercased before the lookup; `--pretokenized` looks them
+//! up exactly as split instead, so they must already be lowercase like the
+//! syllable vocab (see `combined2fst::tokenize`).
+//!
+//! `--validate` re-reads the written file with `validate_bigram` and fails
+//! the build if any format invariant is violated.
+//!
+//! Usage: cargo run --release --bin build_vi_bigram -- <corpus.txt.gz> [--top N] [--unit syllable|phrase] [--pretokenized] [--strict-utf8] [--progress] [--validate]
+
+use anyhow::{bail, Context, Result};
+use combined2fst::bigram::{write_bigram_with_unit, EdgeValues, VocabUnit};
+use combined2fst::builder::{encode_log_max, quantize_weight};
+use combined2fst::corpus::{CorpusLines, Utf8Policy};
+use combined2fst::progress::{file_size, Progress};
+use combined2fst::tokenize::fold_apostrophes;
+use combined2fst::{open_corpus_counted, validate_bigram_file, FastMap, Vocab};
 use fst::Map;
 use memmap2::Mmap;
-use std::collections::HashMap;
+use std::cmp::Reverse;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-
-const MAGIC: u32 = 0x4247524D; // "BGRM"
-const VERSION: u32 = 1;
+use std::io::BufWriter;
 
 /// TopN tracker with pruning
 struct TopNTracker {
-    counts: HashMap<u32, u64>,
+    counts: FastMap<u32, u64>,
     top_n: usize,
     prune_threshold: usize,
 }
@@ -23,7 +42,7 @@ struct TopNTracker {
 impl TopNTracker {
     fn new(top_n: usize) -> Self {
         Self {
-            counts: HashMap::new(),
+            counts: FastMap::default(),
             top_n,
             prune_threshold: top_n * 100,
         }
@@ -41,14 +60,14 @@ impl TopNTracker {
             return;
         }
         let mut items: Vec<_> = self.counts.drain().collect();
-        items.sort_by(|a, b| b.1.cmp(&a.1));
+        items.sort_by_key(|&(next_id, count)| (Reverse(count), next_id));
         items.truncate(self.top_n * 2);
         self.counts = items.into_iter().collect();
     }
 
     fn finalize(self) -> Vec<(u32, u64)> {
         let mut items: Vec<_> = self.counts.into_iter().collect();
-        items.sort_by(|a, b| b.1.cmp(&a.1));
+        items.sort_by_key(|&(next_id, count)| (Reverse(count), next_id));
         items.truncate(self.top_n);
         items
     }
@@ -57,7 +76,10 @@ impl TopNTracker {
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: {} <corpus.txt.gz> [--top N]", args[0]);
+        eprintln!(
+            "Usage: {} <corpus.txt.gz> [--top N] [--unit syllable|phrase] [--pretokenized] [--strict-utf8] [--progress] [--validate]",
+            args[0]
+        );
         std::process::exit(1);
     }
 
@@ -68,38 +90,75 @@ fn main() -> Result<()> {
         .and_then(|i| args.get(i + 1))
         .and_then(|s| s.parse().ok())
         .unwrap_or(10);
+    let unit = match args.iter().position(|a| a == "--unit") {
+        Some(i) => {
+            let name = args.get(i + 1).context("--unit needs a value")?;
+            match VocabUnit::from_name(name) {
+                Some(unit) => unit,
+                None => bail!("--unit must be syllable or phrase, not {:?}", name),
+            }
+        }
+        None => VocabUnit::Syllable,
+    };
+    let show_progress = args.iter().any(|a| a == "--progress");
+    let pretokenized = args.iter().any(|a| a == "--pretokenized");
+    let validate = args.iter().any(|a| a == "--validate");
+    let utf8 = if args.iter().any(|a| a == "--strict-utf8") {
+        Utf8Policy::Strict
+    } else {
+        Utf8Policy::Lossy
+    };
 
     println!("=== Vietnamese Bigram Builder ===");
     println!("Input: {}", input_path);
     println!("Top-N: {}", top_n);
+    println!("Unit: {}", unit.name());
 
-    // Load Vietnamese syllable FST
-    println!("\n[1/3] Loading vi.syllable.fst...");
-    let (vocab_size, syllable_map) = load_syllable_map("vi.syllable.fst", "vi.syllable.vocab.txt")?;
-    println!("  Vocab size: {}", vocab_size);
-    println!("  Syllables loaded: {}", syllable_map.len());
+    let (fst_path, vocab_path) = unit.vi_paths();
+    println!("\n[1/3] Loading {}...", fst_path);
+    let (vocab, unit_map) = load_unit_map(fst_path, vocab_path)?;
+    // Longest phrase, in syllables; 1 when every id is a syllable
+    let max_len = vocab
+        .iter()
+        .map(|w| w.split_whitespace().count())
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let vocab_size = vocab.len();
+    println!(
+        "  Vocab size: {} (fingerprint {:016x})",
+        vocab_size,
+        vocab.fingerprint()
+    );
+    println!("  Entries loaded: {}", unit_map.len());
+    if unit == VocabUnit::Phrase {
+        println!("  Longest phrase: {} syllables", max_len);
+    }
 
     // Stream through corpus
     println!("\n[2/3] Streaming bigrams...");
 
-    let mut trackers: HashMap<u32, TopNTracker> = HashMap::new();
+    let mut trackers: FastMap<u32, TopNTracker> = FastMap::default();
 
-    let file = File::open(input_path)?;
-    let reader: Box<dyn BufRead> = if input_path.ends_with(".gz") {
-        Box::new(BufReader::with_capacity(1 << 20, GzDecoder::new(file)))
-    } else {
-        Box::new(BufReader::with_capacity(1 << 20, file))
-    };
+    let (reader, bytes_read) = open_corpus_counted(input_path)?;
+    let mut corpus = CorpusLines::new(reader, utf8);
+    let mut progress = show_progress.then(|| {
+        let mut progress = Progress::new("bigrams").with_total_bytes(file_size(input_path));
+        progress.track(bytes_read);
+        progress
+    });
 
     let mut lines_processed = 0u64;
     let mut bigrams_seen = 0u64;
     let mut prev_id: Option<u32> = None;
 
-    for line in reader.lines() {
+    for line in &mut corpus {
         let line = line?;
         lines_processed += 1;
 
-        if lines_processed % 500_000 == 0 {
+        if let Some(progress) = progress.as_mut() {
+            progress.tick(lines_processed, bigrams_seen);
+        } else if lines_processed.is_multiple_of(500_000) {
             println!(
                 "  {} K lines, {} K bigrams, {} prevs",
                 lines_processed / 1000,
@@ -109,18 +168,29 @@ fn main() -> Result<()> {
         }
 
         // Vietnamese: split by whitespace, each token is a syllable
-        for word in line.split_whitespace() {
-            let normalized = word.to_lowercase();
-
-            if let Some(&syllable_id) = syllable_map.get(&normalized) {
+        let tokens: Vec<String> = line
+            .split_whitespace()
+            .map(|word| {
+                if pretokenized {
+                    fold_apostrophes(word).into_owned()
+                } else {
+                    fold_apostrophes(word).to_lowercase()
+                }
+            })
+            .collect();
+        let mut i = 0;
+        while i < tokens.len() {
+            let (id, len) = longest_match(&tokens[i..], &unit_map, max_len);
+            i += len;
+            if let Some(id) = id {
                 if let Some(prev) = prev_id {
                     trackers
                         .entry(prev)
                         .or_insert_with(|| TopNTracker::new(top_n))
-                        .add(syllable_id);
+                        .add(id);
                     bigrams_seen += 1;
                 }
-                prev_id = Some(syllable_id);
+                prev_id = Some(id);
             } else {
                 prev_id = None;
             }
@@ -133,13 +203,23 @@ fn main() -> Result<()> {
         lines_processed, bigrams_seen
     );
     println!("  Unique prev_ids: {}", trackers.len());
+    if corpus.malformed() > 0 {
+        println!(
+            "  Malformed UTF-8 lines (decoded lossily): {}",
+            corpus.malformed()
+        );
+    }
 
     // Write binary file
     println!("\n[3/3] Writing vi.bigram.bin...");
 
-    let mut index: Vec<(u32, u16)> = vec![(0, 0); vocab_size as usize];
+    let mut index: Vec<(u32, u16, u16)> = vec![(0, 0, 0); vocab_size];
     let mut edges: Vec<(u32, u16)> = Vec::new();
 
+    // prev_id order keeps the layout independent of HashMap iteration
+    let mut trackers: Vec<(u32, TopNTracker)> = trackers.into_iter().collect();
+    trackers.sort_by_key(|&(prev_id, _)| prev_id);
+
     for (prev_id, tracker) in trackers {
         let top_items = tracker.finalize();
         if top_items.is_empty() {
@@ -156,36 +236,20 @@ fn main() -> Result<()> {
 
         if (prev_id as usize) < index.len() {
             let len = (edges.len() as u32 - offset) as u16;
-            index[prev_id as usize] = (offset * 8, len);
+            index[prev_id as usize] = (offset * 8, len, encode_log_max(max_count));
         }
     }
 
-    // Write file
-    let mut file = BufWriter::new(File::create("vi.bigram.bin")?);
-
-    // Header
-    file.write_all(&MAGIC.to_le_bytes())?;
-    file.write_all(&VERSION.to_le_bytes())?;
-    file.write_all(&(vocab_size as u32).to_le_bytes())?;
-    file.write_all(&(edges.len() as u32).to_le_bytes())?;
-    file.write_all(&(top_n as u32).to_le_bytes())?;
-    file.write_all(&[0u8; 12])?;
-
-    // Index
-    for (offset, len) in &index {
-        file.write_all(&offset.to_le_bytes())?;
-        file.write_all(&len.to_le_bytes())?;
-        file.write_all(&[0u8; 2])?;
-    }
-
-    // Edges
-    for (next_id, weight) in &edges {
-        file.write_all(&next_id.to_le_bytes())?;
-        file.write_all(&weight.to_le_bytes())?;
-        file.write_all(&[0u8; 2])?;
-    }
-
-    file.flush()?;
+    let file = BufWriter::new(File::create("vi.bigram.bin")?);
+    write_bigram_with_unit(
+        file,
+        unit,
+        vocab_size as u32,
+        vocab.fingerprint(),
+        top_n as u32,
+        &index,
+        EdgeValues::Weights(&edges),
+    )?;
 
     let file_size = std::fs::metadata("vi.bigram.bin")?.len();
     println!(
@@ -194,37 +258,54 @@ fn main() -> Result<()> {
     );
     println!(
         "  Vocab entries with bigrams: {}",
-        index.iter().filter(|(_, len)| *len > 0).count()
+        index.iter().filter(|(_, len, _)| *len > 0).count()
     );
     println!("  Total edges: {}", edges.len());
 
+    if validate {
+        println!("\nValidating vi.bigram.bin...");
+        let report = validate_bigram_file("vi.bigram.bin")?;
+        println!(
+            "  ✓ All invariants hold ({} prevs, {} edges)",
+            report.vocab_size, report.edges_count
+        );
+    }
+
     Ok(())
 }
 
-fn load_syllable_map(fst_path: &str, vocab_path: &str) -> Result<(usize, HashMap<String, u32>)> {
-    let file = File::open(fst_path).context("Failed to open vi.syllable.fst")?;
+/// The id of the longest entry `tokens` starts with, up to `max_len`
+/// tokens long, and the tokens it covers; `(None, 1)` if none matches
+fn longest_match(
+    tokens: &[String],
+    map: &FastMap<String, u32>,
+    max_len: usize,
+) -> (Option<u32>, usize) {
+    for len in (1..=max_len.min(tokens.len())).rev() {
+        let id = if len == 1 {
+            map.get(&tokens[0])
+        } else {
+            map.get(&tokens[..len].join(" "))
+        };
+        if let Some(&id) = id {
+            return (Some(id), len);
+        }
+    }
+    (None, 1)
+}
+
+fn load_unit_map(fst_path: &str, vocab_path: &str) -> Result<(Vocab, FastMap<String, u32>)> {
+    let file = File::open(fst_path).with_context(|| format!("Failed to open {}", fst_path))?;
     let mmap = unsafe { Mmap::map(&file)? };
-    let fst = Map::new(mmap)?;
+    Map::new(mmap).with_context(|| format!("Invalid {}", fst_path))?;
 
-    let vocab: Vec<String> = BufReader::new(File::open(vocab_path)?)
-        .lines()
-        .collect::<std::io::Result<_>>()?;
+    let vocab = Vocab::load(vocab_path)?;
 
-    let vocab_size = vocab.len();
-
-    let mut map: HashMap<String, u32> = HashMap::new();
+    let mut map: FastMap<String, u32> = FastMap::default();
     for (id, word) in vocab.iter().enumerate() {
-        let lower = word.to_lowercase();
+        let lower = fold_apostrophes(word).to_lowercase();
         map.insert(lower, id as u32);
     }
 
-    Ok((vocab_size, map))
-}
-
-fn quantize_weight(count: u64, max_count: u64) -> u16 {
-    if count == 0 || max_count == 0 {
-        return 0;
-    }
-    let ratio = (count as f64).ln() / (max_count as f64).ln().max(1.0);
-    (ratio.clamp(0.0, 1.0) * 65535.0) as u16
+    Ok((vocab, map))
 }
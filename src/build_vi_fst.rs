@@ -1,8 +1,28 @@
+//! Build the Vietnamese phrase and syllable FSTs from `words.txt`
+//!
+//! Ids follow sorted key order, as in `combined2fst::lexicon`. With
+//! `--keep-ids`, the existing `vi.phrase.vocab.txt` and
+//! `vi.syllable.vocab.txt` are read first and their words keep their ids,
+//! so models built against them stay valid; new entries are appended.
+//!
+//! Entries are NFC-normalized, so a decomposed "việt" in the list is the
+//! same key as a precomposed one. Syllables go through
+//! `vietnamese::clean_syllable`, which keeps Latin letters and Vietnamese
+//! marks and drops anything else.
+//!
+//! Usage: cargo run --release --bin build_vi_fst -- [--keep-ids]
+
 use anyhow::Result;
-use fst::MapBuilder;
-use std::collections::{BTreeMap, HashSet};
+use combined2fst::corpus::{CorpusLines, Utf8Policy};
+use combined2fst::lexicon::{assign_ids, load_previous_vocab, write_lexicon_with_ids};
+use combined2fst::vietnamese::clean_syllable;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter};
+use std::io::{BufReader, BufWriter};
+use unicode_normalization::UnicodeNormalization;
+
+/// Prob byte for every entry: words.txt carries no frequencies
+const DEFAULT_PROB: u8 = 128;
 
 #[derive(serde::Deserialize)]
 struct WordEntry {
@@ -12,38 +32,31 @@ struct WordEntry {
 }
 
 fn main() -> Result<()> {
+    let keep_ids = std::env::args().any(|a| a == "--keep-ids");
     let input = BufReader::new(File::open("words.txt")?);
 
-    let mut phrases: BTreeMap<String, u64> = BTreeMap::new();
-    let mut syllables: HashSet<String> = HashSet::new();
+    let mut phrases: BTreeMap<String, u8> = BTreeMap::new();
+    let mut syllables: BTreeMap<String, u8> = BTreeMap::new();
 
     println!("Reading words.txt...");
 
-    for (idx, line) in input.lines().enumerate() {
+    // Capped like the corpus readers: one runaway line is skipped, not
+    // buffered whole
+    for line in CorpusLines::new(input, Utf8Policy::Strict) {
         let line = line?;
         if line.trim().is_empty() {
             continue;
         }
 
         let entry: WordEntry = serde_json::from_str(&line)?;
-        let text = entry.text.to_lowercase();
-
-        // Add to phrases (use index as word_id, no frequency available)
-        // Value format: word_id (32 bits) | flags (8 bits) | prob (8 bits)
-        // prob = 128 (default), flags = 0
-        let word_id = idx as u64;
-        let prob: u64 = 128;
-        let flags: u64 = 0;
-        let value = (word_id << 16) | (flags << 8) | prob;
+        let text: String = entry.text.nfc().collect::<String>().to_lowercase();
 
-        phrases.entry(text.clone()).or_insert(value);
+        phrases.insert(text.clone(), DEFAULT_PROB);
 
         // Extract syllables (split by space)
         for syllable in text.split_whitespace() {
-            // Clean syllable (remove hyphens at edges)
-            let clean = syllable.trim_matches('-');
-            if !clean.is_empty() && clean.chars().all(|c| c.is_alphabetic() || c == '-') {
-                syllables.insert(clean.to_string());
+            if let Some(clean) = clean_syllable(syllable) {
+                syllables.insert(clean, DEFAULT_PROB);
             }
         }
     }
@@ -51,56 +64,18 @@ fn main() -> Result<()> {
     println!("Found {} unique phrases", phrases.len());
     println!("Found {} unique syllables", syllables.len());
 
-    // Build phrase FST
     println!("\nBuilding vi.phrase.fst...");
-    {
-        let file = BufWriter::new(File::create("vi.phrase.fst")?);
-        let mut builder = MapBuilder::new(file)?;
-
-        for (key, value) in &phrases {
-            builder.insert(key.as_bytes(), *value)?;
-        }
-        builder.finish()?;
-    }
+    write_vi_lexicon(&phrases, "vi.phrase.fst", "vi.phrase.vocab.txt", keep_ids)?;
     println!("✓ vi.phrase.fst created");
 
-    // Build syllable FST (sorted)
     println!("\nBuilding vi.syllable.fst...");
-    {
-        let mut sorted_syllables: Vec<_> = syllables.into_iter().collect();
-        sorted_syllables.sort();
-
-        let file = BufWriter::new(File::create("vi.syllable.fst")?);
-        let mut builder = MapBuilder::new(file)?;
-
-        for (idx, syllable) in sorted_syllables.iter().enumerate() {
-            // Value: syllable_id (32 bits) | flags (8 bits) | prob (8 bits)
-            let value = ((idx as u64) << 16) | 128;
-            builder.insert(syllable.as_bytes(), value)?;
-        }
-        builder.finish()?;
-
-        // Also write vocab file
-        let mut vocab = BufWriter::new(File::create("vi.syllable.vocab.txt")?);
-        use std::io::Write;
-        for s in &sorted_syllables {
-            writeln!(vocab, "{}", s)?;
-        }
-        println!(
-            "✓ vi.syllable.fst created ({} syllables)",
-            sorted_syllables.len()
-        );
-    }
-
-    // Write phrase vocab
-    {
-        let mut vocab = BufWriter::new(File::create("vi.phrase.vocab.txt")?);
-        use std::io::Write;
-        for (key, _) in &phrases {
-            writeln!(vocab, "{}", key)?;
-        }
-        println!("✓ vi.phrase.vocab.txt created");
-    }
+    write_vi_lexicon(
+        &syllables,
+        "vi.syllable.fst",
+        "vi.syllable.vocab.txt",
+        keep_ids,
+    )?;
+    println!("✓ vi.syllable.fst created ({} syllables)", syllables.len());
 
     println!("\nDone! Files created:");
     println!("  - vi.phrase.fst");
@@ -110,3 +85,30 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Write one FST and its vocab, keeping the ids of the existing vocab file
+/// if `keep_ids` and it exists
+fn write_vi_lexicon(
+    words: &BTreeMap<String, u8>,
+    fst_path: &str,
+    vocab_path: &str,
+    keep_ids: bool,
+) -> Result<()> {
+    let previous = if keep_ids && std::path::Path::new(vocab_path).exists() {
+        Some(load_previous_vocab(vocab_path)?)
+    } else {
+        None
+    };
+    let ids = assign_ids(words, previous.as_ref());
+    if let Some(previous) = &previous {
+        println!(
+            "  Kept {} ids from {}, appended {} new entries",
+            previous.len(),
+            vocab_path,
+            ids.len() - previous.len()
+        );
+    }
+    let fst = BufWriter::new(File::create(fst_path)?);
+    let vocab = BufWriter::new(File::create(vocab_path)?);
+    write_lexicon_with_ids(words, &ids, fst, Some(vocab))
+}
@@ -1,30 +1,64 @@
 //! Build Vietnamese trigram cache for hybrid suggestion
 //!
-//! Only caches trigrams for top K most frequent bigram pairs
+//! Only caches trigrams for top K most frequent bigram pairs. `--progress`
+//! reports throughput and an ETA for each of the two corpus passes.
+//! `--pretokenized` looks syllables up as split, without lowercasing them,
+//! as `build_vi_bigram` does with the same flag.
 //!
-//! Usage: cargo run --release --bin build_vi_trigram -- <corpus.txt.gz> [--pairs K] [--top N]
-
-use anyhow::{Context, Result};
-use flate2::read::GzDecoder;
-use std::collections::HashMap;
+//! Usage: cargo run --release --bin build_vi_trigram -- <corpus.txt.gz> [--pairs K] [--top N] [--pretokenized] [--strict-utf8] [--progress]
+
+use anyhow::Result;
+use combined2fst::bigram::{WEIGHT_BITS, WORD_ID_BITS};
+use combined2fst::builder::{encode_log_max, quantize_weight};
+use combined2fst::corpus::{CorpusLines, Utf8Policy};
+use combined2fst::progress::{file_size, BytesRead, Progress};
+use combined2fst::tokenize::fold_apostrophes;
+use combined2fst::{open_corpus_counted, FastMap, Vocab};
+use std::cmp::Reverse;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{BufWriter, Write};
 
 const MAGIC: u32 = 0x54524743; // "TRGC" = Trigram Cache
-const VERSION: u32 = 1;
+const VERSION: u32 = 3;
+
+/// ((w1, w2), [(next_id, weight)])
+/// ((w1, w2), edges, encoded ln(max_count))
+type PairEdges = ((u32, u32), Vec<(u32, u16)>, u16);
 
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: {} <corpus.txt.gz> [--pairs K] [--top N]", args[0]);
+        eprintln!(
+            "Usage: {} <corpus.txt.gz> [--pairs K] [--top N] [--pretokenized] [--strict-utf8] [--progress]",
+            args[0]
+        );
         eprintln!("  --pairs K : Keep top K bigram pairs (default: 5000)");
         eprintln!("  --top N   : Keep top N next syllables per pair (default: 10)");
+        eprintln!("  --pretokenized : Look syllables up as split, without lowercasing them");
+        eprintln!(
+            "  --strict-utf8 : Fail on lines that aren't valid UTF-8 (default: decode lossily)"
+        );
+        eprintln!("  --progress : Report lines/s, trigrams/s and an ETA every 10s");
         std::process::exit(1);
     }
 
     let input_path = &args[1];
     let max_pairs: usize = parse_arg(&args, "--pairs").unwrap_or(5000);
     let top_n: usize = parse_arg(&args, "--top").unwrap_or(10);
+    let show_progress = args.iter().any(|a| a == "--progress");
+    let pretokenized = args.iter().any(|a| a == "--pretokenized");
+    let normalize = |word: &str| {
+        if pretokenized {
+            fold_apostrophes(word).into_owned()
+        } else {
+            fold_apostrophes(word).to_lowercase()
+        }
+    };
+    let utf8 = if args.iter().any(|a| a == "--strict-utf8") {
+        Utf8Policy::Strict
+    } else {
+        Utf8Policy::Lossy
+    };
 
     println!("=== Vietnamese Trigram Cache Builder ===");
     println!("Input: {}", input_path);
@@ -33,35 +67,38 @@ fn main() -> Result<()> {
 
     // Load syllable vocab
     println!("\n[1/4] Loading vocabulary...");
-    let vocab: Vec<String> = BufReader::new(File::open("vi.syllable.vocab.txt")?)
-        .lines()
-        .collect::<std::io::Result<_>>()?;
+    let vocab = Vocab::load("vi.syllable.vocab.txt")?;
+    let vocab_hash = vocab.fingerprint();
 
-    let mut syllable_to_id: HashMap<String, u32> = HashMap::new();
+    let mut syllable_to_id: FastMap<String, u32> = FastMap::default();
     for (id, w) in vocab.iter().enumerate() {
-        syllable_to_id.insert(w.to_lowercase(), id as u32);
+        syllable_to_id.insert(fold_apostrophes(w).to_lowercase(), id as u32);
     }
-    println!("  Loaded {} syllables", vocab.len());
+    println!(
+        "  Loaded {} syllables (fingerprint {:016x})",
+        vocab.len(),
+        vocab_hash
+    );
 
     // Pass 1: Count bigram pairs frequency
     println!("\n[2/4] Counting bigram pair frequencies...");
-    let mut pair_freq: HashMap<(u32, u32), u64> = HashMap::new();
+    let mut pair_freq: FastMap<(u32, u32), u64> = FastMap::default();
 
-    let file = File::open(input_path)?;
-    let reader: Box<dyn BufRead> = if input_path.ends_with(".gz") {
-        Box::new(BufReader::with_capacity(1 << 20, GzDecoder::new(file)))
-    } else {
-        Box::new(BufReader::with_capacity(1 << 20, file))
-    };
+    let (reader, bytes_read) = open_corpus_counted(input_path)?;
+    let mut corpus = CorpusLines::new(reader, utf8);
+    let mut progress = show_progress.then(|| pass_progress(input_path, bytes_read));
 
     let mut lines = 0u64;
+    let mut trigrams = 0u64;
     let mut prev_id: Option<u32> = None;
     let mut prev_prev_id: Option<u32> = None;
 
-    for line in reader.lines() {
+    for line in &mut corpus {
         let line = line?;
         lines += 1;
-        if lines % 500_000 == 0 {
+        if let Some(progress) = progress.as_mut() {
+            progress.tick(lines, trigrams);
+        } else if lines.is_multiple_of(500_000) {
             println!(
                 "  {} K lines, {} unique pairs",
                 lines / 1000,
@@ -70,10 +107,11 @@ fn main() -> Result<()> {
         }
 
         for word in line.split_whitespace() {
-            let norm = word.to_lowercase();
+            let norm = normalize(word);
             if let Some(&id) = syllable_to_id.get(&norm) {
                 if let (Some(pp), Some(p)) = (prev_prev_id, prev_id) {
                     *pair_freq.entry((pp, p)).or_insert(0) += 1;
+                    trigrams += 1;
                 }
                 prev_prev_id = prev_id;
                 prev_id = Some(id);
@@ -87,13 +125,19 @@ fn main() -> Result<()> {
     }
 
     println!("  Total: {} unique pairs", pair_freq.len());
+    if corpus.malformed() > 0 {
+        println!(
+            "  Malformed UTF-8 lines (decoded lossily): {}",
+            corpus.malformed()
+        );
+    }
 
     // Select top K pairs
     let mut pairs: Vec<_> = pair_freq.into_iter().collect();
-    pairs.sort_by(|a, b| b.1.cmp(&a.1));
+    pairs.sort_by_key(|&(pair, count)| (Reverse(count), pair));
     pairs.truncate(max_pairs);
 
-    let top_pairs: HashMap<(u32, u32), usize> = pairs
+    let top_pairs: FastMap<(u32, u32), usize> = pairs
         .iter()
         .enumerate()
         .map(|(idx, ((a, b), _))| ((*a, *b), idx))
@@ -104,31 +148,32 @@ fn main() -> Result<()> {
     // Pass 2: Collect trigrams for selected pairs
     println!("\n[3/4] Collecting trigrams for top pairs...");
 
-    // trigram_counts[pair_idx] = HashMap<next_id, count>
-    let mut trigram_counts: Vec<HashMap<u32, u64>> = vec![HashMap::new(); top_pairs.len()];
+    // trigram_counts[pair_idx] = FastMap<next_id, count>
+    let mut trigram_counts: Vec<FastMap<u32, u64>> = vec![FastMap::default(); top_pairs.len()];
 
-    let file = File::open(input_path)?;
-    let reader: Box<dyn BufRead> = if input_path.ends_with(".gz") {
-        Box::new(BufReader::with_capacity(1 << 20, GzDecoder::new(file)))
-    } else {
-        Box::new(BufReader::with_capacity(1 << 20, file))
-    };
+    let (reader, bytes_read) = open_corpus_counted(input_path)?;
+    let reader = CorpusLines::new(reader, utf8);
+    let mut progress = show_progress.then(|| pass_progress(input_path, bytes_read));
 
     lines = 0;
+    trigrams = 0;
     prev_id = None;
     prev_prev_id = None;
 
-    for line in reader.lines() {
+    for line in reader {
         let line = line?;
         lines += 1;
-        if lines % 500_000 == 0 {
+        if let Some(progress) = progress.as_mut() {
+            progress.tick(lines, trigrams);
+        } else if lines.is_multiple_of(500_000) {
             println!("  {} K lines processed", lines / 1000);
         }
 
         for word in line.split_whitespace() {
-            let norm = word.to_lowercase();
+            let norm = normalize(word);
             if let Some(&id) = syllable_to_id.get(&norm) {
                 if let (Some(pp), Some(p)) = (prev_prev_id, prev_id) {
+                    trigrams += 1;
                     if let Some(&pair_idx) = top_pairs.get(&(pp, p)) {
                         *trigram_counts[pair_idx].entry(id).or_insert(0) += 1;
                     }
@@ -148,7 +193,7 @@ fn main() -> Result<()> {
     println!("\n[4/4] Writing vi.trigram.cache.bin...");
 
     // Prepare data: sort pairs by (w1, w2), finalize top-N
-    let mut pair_data: Vec<((u32, u32), Vec<(u32, u16)>)> = Vec::new();
+    let mut pair_data: Vec<PairEdges> = Vec::new();
 
     for ((w1, w2), pair_idx) in &top_pairs {
         let counts = &trigram_counts[*pair_idx];
@@ -157,7 +202,7 @@ fn main() -> Result<()> {
         }
 
         let mut nexts: Vec<_> = counts.iter().map(|(&k, &v)| (k, v)).collect();
-        nexts.sort_by(|a, b| b.1.cmp(&a.1));
+        nexts.sort_by_key(|&(next_id, count)| (Reverse(count), next_id));
         nexts.truncate(top_n);
 
         let max_count = nexts.first().map(|(_, c)| *c).unwrap_or(1);
@@ -169,41 +214,43 @@ fn main() -> Result<()> {
             })
             .collect();
 
-        pair_data.push(((*w1, *w2), weighted));
+        pair_data.push(((*w1, *w2), weighted, encode_log_max(max_count)));
     }
 
-    pair_data.sort_by_key(|((a, b), _)| (*a, *b));
+    pair_data.sort_by_key(|((a, b), _, _)| (*a, *b));
 
     // Binary format:
-    // Header: magic(4) version(4) num_pairs(4) top_n(4) reserved(16) = 32 bytes
-    // Index: [w1(4) w2(4) offset(4) len(2) reserved(2)] × num_pairs = 16 bytes each
+    // Header: magic(4) version(4) num_pairs(4) top_n(4) word_id_bits(4) weight_bits(4) reserved(8) = 32 bytes
+    // Index: [w1(4) w2(4) offset(4) len(2) log_max(2)] × num_pairs = 16 bytes each
     // Edges: [next_id(4) weight(2) reserved(2)] × total_edges = 8 bytes each
 
     let mut file = BufWriter::new(File::create("vi.trigram.cache.bin")?);
 
     // Count total edges
-    let total_edges: usize = pair_data.iter().map(|(_, v)| v.len()).sum();
+    let total_edges: usize = pair_data.iter().map(|(_, v, _)| v.len()).sum();
 
     // Header
     file.write_all(&MAGIC.to_le_bytes())?;
     file.write_all(&VERSION.to_le_bytes())?;
     file.write_all(&(pair_data.len() as u32).to_le_bytes())?;
     file.write_all(&(top_n as u32).to_le_bytes())?;
-    file.write_all(&[0u8; 16])?; // reserved
+    file.write_all(&WORD_ID_BITS.to_le_bytes())?;
+    file.write_all(&WEIGHT_BITS.to_le_bytes())?;
+    file.write_all(&vocab_hash.to_le_bytes())?;
 
     // Index
     let mut edge_offset: u32 = 0;
-    for ((w1, w2), edges) in &pair_data {
+    for ((w1, w2), edges, log_max) in &pair_data {
         file.write_all(&w1.to_le_bytes())?;
         file.write_all(&w2.to_le_bytes())?;
         file.write_all(&edge_offset.to_le_bytes())?;
         file.write_all(&(edges.len() as u16).to_le_bytes())?;
-        file.write_all(&[0u8; 2])?;
+        file.write_all(&log_max.to_le_bytes())?;
         edge_offset += edges.len() as u32;
     }
 
     // Edges
-    for (_, edges) in &pair_data {
+    for (_, edges, _) in &pair_data {
         for (next_id, weight) in edges {
             file.write_all(&next_id.to_le_bytes())?;
             file.write_all(&weight.to_le_bytes())?;
@@ -223,14 +270,13 @@ fn main() -> Result<()> {
 
     // Print some examples
     println!("\nSample entries:");
-    for ((w1, w2), edges) in pair_data.iter().take(10) {
-        let s1 = vocab.get(*w1 as usize).map(|s| s.as_str()).unwrap_or("?");
-        let s2 = vocab.get(*w2 as usize).map(|s| s.as_str()).unwrap_or("?");
+    for ((w1, w2), edges, _) in pair_data.iter().take(10) {
+        let s1 = vocab.word(*w1).unwrap_or("?");
+        let s2 = vocab.word(*w2).unwrap_or("?");
         let nexts: Vec<_> = edges
             .iter()
             .take(3)
-            .filter_map(|(id, _)| vocab.get(*id as usize))
-            .map(|s| s.as_str())
+            .filter_map(|(id, _)| vocab.word(*id))
             .collect();
         println!("  ({}, {}) → {}", s1, s2, nexts.join(", "));
     }
@@ -245,10 +291,9 @@ fn parse_arg(args: &[String], flag: &str) -> Option<usize> {
         .and_then(|s| s.parse().ok())
 }
 
-fn quantize_weight(count: u64, max_count: u64) -> u16 {
-    if count == 0 || max_count == 0 {
-        return 0;
-    }
-    let ratio = (count as f64).ln() / (max_count as f64).ln().max(1.0);
-    (ratio.clamp(0.0, 1.0) * 65535.0) as u16
+/// Progress for one pass over the corpus
+fn pass_progress(input_path: &str, bytes_read: BytesRead) -> Progress {
+    let mut progress = Progress::new("trigrams").with_total_bytes(file_size(input_path));
+    progress.track(bytes_read);
+    progress
 }
@@ -0,0 +1,459 @@
+//! Shared counting logic for the streaming bigram builders
+//!
+//! By default tokens are lowercased and keyed on the canonical word_id, so
+//! every case variant shares one bigram row. In case-sensitive mode tokens
+//! keep their case and are keyed on the exact FST word_id (see
+//! `build_exact_map`); a token whose exact form isn't in the vocab falls
+//! back to its lowercase form. The index is still sized by the vocab, but a
+//! case-sensitive model must be queried with exact ids: looking up the
+//! canonical id only reaches the row of that one variant.
+//!
+//! Tokens are cleaned by a `tokenize::Normalizer` before the lookup:
+//! `LettersOnly` unless `BigramCounter::normalizer` picks another.
+//!
+//! Every count-descending sort breaks ties on next_id ascending, so the same
+//! corpus always builds the same bytes.
+//!
+//! With `BigramCounter::mark_cross_sentence` the counter also tallies the
+//! occurrences whose prev token ended a sentence ("end. The"); an edge
+//! mostly seen that way gets `bigram::EDGE_FLAG_CROSS_SENTENCE`.
+//!
+//! The chain breaks at line ends and on OOV tokens unless
+//! `BigramCounter::cross_boundary_weight` is set; then it carries on across
+//! them, at a reduced count, and those edges are tallied as cross-sentence
+//! too. Reduced counts accumulate per pair as fractions; a pair's whole
+//! part is counted as it fills and the remainder is rounded when the
+//! counter is finalized, so counts stay in corpus units.
+
+use crate::bigram::EDGE_FLAG_CROSS_SENTENCE;
+use crate::sketch::{pair_key, CountMinSketch};
+use crate::tokenize::{LettersOnly, Normalizer};
+use crate::FastMap;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+/// Per-prev index entry: (byte offset into edges, len, encoded ln(max_count))
+pub type IndexEntry = (u32, u16, u16);
+/// Edge record: (next_id, weight)
+pub type EdgeEntry = (u32, u16);
+/// Unquantized edge record: (next_id, count)
+pub type CountEntry = (u32, u64);
+/// Edge record with flag bits: (next_id, weight, flags)
+pub type FlaggedEntry = (u32, u16, u16);
+
+/// TopN tracker using exact counting with pruning
+/// Prunes when entry count exceeds threshold
+pub struct TopNTracker {
+    counts: FastMap<u32, u64>, // next_id -> count
+    top_n: usize,
+    prune_threshold: usize, // prune when len > this
+}
+
+impl TopNTracker {
+    pub fn new(top_n: usize) -> Self {
+        Self {
+            counts: FastMap::default(),
+            top_n,
+            prune_threshold: top_n * 100, // keep 100x candidates before pruning
+        }
+    }
+
+    /// Tracker fed with sketch estimates; keeps at most 4N candidates
+    pub fn bounded(top_n: usize) -> Self {
+        Self {
+            counts: FastMap::default(),
+            top_n,
+            prune_threshold: top_n * 4,
+        }
+    }
+
+    pub fn add(&mut self, next_id: u32, delta: u64) {
+        *self.counts.entry(next_id).or_insert(0) += delta;
+
+        if self.counts.len() > self.prune_threshold {
+            self.prune();
+        }
+    }
+
+    /// Record the current (sketch-estimated) total for `next_id`.
+    /// A candidate pruned earlier re-enters with its full estimate.
+    pub fn observe(&mut self, next_id: u32, estimate: u64) {
+        let count = self.counts.entry(next_id).or_insert(0);
+        *count = (*count).max(estimate);
+
+        if self.counts.len() > self.prune_threshold {
+            self.prune();
+        }
+    }
+
+    fn prune(&mut self) {
+        if self.counts.len() <= self.top_n * 2 {
+            return;
+        }
+
+        // Keep top 2*N by count
+        let mut items: Vec<_> = self.counts.drain().collect();
+        items.sort_by_key(|&(next_id, count)| (Reverse(count), next_id));
+        items.truncate(self.top_n * 2);
+
+        self.counts = items.into_iter().collect();
+    }
+
+    pub fn finalize(self) -> Vec<(u32, u64)> {
+        let mut items: Vec<_> = self.counts.into_iter().collect();
+        items.sort_by_key(|&(next_id, count)| (Reverse(count), next_id));
+        items.truncate(self.top_n);
+        items
+    }
+}
+
+/// Streaming bigram counter: one `TopNTracker` per prev_id.
+///
+/// With a sketch attached, pair counts live in the fixed-size sketch and each
+/// tracker only holds a bounded candidate list ranked by sketch estimates.
+pub struct BigramCounter {
+    pub trackers: FastMap<u32, TopNTracker>,
+    pub bigrams_seen: u64,
+    top_n: usize,
+    case_sensitive: bool,
+    sketch: Option<CountMinSketch>,
+    normalizer: Box<dyn Normalizer>,
+    /// Occurrences per `pair_key` whose prev token ended a sentence
+    cross_sentence: Option<FastMap<u64, u64>>,
+    /// Share of an increment an edge across a boundary counts, with
+    /// `cross_boundary_weight`; None breaks the chain at boundaries
+    boundary_weight: Option<f64>,
+    /// Per `pair_key`, the uncounted fraction of its cross-boundary edges
+    boundary_fractions: FastMap<u64, f64>,
+    /// Last token of the previous line, while `boundary_weight` is set
+    carry: Option<u32>,
+}
+
+impl BigramCounter {
+    pub fn new(top_n: usize) -> Self {
+        Self {
+            trackers: FastMap::default(),
+            bigrams_seen: 0,
+            top_n,
+            case_sensitive: false,
+            sketch: None,
+            normalizer: Box::new(LettersOnly),
+            cross_sentence: None,
+            boundary_weight: None,
+            boundary_fractions: FastMap::default(),
+            carry: None,
+        }
+    }
+
+    /// Approximate pair counts with `sketch` instead of exact per-prev maps
+    pub fn with_sketch(mut self, sketch: CountMinSketch) -> Self {
+        self.sketch = Some(sketch);
+        self
+    }
+
+    /// Key edges on exact surface forms; `feed_line` then expects an exact map
+    pub fn case_sensitive(mut self, on: bool) -> Self {
+        self.case_sensitive = on;
+        self
+    }
+
+    /// Clean tokens with `normalizer` instead of `LettersOnly`
+    pub fn normalizer(mut self, normalizer: impl Normalizer + 'static) -> Self {
+        self.normalizer = Box::new(normalizer);
+        self
+    }
+
+    /// Tally cross-sentence occurrences so `into_flagged` can flag edges
+    pub fn mark_cross_sentence(mut self, on: bool) -> Self {
+        self.cross_sentence = on.then(FastMap::default);
+        self
+    }
+
+    /// Continue the chain across line ends and OOV tokens instead of
+    /// breaking it, counting an edge formed across one at `weight`
+    /// (0 < weight <= 1) of an edge inside a line, and tally those edges for
+    /// `into_flagged` as `mark_cross_sentence` does. Edges inside a line
+    /// count the line's increment; see the module docs for how the
+    /// fractional counts of cross edges are kept.
+    pub fn cross_boundary_weight(mut self, weight: f64) -> Self {
+        self.boundary_weight = Some(weight);
+        self.cross_sentence.get_or_insert_with(FastMap::default);
+        self
+    }
+
+    /// Drop the word carried over from the last line, e.g. between corpora
+    pub fn break_chain(&mut self) {
+        self.carry = None;
+    }
+
+    /// Count the bigrams of one corpus line, adding `increment` per occurrence.
+    /// The chain breaks on OOV tokens and at the end of the line, unless
+    /// `cross_boundary_weight` is set.
+    pub fn feed_line<S: BuildHasher>(
+        &mut self,
+        line: &str,
+        word_ids: &HashMap<String, u32, S>,
+        increment: u64,
+    ) {
+        let mut prev_id = self.carry.take();
+        let mut prev_ends_sentence = false;
+        // The next edge spans a line end or OOV token
+        let mut crossing = prev_id.is_some();
+
+        for word in line.split_whitespace() {
+            if let Some(word_id) = token_id(
+                word,
+                word_ids,
+                self.case_sensitive,
+                self.normalizer.as_ref(),
+            ) {
+                if let Some(prev) = prev_id {
+                    match self.boundary_weight {
+                        Some(weight) if crossing => {
+                            let key = pair_key(prev, word_id);
+                            let fraction = self.boundary_fractions.entry(key).or_insert(0.0);
+                            *fraction += increment as f64 * weight;
+                            let whole = fraction.floor();
+                            *fraction -= whole;
+                            if whole > 0.0 {
+                                self.add_pair(prev, word_id, whole as u64, true);
+                            }
+                        }
+                        _ => self.add_pair(prev, word_id, increment, prev_ends_sentence),
+                    }
+                    self.bigrams_seen += 1;
+                }
+                prev_id = Some(word_id);
+                prev_ends_sentence = ends_sentence(word);
+                crossing = false;
+            } else if self.boundary_weight.is_some() {
+                crossing = prev_id.is_some();
+            } else {
+                prev_id = None;
+            }
+        }
+        if self.boundary_weight.is_some() {
+            self.carry = prev_id;
+        }
+    }
+
+    /// Count `delta` occurrences of `prev next`, tallied as cross-sentence
+    /// when `cross`
+    fn add_pair(&mut self, prev: u32, next: u32, delta: u64, cross: bool) {
+        let top_n = self.top_n;
+        match &mut self.sketch {
+            Some(sketch) => {
+                let key = pair_key(prev, next);
+                sketch.add(key, delta);
+                self.trackers
+                    .entry(prev)
+                    .or_insert_with(|| TopNTracker::bounded(top_n))
+                    .observe(next, sketch.estimate(key));
+            }
+            None => self
+                .trackers
+                .entry(prev)
+                .or_insert_with(|| TopNTracker::new(top_n))
+                .add(next, delta),
+        }
+        if let Some(tally) = self.cross_sentence.as_mut().filter(|_| cross) {
+            *tally.entry(pair_key(prev, next)).or_insert(0) += delta;
+        }
+    }
+
+    /// Count the cross-boundary fractions still pending, rounded to the
+    /// nearest whole occurrence
+    fn flush_fractions(&mut self) {
+        let mut pending: Vec<(u64, f64)> = self.boundary_fractions.drain().collect();
+        // Sketch estimates depend on insertion order
+        pending.sort_unstable_by_key(|&(key, _)| key);
+        for (key, fraction) in pending {
+            if fraction >= 0.5 {
+                self.add_pair((key >> 32) as u32, key as u32, 1, true);
+            }
+        }
+    }
+
+    /// Finalize trackers into `(index, edges)`, indexed by prev_id
+    pub fn into_edges(self, vocab_size: usize) -> (Vec<IndexEntry>, Vec<EdgeEntry>) {
+        let (index, counts) = self.into_counts(vocab_size);
+        let edges = quantize_rows(&index, &counts);
+        (index, edges)
+    }
+
+    /// Like `into_counts`, plus each edge's flag bits (parallel to the
+    /// counts). All zero unless `mark_cross_sentence` was on.
+    pub fn into_flagged(
+        mut self,
+        vocab_size: usize,
+    ) -> (Vec<IndexEntry>, Vec<CountEntry>, Vec<u16>) {
+        self.flush_fractions();
+        let cross_sentence = self.cross_sentence.take();
+        let (index, counts) = self.into_counts(vocab_size);
+        let mut flags = vec![0u16; counts.len()];
+        if let Some(cross) = cross_sentence {
+            for (prev, &(offset, len, _)) in index.iter().enumerate() {
+                let start = offset as usize / 8;
+                for i in start..start + len as usize {
+                    let (next_id, count) = counts[i];
+                    let key = pair_key(prev as u32, next_id);
+                    // Mostly seen across a sentence boundary
+                    if cross.get(&key).is_some_and(|&crossed| crossed * 2 > count) {
+                        flags[i] |= EDGE_FLAG_CROSS_SENTENCE;
+                    }
+                }
+            }
+        }
+        (index, counts, flags)
+    }
+
+    /// Like `into_edges`, but keep each edge's count instead of its weight
+    pub fn into_counts(mut self, vocab_size: usize) -> (Vec<IndexEntry>, Vec<CountEntry>) {
+        self.flush_fractions();
+        let mut index: Vec<IndexEntry> = vec![(0, 0, 0); vocab_size];
+        let mut edges: Vec<CountEntry> = Vec::new();
+
+        // Lay rows out in prev_id order so the edge offsets don't depend
+        // on HashMap iteration order
+        let mut trackers: Vec<(u32, TopNTracker)> = self.trackers.into_iter().collect();
+        trackers.sort_by_key(|&(prev_id, _)| prev_id);
+
+        for (prev_id, tracker) in trackers {
+            let top_items = tracker.finalize();
+            if top_items.is_empty() {
+                continue;
+            }
+
+            let offset = edges.len() as u32;
+            let max_count = top_items.first().map(|(_, c)| *c).unwrap_or(1);
+            edges.extend(top_items);
+
+            if (prev_id as usize) < index.len() {
+                let len = (edges.len() as u32 - offset) as u16;
+                // offset in bytes
+                index[prev_id as usize] = (offset * 8, len, encode_log_max(max_count));
+            }
+        }
+
+        (index, edges)
+    }
+}
+
+/// Quantize count rows laid out by `index` into weights, each row against
+/// its own (first, largest) count
+pub fn quantize_rows(index: &[IndexEntry], counts: &[CountEntry]) -> Vec<EdgeEntry> {
+    let mut edges: Vec<EdgeEntry> = counts.iter().map(|&(next_id, _)| (next_id, 0)).collect();
+    for &(offset, len, _) in index {
+        let start = offset as usize / 8;
+        let row = &counts[start..start + len as usize];
+        let max_count = row.first().map(|(_, c)| *c).unwrap_or(1);
+        for (i, &(_, count)) in row.iter().enumerate() {
+            edges[start + i].1 = quantize_weight(count, max_count);
+        }
+    }
+    edges
+}
+
+/// Trim count rows laid out by `index` to at most `max_edges` edges in
+/// total, for a hard file-size budget on top of the per-prev top N. The
+/// edges dropped are the lowest-weight ones across all rows (ties to the
+/// smaller count), but every row keeps its top edge unless there are more
+/// non-empty rows than `max_edges`; then the rows with the smallest top
+/// counts go. Returns the new index and the positions in `counts` of the
+/// edges kept, in order, so parallel arrays (edge flags) can follow.
+pub fn cap_total_edges(
+    index: &[IndexEntry],
+    counts: &[CountEntry],
+    max_edges: usize,
+) -> (Vec<IndexEntry>, Vec<usize>) {
+    if counts.len() <= max_edges {
+        return (index.to_vec(), (0..counts.len()).collect());
+    }
+
+    // (is a row's top edge, weight, count, position): the smallest go first
+    let mut order: Vec<(bool, u16, u64, Reverse<usize>)> = Vec::with_capacity(counts.len());
+    for &(offset, len, _) in index {
+        let start = offset as usize / 8;
+        let row = &counts[start..start + len as usize];
+        let max_count = row.first().map(|(_, c)| *c).unwrap_or(1);
+        for (i, &(_, count)) in row.iter().enumerate() {
+            let weight = quantize_weight(count, max_count);
+            order.push((i == 0, weight, count, Reverse(start + i)));
+        }
+    }
+    let excess = order.len().saturating_sub(max_edges);
+    let mut keep = vec![true; counts.len()];
+    if excess > 0 {
+        order.select_nth_unstable(excess - 1);
+        for &(_, _, _, Reverse(pos)) in &order[..excess] {
+            keep[pos] = false;
+        }
+    }
+
+    let mut new_index: Vec<IndexEntry> = Vec::with_capacity(index.len());
+    let mut kept: Vec<usize> = Vec::with_capacity(max_edges);
+    for &(offset, len, log_max) in index {
+        let start = offset as usize / 8;
+        let new_start = kept.len();
+        kept.extend((start..start + len as usize).filter(|&i| keep[i]));
+        let new_len = kept.len() - new_start;
+        let log_max = if new_len == 0 { 0 } else { log_max };
+        new_index.push(((new_start * 8) as u32, new_len as u16, log_max));
+    }
+    (new_index, kept)
+}
+
+/// Whether a raw token closes a sentence: it ends in '.', '!', '?' or '…',
+/// possibly followed by closing quotes or brackets
+pub fn ends_sentence(word: &str) -> bool {
+    word.trim_end_matches(['"', '\'', ')', ']', '”', '’', '»'])
+        .ends_with(['.', '!', '?', '…'])
+}
+
+/// Map a raw corpus token to its word_id (see the module docs for the modes)
+pub fn token_id<S: BuildHasher>(
+    word: &str,
+    word_ids: &HashMap<String, u32, S>,
+    case_sensitive: bool,
+    normalizer: &dyn Normalizer,
+) -> Option<u32> {
+    if !case_sensitive {
+        return word_ids.get(&normalizer.normalize(word, false)).copied();
+    }
+    let stripped = normalizer.normalize(word, true);
+    word_ids
+        .get(&stripped)
+        .or_else(|| word_ids.get(&stripped.to_lowercase()))
+        .copied()
+}
+
+/// Assumed `ln(max_count)` for models that don't store one (a top count of ~1000)
+pub const DEFAULT_LOG_MAX: f64 = 6.9;
+
+/// Encode a prev's `ln(max_count)` for the index entry's spare u16, in
+/// thousandths and offset by one so 0 keeps meaning "not stored"
+pub fn encode_log_max(max_count: u64) -> u16 {
+    let ln = (max_count.max(1) as f64).ln();
+    ((ln * 1000.0).round() as u64 + 1).min(u16::MAX as u64) as u16
+}
+
+pub fn decode_log_max(raw: u16) -> Option<f64> {
+    raw.checked_sub(1).map(|v| v as f64 / 1000.0)
+}
+
+/// Invert `quantize_weight`: the edge's count relative to the prev's top
+/// count (1.0 for the top edge), given `log_max = ln(max_count)`
+pub fn relative_count(weight: u16, log_max: f64) -> f64 {
+    let ln_count = weight as f64 / 65535.0 * log_max.max(1.0);
+    (ln_count - log_max).exp().min(1.0)
+}
+
+/// Quantize count to 16-bit weight using log scale
+pub fn quantize_weight(count: u64, max_count: u64) -> u16 {
+    if count == 0 || max_count == 0 {
+        return 0;
+    }
+    let ratio = (count as f64).ln() / (max_count as f64).ln().max(1.0);
+    (ratio.clamp(0.0, 1.0) * 65535.0) as u16
+}
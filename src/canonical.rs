@@ -0,0 +1,104 @@
+//! Precomputed canonical lowercase -> word_id map
+//!
+//! `build_canonical_map` re-reads and lowercases the whole vocab on every
+//! launch. `CanonicalMap` runs it once and saves the result, so apps can load
+//! the map at startup instead of rebuilding it.
+//!
+//! File layout: magic `CMAP` (4 bytes) + version (4 bytes) + bincode of the
+//! vocab size and the entries sorted by key, so the same map always saves to
+//! the same bytes.
+
+use crate::build_canonical_map;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+pub const MAGIC: u32 = 0x434D4150; // "CMAP"
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanonicalMap {
+    vocab_size: u32,
+    ids: HashMap<String, u32>,
+}
+
+/// On-disk form: sorted entries instead of a HashMap
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    vocab_size: u32,
+    entries: Vec<(String, u32)>,
+}
+
+impl CanonicalMap {
+    pub fn build(fst_path: &str, vocab_path: &str) -> Result<Self> {
+        let (vocab_size, ids) = build_canonical_map(fst_path, vocab_path)?;
+        Ok(Self { vocab_size, ids })
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut entries: Vec<(String, u32)> =
+            self.ids.iter().map(|(k, &v)| (k.clone(), v)).collect();
+        entries.sort();
+        let stored = Stored {
+            vocab_size: self.vocab_size,
+            entries,
+        };
+
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&MAGIC.to_le_bytes())?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &stored)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0u8; 8];
+        reader
+            .read_exact(&mut header)
+            .with_context(|| format!("{}: truncated header", path))?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic != MAGIC {
+            bail!("{}: bad magic 0x{:08X}", path, magic);
+        }
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if version != VERSION {
+            bail!("{}: unsupported version {}", path, version);
+        }
+
+        let stored: Stored = bincode::deserialize_from(reader)
+            .with_context(|| format!("{}: corrupt canonical map", path))?;
+        Ok(Self {
+            vocab_size: stored.vocab_size,
+            ids: stored.entries.into_iter().collect(),
+        })
+    }
+
+    /// Number of lines in the vocab the map was built from
+    pub fn vocab_size(&self) -> u32 {
+        self.vocab_size
+    }
+
+    pub fn get(&self, word: &str) -> Option<u32> {
+        self.ids.get(word).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The plain map, as `ImeEngine::from_parts` takes it
+    pub fn into_map(self) -> HashMap<String, u32> {
+        self.ids
+    }
+}
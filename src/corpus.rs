@@ -0,0 +1,230 @@
+//! Corpus inputs shared by the builders
+//!
+//! Builders read corpora, and the word lists the lexicon builders start
+//! from, through `CorpusLines` rather than `BufRead::lines`, so one bad
+//! byte in a multi-gigabyte corpus doesn't abort the build: by default a
+//! line that isn't valid UTF-8 is decoded lossily (invalid sequences
+//! become U+FFFD, which never matches a vocab word) and counted.
+//! `Utf8Policy::Strict` fails on it instead.
+//!
+//! Lines are also capped in length (`DEFAULT_MAX_LINE_LEN`, or
+//! `with_max_line_len`): a corpus with a multi-gigabyte "line", such as a
+//! scraped JSON blob without newlines, would otherwise be read into memory
+//! whole. A longer line is skipped with a warning on stderr, and counted;
+//! at most the cap is ever buffered for it.
+//!
+//! `LineSampler` keeps a fixed fraction of a corpus's lines for quick
+//! experiments (`--sample-rate`). Which lines is decided by a hash of the
+//! line number alone, so every run over the same file sees the same sample.
+
+use crate::sketch::mix64;
+use anyhow::{bail, Result};
+use std::io::{self, BufRead};
+
+/// One input corpus and its relative weight
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorpusSource {
+    pub path: String,
+    pub weight: f64,
+}
+
+impl CorpusSource {
+    /// Parse a `--corpus` spec of the form `path:weight`.
+    /// A bare path (or one whose suffix isn't a number) gets weight 1.0.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (path, weight) = match spec.rsplit_once(':') {
+            Some((p, w)) if !p.is_empty() => match w.parse::<f64>() {
+                Ok(w) => (p, w),
+                Err(_) => (spec, 1.0),
+            },
+            _ => (spec, 1.0),
+        };
+        if !weight.is_finite() || weight <= 0.0 {
+            bail!("corpus weight must be a positive number: {}", spec);
+        }
+        Ok(Self {
+            path: path.to_string(),
+            weight,
+        })
+    }
+}
+
+/// Convert source weights to integer count increments.
+///
+/// The lightest source gets an increment of 1 and the others are scaled
+/// relative to it, so a single unweighted corpus counts exactly as before.
+/// Note that `quantize_weight` normalizes per prev on a log scale
+/// (`ln(count) / ln(max_count)`), which is not scale-invariant: only the
+/// ratios between sources are meaningful, not their absolute values.
+pub fn weight_increments(sources: &[CorpusSource]) -> Vec<u64> {
+    let min = sources
+        .iter()
+        .map(|s| s.weight)
+        .fold(f64::INFINITY, f64::min);
+    sources
+        .iter()
+        .map(|s| ((s.weight / min).round() as u64).max(1))
+        .collect()
+}
+
+/// Deterministic line sampling for `--sample-rate` (see the module docs).
+///
+/// Counts built from a sample shrink by about the rate: a pair seen n times
+/// in the full corpus is seen about n * rate times. Per-prev rankings, and
+/// so the top-N rows, are roughly preserved, but pairs rarer than 1 / rate
+/// may be missed entirely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineSampler {
+    /// Lines whose hash is below this are kept; None keeps every line
+    threshold: Option<u64>,
+}
+
+impl LineSampler {
+    /// Keep about `rate` (0 < rate <= 1) of the lines
+    pub fn new(rate: f64) -> Result<Self> {
+        if !(rate > 0.0 && rate <= 1.0) {
+            bail!("sample rate must be in (0, 1], got {}", rate);
+        }
+        let threshold = (rate < 1.0).then_some((rate * u64::MAX as f64) as u64);
+        Ok(Self { threshold })
+    }
+
+    /// Whether line `line_no` (counted from 0 within its corpus) is kept
+    pub fn keeps(&self, line_no: u64) -> bool {
+        self.threshold.is_none_or(|t| mix64(line_no) < t)
+    }
+}
+
+/// Longest line `CorpusLines` reads by default, in bytes (1 MiB): far past
+/// any real sentence, small enough to buffer safely
+pub const DEFAULT_MAX_LINE_LEN: usize = 1 << 20;
+
+/// What `CorpusLines` does with a line that isn't valid UTF-8
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Decode lossily, count the line and carry on
+    #[default]
+    Lossy,
+    /// Fail with `InvalidData`
+    Strict,
+}
+
+/// Line iterator over a corpus reader that applies a `Utf8Policy` and a
+/// line length cap. Strips `\n` and `\r\n` like `BufRead::lines`.
+pub struct CorpusLines<R> {
+    reader: R,
+    policy: Utf8Policy,
+    max_line_len: usize,
+    line_no: u64,
+    malformed: u64,
+    overlong: u64,
+}
+
+impl<R: BufRead> CorpusLines<R> {
+    pub fn new(reader: R, policy: Utf8Policy) -> Self {
+        Self {
+            reader,
+            policy,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            line_no: 0,
+            malformed: 0,
+            overlong: 0,
+        }
+    }
+
+    /// Skip lines longer than `max_line_len` bytes instead of
+    /// `DEFAULT_MAX_LINE_LEN`
+    pub fn with_max_line_len(mut self, max_line_len: usize) -> Self {
+        self.max_line_len = max_line_len;
+        self
+    }
+
+    /// Lines decoded lossily so far
+    pub fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    /// Lines skipped so far for being longer than the cap
+    pub fn overlong(&self) -> u64 {
+        self.overlong
+    }
+
+    /// Read the next line into `buf`, without its `\n`. None at the end of
+    /// the input; Some(false) if the line was longer than `max_line_len`,
+    /// in which case it is consumed but `buf` is left empty.
+    fn read_capped(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<bool>> {
+        let mut read_any = false;
+        let mut fits = true;
+        loop {
+            let (used, done) = {
+                let available = match self.reader.fill_buf() {
+                    Ok(available) => available,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                if available.is_empty() {
+                    return Ok(read_any.then_some(fits));
+                }
+                read_any = true;
+                let (len, done) = match available.iter().position(|&b| b == b'\n') {
+                    Some(i) => (i, true),
+                    None => (available.len(), false),
+                };
+                if fits && buf.len() + len > self.max_line_len {
+                    fits = false;
+                    *buf = Vec::new();
+                }
+                if fits {
+                    buf.extend_from_slice(&available[..len]);
+                }
+                (len + done as usize, done)
+            };
+            self.reader.consume(used);
+            if done {
+                if buf.last() == Some(&b'\r') {
+                    buf.pop();
+                }
+                return Ok(Some(fits));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CorpusLines<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+        loop {
+            match self.read_capped(&mut buf) {
+                Ok(None) => return None,
+                Ok(Some(fits)) => {
+                    self.line_no += 1;
+                    if fits {
+                        break;
+                    }
+                    self.overlong += 1;
+                    eprintln!(
+                        "warning: line {} is longer than {} bytes; skipped",
+                        self.line_no, self.max_line_len
+                    );
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        match String::from_utf8(buf) {
+            Ok(line) => Some(Ok(line)),
+            Err(e) => match self.policy {
+                Utf8Policy::Lossy => {
+                    self.malformed += 1;
+                    Some(Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+                }
+                Utf8Policy::Strict => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: invalid UTF-8", self.line_no),
+                ))),
+            },
+        }
+    }
+}
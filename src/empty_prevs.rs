@@ -0,0 +1,68 @@
+//! List the words a bigram model has no edges for, most frequent first
+//!
+//! A common word with an empty row is a coverage gap worth fixing (lower
+//! `--min-count`, add data); a rare one usually isn't. Words are ranked by
+//! their unigram prob in the lexicon FST, ties in id order. Case variants
+//! are folded onto their canonical word as the default builders fold them
+//! (see `combined2fst::top_unigrams`), so for a `--case-sensitive` model
+//! only the canonical spellings are checked.
+//!
+//! Each line: word<TAB>prob
+//!
+//! Usage: cargo run --release --bin empty_prevs -- <bigram.bin> <vocab.txt> <lexicon.fst> [--limit N]
+
+use anyhow::{Context, Result};
+use combined2fst::{top_unigrams, BigramModel, Vocab};
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        eprintln!(
+            "Usage: {} <bigram.bin> <vocab.txt> <lexicon.fst> [--limit N]",
+            args[0]
+        );
+        eprintln!("  --limit N : Print only the N most frequent gaps (default: all)");
+        std::process::exit(1);
+    }
+
+    let model = BigramModel::open(&args[1])?;
+    let vocab = Vocab::load(&args[2])?;
+    model
+        .check_vocab(&vocab)
+        .with_context(|| format!("{} doesn't match {}", args[1], args[2]))?;
+    let limit: usize = match flag_value(&args, "--limit") {
+        Some(v) => v.parse().context("--limit must be a number")?,
+        None => usize::MAX,
+    };
+
+    // Canonical words only, as the default builders key rows: "South" has
+    // no row of its own because its edges are under "south"
+    let empty: HashSet<&str> = model.empty_prevs(&vocab).collect();
+    let gaps: Vec<(&str, u8)> = top_unigrams(&args[3], usize::MAX)?
+        .into_iter()
+        .filter_map(|(id, prob)| Some((vocab.word(id)?, prob)))
+        .filter(|(word, _)| empty.contains(word))
+        .collect();
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    for (word, prob) in gaps.iter().take(limit) {
+        writeln!(out, "{}\t{}", word, prob)?;
+    }
+    out.flush()?;
+    eprintln!(
+        "{} words have no edges ({} counting case variants)",
+        gaps.len(),
+        empty.len()
+    );
+
+    Ok(())
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
@@ -0,0 +1,1478 @@
+//! Suggestion engine: canonical map + vocab + bigram model + optional trigram cache
+//!
+//! `suggest` serves a keyboard's suggestion strip from the raw text before
+//! the cursor: completions of the word being typed (from the lexicon FST),
+//! or next-word predictions once the text ends in whitespace.
+//!
+//! `load_async` builds the engine on a background thread, since the
+//! canonical map takes a pass over the whole vocab; the returned
+//! `EngineLoader` answers exact word lookups from the FST meanwhile.
+//!
+//! The model files are memory-mapped read-only and never written. What the
+//! engine learns from the user goes into a `UserHistory` overlay
+//! (`set_personalization`), whose edges `suggest_next` ranks with the
+//! trigram results. The overlay lives in memory: it reaches disk only if
+//! the app saves it (`with_personalization(|h| h.save(path))`), so a
+//! deployment that mustn't persist typing attaches `UserHistory::new()`,
+//! never saves it, and calls `clear_personalization` to forget the
+//! session.
+
+use crate::bigram::{map_file, BigramModel};
+use crate::blocklist::Blocklist;
+use crate::bloom::BloomFilter;
+use crate::builder::DEFAULT_LOG_MAX;
+use crate::error::{EngineError, EngineResult};
+use crate::gating::{apply_gating, BoostTable, Scored};
+use crate::lexicon::search_fst_fuzzy_prefix;
+use crate::recency::RecencyBuffer;
+use crate::reverse::ReverseBigrams;
+use crate::shortcuts::ShortcutTable;
+use crate::tokenize::{LettersOnly, Normalizer};
+use crate::trigram::TrigramCache;
+use crate::user_history::{HistorySession, UserHistory};
+use crate::vocab::Vocab;
+use crate::{canonical_map_for, top_unigrams_in, Count, Edge, ResolvedEdge, Score, Weight};
+use fst::{Map, Streamer};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread::JoinHandle;
+
+/// Warn at load when more than this fraction of bigram edges can't be resolved
+const MAX_UNRESOLVED_FRACTION: f64 = 0.01;
+
+/// Multiplier for bigram weights used to pad a short trigram list
+/// ("stupid backoff"), so padded entries rank below the trigram ones
+const BIGRAM_BACKOFF: f64 = 0.4;
+
+/// Paths kept per step by `suggest_phrase`, and edges expanded per path
+const PHRASE_BEAM_WIDTH: usize = 16;
+
+/// Multiplier for the unigram prior (prob byte scaled to 16 bits) when it
+/// pads a short list, kept small so it never competes with real edges
+const UNIGRAM_BACKOFF: f64 = 0.1;
+
+/// Lexicon words kept as sentence starters when no unigram prior is set,
+/// enough to fill a suggestion strip after blocklist filtering
+const STARTER_CANDIDATES: usize = 100;
+
+/// How `suggest_next` combines trigram and bigram predictions
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Interpolation {
+    /// Trigram results win outright; bigrams only pad a short list, at
+    /// `BIGRAM_BACKOFF` (the default)
+    #[default]
+    TrigramFirst,
+    /// When the last two words have trigrams, score every candidate of
+    /// either model as `lambda * p_trigram + (1 - lambda) * p_bigram`, each
+    /// `p` the count relative to its context's top count (0 if the model
+    /// doesn't predict the word). A trigram seen once can then lose to a
+    /// word the bigram model is sure of. `lambda` is clamped to 0..=1.
+    Linear { lambda: f64 },
+}
+
+/// Which model produced a suggestion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Trigram,
+    Bigram,
+    User,
+    Shortcut,
+    Unigram,
+}
+
+impl Source {
+    pub const ALL: [Source; 5] = [
+        Source::Trigram,
+        Source::Bigram,
+        Source::User,
+        Source::Shortcut,
+        Source::Unigram,
+    ];
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Trigram => write!(f, "Trigram"),
+            Source::Bigram => write!(f, "Bigram"),
+            Source::User => write!(f, "User"),
+            Source::Shortcut => write!(f, "Shortcut"),
+            Source::Unigram => write!(f, "Unigram"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub word: String,
+    pub score: Score,
+    pub source: Source,
+    /// Global word_id, or None for words only the user history knows
+    pub id: Option<u32>,
+    probability: f32,
+}
+
+impl Suggestion {
+    /// A candidate from outside the global models, e.g. user history.
+    /// It has no stored probability (0.0).
+    pub fn new(word: String, score: Score, source: Source, id: Option<u32>) -> Self {
+        Self {
+            word,
+            score,
+            source,
+            id,
+            probability: 0.0,
+        }
+    }
+
+    /// Frequency relative to the most likely continuation of the same
+    /// context (1.0 for that one), recovered from the log-quantized weight.
+    ///
+    /// This is relative within one prev word (or trigram pair), not a global
+    /// language-model probability, and it ignores gating and backoff.
+    /// Dividing by the sum over a context's edges gives a distribution over
+    /// its stored top-N.
+    pub fn probability(&self) -> f32 {
+        self.probability
+    }
+}
+
+/// How often each source contributed to suggestion lists, over many
+/// queries: e.g. the share of contexts the trigram cache covers, for sizing
+/// it. `ImeEngine` tallies its `suggest_next` calls (`source_stats`); a
+/// benchmark or CI check can also `record` lists itself.
+///
+/// Each source counts the queries with at least one suggestion from it, so
+/// the shares don't add up to 100%: a trigram list padded with bigrams
+/// counts for both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceStats {
+    pub queries: u64,
+    /// Queries that got no suggestions at all
+    pub empty: u64,
+    pub trigram: u64,
+    pub bigram: u64,
+    pub user: u64,
+    pub shortcut: u64,
+    pub unigram: u64,
+}
+
+impl SourceStats {
+    pub fn record(&mut self, suggestions: &[Suggestion]) {
+        self.queries += 1;
+        if suggestions.is_empty() {
+            self.empty += 1;
+        }
+        for source in Source::ALL {
+            if suggestions.iter().any(|s| s.source == source) {
+                *self.hits_mut(source) += 1;
+            }
+        }
+    }
+
+    /// Queries with at least one suggestion from `source`
+    pub fn hits(&self, source: Source) -> u64 {
+        match source {
+            Source::Trigram => self.trigram,
+            Source::Bigram => self.bigram,
+            Source::User => self.user,
+            Source::Shortcut => self.shortcut,
+            Source::Unigram => self.unigram,
+        }
+    }
+
+    fn hits_mut(&mut self, source: Source) -> &mut u64 {
+        match source {
+            Source::Trigram => &mut self.trigram,
+            Source::Bigram => &mut self.bigram,
+            Source::User => &mut self.user,
+            Source::Shortcut => &mut self.shortcut,
+            Source::Unigram => &mut self.unigram,
+        }
+    }
+
+    /// Share of queries (0.0 to 1.0) `source` contributed to; 0.0 before
+    /// any query
+    pub fn coverage(&self, source: Source) -> f64 {
+        self.hits(source) as f64 / self.queries.max(1) as f64
+    }
+
+    /// Share of queries that got no suggestions
+    pub fn empty_rate(&self) -> f64 {
+        self.empty as f64 / self.queries.max(1) as f64
+    }
+
+    /// Add another tally, e.g. from a second test set
+    pub fn merge(&mut self, other: &SourceStats) {
+        self.queries += other.queries;
+        self.empty += other.empty;
+        for source in Source::ALL {
+            *self.hits_mut(source) += other.hits(source);
+        }
+    }
+}
+
+impl fmt::Display for SourceStats {
+    /// "Trigram 40.0%, Bigram 90.0%, ..., empty 10.0% of 10 queries"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for source in Source::ALL {
+            write!(f, "{} {:.1}%, ", source, 100.0 * self.coverage(source))?;
+        }
+        write!(
+            f,
+            "empty {:.1}% of {} queries",
+            100.0 * self.empty_rate(),
+            self.queries
+        )
+    }
+}
+
+/// What a custom ranker (`ImeEngine::set_ranker`) sees of a suggestion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate<'a> {
+    pub word: &'a str,
+    /// After backoff, recency and gating; what ranks it by default
+    pub score: Score,
+    /// As `Suggestion::probability`
+    pub probability: f32,
+    pub source: Source,
+    /// Length of `word` in chars
+    pub len: usize,
+    /// Learned from the user's own typing rather than the global models
+    pub is_user: bool,
+}
+
+impl<'a> Candidate<'a> {
+    pub fn of(suggestion: &'a Suggestion) -> Self {
+        Self {
+            word: &suggestion.word,
+            score: suggestion.score,
+            probability: suggestion.probability,
+            source: suggestion.source,
+            len: suggestion.word.chars().count(),
+            is_user: suggestion.source == Source::User,
+        }
+    }
+}
+
+/// Ranks candidates for `ImeEngine::set_ranker`: higher first
+pub type Ranker = Box<dyn Fn(&Candidate) -> f32 + Send + Sync>;
+
+/// The ranking the engine uses without a custom ranker: by score. A custom
+/// ranker can start from it, e.g. `default_rank(c) - 500.0 * c.len as f32`.
+pub fn default_rank(candidate: &Candidate) -> f32 {
+    candidate.score.0 as f32
+}
+
+/// Summary of which models contributed to a suggestion list,
+/// e.g. "Trigram+Bigram" when trigram results were padded
+pub fn source_label(suggestions: &[Suggestion]) -> &'static str {
+    let trigram = suggestions.iter().any(|s| s.source == Source::Trigram);
+    let bigram = suggestions.iter().any(|s| s.source == Source::Bigram);
+    match (trigram, bigram) {
+        (true, true) => "Trigram+Bigram",
+        (true, false) => "Trigram",
+        (false, true) => "Bigram",
+        (false, false) => "None",
+    }
+}
+
+/// How many chars of a suggestion the command-line tools print
+pub const MAX_DISPLAY_CHARS: usize = 32;
+
+/// `word` cut to at most `max_chars` chars, the last of them "…" when it
+/// had to be cut. Counts and cuts chars, never bytes, so multibyte
+/// Vietnamese letters and emoji can't be split (slicing a `String` at a
+/// byte index inside one panics).
+pub fn truncate_display(word: &str, max_chars: usize) -> String {
+    if max_chars == 0 {
+        return String::new();
+    }
+    if word.char_indices().nth(max_chars).is_none() {
+        return word.to_string();
+    }
+    let end = word
+        .char_indices()
+        .nth(max_chars - 1)
+        .map_or(word.len(), |(i, _)| i);
+    format!("{}…", &word[..end])
+}
+
+/// Merge suggestions from several sources into one ranked list.
+///
+/// Each word is kept once, with the highest score any source gave it; the
+/// list is then stable-sorted by score descending. Equal scores fall back
+/// to the global id, then to the word itself for user-only words, so the
+/// order doesn't depend on which source was appended first.
+pub fn finalize_suggestions(suggestions: &mut Vec<Suggestion>) {
+    let mut best: HashMap<String, usize> = HashMap::new();
+    let mut kept: Vec<Suggestion> = Vec::with_capacity(suggestions.len());
+    for s in suggestions.drain(..) {
+        match best.get(&s.word) {
+            Some(&i) => {
+                if s.score > kept[i].score {
+                    kept[i] = s;
+                }
+            }
+            None => {
+                best.insert(s.word.clone(), kept.len());
+                kept.push(s);
+            }
+        }
+    }
+
+    // None sorts after every Some, so user-only words follow id'd ones
+    kept.sort_by(|a, b| {
+        b.score.cmp(&a.score).then_with(|| match (a.id, b.id) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => a.word.cmp(&b.word),
+        })
+    });
+    *suggestions = kept;
+}
+
+/// `finalize_suggestions`, with equal scores going to the word with the
+/// higher global unigram prob first, then as before. Quantized weights are
+/// lossy, so many edges of a row share one; among those the more common
+/// word is the better guess. Words without an id count as prob 0.
+pub fn finalize_suggestions_with(
+    suggestions: &mut Vec<Suggestion>,
+    unigram_prob: impl Fn(u32) -> u8,
+) {
+    finalize_suggestions(suggestions);
+    suggestions.sort_by_cached_key(|s| {
+        (
+            std::cmp::Reverse(s.score),
+            std::cmp::Reverse(s.id.map_or(0, &unigram_prob)),
+        )
+    });
+}
+
+/// Whether a `suggest` entry finishes the word being typed or follows it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SuggestMode {
+    /// Replaces the partial word before the cursor
+    Completion,
+    /// Goes after the text (after the completed word, when bridging)
+    Prediction,
+}
+
+/// One entry of `ImeEngine::suggest`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaggedSuggestion {
+    pub mode: SuggestMode,
+    pub suggestion: Suggestion,
+}
+
+fn tag(suggestions: Vec<Suggestion>, mode: SuggestMode) -> Vec<TaggedSuggestion> {
+    suggestions
+        .into_iter()
+        .map(|suggestion| TaggedSuggestion { mode, suggestion })
+        .collect()
+}
+
+impl Scored for Suggestion {
+    fn word(&self) -> &str {
+        &self.word
+    }
+    fn score(&self) -> Score {
+        self.score
+    }
+    fn set_score(&mut self, score: Score) {
+        self.score = score;
+    }
+}
+
+pub struct ImeEngine {
+    vocab: Vocab,
+    canonical_map: HashMap<String, u32>,
+    /// Orders each tier instead of the score when set
+    ranker: Option<Ranker>,
+    /// Checked before `canonical_map` once `enable_canonical_bloom` is called
+    canonical_bloom: Option<BloomFilter>,
+    bigram: BigramModel,
+    trigram: Option<TrigramCache>,
+    /// Word FST for completions; set by `load` or `set_lexicon`
+    lexicon: Option<Map<Mmap>>,
+    /// Built on the first `suggest_given_next`
+    reverse: OnceLock<ReverseBigrams>,
+    boost: BoostTable,
+    /// Shared with the personalization overlay
+    normalizer: Arc<dyn Normalizer>,
+    shortcuts: ShortcutTable,
+    capitalize: bool,
+    interpolation: Interpolation,
+    blocklist: Blocklist,
+    /// Words committed this session, boosted by `suggest_next`
+    recency: Mutex<RecencyBuffer>,
+    /// (word_id, prob) by prob descending; empty unless set
+    unigram_prior: Vec<(u32, u8)>,
+    /// The lexicon's most probable words, as `unigram_prior`; found on the
+    /// first empty context when no prior is set
+    starters: OnceLock<Vec<(u32, u8)>>,
+    /// Each word id's prob in the lexicon, for tiebreaks; built on first use
+    unigram_probs: OnceLock<Vec<u8>>,
+    /// Edges skipped during lookups because next_id >= vocab.len()
+    decode_errors: AtomicU64,
+    /// User edges blended into `suggest_next`; None until
+    /// `set_personalization`
+    personal: Mutex<Option<UserHistory>>,
+    /// Sources of the `suggest_next` results so far
+    source_stats: Mutex<SourceStats>,
+    /// Bigram edges found out of range when the engine was built
+    unresolved_at_load: usize,
+}
+
+impl ImeEngine {
+    /// Load all models. A missing trigram file is not an error; a bigram
+    /// model built for a different vocab size is
+    /// (`EngineError::VocabMismatch`), as is one whose recorded vocab
+    /// fingerprint differs (`EngineError::VocabHashMismatch`). A trigram
+    /// cache built for another vocab is dropped with a warning, leaving
+    /// `has_trigram` false.
+    pub fn load(
+        fst_path: &str,
+        vocab_path: &str,
+        bigram_path: &str,
+        trigram_path: Option<&str>,
+    ) -> EngineResult<Self> {
+        let vocab = Vocab::load(vocab_path)?;
+        Self::load_with_vocab(fst_path, vocab, bigram_path, trigram_path)
+    }
+
+    /// `load` with a vocab that's already in memory, e.g. a clone shared
+    /// with another engine (see `registry::EngineRegistry`). The same
+    /// checks apply.
+    pub fn load_with_vocab(
+        fst_path: &str,
+        vocab: Vocab,
+        bigram_path: &str,
+        trigram_path: Option<&str>,
+    ) -> EngineResult<Self> {
+        let canonical_map = canonical_map_for(fst_path, &vocab)?;
+        let bigram = BigramModel::open(bigram_path)?;
+        bigram.check_vocab(&vocab)?;
+        let trigram = match trigram_path {
+            Some(p) if std::path::Path::new(p).exists() => {
+                match TrigramCache::open_for(p, &vocab) {
+                    Ok(cache) => Some(cache),
+                    // Optional model: fall back to bigrams rather than
+                    // resolve its ids to the wrong words
+                    Err(err @ EngineError::TrigramVocabHashMismatch { .. }) => {
+                        eprintln!("warning: {}: {}; trigrams disabled", p, err);
+                        None
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            _ => None,
+        };
+        let mut engine = Self::from_parts(vocab, canonical_map, bigram, trigram);
+        engine.set_lexicon(fst_path)?;
+        Ok(engine)
+    }
+
+    /// `load` on a background thread, so the caller (a keyboard's UI
+    /// thread) isn't blocked on the canonical map. The lexicon FST is
+    /// opened before returning, so a missing or corrupt one fails here and
+    /// `EngineLoader::get` works at once; every other error comes from
+    /// `EngineLoader::wait`.
+    pub fn load_async(
+        fst_path: &str,
+        vocab_path: &str,
+        bigram_path: &str,
+        trigram_path: Option<&str>,
+    ) -> EngineResult<EngineLoader> {
+        let lexicon = Map::new(map_file(fst_path)?).map_err(|source| EngineError::Fst {
+            path: fst_path.to_string(),
+            source,
+        })?;
+        let (fst_path, vocab_path, bigram_path) = (
+            fst_path.to_string(),
+            vocab_path.to_string(),
+            bigram_path.to_string(),
+        );
+        let trigram_path = trigram_path.map(str::to_string);
+        let thread = std::thread::spawn(move || {
+            Self::load(
+                &fst_path,
+                &vocab_path,
+                &bigram_path,
+                trigram_path.as_deref(),
+            )
+        });
+        Ok(EngineLoader { lexicon, thread })
+    }
+
+    pub fn from_parts(
+        vocab: Vocab,
+        canonical_map: HashMap<String, u32>,
+        bigram: BigramModel,
+        trigram: Option<TrigramCache>,
+    ) -> Self {
+        let unresolved_at_load = bigram.count_out_of_range(vocab.len());
+        let total = bigram.edges_count().max(1);
+        if unresolved_at_load as f64 / total as f64 > MAX_UNRESOLVED_FRACTION {
+            eprintln!(
+                "warning: {}/{} bigram edges reference ids past the vocab ({} words); \
+                 model and vocab are probably mismatched",
+                unresolved_at_load,
+                bigram.edges_count(),
+                vocab.len()
+            );
+        }
+
+        Self {
+            vocab,
+            canonical_map,
+            canonical_bloom: None,
+            ranker: None,
+            bigram,
+            trigram,
+            lexicon: None,
+            reverse: OnceLock::new(),
+            boost: BoostTable::default(),
+            normalizer: Arc::new(LettersOnly),
+            shortcuts: ShortcutTable::default(),
+            capitalize: false,
+            interpolation: Interpolation::default(),
+            blocklist: Blocklist::default(),
+            recency: Mutex::new(RecencyBuffer::default()),
+            unigram_prior: Vec::new(),
+            starters: OnceLock::new(),
+            unigram_probs: OnceLock::new(),
+            decode_errors: AtomicU64::new(0),
+            personal: Mutex::new(None),
+            source_stats: Mutex::new(SourceStats::default()),
+            unresolved_at_load,
+        }
+    }
+
+    /// How context words are cleaned before lookup (`LettersOnly` by
+    /// default); should match the tokenizer the models were built with.
+    /// The personalization overlay learns through it too.
+    pub fn set_normalizer(&mut self, normalizer: impl Normalizer + 'static) {
+        self.normalizer = Arc::new(normalizer);
+        if let Some(history) = self.personal_overlay().as_mut() {
+            history.share_normalizer(self.normalizer.clone());
+        }
+    }
+
+    /// Function-word gating applied by `suggest_next` (none by default)
+    pub fn set_boost_table(&mut self, boost: BoostTable) {
+        self.boost = boost;
+    }
+
+    /// Shortcut expansions offered by `suggest_next` (none by default)
+    pub fn set_shortcuts(&mut self, shortcuts: ShortcutTable) {
+        self.shortcuts = shortcuts;
+    }
+
+    /// Restore case in `suggest_next` output (off by default): capitalize
+    /// suggestions at the start of a sentence, and the English pronoun "I"
+    /// everywhere
+    pub fn set_capitalization(&mut self, enabled: bool) {
+        self.capitalize = enabled;
+    }
+
+    /// How trigram and bigram scores combine in `suggest_next`
+    /// (`Interpolation::TrigramFirst` by default)
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    /// Rank suggestions by `ranker` instead of by score (`default_rank`).
+    /// It orders each tier of `suggest_next`, `sentence_starters` and the
+    /// completions of `suggest` once they are gated and deduplicated;
+    /// candidates of equal rank keep their score order. Tiers still come
+    /// in their fixed order, so a ranker can't lift a bigram over a
+    /// trigram. Setting `Box::new(default_rank)` restores the default.
+    pub fn set_ranker(&mut self, ranker: Ranker) {
+        self.ranker = Some(ranker);
+    }
+
+    /// Words never returned by `lookup_bigram`, `lookup_trigram`,
+    /// `suggest`, `suggest_next`, `suggest_phrase` or `suggest_given_next`
+    /// (none by default)
+    pub fn set_blocklist(&mut self, blocklist: Blocklist) {
+        self.blocklist = blocklist;
+    }
+
+    pub fn blocklist(&self) -> &Blocklist {
+        &self.blocklist
+    }
+
+    /// Boost suggestions the user committed in the last `capacity` words
+    /// (see `recency`), the latest by `boost`; 0 turns it off, the default.
+    /// Replaces what was recorded so far.
+    pub fn set_recency(&mut self, capacity: usize, boost: f64) {
+        self.recency = Mutex::new(RecencyBuffer::new(capacity, boost));
+    }
+
+    /// Note a word the user just committed, for the recency boost. Words
+    /// outside the lexicon are ignored.
+    pub fn record_commit(&self, word: &str) {
+        if let Some(id) = self.word_id(&self.normalizer.normalize(word, false)) {
+            self.recency_buffer().push(id);
+        }
+    }
+
+    /// Forget the words recorded by `record_commit`
+    pub fn clear_recency(&self) {
+        self.recency_buffer().clear();
+    }
+
+    /// Globally frequent words used to pad short lists, as (word_id, prob)
+    /// from `top_unigrams` (empty by default, so unseen contexts return
+    /// nothing)
+    pub fn set_unigram_prior(&mut self, prior: Vec<(u32, u8)>) {
+        self.unigram_prior = prior;
+    }
+
+    /// Complete partial words in `suggest` from the lexicon FST at
+    /// `fst_path` (`load` sets the one it was given; without one `suggest`
+    /// only predicts)
+    pub fn set_lexicon(&mut self, fst_path: &str) -> EngineResult<()> {
+        let map = Map::new(map_file(fst_path)?).map_err(|source| EngineError::Fst {
+            path: fst_path.to_string(),
+            source,
+        })?;
+        self.lexicon = Some(map);
+        self.starters = OnceLock::new();
+        self.unigram_probs = OnceLock::new();
+        Ok(())
+    }
+
+    /// Expansion of a typed shortcut such as "omw"
+    pub fn expand_shortcut(&self, typed: &str) -> Option<&str> {
+        self.shortcuts.expand(typed)
+    }
+
+    /// Blend what `history` learned into `suggest_next` (see the module
+    /// docs), and learn into it with `learn_personal`. `UserHistory::new()`
+    /// gives a memory-only overlay. Replaces any overlay set before.
+    /// `history` takes this engine's normalizer.
+    pub fn set_personalization(&mut self, mut history: UserHistory) {
+        history.share_normalizer(self.normalizer.clone());
+        *self.personal_overlay() = Some(history);
+    }
+
+    /// Learn `text` (a sentence or more the user committed) into the
+    /// overlay; words in this engine's lexicon are learned under their
+    /// global ids. Does nothing without an overlay.
+    pub fn learn_personal(&self, text: &str) {
+        if let Some(history) = self.personal_overlay().as_mut() {
+            history.learn(text, |word: &str| self.word_id(word));
+        }
+    }
+
+    /// Forget everything personal: the overlay is emptied (it stays
+    /// attached, with its config) and so is the recency window, leaving
+    /// only the global models to rank by
+    pub fn clear_personalization(&self) {
+        if let Some(history) = self.personal_overlay().as_mut() {
+            *history = UserHistory::new_with_config(*history.config());
+            history.share_normalizer(self.normalizer.clone());
+        }
+        self.clear_recency();
+    }
+
+    /// Run `f` on the overlay, e.g. to save it; None without one
+    pub fn with_personalization<R>(&self, f: impl FnOnce(&UserHistory) -> R) -> Option<R> {
+        self.personal_overlay().as_ref().map(f)
+    }
+
+    fn personal_overlay(&self) -> MutexGuard<'_, Option<UserHistory>> {
+        self.personal.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The overlay's predictions after the last two words of `words`
+    /// (`UserHistory::predict_trigram`, which backs off to the last word
+    /// alone), as suggestions
+    fn personal_predictions(&self, words: &[String], ids: &[Option<u32>]) -> Vec<Suggestion> {
+        let overlay = self.personal_overlay();
+        let Some(history) = overlay.as_ref() else {
+            return Vec::new();
+        };
+        // Global id, else the overlay's id for a word only the user typed
+        let id_at = |back: usize| {
+            let i = words.len().checked_sub(back)?;
+            ids[i].or_else(|| history.get_user_word_id(&words[i]))
+        };
+        let Some(prev) = id_at(1) else {
+            return Vec::new();
+        };
+        let predictions = match id_at(2) {
+            Some(prev_prev) => history.predict_trigram(prev_prev, prev),
+            None => history.predict(prev),
+        };
+        let mut suggestions = Vec::new();
+        // The pair's words come before the backoff even when it scores them
+        // lower; keep that order through ranking by never scoring an entry
+        // above the one before it
+        let mut ceiling = Score::MAX;
+        for (id, score) in predictions {
+            let (word, global_id) = match self.vocab.word(id) {
+                Some(word) => (word, Some(id)),
+                None => match history.get_user_word(id) {
+                    Some(word) => (word, None),
+                    None => continue,
+                },
+            };
+            if self.blocklist.contains_id(id) || self.blocklist.contains_word(word) {
+                continue;
+            }
+            let score = score.to_score().min(ceiling);
+            ceiling = Score(score.0.saturating_sub(1));
+            suggestions.push(Suggestion::new(
+                word.to_string(),
+                score,
+                Source::User,
+                global_id,
+            ));
+        }
+        suggestions
+    }
+
+    /// Start learning into `history` word by word, as the keyboard commits
+    /// them (see `UserHistory::session`); words in this engine's lexicon are
+    /// learned under their global ids. Call `reset` on the session at
+    /// sentence boundaries.
+    pub fn begin_session<'a>(
+        &'a self,
+        history: &'a mut UserHistory,
+    ) -> HistorySession<'a, impl Fn(&str) -> Option<u32> + 'a> {
+        history.session(move |word: &str| self.word_id(word))
+    }
+
+    /// Warm the bigram and trigram pages (see `BigramModel::prefetch`).
+    /// Returns the bytes covered.
+    pub fn prefetch(&self, include_edges: bool) -> usize {
+        self.bigram.prefetch(include_edges)
+            + self
+                .trigram
+                .as_ref()
+                .map_or(0, |t| t.prefetch(include_edges))
+    }
+
+    /// Resident bytes across the mmap'd models, if the platform can tell
+    pub fn resident_estimate(&self) -> Option<usize> {
+        let trigram = match &self.trigram {
+            Some(t) => t.resident_estimate()?,
+            None => 0,
+        };
+        Some(self.bigram.resident_estimate()? + trigram)
+    }
+
+    pub fn vocab(&self) -> &Vocab {
+        &self.vocab
+    }
+
+    pub fn has_trigram(&self) -> bool {
+        self.trigram.is_some()
+    }
+
+    /// Canonical word_id for an already-normalized token
+    pub fn word_id(&self, word: &str) -> Option<u32> {
+        if !self.might_be_word(word) {
+            return None;
+        }
+        self.canonical_map.get(word).copied()
+    }
+
+    /// Check a Bloom filter of the canonical map's keys before the map
+    /// itself (see `bloom`), sized for `fp_rate` false positives
+    /// (`bloom::DEFAULT_FP_RATE` suits most vocabs). Off by default.
+    pub fn enable_canonical_bloom(&mut self, fp_rate: f64) {
+        self.canonical_bloom = Some(BloomFilter::from_keys(self.canonical_map.keys(), fp_rate));
+    }
+
+    pub fn canonical_bloom(&self) -> Option<&BloomFilter> {
+        self.canonical_bloom.as_ref()
+    }
+
+    /// False when an already-normalized token is definitely not a vocab
+    /// word, decided by the Bloom filter alone; true when it may be one.
+    /// Always true without a filter.
+    pub fn might_be_word(&self, word: &str) -> bool {
+        self.canonical_bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.may_contain(word))
+    }
+
+    pub fn lookup_bigram(&self, prev_id: u32) -> Vec<ResolvedEdge<'_>> {
+        self.resolve_edges(self.bigram.next(prev_id, usize::MAX))
+    }
+
+    pub fn lookup_trigram(&self, w1: u32, w2: u32) -> Option<Vec<ResolvedEdge<'_>>> {
+        let edges = self.trigram.as_ref()?.lookup(w1, w2, usize::MAX)?;
+        Some(self.resolve_edges(edges))
+    }
+
+    /// Next-word suggestions for `context`.
+    ///
+    /// Trigram results for the last two words come first; if there are fewer
+    /// than `limit`, the list is padded with bigram results for the last word
+    /// that aren't already present, their scores scaled by `BIGRAM_BACKOFF`.
+    /// With `Interpolation::Linear` the trigram and bigram candidates are
+    /// instead ranked together by their interpolated score, when the last
+    /// two words have trigrams.
+    /// If still short and a unigram prior is set, the most frequent words
+    /// fill the rest at `UNIGRAM_BACKOFF`.
+    /// A context with no words (empty, whitespace or punctuation only)
+    /// gets `sentence_starters` instead, with the overlay's history if one
+    /// is set.
+    /// With a personalization overlay, what the user typed after the last
+    /// word ranks with the trigram results, at its own score.
+    /// Within each of these tiers, words recorded with `record_commit`
+    /// are boosted when a recency window is set.
+    /// If the last word typed is a shortcut, its expansion goes first.
+    /// Each tier is cut to the room left only once gated, deduplicated and
+    /// ranked, so the result holds at most `limit` entries and they are the
+    /// best of the final ranking.
+    pub fn suggest_next(&self, context: &str, limit: usize) -> Vec<Suggestion> {
+        let suggestions = self.capitalized(limit, at_sentence_start(context), |n| {
+            self.ranked_next(context, n)
+        });
+        self.source_stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(&suggestions);
+        suggestions
+    }
+
+    /// `suggest_next` before capitalization
+    fn ranked_next(&self, context: &str, limit: usize) -> Vec<Suggestion> {
+        let words: Vec<String> = context
+            .split_whitespace()
+            .map(|w| self.normalizer.normalize(w, false))
+            .filter(|w| !w.is_empty())
+            .collect();
+        let ids: Vec<Option<u32>> = words.iter().map(|w| self.word_id(w)).collect();
+
+        let mut suggestions: Vec<Suggestion> = Vec::new();
+
+        if let [.., Some(id1), Some(id2)] = ids[..] {
+            if let Some(trigram) = &self.trigram {
+                // Read every stored edge: gating can lift one past `limit`
+                if let Some(edges) = trigram.lookup(id1, id2, usize::MAX) {
+                    let log_max = trigram.log_max(id1, id2).unwrap_or(DEFAULT_LOG_MAX);
+                    let hits =
+                        self.resolve(edges)
+                            .into_iter()
+                            .map(|(id, word, weight)| Suggestion {
+                                word,
+                                score: weight.into(),
+                                source: Source::Trigram,
+                                id: Some(id),
+                                probability: weight.relative_count(log_max) as f32,
+                            });
+                    match self.interpolation {
+                        Interpolation::TrigramFirst => suggestions.extend(hits),
+                        Interpolation::Linear { lambda } => {
+                            suggestions = self.interpolate(hits.collect(), id2, lambda)
+                        }
+                    }
+                }
+            }
+        }
+        suggestions.extend(self.personal_predictions(&words, &ids));
+        self.rank_tier(&mut suggestions, limit);
+
+        if suggestions.len() < limit {
+            if let Some(Some(id)) = ids.last() {
+                let log_max = self.bigram.log_max(*id).unwrap_or(DEFAULT_LOG_MAX);
+                let mut padding: Vec<Suggestion> = Vec::new();
+                // Every edge, not just `limit`: gating can lift one past
+                // those
+                for (next_id, word, weight) in self.resolve(self.bigram.next(*id, usize::MAX)) {
+                    if suggestions.iter().any(|s| s.word == word) {
+                        continue;
+                    }
+                    padding.push(Suggestion {
+                        word,
+                        score: Score::from(weight).scale(BIGRAM_BACKOFF),
+                        source: Source::Bigram,
+                        id: Some(next_id),
+                        probability: weight.relative_count(log_max) as f32,
+                    });
+                }
+                // Gated separately so a boosted bigram can't jump a trigram entry
+                self.rank_tier(&mut padding, limit - suggestions.len());
+                suggestions.extend(padding);
+            }
+        }
+
+        if words.is_empty() {
+            // Nothing typed yet, or only punctuation
+            suggestions = self.starters(self.personal_overlay().as_ref(), limit);
+        } else if suggestions.len() < limit {
+            let mut padding: Vec<Suggestion> = Vec::new();
+            for &(id, prob) in &self.unigram_prior {
+                let Some(word) = self.vocab.word(id) else {
+                    continue;
+                };
+                if self.blocklist.contains_id(id) || suggestions.iter().any(|s| s.word == word) {
+                    continue;
+                }
+                let score = prob as f64 * 257.0 * UNIGRAM_BACKOFF;
+                padding.push(Suggestion::new(
+                    word.to_string(),
+                    Score(score as u16),
+                    Source::Unigram,
+                    Some(id),
+                ));
+            }
+            self.rank_tier(&mut padding, limit - suggestions.len());
+            suggestions.extend(padding);
+        }
+
+        let typed = context.split_whitespace().last().unwrap_or("");
+        if let Some(expansion) = self
+            .expand_shortcut(typed)
+            .filter(|e| !self.blocklist.contains_word(e))
+        {
+            suggestions.retain(|s| s.word != expansion);
+            suggestions.insert(
+                0,
+                Suggestion {
+                    word: expansion.to_string(),
+                    score: Score::MAX,
+                    source: Source::Shortcut,
+                    id: None,
+                    probability: 1.0,
+                },
+            );
+            suggestions.truncate(limit);
+        }
+        suggestions
+    }
+
+    /// `limit` suggestions from `fetch`, capitalized when that is on.
+    /// Capitalizing can make two suggestions the same word ("the" and
+    /// "The" at a sentence start); the duplicate is dropped and `fetch`
+    /// asked for more, so the list still fills up to `limit`.
+    fn capitalized(
+        &self,
+        limit: usize,
+        sentence_start: bool,
+        fetch: impl Fn(usize) -> Vec<Suggestion>,
+    ) -> Vec<Suggestion> {
+        if !self.capitalize {
+            return fetch(limit);
+        }
+        let mut want = limit;
+        loop {
+            let mut suggestions = fetch(want);
+            let fetched = suggestions.len();
+            capitalize_suggestions(&mut suggestions, sentence_start);
+            if suggestions.len() >= limit || fetched < want {
+                suggestions.truncate(limit);
+                return suggestions;
+            }
+            want = want.saturating_add(limit - suggestions.len());
+        }
+    }
+
+    /// Suggestions before the user has typed a word: the most frequent
+    /// words by FST prob. These come from the unigram prior when one is
+    /// set, else from the lexicon, whose top words are found with a pass
+    /// over it on the first call. With `history`, the words the user types
+    /// most (`UserHistory::top_words`) lead, taking up to half the list.
+    pub fn sentence_starters(
+        &self,
+        history: Option<&UserHistory>,
+        limit: usize,
+    ) -> Vec<Suggestion> {
+        self.capitalized(limit, true, |n| self.starters(history, n))
+    }
+
+    fn starters(&self, history: Option<&UserHistory>, limit: usize) -> Vec<Suggestion> {
+        let mut suggestions: Vec<Suggestion> = Vec::new();
+        if let Some(history) = history {
+            for (id, score) in history.top_words(limit.div_ceil(2)) {
+                let (word, global_id) = match self.vocab.word(id) {
+                    Some(word) => (word, Some(id)),
+                    None => match history.get_user_word(id) {
+                        Some(word) => (word, None),
+                        None => continue,
+                    },
+                };
+                if self.blocklist.contains_id(id) || self.blocklist.contains_word(word) {
+                    continue;
+                }
+                suggestions.push(Suggestion::new(
+                    word.to_string(),
+                    score.to_score(),
+                    Source::User,
+                    global_id,
+                ));
+            }
+        }
+
+        let starters = if self.unigram_prior.is_empty() {
+            self.starters.get_or_init(|| {
+                self.lexicon
+                    .as_ref()
+                    .map(|lexicon| top_unigrams_in(lexicon, STARTER_CANDIDATES))
+                    .unwrap_or_default()
+            })
+        } else {
+            &self.unigram_prior
+        };
+        let mut global: Vec<Suggestion> = Vec::new();
+        for &(id, prob) in starters {
+            let Some(word) = self.vocab.word(id) else {
+                continue;
+            };
+            if self.blocklist.contains_id(id) || suggestions.iter().any(|s| s.word == word) {
+                continue;
+            }
+            global.push(Suggestion::new(
+                word.to_string(),
+                Score(prob as u16 * 257),
+                Source::Unigram,
+                Some(id),
+            ));
+        }
+        self.rank_tier(&mut global, limit.saturating_sub(suggestions.len()));
+        suggestions.extend(global);
+        suggestions
+    }
+
+    /// Suggestions for the text before the cursor, tagged by mode.
+    ///
+    /// If `text` ends in whitespace (or is empty) the user is between
+    /// words, and this is `suggest_next`. Otherwise its last token is a
+    /// word in progress, completed from the lexicon: words that follow the
+    /// previous word in the bigram model come first, by bigram weight,
+    /// then the lexicon's other matches by prob at `UNIGRAM_BACKOFF`. When
+    /// there are fewer than `limit` completions, predictions for the text
+    /// with its best completion typed out fill the rest ("i lov" -> "love",
+    /// then "you"), so a word can appear once per mode.
+    pub fn suggest(&self, text: &str, limit: usize) -> Vec<TaggedSuggestion> {
+        if text.is_empty() || text.ends_with(char::is_whitespace) {
+            return tag(self.suggest_next(text, limit), SuggestMode::Prediction);
+        }
+        let partial = text.split_whitespace().last().unwrap_or("");
+        let context = &text[..text.len() - partial.len()];
+
+        let completions = self.capitalized(limit, at_sentence_start(context), |n| {
+            self.complete(context, partial, n)
+        });
+        let bridge = match completions.first() {
+            Some(best) if completions.len() < limit => self.suggest_next(
+                &format!("{}{}", context, best.word),
+                limit - completions.len(),
+            ),
+            _ => Vec::new(),
+        };
+        let mut tagged = tag(completions, SuggestMode::Completion);
+        tagged.extend(tag(bridge, SuggestMode::Prediction));
+        tagged
+    }
+
+    /// Lexicon words starting with `partial`, ranked for `suggest`
+    fn complete(&self, context: &str, partial: &str, limit: usize) -> Vec<Suggestion> {
+        let prefix = self.normalizer.normalize(partial, false);
+        let Some(lexicon) = &self.lexicon else {
+            return Vec::new();
+        };
+        if prefix.is_empty() {
+            return Vec::new();
+        }
+
+        let prev = context
+            .split_whitespace()
+            .map(|w| self.normalizer.normalize(w, false))
+            .rfind(|w| !w.is_empty())
+            .and_then(|w| self.word_id(&w));
+        let mut suggestions: Vec<Suggestion> = Vec::new();
+        if let Some(prev) = prev {
+            let log_max = self.bigram.log_max(prev).unwrap_or(DEFAULT_LOG_MAX);
+            for (id, word, weight) in self.resolve(self.bigram.next(prev, usize::MAX)) {
+                if word.starts_with(&prefix) {
+                    suggestions.push(Suggestion {
+                        word,
+                        score: weight.into(),
+                        source: Source::Bigram,
+                        id: Some(id),
+                        probability: weight.relative_count(log_max) as f32,
+                    });
+                }
+            }
+        }
+        self.apply_recency(&mut suggestions);
+        self.finalize(&mut suggestions);
+        suggestions.truncate(limit);
+
+        if suggestions.len() < limit {
+            // An exact search can't fail; only the fuzzy automaton can
+            let matches =
+                search_fst_fuzzy_prefix(lexicon, &prefix, 0, usize::MAX).unwrap_or_default();
+            let mut padding: Vec<Suggestion> = Vec::new();
+            for m in matches {
+                if suggestions.len() + padding.len() >= limit {
+                    break;
+                }
+                if self.blocklist.contains_id(m.word_id)
+                    || self.blocklist.contains_word(&m.word)
+                    || suggestions.iter().any(|s| s.word == m.word)
+                {
+                    continue;
+                }
+                let score = m.prob as f64 * 257.0 * UNIGRAM_BACKOFF;
+                padding.push(Suggestion::new(
+                    m.word,
+                    Score(score as u16),
+                    Source::Unigram,
+                    Some(m.word_id),
+                ));
+            }
+            self.apply_recency(&mut padding);
+            self.finalize(&mut padding);
+            suggestions.extend(padding);
+        }
+        suggestions
+    }
+
+    /// Mix `trigram` hits with the bigram row of `prev` for
+    /// `Interpolation::Linear`. Each candidate's score is its interpolated
+    /// score scaled to 0..=65535, its source whichever model contributed
+    /// more.
+    fn interpolate(&self, trigram: Vec<Suggestion>, prev: u32, lambda: f64) -> Vec<Suggestion> {
+        let lambda = lambda.clamp(0.0, 1.0);
+        let log_max = self.bigram.log_max(prev).unwrap_or(DEFAULT_LOG_MAX);
+        let mut bigram: Vec<(u32, String, f64)> = self
+            .resolve(self.bigram.next(prev, usize::MAX))
+            .into_iter()
+            .map(|(id, word, weight)| (id, word, weight.relative_count(log_max)))
+            .collect();
+
+        let mix = |word: String, id: u32, p3: f64, p2: f64| {
+            let (tri, bi) = (lambda * p3, (1.0 - lambda) * p2);
+            let p = tri + bi;
+            Suggestion {
+                word,
+                score: Score((p * 65535.0).round() as u16),
+                source: if tri >= bi {
+                    Source::Trigram
+                } else {
+                    Source::Bigram
+                },
+                id: Some(id),
+                probability: p as f32,
+            }
+        };
+        let mut mixed: Vec<Suggestion> = Vec::with_capacity(trigram.len() + bigram.len());
+        for s in trigram {
+            let Some(id) = s.id else { continue };
+            let p2 = match bigram.iter().position(|&(b, _, _)| b == id) {
+                Some(i) => bigram.swap_remove(i).2,
+                None => 0.0,
+            };
+            mixed.push(mix(s.word, id, s.probability as f64, p2));
+        }
+        for (id, word, p2) in bigram {
+            mixed.push(mix(word, id, 0.0, p2));
+        }
+        mixed
+    }
+
+    /// Multi-word continuations of `context`, `depth` bigram steps long.
+    ///
+    /// A beam search from the last word: each step expands the best
+    /// `PHRASE_BEAM_WIDTH` paths by their top edges, scoring a path by the
+    /// product of its edges' relative counts (summed in log space). Words
+    /// already on a path aren't revisited. Returns up to `limit` phrases with
+    /// their score scaled to 0..=65535, best first.
+    pub fn suggest_phrase(
+        &self,
+        context: &str,
+        depth: usize,
+        limit: usize,
+    ) -> Vec<(String, Score)> {
+        let start = context
+            .split_whitespace()
+            .map(|w| self.normalizer.normalize(w, false))
+            .rfind(|w| !w.is_empty())
+            .and_then(|w| self.word_id(&w));
+        let Some(start) = start else {
+            return Vec::new();
+        };
+        if depth == 0 {
+            return Vec::new();
+        }
+
+        // (path of word ids after `start`, sum of ln relative counts)
+        let mut beam: Vec<(Vec<u32>, f64)> = vec![(Vec::new(), 0.0)];
+        for _ in 0..depth {
+            let mut expanded: Vec<(Vec<u32>, f64)> = Vec::new();
+            for (path, score) in &beam {
+                let prev = path.last().copied().unwrap_or(start);
+                let log_max = self.bigram.log_max(prev).unwrap_or(DEFAULT_LOG_MAX);
+                for Edge { next_id, weight } in self.bigram.next(prev, PHRASE_BEAM_WIDTH) {
+                    if next_id == start
+                        || path.contains(&next_id)
+                        || self.blocklist.contains_id(next_id)
+                    {
+                        continue;
+                    }
+                    let mut next_path = path.clone();
+                    next_path.push(next_id);
+                    expanded.push((next_path, score + weight.relative_count(log_max).ln()));
+                }
+            }
+            expanded.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            expanded.truncate(PHRASE_BEAM_WIDTH);
+            beam = expanded;
+        }
+
+        let mut phrases = Vec::new();
+        for (path, score) in beam {
+            let words: Option<Vec<&str>> = path.iter().map(|&id| self.vocab.word(id)).collect();
+            match words {
+                Some(words) => {
+                    let score = Score((score.exp() * 65535.0).round() as u16);
+                    phrases.push((words.join(" "), score));
+                }
+                None => {
+                    self.decode_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if phrases.len() >= limit {
+                break;
+            }
+        }
+        phrases
+    }
+
+    /// Words likely to come right before `next_word`, for filling a gap
+    /// left by a deleted word ("___ store" -> "the").
+    ///
+    /// Reads a reverse index inverted from the bigram model on the first
+    /// call (see `reverse`), so the first call pays for a full pass over
+    /// the model. Weights are log-quantized against the most frequent
+    /// preceder, as bigram weights are against a row's top edge.
+    pub fn suggest_given_next(&self, next_word: &str, limit: usize) -> Vec<Suggestion> {
+        let next_id = self.word_id(&self.normalizer.normalize(next_word.trim(), false));
+        let Some(next_id) = next_id else {
+            return Vec::new();
+        };
+        let reverse = self
+            .reverse
+            .get_or_init(|| ReverseBigrams::build(&self.bigram));
+        let preceders = reverse.preceders(next_id);
+        let max_count = preceders.first().map_or(1.0, |&(_, count)| count as f64);
+
+        let mut suggestions = Vec::new();
+        for &(prev_id, count) in preceders {
+            if suggestions.len() >= limit {
+                break;
+            }
+            if self.blocklist.contains_id(prev_id) {
+                continue;
+            }
+            let Some(word) = self.vocab.word(prev_id) else {
+                self.decode_errors.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            let count = count as f64;
+            suggestions.push(Suggestion {
+                word: word.to_string(),
+                score: Count(count.round() as u64)
+                    .quantize(Count(max_count.round() as u64))
+                    .into(),
+                source: Source::Bigram,
+                id: Some(prev_id),
+                probability: (count / max_count) as f32,
+            });
+        }
+        suggestions
+    }
+
+    /// Number of edges dropped during lookups because their id was past the vocab
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// Which sources the `suggest_next` results came from, over every call
+    /// so far (including the predictions `suggest` makes) or since
+    /// `reset_source_stats`
+    pub fn source_stats(&self) -> SourceStats {
+        *self.source_stats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn reset_source_stats(&self) {
+        *self.source_stats.lock().unwrap_or_else(|e| e.into_inner()) = SourceStats::default();
+    }
+
+    /// Number of bigram edges that reference ids past the vocab, counted at load
+    pub fn unresolved_at_load(&self) -> usize {
+        self.unresolved_at_load
+    }
+
+    fn recency_buffer(&self) -> MutexGuard<'_, RecencyBuffer> {
+        // The buffer holds no invariant a panicking writer could break
+        self.recency.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Dedup and rank a tier, breaking score ties by unigram prob (see
+    /// `finalize_suggestions_with`). Without a lexicon there is no prob to
+    /// go by, and ties fall back to the id. A custom ranker then reorders
+    /// it, stably.
+    fn finalize(&self, suggestions: &mut Vec<Suggestion>) {
+        let probs = self.unigram_probs.get_or_init(|| {
+            let mut probs = vec![0u8; self.vocab.len()];
+            if let Some(lexicon) = &self.lexicon {
+                let mut stream = lexicon.stream();
+                while let Some((_, v)) = stream.next() {
+                    let id = ((v >> 16) & 0xFFFF_FFFF) as usize;
+                    if let Some(p) = probs.get_mut(id) {
+                        *p = (v & 0xFF) as u8;
+                    }
+                }
+            }
+            probs
+        });
+        finalize_suggestions_with(suggestions, |id| {
+            probs.get(id as usize).copied().unwrap_or(0)
+        });
+        if let Some(ranker) = &self.ranker {
+            let mut ranked: Vec<(f32, Suggestion)> = suggestions
+                .drain(..)
+                .map(|s| (ranker(&Candidate::of(&s)), s))
+                .collect();
+            ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+            suggestions.extend(ranked.into_iter().map(|(_, s)| s));
+        }
+    }
+
+    /// Recency, gating and dedup for one tier of suggestions, then its best
+    /// `room`. Cutting only after ranking keeps a word gating lifts from
+    /// being dropped for one it has overtaken.
+    fn rank_tier(&self, suggestions: &mut Vec<Suggestion>, room: usize) {
+        self.apply_recency(suggestions);
+        apply_gating(suggestions, &self.boost);
+        self.finalize(suggestions);
+        suggestions.truncate(room);
+    }
+
+    /// Scale the scores of recently committed words (see `recency`)
+    fn apply_recency(&self, suggestions: &mut [Suggestion]) {
+        let recency = self.recency_buffer();
+        if recency.is_empty() {
+            return;
+        }
+        for s in suggestions.iter_mut() {
+            if let Some(id) = s.id {
+                let factor = recency.factor(id);
+                if factor != 1.0 {
+                    s.score = s.score.scale(factor);
+                }
+            }
+        }
+    }
+
+    /// Attach words to edges, counting and dropping ids past the vocab.
+    /// Blocked ids are dropped too.
+    fn resolve(&self, edges: Vec<Edge>) -> Vec<(u32, String, Weight)> {
+        edges
+            .into_iter()
+            .filter_map(|edge| {
+                let word = self.edge_word(edge.next_id)?;
+                Some((edge.next_id, word.to_string(), edge.weight))
+            })
+            .collect()
+    }
+
+    /// `resolve` for the public lookups, borrowing the words
+    fn resolve_edges(&self, edges: Vec<Edge>) -> Vec<ResolvedEdge<'_>> {
+        edges
+            .into_iter()
+            .filter_map(|edge| {
+                let word = self.edge_word(edge.next_id)?;
+                Some(ResolvedEdge {
+                    word,
+                    weight: edge.weight,
+                })
+            })
+            .collect()
+    }
+
+    /// The word an edge leads to; None if it's blocked or, counted as a
+    /// decode error, missing from the vocab
+    fn edge_word(&self, next_id: u32) -> Option<&str> {
+        if self.blocklist.contains_id(next_id) {
+            return None;
+        }
+        let word = self.vocab.word(next_id);
+        if word.is_none() {
+            self.decode_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        word
+    }
+}
+
+/// An `ImeEngine` being loaded by `ImeEngine::load_async`
+pub struct EngineLoader {
+    lexicon: Map<Mmap>,
+    thread: JoinHandle<EngineResult<ImeEngine>>,
+}
+
+impl EngineLoader {
+    /// Word id of `word` exactly as spelled in the lexicon, available
+    /// before the engine is: no case folding, since that needs the
+    /// canonical map still being built
+    pub fn get(&self, word: &str) -> Option<u32> {
+        self.lexicon
+            .get(word)
+            .map(|v| ((v >> 16) & 0xFFFF_FFFF) as u32)
+    }
+
+    /// True once `wait` won't block
+    pub fn is_ready(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Block until the engine is loaded. A panic while loading resumes here.
+    pub fn wait(self) -> EngineResult<ImeEngine> {
+        self.thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+/// True when the next word starts a sentence: nothing typed yet, or the
+/// context ends in sentence-final punctuation
+fn at_sentence_start(context: &str) -> bool {
+    let trimmed = context.trim_end();
+    trimmed.is_empty() || trimmed.ends_with(['.', '!', '?'])
+}
+
+/// Uppercase "I" (and "i'm", "i'll", ...) and, at a sentence start, the
+/// first letter of every suggestion. Later duplicates created by the case
+/// change are dropped.
+fn capitalize_suggestions(suggestions: &mut Vec<Suggestion>, sentence_start: bool) {
+    for s in suggestions.iter_mut() {
+        if sentence_start || s.word == "i" || s.word.starts_with("i'") {
+            s.word = capitalize_first(&s.word);
+        }
+    }
+    let mut seen = HashSet::new();
+    suggestions.retain(|s| seen.insert(s.word.clone()));
+}
+
+fn capitalize_first(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,72 @@
+//! Typed errors for the model readers and `ImeEngine::load`
+//!
+//! The binaries stay on `anyhow` (`EngineError` converts through `?`);
+//! library users can match on the variant to tell a missing file from a
+//! corrupt or mismatched one.
+
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum EngineError {
+    /// A model file couldn't be opened, mapped or read
+    #[error("failed to read {path}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The file isn't the expected format (or is for another reader)
+    #[error("bad magic 0x{found:08X} (expected 0x{expected:08X})")]
+    BadMagic { found: u32, expected: u32 },
+    #[error("unsupported version {0}")]
+    UnsupportedVersion(u32),
+    /// A header field (bit width, flags) this reader can't handle
+    #[error("unsupported {field} {value}")]
+    UnsupportedField { field: &'static str, value: u32 },
+    /// Header bytes the file's version reserves aren't zero: most likely
+    /// written by a newer format this reader doesn't know
+    #[error("reserved header byte at offset {offset} is not zero")]
+    ReservedNonZero { offset: usize },
+    /// The file is shorter than its header says it should be
+    #[error("truncated: need {need} bytes, have {have}")]
+    Truncated { need: usize, have: usize },
+    /// The bigram model was built for a different vocab
+    #[error("bigram model has {model} words but the vocab has {vocab}")]
+    VocabMismatch { model: usize, vocab: usize },
+    /// The bigram model was built for a vocab with the same size but other
+    /// words or another order (see `Vocab::fingerprint`)
+    #[error("bigram model was built for vocab {model:016x}, the vocab is {vocab:016x}")]
+    VocabHashMismatch { model: u64, vocab: u64 },
+    /// The trigram cache was built for another vocab (see `TrigramCache::open_for`)
+    #[error("trigram cache was built for vocab {model:016x}, the vocab is {vocab:016x}")]
+    TrigramVocabHashMismatch { model: u64, vocab: u64 },
+    /// A vocab file lists a word twice, so it isn't one line per id
+    #[error("{path}: {word:?} at id {id} repeats id {first}")]
+    DuplicateWord {
+        path: String,
+        word: String,
+        first: u32,
+        id: u32,
+    },
+    /// `EngineRegistry` has no engine for this language code
+    #[error("no engine registered for language {0:?}")]
+    UnknownLanguage(String),
+    #[error("invalid FST {path}")]
+    Fst {
+        path: String,
+        #[source]
+        source: fst::Error,
+    },
+}
+
+pub type EngineResult<T> = Result<T, EngineError>;
+
+impl EngineError {
+    pub(crate) fn io(path: &str, source: io::Error) -> Self {
+        EngineError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use combined2fst::lexicon::search_fst_fuzzy_prefix;
 use fst::automaton::{Automaton, Str};
 use fst::{IntoStreamer, Map, Streamer};
 use memmap2::Mmap;
@@ -30,5 +31,10 @@ fn main() -> anyhow::Result<()> {
             break;
         }
     }
+
+    // 3) prefix with one typo
+    for m in search_fst_fuzzy_prefix(&map, "recie", 1, 5)? {
+        println!("recie~: {} prob={} exact={}", m.word, m.prob, m.exact);
+    }
     Ok(())
 }
@@ -0,0 +1,153 @@
+//! Function-word gating: scale the scores of common function words
+//!
+//! Boost lists can be tuned without recompiling: `BoostTable::for_language`
+//! reads `boost.<lang>.txt` when present, one `word [factor]` per line (the
+//! factor defaults to `DEFAULT_BOOST_FACTOR`; `#` starts a comment), and
+//! falls back to the built-in list for the language otherwise.
+
+use crate::Score;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Default multiplier applied to boost words
+pub const DEFAULT_BOOST_FACTOR: f64 = 1.25;
+
+const EN_BOOST_WORDS: [&str; 10] = [
+    "to", "for", "are", "is", "of", "the", "a", "in", "on", "that",
+];
+const VI_BOOST_WORDS: [&str; 10] = [
+    "là", "của", "và", "có", "những", "trong", "được", "một", "cho", "với",
+];
+
+/// Per-word score multipliers
+#[derive(Debug, Clone, Default)]
+pub struct BoostTable {
+    factors: HashMap<String, f64>,
+}
+
+impl BoostTable {
+    /// All `words` share the same `factor`
+    pub fn new(words: &[&str], factor: f64) -> Self {
+        Self {
+            factors: words.iter().map(|w| (w.to_string(), factor)).collect(),
+        }
+    }
+
+    pub fn english() -> Self {
+        Self::new(&EN_BOOST_WORDS, DEFAULT_BOOST_FACTOR)
+    }
+
+    pub fn vietnamese() -> Self {
+        Self::new(&VI_BOOST_WORDS, DEFAULT_BOOST_FACTOR)
+    }
+
+    /// Built-in list for a language code ("en", "vi"); empty for others
+    pub fn builtin(lang: &str) -> Self {
+        match lang {
+            "en" => Self::english(),
+            "vi" => Self::vietnamese(),
+            _ => Self::default(),
+        }
+    }
+
+    /// `dir/boost.<lang>.txt` if it exists, else `builtin(lang)`. A file
+    /// that exists but doesn't parse is an error, not a silent fallback.
+    pub fn for_language(lang: &str, dir: &str) -> Result<Self> {
+        let path = Path::new(dir).join(format!("boost.{}.txt", lang));
+        if !path.exists() {
+            return Ok(Self::builtin(lang));
+        }
+        Self::from_file(&path.to_string_lossy())
+    }
+
+    /// Read a boost list (see the module docs). Every bad line is reported
+    /// in the error, with its line number.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        let mut table = Self::default();
+        let mut bad = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_boost_line(line) {
+                Some((word, factor)) => {
+                    table.factors.insert(word.to_string(), factor);
+                }
+                None => bad.push(format!("  {}:{}: {:?}", path, i + 1, line)),
+            }
+        }
+        if !bad.is_empty() {
+            bail!(
+                "{} bad line(s) in boost list, expected `word [factor]` with a positive factor:\n{}",
+                bad.len(),
+                bad.join("\n")
+            );
+        }
+        Ok(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.factors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.factors.is_empty()
+    }
+
+    /// Multiplier for `word` (1.0 if not a boost word)
+    pub fn factor(&self, word: &str) -> f64 {
+        self.factors.get(word).copied().unwrap_or(1.0)
+    }
+}
+
+/// `word` or `word factor`, with a finite factor above 0
+fn parse_boost_line(line: &str) -> Option<(&str, f64)> {
+    let mut fields = line.split_whitespace();
+    let word = fields.next()?;
+    let factor = match fields.next() {
+        Some(f) => f.parse::<f64>().ok()?,
+        None => DEFAULT_BOOST_FACTOR,
+    };
+    if fields.next().is_some() || !factor.is_finite() || factor <= 0.0 {
+        return None;
+    }
+    Some((word, factor))
+}
+
+/// Anything with a word and a score can be gated
+pub trait Scored {
+    fn word(&self) -> &str;
+    fn score(&self) -> Score;
+    fn set_score(&mut self, score: Score);
+}
+
+impl Scored for (String, Score) {
+    fn word(&self) -> &str {
+        &self.0
+    }
+    fn score(&self) -> Score {
+        self.1
+    }
+    fn set_score(&mut self, score: Score) {
+        self.1 = score;
+    }
+}
+
+/// Multiply each boost word's score by its factor and re-sort by score.
+///
+/// Unlike hoisting boost words to the top, a low-probability function word
+/// stays low: it only wins against candidates within its boost margin.
+/// The sort is stable, so equal scores keep their incoming order.
+pub fn apply_gating<T: Scored>(suggestions: &mut [T], boost: &BoostTable) {
+    for s in suggestions.iter_mut() {
+        let factor = boost.factor(s.word());
+        if factor != 1.0 {
+            s.set_score(s.score().scale(factor));
+        }
+    }
+    suggestions.sort_by_key(|s| std::cmp::Reverse(s.score()));
+}
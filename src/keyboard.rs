@@ -0,0 +1,106 @@
+//! Keyboard-layout substitution costs for fuzzy matching
+//!
+//! Plain Levenshtein charges every substitution one edit, but a finger
+//! that slips usually lands on a neighbouring key: "wprld" is far more
+//! likely a try at "world" (o and p touch on QWERTY) than "wxrld" is.
+//! A `KeyboardLayout` prices substituting one key for another, so
+//! `search_fst_fuzzy_prefix_with` can rank such near-misses above other
+//! completions the same number of edits away.
+//!
+//! `from_rows` derives adjacency from rows of keys, each row shifted half a
+//! key right of the one above it, as on a staggered keyboard: keys touch
+//! when they sit side by side in a row, or diagonally in neighbouring rows.
+//! `set_cost` prices single pairs on top of that.
+
+use std::collections::HashMap;
+
+/// Cost of substituting a neighbouring key, in edits
+pub const DEFAULT_ADJACENT_COST: f32 = 0.5;
+
+/// Letter rows of a US QWERTY keyboard, top to bottom
+pub const QWERTY_ROWS: [&str; 3] = ["qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyboardLayout {
+    /// Keyed with the smaller char first; pairs not here cost 1.0
+    costs: HashMap<(char, char), f32>,
+}
+
+impl KeyboardLayout {
+    /// The QWERTY letter rows at `DEFAULT_ADJACENT_COST`
+    pub fn qwerty() -> Self {
+        Self::from_rows(&QWERTY_ROWS, DEFAULT_ADJACENT_COST)
+    }
+
+    /// Neighbouring keys of `rows` (see the module docs) cost `cost`
+    pub fn from_rows(rows: &[&str], cost: f32) -> Self {
+        let rows: Vec<Vec<char>> = rows.iter().map(|row| row.chars().collect()).collect();
+        let mut layout = Self::default();
+        for (r, row) in rows.iter().enumerate() {
+            for (c, &key) in row.iter().enumerate() {
+                if let Some(&right) = row.get(c + 1) {
+                    layout.set_cost(key, right, cost);
+                }
+                // Half a key further right than the row above: the keys
+                // below this one are at columns c - 1 and c of that row
+                if let Some(below) = rows.get(r + 1) {
+                    for b in [c.wrapping_sub(1), c] {
+                        if let Some(&other) = below.get(b) {
+                            layout.set_cost(key, other, cost);
+                        }
+                    }
+                }
+            }
+        }
+        layout
+    }
+
+    /// Substituting `a` for `b`, or `b` for `a`, costs `cost` edits
+    pub fn set_cost(&mut self, a: char, b: char, cost: f32) {
+        self.costs.insert(ordered(a, b), cost);
+    }
+
+    /// Cost of typing `typed` where `intended` was meant: 0 for the same
+    /// char, the pair's cost if one is set, else 1
+    pub fn substitution_cost(&self, typed: char, intended: char) -> f32 {
+        if typed == intended {
+            return 0.0;
+        }
+        self.costs
+            .get(&ordered(typed, intended))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    pub fn are_adjacent(&self, a: char, b: char) -> bool {
+        a != b && self.costs.contains_key(&ordered(a, b))
+    }
+
+    /// Weighted edit distance from `typed` to the closest prefix of
+    /// `word`: substitutions priced by this layout, insertions and
+    /// deletions a full edit each
+    pub fn prefix_distance(&self, typed: &str, word: &str) -> f32 {
+        let word: Vec<char> = word.chars().collect();
+        // prev[j]: cost of turning the typed chars so far into word[..j]
+        let mut prev: Vec<f32> = (0..=word.len()).map(|j| j as f32).collect();
+        let mut cur = vec![0.0; word.len() + 1];
+        for (i, t) in typed.chars().enumerate() {
+            cur[0] = (i + 1) as f32;
+            for (j, &w) in word.iter().enumerate() {
+                cur[j + 1] = (prev[j] + self.substitution_cost(t, w))
+                    .min(prev[j + 1] + 1.0)
+                    .min(cur[j] + 1.0);
+            }
+            std::mem::swap(&mut prev, &mut cur);
+        }
+        prev.into_iter().fold(f32::INFINITY, f32::min)
+    }
+}
+
+fn ordered(a: char, b: char) -> (char, char) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
@@ -0,0 +1,254 @@
+//! Lexicon FST packing shared by `combined2fst` and `build_en_fst`
+//!
+//! Each word maps to `word_id << 16 | flags << 8 | prob`. Line `i` of the
+//! vocab file is word id `i`. A prob of 0 sets `FLAG_NOSUGGEST`.
+//!
+//! Fresh ids follow the sorted key order. Every bigram and trigram model
+//! is keyed on these ids, so rebuilding the lexicon from an updated word
+//! list would shift them all; `assign_ids` with the previous vocab keeps
+//! each known word's id and appends new words after them instead. Models
+//! record the vocab's fingerprint, so a pairing that slipped through
+//! anyway is refused at load (see `bigram`).
+//!
+//! `search_fst_fuzzy_prefix` completes a typed prefix that may hold a typo:
+//! a Levenshtein automaton over the prefix, extended with `starts_with`,
+//! accepts every word that begins with something within the edit budget.
+//! `search_fst_fuzzy_prefix_with` also takes a `KeyboardLayout`, and ranks
+//! the typo completions by how likely the slip is on that keyboard.
+
+use crate::keyboard::KeyboardLayout;
+use crate::vocab::Vocab;
+use anyhow::{Context, Result};
+use fst::automaton::{Automaton, Levenshtein, Str};
+use fst::{IntoStreamer, Map, MapBuilder, Streamer};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+/// Flags bit for words that shouldn't be suggested (prob 0)
+pub const FLAG_NOSUGGEST: u8 = 1 << 0;
+
+/// Largest edit budget `search_fst_fuzzy_prefix` accepts; Levenshtein
+/// automata grow quickly with the distance
+pub const MAX_FUZZY_EDITS: u32 = 2;
+/// Prefixes shorter than this (in chars) are matched exactly: with so few
+/// letters one edit matches a large part of the dictionary
+pub const FUZZY_MIN_PREFIX: usize = 3;
+
+pub fn pack_value(prob_q: u8, flags: u8, word_id: u32) -> u64 {
+    (prob_q as u64) | ((flags as u64) << 8) | ((word_id as u64) << 16)
+}
+
+/// Inverse of `pack_value`: (prob, flags, word_id)
+pub fn unpack_value(v: u64) -> (u8, u8, u32) {
+    (
+        (v & 0xFF) as u8,
+        ((v >> 8) & 0xFF) as u8,
+        ((v >> 16) & 0xFFFF_FFFF) as u32,
+    )
+}
+
+/// Parse a `word<TAB>count` frequency list line
+pub fn parse_frequency_line(line: &str) -> Option<(&str, u64)> {
+    let (word, count) = line.split_once('\t')?;
+    let word = word.trim();
+    if word.is_empty() {
+        return None;
+    }
+    Some((word, count.trim().parse().ok()?))
+}
+
+/// Quantize a count to the prob byte on a log scale: the top count gets
+/// 255, a count of 1 gets 1 and only a count of 0 gets 0 (nosuggest)
+pub fn quantize_prob(count: u64, max_count: u64) -> u8 {
+    if count == 0 || max_count == 0 {
+        return 0;
+    }
+    let ratio = (count as f64).ln() / (max_count as f64).ln().max(1.0);
+    1 + (ratio.clamp(0.0, 1.0) * 254.0).round() as u8
+}
+
+/// The vocab (words in id order) for a lexicon of `words`.
+///
+/// Without `previous`, ids follow key order. With it, every word of
+/// `previous` keeps its id, including words no longer in `words` (their
+/// lines stay so later ids don't move, but the FST no longer maps them),
+/// and new words take the following ids in key order.
+pub fn assign_ids<P>(words: &BTreeMap<String, P>, previous: Option<&Vocab>) -> Vocab {
+    let Some(previous) = previous else {
+        return words.keys().map(String::as_str).collect();
+    };
+    let known: HashSet<&str> = previous.iter().collect();
+    previous
+        .iter()
+        .chain(
+            words
+                .keys()
+                .map(String::as_str)
+                .filter(|w| !known.contains(w)),
+        )
+        .collect()
+}
+
+/// Read the previous vocab for `assign_ids`. A word on two lines would map
+/// to the first, so the repeats are dropped (see `Vocab::dedup`) with a
+/// warning: ids after the first repeat move, and models built on the old
+/// file need rebuilding.
+pub fn load_previous_vocab(path: &str) -> Result<Vocab> {
+    let vocab = Vocab::load_unchecked(path)?;
+    let (deduped, remap) = vocab.dedup();
+    if deduped.len() < vocab.len() {
+        let moved = remap
+            .iter()
+            .enumerate()
+            .position(|(old, &new)| old as u32 != new)
+            .unwrap_or(0);
+        eprintln!(
+            "warning: {} repeats {} words; dropped them, moving ids from {} on",
+            path,
+            vocab.len() - deduped.len(),
+            moved
+        );
+    }
+    Ok(deduped)
+}
+
+/// Write the FST for `words` (word -> prob) to `fst_out`, and one word per
+/// line to `vocab_out` if given. Ids follow key order.
+pub fn write_lexicon<W: Write, V: Write>(
+    words: &BTreeMap<String, u8>,
+    fst_out: W,
+    vocab_out: Option<V>,
+) -> Result<()> {
+    write_lexicon_with_ids(words, &assign_ids(words, None), fst_out, vocab_out)
+}
+
+/// `write_lexicon` with ids taken from `ids` (see `assign_ids`), which must
+/// hold every word of `words`. `vocab_out` gets `ids` line by line.
+pub fn write_lexicon_with_ids<W: Write, V: Write>(
+    words: &BTreeMap<String, u8>,
+    ids: &Vocab,
+    fst_out: W,
+    mut vocab_out: Option<V>,
+) -> Result<()> {
+    let mut id_of: HashMap<&str, u32> = HashMap::with_capacity(ids.len());
+    for (id, word) in ids.iter().enumerate() {
+        id_of.entry(word).or_insert(id as u32);
+    }
+
+    let mut builder = MapBuilder::new(fst_out).context("fst MapBuilder")?;
+    for (w, prob_q) in words {
+        let id = *id_of
+            .get(w.as_str())
+            .with_context(|| format!("no id assigned to {}", w))?;
+        let flags = if *prob_q == 0 { FLAG_NOSUGGEST } else { 0 };
+        builder
+            .insert(w, pack_value(*prob_q, flags, id))
+            .with_context(|| format!("insert {}", w))?;
+    }
+    builder.into_inner().context("finish fst")?.flush()?;
+
+    if let Some(vw) = vocab_out.as_mut() {
+        for w in ids.iter() {
+            writeln!(vw, "{w}")?;
+        }
+        vw.flush()?;
+    }
+    Ok(())
+}
+
+/// One completion from `search_fst_fuzzy_prefix`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyMatch {
+    pub word: String,
+    pub word_id: u32,
+    pub prob: u8,
+    /// The word starts with the typed prefix exactly
+    pub exact: bool,
+    /// Edits from the prefix to the start of the word, substitutions
+    /// priced by the keyboard layout if one was given (see
+    /// `KeyboardLayout::prefix_distance`); 0 when `exact`
+    pub cost: f32,
+}
+
+/// Complete `prefix` with words that start with it, or with something
+/// within `max_edits` (capped at `MAX_FUZZY_EDITS`) edits of it. Exact
+/// completions rank first, each group by stored prob, then by word. Words
+/// flagged nosuggest are skipped. At most `limit` results.
+pub fn search_fst_fuzzy_prefix<D: AsRef<[u8]>>(
+    map: &Map<D>,
+    prefix: &str,
+    max_edits: u32,
+    limit: usize,
+) -> Result<Vec<FuzzyMatch>> {
+    search_fst_fuzzy_prefix_with(map, prefix, max_edits, limit, None)
+}
+
+/// `search_fst_fuzzy_prefix`, with the typo completions ranked by their
+/// cost on `layout` before prob when one is given: an adjacent-key slip
+/// beats a completion the same number of plain edits away. Which words
+/// match is unchanged; the plain edit budget still decides that.
+pub fn search_fst_fuzzy_prefix_with<D: AsRef<[u8]>>(
+    map: &Map<D>,
+    prefix: &str,
+    max_edits: u32,
+    limit: usize,
+    layout: Option<&KeyboardLayout>,
+) -> Result<Vec<FuzzyMatch>> {
+    let max_edits = if prefix.chars().count() < FUZZY_MIN_PREFIX {
+        0
+    } else {
+        max_edits.min(MAX_FUZZY_EDITS)
+    };
+
+    // Unit costs without a layout
+    let plain = KeyboardLayout::default();
+    let costs = layout.unwrap_or(&plain);
+    let mut matches = Vec::new();
+    let mut collect = |key: &[u8], v: u64| {
+        let (prob, flags, word_id) = unpack_value(v);
+        if flags & FLAG_NOSUGGEST != 0 {
+            return;
+        }
+        let word = String::from_utf8_lossy(key).into_owned();
+        let exact = word.starts_with(prefix);
+        let cost = if exact {
+            0.0
+        } else {
+            costs.prefix_distance(prefix, &word)
+        };
+        matches.push(FuzzyMatch {
+            exact,
+            word,
+            word_id,
+            prob,
+            cost,
+        });
+    };
+    if max_edits == 0 {
+        let mut stream = map.search(Str::new(prefix).starts_with()).into_stream();
+        while let Some((key, v)) = stream.next() {
+            collect(key, v);
+        }
+    } else {
+        let fuzzy = Levenshtein::new(prefix, max_edits)
+            .with_context(|| format!("fuzzy automaton for {:?}", prefix))?;
+        let mut stream = map.search(fuzzy.starts_with()).into_stream();
+        while let Some((key, v)) = stream.next() {
+            collect(key, v);
+        }
+    }
+
+    matches.sort_by(|a, b| {
+        let by_cost = match layout {
+            Some(_) => a.cost.total_cmp(&b.cost),
+            None => std::cmp::Ordering::Equal,
+        };
+        Reverse(a.exact)
+            .cmp(&Reverse(b.exact))
+            .then(by_cost)
+            .then_with(|| (Reverse(a.prob), &a.word).cmp(&(Reverse(b.prob), &b.word)))
+    });
+    matches.truncate(limit);
+    Ok(matches)
+}
@@ -1,67 +1,551 @@
 use anyhow::{Context, Result};
-use fst::Map;
-use memmap2::Mmap;
+use fst::{Map, Streamer};
+use progress::{BytesRead, CountingReader};
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::OnceLock;
+use tokenize::Locale;
+
+pub mod bigram;
+pub mod blocklist;
+pub mod bloom;
+pub mod builder;
+pub mod canonical;
+pub mod corpus;
+pub mod engine;
+pub mod error;
+pub mod gating;
+pub mod keyboard;
+pub mod lexicon;
+pub mod progress;
+pub mod recency;
+pub mod registry;
+pub mod reverse;
+pub mod shard;
+pub mod shortcuts;
+pub mod sketch;
+pub mod tokenize;
+pub mod trigram;
+pub mod trigram_counts;
+pub mod user_history;
+pub mod validate;
+pub mod vietnamese;
+pub mod vocab;
+
+pub use bigram::{parse_header, read_headers, BigramHeader, BigramModel, BigramStats, EdgeRecord};
+pub use blocklist::Blocklist;
+pub use bloom::BloomFilter;
+pub use canonical::CanonicalMap;
+pub use engine::{
+    default_rank, finalize_suggestions, finalize_suggestions_with, source_label, truncate_display,
+    Candidate, EngineLoader, ImeEngine, Interpolation, Ranker, Source, SourceStats, SuggestMode,
+    Suggestion, TaggedSuggestion, MAX_DISPLAY_CHARS,
+};
+pub use error::{EngineError, EngineResult};
+pub use gating::{apply_gating, BoostTable};
+pub use keyboard::KeyboardLayout;
+pub use registry::{EngineRegistry, ModelFiles};
+pub use shortcuts::ShortcutTable;
+pub use trigram::TrigramCache;
+pub use trigram_counts::TrigramCounts;
+pub use validate::{validate_bigram, validate_bigram_file, ValidationReport};
+pub use vietnamese::{Segmentation, SyllableMatch, VietnameseEngine};
+pub use vocab::Vocab;
+
+/// HashMap with the Fx hasher, for the hot internal maps of the builders
+/// (token -> id lookups, per-prev and per-pair counters). Several times
+/// faster than SipHash on short keys but not DoS-resistant, so maps keyed
+/// on untrusted runtime input (user history, engine queries) keep the std
+/// hasher. Public signatures take std `HashMap` or are generic over the
+/// hasher, so either kind can be passed in.
+pub type FastMap<K, V> = rustc_hash::FxHashMap<K, V>;
+
+/// An occurrence count, as the builders tally it before quantizing.
+///
+/// Counts, weights and scores are all small integers and used to travel
+/// as bare `u16`/`u64`s in tuple positions, which made passing one where
+/// another was meant an easy and silent mistake. The public APIs (the model
+/// readers, the engine, user history predictions) take and return the
+/// newtypes instead, and converting between them is explicit:
+/// `Count::quantize` turns a count into a `Weight`, `Weight::dequantize`
+/// turns a weight back into a count ratio, and `Score::from` ranks a
+/// weight as it is. The builders' hot loops still work on the primitives
+/// (`builder::quantize_weight`, `builder::relative_count`).
+///
+/// A count is not a weight:
+///
+/// ```compile_fail
+/// use combined2fst::{Count, Weight};
+/// let weight: Weight = Count(42);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Count(pub u64);
+
+impl Count {
+    /// This count's weight in a row whose top count is `max`
+    pub fn quantize(self, max: Count) -> Weight {
+        Weight(builder::quantize_weight(self.0, max.0))
+    }
+}
+
+/// A log-quantized edge weight: `ln(count) / ln(max_count)` scaled to
+/// 0-65535 (see `Edge`). It only compares within one row.
+///
+/// A weight is not a score, even though both are 16 bits:
+///
+/// ```compile_fail
+/// use combined2fst::{Score, Weight};
+/// fn rank(score: Score) {}
+/// rank(Weight(65535));
+/// ```
+///
+/// nor does it compare with one:
+///
+/// ```compile_fail
+/// use combined2fst::{Score, Weight};
+/// assert!(Weight(1) < Score(2));
+/// ```
+///
+/// The conversion has to be spelled out:
+///
+/// ```
+/// use combined2fst::{Count, Score, Weight};
+/// assert_eq!(Count(1000).quantize(Count(1000)), Weight::MAX);
+/// assert_eq!(Score::from(Weight(300)), Score(300));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Weight(pub u16);
+
+impl Weight {
+    /// The row's top edge
+    pub const MAX: Weight = Weight(u16::MAX);
+
+    /// Count relative to the row's top count; see `dequantize`
+    pub fn dequantize(self) -> f32 {
+        dequantize(self)
+    }
+
+    /// Count relative to the row's top count, given `log_max = ln(max_count)`
+    pub fn relative_count(self, log_max: f64) -> f64 {
+        builder::relative_count(self.0, log_max)
+    }
+}
+
+/// A suggestion's ranking score. Model weights start out as scores
+/// (`Score::from`) and are then scaled by gating and recency, or come from
+/// other sources such as user history, so scores compare across sources
+/// and contexts where weights don't.
+///
+/// ```compile_fail
+/// use combined2fst::{Score, Weight};
+/// let weight: Weight = Score(10).scale(2.0);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Score(pub u16);
+
+impl Score {
+    pub const MAX: Score = Score(u16::MAX);
+
+    /// Multiply by `factor`, saturating at `Score::MAX`
+    pub fn scale(self, factor: f64) -> Score {
+        Score((self.0 as f64 * factor).clamp(0.0, u16::MAX as f64) as u16)
+    }
+}
+
+impl From<Weight> for Score {
+    fn from(weight: Weight) -> Self {
+        Score(weight.0)
+    }
+}
+
+/// A user-history score: decayed, then clamped to
+/// `HistoryConfig::max_score`. Wider than `Score` so that a ceiling raised
+/// past 65535 still ranks strong personal signals apart; `to_score`
+/// narrows it once history is blended with the models.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HistoryScore(pub u32);
+
+impl HistoryScore {
+    /// This score as a suggestion `Score`, saturating at `Score::MAX`
+    pub fn to_score(self) -> Score {
+        Score(self.0.min(u16::MAX as u32) as u16)
+    }
+}
+
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for Weight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for HistoryScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A successor as the readers return it: the next word's id and its weight.
+///
+/// The weight is log-quantized against the row's top count, not a count or
+/// a score: `ln(count) / ln(max_count)` scaled to 0-65535, so the strongest
+/// successor of every context is 65535 and the rest fall off with the log
+/// of their count. Weights only compare within one row (one context); the
+/// model's stored `log_max` turns them back into count ratios
+/// (`Weight::relative_count`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Edge {
+    pub next_id: u32,
+    pub weight: Weight,
+}
+
+impl From<(u32, Weight)> for Edge {
+    fn from((next_id, weight): (u32, Weight)) -> Self {
+        Self { next_id, weight }
+    }
+}
+
+/// An `Edge` with its next id resolved to the word, borrowed from the vocab.
+/// The weight is the same log-quantized value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResolvedEdge<'a> {
+    pub word: &'a str,
+    pub weight: Weight,
+}
+
+/// Lookup table inverting `builder::quantize_weight` for one top count:
+/// each weight's count relative to the row's top count, 1.0 for the top
+/// edge. Quantization keeps `ln(count) / ln(max_count)`, so the inverse
+/// needs `log_max = ln(max_count)`; a model that stores it per row is best
+/// read with `dequantize_with`.
+#[derive(Debug, Clone)]
+pub struct DequantTable {
+    log_max: f64,
+    values: Vec<f32>,
+}
+
+impl DequantTable {
+    pub fn new(log_max: f64) -> Self {
+        let values = (0..=u16::MAX)
+            .map(|w| builder::relative_count(w, log_max) as f32)
+            .collect();
+        Self { log_max, values }
+    }
+
+    pub fn log_max(&self) -> f64 {
+        self.log_max
+    }
+
+    pub fn get(&self, weight: Weight) -> f32 {
+        self.values[weight.0 as usize]
+    }
+}
+
+/// The inverse of `builder::quantize_weight`, assuming a top count of
+/// `builder::DEFAULT_LOG_MAX` (see `DequantTable`). This is what readers
+/// display as a weight's confidence when the row's `log_max` is unknown;
+/// it is relative to the row's top edge, not a probability over the row.
+pub fn dequantize(weight: Weight) -> f32 {
+    static DEFAULT: OnceLock<DequantTable> = OnceLock::new();
+    DEFAULT
+        .get_or_init(|| DequantTable::new(builder::DEFAULT_LOG_MAX))
+        .get(weight)
+}
+
+/// `dequantize` against the row's stored `log_max` when there is one
+pub fn dequantize_with(weight: Weight, log_max: Option<f64>) -> f32 {
+    match log_max {
+        Some(log_max) => weight.relative_count(log_max) as f32,
+        None => dequantize(weight),
+    }
+}
+
+/// Open a corpus for line-by-line streaming, decompressing by extension:
+/// `.gz` (gzip), `.zst` (zstd), `.bz2` (bzip2), anything else as plain text.
+/// Reads through a 1 MB buffer.
+pub fn open_corpus(path: &str) -> Result<Box<dyn BufRead>> {
+    Ok(open_corpus_counted(path)?.0)
+}
+
+/// `open_corpus`, also returning a running count of the bytes read from the
+/// file itself (before decompression), for `progress::Progress`
+pub fn open_corpus_counted(path: &str) -> Result<(Box<dyn BufRead>, BytesRead)> {
+    let file = File::open(path).with_context(|| format!("Failed to open corpus {}", path))?;
+    let (file, bytes) = CountingReader::new(file);
+    let decoder: Box<dyn Read> = if path.ends_with(".gz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if path.ends_with(".zst") {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else if path.ends_with(".bz2") {
+        Box::new(bzip2::read::BzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok((Box::new(BufReader::with_capacity(1 << 20, decoder)), bytes))
+}
+
+/// Replace `path` with what `write` produces, durably: the content goes to
+/// a temp file next to `path`, which is flushed, fsynced and renamed over
+/// `path`. A crash or an error from `write` leaves the previous file as it
+/// was (and the temp file removed, on error).
+pub fn write_atomic<F>(path: &str, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    let target = std::path::Path::new(path);
+    let tmp = target.with_file_name(format!(
+        ".{}.{}.tmp",
+        target
+            .file_name()
+            .map_or("out".into(), |n| n.to_string_lossy()),
+        std::process::id()
+    ));
+    let result = (|| {
+        let file = File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?;
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.flush()?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, target).with_context(|| format!("rename over {}", path))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+        return result;
+    }
+
+    // Persist the rename itself
+    #[cfg(unix)]
+    if let Some(dir) = target.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            std::path::Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir).and_then(|d| d.sync_all())?;
+    }
+    Ok(())
+}
 
 /// Build canonical lowercase -> best word_id map
 ///
+/// Apps that load the map at every startup can precompute it once with
+/// [`CanonicalMap`].
+///
 /// Logic:
 /// 1. If exact lowercase match exists in FST, use it.
 /// 2. Else, use the case variant with highest probability.
 pub fn build_canonical_map(
     fst_path: &str,
     vocab_path: &str,
-) -> Result<(u32, HashMap<String, u32>)> {
-    let file = File::open(fst_path).context("Failed to open FST")?;
-    let mmap = unsafe { Mmap::map(&file)? };
-    let fst = Map::new(mmap)?;
+) -> EngineResult<(u32, HashMap<String, u32>)> {
+    let vocab = Vocab::load(vocab_path)?;
+    let map = canonical_map_for(fst_path, &vocab)?;
+    Ok((vocab.len() as u32, map))
+}
+
+/// `build_canonical_map` over an already loaded vocab
+pub fn canonical_map_for(fst_path: &str, vocab: &Vocab) -> EngineResult<HashMap<String, u32>> {
+    canonical_map_for_locale(fst_path, vocab, Locale::Root)
+}
+
+/// `canonical_map_for` with keys lowercased under `locale`; pair it with a
+/// `tokenize::Localized` normalizer of the same locale
+pub fn canonical_map_for_locale(
+    fst_path: &str,
+    vocab: &Vocab,
+    locale: Locale,
+) -> EngineResult<HashMap<String, u32>> {
+    Ok(canonical_entries(fst_path, vocab, locale)?
+        .into_iter()
+        .map(|(k, entry)| (k, entry.id))
+        .collect())
+}
+
+/// Canonical lowercase key -> (word_id, display form)
+pub type DisplayMap = HashMap<String, (u32, String)>;
+
+/// `build_canonical_map`, with each key's display form next to its id:
+/// the most probable case variant's spelling, so "paris" can keep the id
+/// of the lowercase entry while suggestions show "Paris". Ties go to the
+/// lowercase form, then to the variant first in the vocab.
+pub fn build_canonical_display_map(
+    fst_path: &str,
+    vocab_path: &str,
+) -> EngineResult<(u32, DisplayMap)> {
+    let vocab = Vocab::load(vocab_path)?;
+    let map = canonical_display_map_for_locale(fst_path, &vocab, Locale::Root)?;
+    Ok((vocab.len() as u32, map))
+}
+
+/// `build_canonical_display_map` over an already loaded vocab, with keys
+/// lowercased under `locale`
+pub fn canonical_display_map_for_locale(
+    fst_path: &str,
+    vocab: &Vocab,
+    locale: Locale,
+) -> EngineResult<DisplayMap> {
+    Ok(canonical_entries(fst_path, vocab, locale)?
+        .into_iter()
+        .map(|(k, entry)| (k, (entry.id, entry.display.to_string())))
+        .collect())
+}
 
-    let vocab_file = BufReader::new(File::open(vocab_path).context("Failed to open vocab")?);
-    let mut canonical: HashMap<String, (u32, u8, bool)> = HashMap::new(); // lower -> (best_id, best_prob, is_exact)
-    let mut vocab_size: u32 = 0;
+/// What `canonical_entries` keeps per lowercase key
+struct CanonicalEntry<'a> {
+    id: u32,
+    prob: u8,
+    is_exact: bool,
+    /// Most probable surface form, and its prob
+    display: &'a str,
+    display_prob: u8,
+}
 
-    for line in vocab_file.lines() {
-        let word = line?;
-        vocab_size += 1;
+fn canonical_entries<'a>(
+    fst_path: &str,
+    vocab: &'a Vocab,
+    locale: Locale,
+) -> EngineResult<FastMap<String, CanonicalEntry<'a>>> {
+    let mmap = bigram::map_file(fst_path)?;
+    let fst = Map::new(mmap).map_err(|source| EngineError::Fst {
+        path: fst_path.to_string(),
+        source,
+    })?;
 
-        if let Some(v) = fst.get(&word) {
+    let mut canonical: FastMap<String, CanonicalEntry> = FastMap::default();
+
+    for word in vocab.iter() {
+        if let Some(v) = fst.get(word) {
             let word_id = ((v >> 16) & 0xFFFF_FFFF) as u32;
             let prob = (v & 0xFF) as u8;
-            let lower = word.to_lowercase();
+            let lower = locale.lowercase(word);
             let is_exact = word == lower;
 
             canonical
-                .entry(lower)
-                .and_modify(|(best_id, best_prob, best_exact)| {
+                .entry(tokenize::fold_apostrophes(&lower).into_owned())
+                .and_modify(|best| {
+                    if prob > best.display_prob || (prob == best.display_prob && is_exact) {
+                        best.display = word;
+                        best.display_prob = prob;
+                    }
+
                     // If we already have an exact match, don't change unless this is also exact (unlikely for duplicate keys)
-                    if *best_exact {
+                    if best.is_exact {
                         return;
                     }
 
                     // If this is exact, take it immediately
                     if is_exact {
-                        *best_id = word_id;
-                        *best_prob = prob;
-                        *best_exact = true;
+                        best.id = word_id;
+                        best.prob = prob;
+                        best.is_exact = true;
                         return;
                     }
 
                     // Otherwise, follow probability
-                    if prob > *best_prob {
-                        *best_id = word_id;
-                        *best_prob = prob;
+                    if prob > best.prob {
+                        best.id = word_id;
+                        best.prob = prob;
                     }
                 })
-                .or_insert((word_id, prob, is_exact));
+                .or_insert(CanonicalEntry {
+                    id: word_id,
+                    prob,
+                    is_exact,
+                    display: word,
+                    display_prob: prob,
+                });
         }
     }
+    Ok(canonical)
+}
 
-    // Convert to simple id map
-    let map: HashMap<String, u32> = canonical
-        .into_iter()
-        .map(|(k, (id, _, _))| (k, id))
-        .collect();
-    Ok((vocab_size, map))
+/// The `k` most probable words in the FST as (word_id, prob), by prob
+/// descending with ties on word_id. Case variants are folded as in
+/// `build_canonical_map`: the exact lowercase form if present, else the
+/// most probable variant.
+pub fn top_unigrams(fst_path: &str, k: usize) -> EngineResult<Vec<(u32, u8)>> {
+    let fst = Map::new(bigram::map_file(fst_path)?).map_err(|source| EngineError::Fst {
+        path: fst_path.to_string(),
+        source,
+    })?;
+    Ok(top_unigrams_in(&fst, k))
+}
+
+/// `top_unigrams` over an FST that is already open
+pub fn top_unigrams_in<D: AsRef<[u8]>>(fst: &Map<D>, k: usize) -> Vec<(u32, u8)> {
+    top_unigrams_in_locale(fst, k, Locale::Root)
+}
+
+/// `top_unigrams_in` with case variants folded under `locale`, as
+/// `canonical_map_for_locale` folds them
+pub fn top_unigrams_in_locale<D: AsRef<[u8]>>(
+    fst: &Map<D>,
+    k: usize,
+    locale: Locale,
+) -> Vec<(u32, u8)> {
+    let mut best: FastMap<String, (u32, u8, bool)> = FastMap::default(); // key -> (id, prob, is_exact)
+    let mut stream = fst.stream();
+    while let Some((key, v)) = stream.next() {
+        let word_id = ((v >> 16) & 0xFFFF_FFFF) as u32;
+        let prob = (v & 0xFF) as u8;
+        let word = String::from_utf8_lossy(key);
+        let lower = locale.lowercase(&word);
+        let is_exact = word == lower;
+        best.entry(tokenize::fold_apostrophes(&lower).into_owned())
+            .and_modify(|e| {
+                if !e.2 && (is_exact || prob > e.1) {
+                    *e = (word_id, prob, is_exact);
+                }
+            })
+            .or_insert((word_id, prob, is_exact));
+    }
+
+    let mut unigrams: Vec<(u32, u8)> = best.into_values().map(|(id, prob, _)| (id, prob)).collect();
+    unigrams.sort_by_key(|&(id, prob)| (std::cmp::Reverse(prob), id));
+    unigrams.truncate(k);
+    unigrams
+}
+
+/// Build exact surface form -> word_id map (no case folding)
+///
+/// Used by the case-sensitive bigram builders: "Apple" and "apple" keep
+/// their own FST word_ids instead of collapsing onto the canonical one.
+pub fn build_exact_map(
+    fst_path: &str,
+    vocab_path: &str,
+) -> EngineResult<(u32, HashMap<String, u32>)> {
+    let vocab = Vocab::load(vocab_path)?;
+    let map = exact_map_for(fst_path, &vocab)?;
+    Ok((vocab.len() as u32, map))
+}
+
+/// `build_exact_map` over an already loaded vocab
+pub fn exact_map_for(fst_path: &str, vocab: &Vocab) -> EngineResult<HashMap<String, u32>> {
+    let fst = Map::new(bigram::map_file(fst_path)?).map_err(|source| EngineError::Fst {
+        path: fst_path.to_string(),
+        source,
+    })?;
+
+    let mut map: HashMap<String, u32> = HashMap::new();
+    for word in vocab.iter() {
+        if let Some(v) = fst.get(word) {
+            let word_id = ((v >> 16) & 0xFFFF_FFFF) as u32;
+            map.insert(tokenize::fold_apostrophes(word).into_owned(), word_id);
+        }
+    }
+    Ok(map)
 }
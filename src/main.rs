@@ -1,44 +1,52 @@
 use anyhow::{Context, Result};
+use combined2fst::corpus::{CorpusLines, Utf8Policy};
+use combined2fst::lexicon::{
+    assign_ids, load_previous_vocab, quantize_prob, write_lexicon_with_ids,
+};
+use combined2fst::shortcuts::{parse_shortcut, ShortcutTable};
 use flate2::read::GzDecoder;
-use fst::MapBuilder;
 use std::{
     collections::BTreeMap,
     env,
     fs::File,
-    io::{BufRead, BufReader, Write},
+    io::{BufReader, BufWriter},
 };
 
 fn parse_kv_csvish(s: &str) -> Vec<(&str, &str)> {
     // "word=the,f=222,flags=,originalFreq=222" -> [("word","the"), ("f","222"), ...]
-    s.split(',')
-        .filter_map(|p| p.split_once('='))
-        .collect()
-}
-
-fn pack_value(prob_q: u8, flags: u8, word_id: u32) -> u64 {
-    (prob_q as u64) | ((flags as u64) << 8) | ((word_id as u64) << 16)
+    s.split(',').filter_map(|p| p.split_once('=')).collect()
 }
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // --keep-ids <old.vocab.txt>: keep the word ids of an earlier build
+    let keep_ids = match args.iter().position(|a| a == "--keep-ids") {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..i + 2).nth(1).unwrap()),
+        _ => None,
+    };
     if args.len() < 3 {
-        eprintln!("Usage: {} <input.combined.gz> <out.lex.fst> [out.vocab.txt]", args[0]);
+        eprintln!("Usage: {} <input.combined.gz> <out.lex.fst> [out.vocab.txt] [out.shortcuts.tsv] [--keep-ids old.vocab.txt]", args[0]);
+        eprintln!("  --keep-ids : Words of the old vocab keep their ids; new words are appended");
         std::process::exit(2);
     }
     let input_gz = &args[1];
     let out_fst = &args[2];
     let out_vocab = args.get(3);
+    let out_shortcuts = args.get(4);
 
-    // Read gz line-by-line
+    // Read gz line-by-line, skipping lines past the corpus length cap
     let f = File::open(input_gz).with_context(|| format!("open {}", input_gz))?;
     let gz = GzDecoder::new(f);
     let rd = BufReader::new(gz);
 
-    // Use BTreeMap to keep keys sorted (fst::MapBuilder requires sorted inserts)
-    let mut unigram: BTreeMap<String, u8> = BTreeMap::new();
+    // Raw f per word, quantized once the largest is known
+    let mut freqs: BTreeMap<String, u64> = BTreeMap::new();
+    let mut shortcuts = ShortcutTable::default();
+    // Shortcut lines belong to the word line above them
+    let mut current_word: Option<String> = None;
 
     let mut saw_header = false;
-    for line in rd.lines() {
+    for line in CorpusLines::new(rd, Utf8Policy::Strict) {
         let line = line?;
         let t = line.trim();
         if t.is_empty() || t.starts_with('#') {
@@ -51,57 +59,73 @@ fn main() -> Result<()> {
             continue;
         }
 
+        if let Some((target, freq)) = parse_shortcut(t) {
+            if let Some(w) = current_word.as_deref() {
+                shortcuts.insert(w, target, freq);
+            }
+            continue;
+        }
+
         // The file has leading spaces before "word="
         if t.starts_with("word=") {
+            current_word = None;
             let kv = parse_kv_csvish(t);
             let mut word: Option<&str> = None;
-            let mut fval: Option<u16> = None;
+            let mut fval: Option<u64> = None;
 
             for (k, v) in kv {
                 if k == "word" {
                     word = Some(v);
                 } else if k == "f" {
-                    fval = Some(v.parse::<u16>().unwrap_or(0));
+                    fval = Some(v.parse::<u64>().unwrap_or(0));
                 }
             }
 
-            if let (Some(w), Some(fu16)) = (word, fval) {
+            if let (Some(w), Some(f)) = (word, fval) {
                 if w.is_empty() {
                     continue;
                 }
-                let prob_q = fu16.min(255) as u8;
+                current_word = Some(w.to_string());
                 // keep max if duplicated
-                unigram
+                freqs
                     .entry(w.to_string())
-                    .and_modify(|old| *old = (*old).max(prob_q))
-                    .or_insert(prob_q);
+                    .and_modify(|old| *old = (*old).max(f))
+                    .or_insert(f);
             }
         }
     }
 
-    // Build FST
-    let mut out = File::create(out_fst).with_context(|| format!("create {}", out_fst))?;
-    let mut builder = MapBuilder::new(&mut out).context("fst MapBuilder")?;
+    // Log-scale f into the prob byte against the largest f, so frequent
+    // words stay ordered instead of all clamping to 255; f=0 stays 0
+    // (nosuggest). BTreeMap keeps keys sorted for fst::MapBuilder.
+    let max_f = freqs.values().copied().max().unwrap_or(0);
+    let unigram: BTreeMap<String, u8> = freqs
+        .into_iter()
+        .map(|(w, f)| (w, quantize_prob(f, max_f)))
+        .collect();
 
-    let mut vocab_writer: Option<File> = match out_vocab {
-        Some(p) => Some(File::create(p).with_context(|| format!("create {}", p))?),
+    // Build FST
+    let previous = keep_ids.as_deref().map(load_previous_vocab).transpose()?;
+    let ids = assign_ids(&unigram, previous.as_ref());
+    if let Some(previous) = &previous {
+        println!(
+            "Kept {} ids, appended {} new words",
+            previous.len(),
+            ids.len() - previous.len()
+        );
+    }
+    let out = BufWriter::new(File::create(out_fst).with_context(|| format!("create {}", out_fst))?);
+    let vocab_writer: Option<BufWriter<File>> = match out_vocab {
+        Some(p) => Some(BufWriter::new(
+            File::create(p).with_context(|| format!("create {}", p))?,
+        )),
         None => None,
     };
+    write_lexicon_with_ids(&unigram, &ids, out, vocab_writer)?;
 
-    for (i, (w, prob_q)) in unigram.iter().enumerate() {
-        let word_id = i as u32;
-
-        let mut flags: u8 = 0;
-        if *prob_q == 0 {
-            flags |= 1 << 0; // IS_PROFANITY / nosuggest-like marker (your engine decides)
-        }
-        let v = pack_value(*prob_q, flags, word_id);
-        builder.insert(w, v).with_context(|| format!("insert {}", w))?;
-
-        if let Some(vw) = vocab_writer.as_mut() {
-            writeln!(vw, "{w}")?;
-        }
+    if let Some(p) = out_shortcuts {
+        shortcuts.save(p)?;
+        println!("Wrote {} shortcuts to {}", shortcuts.len(), p);
     }
-    builder.finish().context("finish fst")?;
     Ok(())
 }
@@ -0,0 +1,40 @@
+//! Merge two user-history files (e.g. device + cloud sync)
+//!
+//! Usage: cargo run --release --bin merge_history -- <target.json> <other.json> [--out merged.json]
+//! Writes back to <target.json> unless --out is given.
+
+use anyhow::Result;
+use combined2fst::user_history::UserHistory;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!(
+            "Usage: {} <target.json> <other.json> [--out merged.json]",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+
+    let target_path = &args[1];
+    let other_path = &args[2];
+    let out_path = args
+        .iter()
+        .position(|a| a == "--out")
+        .and_then(|i| args.get(i + 1))
+        .unwrap_or(target_path);
+
+    let mut history = UserHistory::load(target_path)?;
+    let other = UserHistory::load(other_path)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32;
+
+    history.merge(&other, now);
+    history.save(out_path)?;
+    println!("Merged {} into {} -> {}", other_path, target_path, out_path);
+
+    Ok(())
+}
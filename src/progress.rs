@@ -0,0 +1,190 @@
+//! Throughput reporting for the corpus builders (`--progress`)
+//!
+//! `Progress::tick` is called once per corpus line and prints a report
+//! every `REPORT_INTERVAL`: lines/sec, items (bigrams, trigrams) per second
+//! and, when the corpus is a regular file, the share of it read so far and
+//! an ETA. Bytes are counted below the decompressor (see
+//! `open_corpus_counted`), so for a compressed corpus the ETA follows the
+//! compressed file, which decompresses at a roughly steady ratio.
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Time between two reports
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lines between clock reads in `tick`
+const CHECK_EVERY: u64 = 16_384;
+
+/// Running count of bytes read from a corpus file, shared with the reader
+#[derive(Debug, Clone, Default)]
+pub struct BytesRead(Arc<AtomicU64>);
+
+impl BytesRead {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Reader that adds every byte it passes on to a `BytesRead`
+pub struct CountingReader<R> {
+    inner: R,
+    bytes: BytesRead,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> (Self, BytesRead) {
+        let bytes = BytesRead::default();
+        (
+            Self {
+                inner,
+                bytes: bytes.clone(),
+            },
+            bytes,
+        )
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.0.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Size of `path` if it's a regular file; None for pipes and the like,
+/// whose progress can't be measured
+pub fn file_size(path: &str) -> Option<u64> {
+    std::fs::metadata(path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+}
+
+/// Throughput at one point of a run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub elapsed: Duration,
+    pub lines: u64,
+    pub items: u64,
+    pub lines_per_sec: f64,
+    pub items_per_sec: f64,
+    /// Share of the input read (0..=1), when its size is known
+    pub fraction: Option<f64>,
+    /// Time left at the average rate so far
+    pub eta: Option<Duration>,
+}
+
+impl Snapshot {
+    /// Rates after `elapsed`, with `bytes` = (read, total) if known
+    pub fn new(elapsed: Duration, lines: u64, items: u64, bytes: Option<(u64, u64)>) -> Self {
+        let secs = elapsed.as_secs_f64();
+        let rate = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+        let fraction = bytes
+            .filter(|&(_, total)| total > 0)
+            .map(|(read, total)| (read as f64 / total as f64).min(1.0));
+        let eta = fraction
+            .filter(|&f| f > 0.0)
+            .map(|f| Duration::from_secs_f64(secs * (1.0 - f) / f));
+        Self {
+            elapsed,
+            lines,
+            items,
+            lines_per_sec: rate(lines),
+            items_per_sec: rate(items),
+            fraction,
+            eta,
+        }
+    }
+
+    /// One report line, naming the items `item_label`
+    pub fn describe(&self, item_label: &str) -> String {
+        let mut line = format!(
+            "  [{}] {:.1} M lines ({:.0} lines/s), {:.1} M {} ({:.0}/s)",
+            format_duration(self.elapsed),
+            self.lines as f64 / 1e6,
+            self.lines_per_sec,
+            self.items as f64 / 1e6,
+            item_label,
+            self.items_per_sec
+        );
+        if let (Some(fraction), Some(eta)) = (self.fraction, self.eta) {
+            line.push_str(&format!(
+                ", {:.1}% read, ETA {}",
+                fraction * 100.0,
+                format_duration(eta)
+            ));
+        }
+        line
+    }
+}
+
+/// Periodic progress printer for one builder pass
+pub struct Progress {
+    start: Instant,
+    last_report: Instant,
+    item_label: &'static str,
+    total_bytes: Option<u64>,
+    /// Bytes of inputs already finished
+    done_bytes: u64,
+    current: Option<BytesRead>,
+}
+
+impl Progress {
+    pub fn new(item_label: &'static str) -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last_report: now,
+            item_label,
+            total_bytes: None,
+            done_bytes: 0,
+            current: None,
+        }
+    }
+
+    /// Size of all inputs together; without it no ETA is shown
+    pub fn with_total_bytes(mut self, total_bytes: Option<u64>) -> Self {
+        self.total_bytes = total_bytes;
+        self
+    }
+
+    /// Start counting a new input; bytes of the previous one stay counted
+    pub fn track(&mut self, bytes: BytesRead) {
+        if let Some(previous) = self.current.replace(bytes) {
+            self.done_bytes += previous.get();
+        }
+    }
+
+    pub fn snapshot(&self, lines: u64, items: u64) -> Snapshot {
+        let read = self.done_bytes + self.current.as_ref().map_or(0, BytesRead::get);
+        Snapshot::new(
+            self.start.elapsed(),
+            lines,
+            items,
+            self.total_bytes.map(|total| (read, total)),
+        )
+    }
+
+    /// Print a report if `REPORT_INTERVAL` has passed since the last one
+    pub fn tick(&mut self, lines: u64, items: u64) {
+        if !lines.is_multiple_of(CHECK_EVERY) || self.last_report.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+        println!("{}", self.snapshot(lines, items).describe(self.item_label));
+    }
+}
+
+/// "1h 02m", "3m 20s" or "45s"
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
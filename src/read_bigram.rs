@@ -2,13 +2,14 @@
 //!
 //! Usage: cargo run --release --bin read_bigram [word]
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use combined2fst::bigram::header_size;
+use combined2fst::builder::decode_log_max;
+use combined2fst::{dequantize_with, parse_header, Weight};
 use memmap2::Mmap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-const MAGIC: u32 = 0x4247524D;
-
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
 
@@ -17,27 +18,33 @@ fn main() -> Result<()> {
     let mmap = unsafe { Mmap::map(&file)? };
     let data = mmap.as_ref();
 
-    // Parse header
-    let magic = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-    let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
-    let vocab_size = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
-    let edges_count = u32::from_le_bytes([data[12], data[13], data[14], data[15]]);
-    let top_n = u32::from_le_bytes([data[16], data[17], data[18], data[19]]);
+    // Parse header (length, magic and version are checked)
+    let header = parse_header(data).context("en.bigram.bin")?;
+    let vocab_size = header.vocab_size;
+    let edges_count = header.edges_count;
 
     println!("=== en.bigram.bin ===");
-    println!(
-        "Magic: 0x{:08X} ({})",
-        magic,
-        if magic == MAGIC { "OK" } else { "BAD" }
-    );
-    println!("Version: {}", version);
+    println!("Magic: OK");
+    println!("Version: {}", header.version);
+    println!("Word id bits: {}", header.word_id_bits);
+    println!("Weight bits: {}", header.weight_bits);
     println!("Vocab size: {}", vocab_size);
     println!("Edges count: {}", edges_count);
-    println!("Top-N: {}", top_n);
+    println!("Top-N: {}", header.top_n);
+    if let Some(hash) = header.vocab_hash {
+        println!("Vocab fingerprint: {:016x}", hash);
+    }
 
-    let header_size = 32;
+    let header_size = header_size(header.version);
     let index_size = (vocab_size as usize) * 8;
     let edges_size = (edges_count as usize) * 8;
+    if data.len() < header_size + index_size + edges_size {
+        bail!(
+            "en.bigram.bin: {} bytes, layout needs {}",
+            data.len(),
+            header_size + index_size + edges_size
+        );
+    }
 
     println!("\nLayout:");
     println!("  Header: {} bytes", header_size);
@@ -71,11 +78,16 @@ fn main() -> Result<()> {
                     data[index_offset + 3],
                 ]);
                 let len = u16::from_le_bytes([data[index_offset + 4], data[index_offset + 5]]);
+                let log_max = decode_log_max(u16::from_le_bytes([
+                    data[index_offset + 6],
+                    data[index_offset + 7],
+                ]));
 
                 println!("Index: offset={}, len={}", offset, len);
 
                 if len > 0 {
                     println!("\nNext words:");
+                    let mut unresolved = 0;
                     let edges_base = header_size + index_size;
                     for i in 0..len as usize {
                         let edge_offset = edges_base + offset as usize + i * 8;
@@ -88,17 +100,46 @@ fn main() -> Result<()> {
                         let weight =
                             u16::from_le_bytes([data[edge_offset + 4], data[edge_offset + 5]]);
 
-                        let next_word = vocab
-                            .get(next_id as usize)
-                            .map(|s| s.as_str())
-                            .unwrap_or("<unknown>");
-
+                        let next_word = match vocab.get(next_id as usize) {
+                            Some(w) => w.as_str(),
+                            None => {
+                                unresolved += 1;
+                                "<unknown>"
+                            }
+                        };
+
+                        if header.raw_counts() {
+                            let count = u32::from_le_bytes([
+                                data[edge_offset + 4],
+                                data[edge_offset + 5],
+                                data[edge_offset + 6],
+                                data[edge_offset + 7],
+                            ]);
+                            println!(
+                                "  {:2}. {} (id={}, count={})",
+                                i + 1,
+                                next_word,
+                                next_id,
+                                count
+                            );
+                            continue;
+                        }
+                        // Relative to the top edge (see `dequantize`)
+                        let confidence = dequantize_with(Weight(weight), log_max) * 100.0;
                         println!(
-                            "  {:2}. {} (id={}, weight={})",
+                            "  {:2}. {} (id={}, weight={}, ~{:.0}%)",
                             i + 1,
                             next_word,
                             next_id,
-                            weight
+                            weight,
+                            confidence
+                        );
+                    }
+                    if unresolved > 0 {
+                        println!(
+                            "\nwarning: {} edges reference ids past the vocab ({} words)",
+                            unresolved,
+                            vocab.len()
                         );
                     }
                 } else {
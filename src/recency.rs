@@ -0,0 +1,62 @@
+//! Session recency: boost words the user committed moments ago
+//!
+//! A fixed-size ring of the last K committed word ids, kept in memory only:
+//! unlike `UserHistory` it learns nothing and is gone when the process
+//! exits. A word's boost decays linearly with how many commits ago it was
+//! typed, from the full factor for the last word down towards 1.0 for the
+//! oldest one in the ring; older words get no boost.
+
+use std::collections::VecDeque;
+
+/// Default boost for the word committed last
+pub const DEFAULT_RECENCY_BOOST: f64 = 1.5;
+
+#[derive(Debug, Clone, Default)]
+pub struct RecencyBuffer {
+    /// Most recent at the back
+    ids: VecDeque<u32>,
+    capacity: usize,
+    boost: f64,
+}
+
+impl RecencyBuffer {
+    /// Remember the last `capacity` commits, the latest boosted by `boost`.
+    /// A capacity of 0 disables it.
+    pub fn new(capacity: usize, boost: f64) -> Self {
+        Self {
+            ids: VecDeque::with_capacity(capacity),
+            capacity,
+            boost,
+        }
+    }
+
+    pub fn push(&mut self, id: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ids.len() == self.capacity {
+            self.ids.pop_front();
+        }
+        self.ids.push_back(id);
+    }
+
+    pub fn clear(&mut self) {
+        self.ids.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Multiplier for `id`: `boost` if it was the last commit, falling by
+    /// `(boost - 1) / capacity` per commit since; 1.0 if not in the ring
+    pub fn factor(&self, id: u32) -> f64 {
+        match self.ids.iter().rev().position(|&x| x == id) {
+            Some(age) => {
+                let left = (self.capacity - age) as f64 / self.capacity as f64;
+                1.0 + (self.boost - 1.0) * left
+            }
+            None => 1.0,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Several engines in one process, routed by language code
+//!
+//! A multilingual keyboard keeps one `ImeEngine` per language and sends
+//! each query to the active one. `EngineRegistry::register` only records a
+//! language's model files; its engine is loaded by the first query for
+//! that language, so languages the user never switches to cost nothing.
+//!
+//! Languages registered with the same vocab file share one copy of it
+//! (cloning a `Vocab` shares its buffer), e.g. "en" and an "en-US" variant
+//! that differ only in their bigram model or engine settings. The FST,
+//! bigram and trigram files are memory-mapped, so the OS shares their pages
+//! between engines anyway.
+//!
+//! A load error is returned from the query that triggered it; the next
+//! query for that language tries again.
+
+use crate::engine::{ImeEngine, TaggedSuggestion};
+use crate::error::{EngineError, EngineResult};
+use crate::vocab::Vocab;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+/// The files `ImeEngine::load` reads for one language
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelFiles {
+    pub fst: String,
+    pub vocab: String,
+    pub bigram: String,
+    /// Optional, as for `ImeEngine::load`
+    pub trigram: Option<String>,
+}
+
+/// Settings applied to an engine right after it is loaded
+type Configure = Box<dyn Fn(&mut ImeEngine) + Send + Sync>;
+
+struct Slot {
+    /// None for engines inserted already built
+    files: Option<ModelFiles>,
+    configure: Option<Configure>,
+    engine: OnceLock<ImeEngine>,
+    /// Held while loading, so two threads don't load the same engine
+    loading: Mutex<()>,
+}
+
+#[derive(Default)]
+pub struct EngineRegistry {
+    slots: HashMap<String, Slot>,
+    /// Vocabs loaded so far, by path
+    vocabs: Mutex<HashMap<String, Vocab>>,
+}
+
+impl EngineRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Route `lang` to the models in `files`, loaded on first use. Replaces
+    /// any engine registered for `lang` before.
+    pub fn register(&mut self, lang: &str, files: ModelFiles) {
+        self.insert_slot(lang, Some(files), None, OnceLock::new());
+    }
+
+    /// `register`, running `configure` on the engine once it is loaded
+    /// (normalizer, boost table, shortcuts and so on)
+    pub fn register_with(
+        &mut self,
+        lang: &str,
+        files: ModelFiles,
+        configure: impl Fn(&mut ImeEngine) + Send + Sync + 'static,
+    ) {
+        self.insert_slot(
+            lang,
+            Some(files),
+            Some(Box::new(configure)),
+            OnceLock::new(),
+        );
+    }
+
+    /// Route `lang` to an engine that is already built
+    pub fn insert(&mut self, lang: &str, engine: ImeEngine) {
+        self.insert_slot(lang, None, None, OnceLock::from(engine));
+    }
+
+    fn insert_slot(
+        &mut self,
+        lang: &str,
+        files: Option<ModelFiles>,
+        configure: Option<Configure>,
+        engine: OnceLock<ImeEngine>,
+    ) {
+        let slot = Slot {
+            files,
+            configure,
+            engine,
+            loading: Mutex::new(()),
+        };
+        self.slots.insert(lang.to_string(), slot);
+    }
+
+    /// Registered language codes, sorted
+    pub fn languages(&self) -> Vec<&str> {
+        let mut langs: Vec<&str> = self.slots.keys().map(String::as_str).collect();
+        langs.sort_unstable();
+        langs
+    }
+
+    /// True once `lang`'s engine has been loaded (or was inserted built)
+    pub fn is_loaded(&self, lang: &str) -> bool {
+        self.slots
+            .get(lang)
+            .is_some_and(|slot| slot.engine.get().is_some())
+    }
+
+    /// The engine for `lang`, loading it on first use
+    pub fn engine(&self, lang: &str) -> EngineResult<&ImeEngine> {
+        let slot = self
+            .slots
+            .get(lang)
+            .ok_or_else(|| EngineError::UnknownLanguage(lang.to_string()))?;
+        if let Some(engine) = slot.engine.get() {
+            return Ok(engine);
+        }
+        let _loading = slot.loading.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(engine) = slot.engine.get() {
+            return Ok(engine);
+        }
+        let files = slot
+            .files
+            .as_ref()
+            .expect("inserted engines are set on insert");
+        let mut engine = ImeEngine::load_with_vocab(
+            &files.fst,
+            self.vocab(&files.vocab)?,
+            &files.bigram,
+            files.trigram.as_deref(),
+        )?;
+        if let Some(configure) = &slot.configure {
+            configure(&mut engine);
+        }
+        Ok(slot.engine.get_or_init(|| engine))
+    }
+
+    /// `ImeEngine::suggest` on `lang`'s engine
+    pub fn suggest(
+        &self,
+        lang: &str,
+        context: &str,
+        limit: usize,
+    ) -> EngineResult<Vec<TaggedSuggestion>> {
+        Ok(self.engine(lang)?.suggest(context, limit))
+    }
+
+    /// The vocab at `path`, shared with any engine that loaded it already
+    fn vocab(&self, path: &str) -> EngineResult<Vocab> {
+        let mut vocabs = self.vocabs.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(vocab) = vocabs.get(path) {
+            return Ok(vocab.clone());
+        }
+        let vocab = Vocab::load(path)?;
+        vocabs.insert(path.to_string(), vocab.clone());
+        Ok(vocab)
+    }
+}
@@ -0,0 +1,71 @@
+//! Reverse bigram index: which words precede a given word
+//!
+//! Built in memory from the forward model, so it only knows the edges each
+//! prev kept in its top-N: a prev whose row doesn't reach `next` is missing
+//! from `next`'s preceders even if the pair occurred. Forward weights are
+//! relative to their own row, so they're turned back into approximate
+//! corpus counts (the dequantized relative count times the row's max
+//! count) before rows from different prevs are compared; raw-counts files give
+//! the counts directly.
+
+use crate::bigram::BigramModel;
+use crate::builder::DEFAULT_LOG_MAX;
+
+pub struct ReverseBigrams {
+    /// Start of each next_id's preceders in `entries`, plus the end
+    offsets: Vec<usize>,
+    /// (prev_id, approximate count), count-descending within each next_id
+    entries: Vec<(u32, f32)>,
+}
+
+impl ReverseBigrams {
+    /// Invert `model`, keeping up to its `top_n` preceders per word
+    pub fn build<D: AsRef<[u8]>>(model: &BigramModel<D>) -> Self {
+        let vocab_size = model.vocab_size();
+        let mut by_next: Vec<Vec<(u32, f32)>> = vec![Vec::new(); vocab_size];
+        for (prev, row) in model.iter() {
+            let log_max = model.log_max(prev).unwrap_or(DEFAULT_LOG_MAX).max(1.0);
+            for edge in row {
+                let Some(preceders) = by_next.get_mut(edge.next_id() as usize) else {
+                    continue;
+                };
+                let count = if model.has_raw_counts() {
+                    edge.count().0 as f64
+                } else {
+                    edge.weight().relative_count(log_max) * log_max.exp()
+                };
+                preceders.push((prev, count as f32));
+            }
+        }
+
+        let mut offsets = Vec::with_capacity(vocab_size + 1);
+        let mut entries = Vec::new();
+        offsets.push(0);
+        for mut preceders in by_next {
+            preceders.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            preceders.truncate(model.top_n());
+            entries.extend(preceders);
+            offsets.push(entries.len());
+        }
+        Self { offsets, entries }
+    }
+
+    /// Words seen before `next_id` as (prev_id, approximate count), most
+    /// frequent first; empty for ids outside the model
+    pub fn preceders(&self, next_id: u32) -> &[(u32, f32)] {
+        let next = next_id as usize;
+        match (self.offsets.get(next), self.offsets.get(next + 1)) {
+            (Some(&start), Some(&end)) => &self.entries[start..end],
+            _ => &[],
+        }
+    }
+
+    /// Total (prev, next) pairs held
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
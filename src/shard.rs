@@ -0,0 +1,509 @@
+//! Sharded bigram counting for `build_bigram`, with a resume manifest
+//!
+//! Phase 1 streams the corpus into `num_shards` files of (prev, next) pairs;
+//! phase 2 reduces them to top-N edges per prev. Once phase 1 completes a
+//! manifest is written next to the shards, so an interrupted reduce can be
+//! resumed without re-reading the corpus. The manifest records the corpus
+//! path, a hash of the word map and the shard count; a resume with different
+//! inputs is refused.
+//!
+//! Reduce reads a whole shard into memory and counts it in a HashMap, so
+//! its peak RAM is a few times the shard size (`REDUCE_RAM_FACTOR`).
+//! `auto_shard_count` sizes the shard count from the corpus file against a
+//! RAM budget, and `reduce_shards` splits any shard that still exceeds the
+//! budget into sub-shards by prev id before counting it.
+
+use crate::bigram::BigramWriter;
+use crate::builder::{encode_log_max, quantize_rows, token_id, CountEntry, EdgeEntry, IndexEntry};
+use crate::corpus::{CorpusLines, Utf8Policy};
+use crate::progress::{file_size, Progress};
+use crate::tokenize::{LettersOnly, Normalizer};
+use crate::{open_corpus_counted, FastMap};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// RAM budget for the reduce phase when `--max-ram` isn't given
+pub const DEFAULT_MAX_RAM: u64 = 2 << 30;
+
+/// Peak reduce RAM per shard byte: the raw buffer plus a HashMap entry
+/// (~19 bytes) per 8-byte pair when most pairs are distinct
+pub const REDUCE_RAM_FACTOR: u64 = 4;
+
+/// Shard bytes per corpus byte: one 8-byte pair per ~5.5-byte word
+const PAIR_BYTES_PER_TEXT_BYTE: f64 = 1.5;
+
+/// Typical size ratio of plain to gzip/zstd/bzip2 text
+const COMPRESSION_RATIO: f64 = 3.5;
+
+const MAX_AUTO_SHARDS: usize = 1024;
+
+/// What a completed shard phase was built from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardManifest {
+    pub corpus: String,
+    pub map_hash: u64,
+    pub num_shards: usize,
+    pub case_sensitive: bool,
+    /// `Normalizer::name` of the tokenizer (missing in older manifests,
+    /// which were all built with `LettersOnly`)
+    #[serde(default = "default_tokenizer")]
+    pub tokenizer: String,
+}
+
+fn default_tokenizer() -> String {
+    LettersOnly.name()
+}
+
+impl ShardManifest {
+    /// Load the manifest from `shard_dir`, or None if the shard phase never finished
+    pub fn load(shard_dir: &Path) -> Result<Option<Self>> {
+        let path = shard_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&path).context("Failed to open shard manifest")?;
+        let manifest = serde_json::from_reader(file).context("Failed to parse shard manifest")?;
+        Ok(Some(manifest))
+    }
+
+    pub fn save(&self, shard_dir: &Path) -> Result<()> {
+        let file = File::create(shard_dir.join(MANIFEST_FILE))
+            .context("Failed to create shard manifest")?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Error out if shards built for `self` can't stand in for `expected`
+    pub fn ensure_matches(&self, expected: &ShardManifest) -> Result<()> {
+        if self.corpus != expected.corpus {
+            bail!(
+                "shards were built from {}, not {}",
+                self.corpus,
+                expected.corpus
+            );
+        }
+        if self.map_hash != expected.map_hash || self.case_sensitive != expected.case_sensitive {
+            bail!("shards were built with a different word map");
+        }
+        if self.tokenizer != expected.tokenizer {
+            bail!(
+                "shards were tokenized with {}, not {}",
+                self.tokenizer,
+                expected.tokenizer
+            );
+        }
+        if self.num_shards != expected.num_shards {
+            bail!(
+                "shards were built with --shards {}, not {}",
+                self.num_shards,
+                expected.num_shards
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Removes a shard directory when dropped, so a build that errors out
+/// after sharding doesn't leave the shards behind for a later run to trip
+/// over. `commit` keeps them instead: `build_bigram --resume` commits up
+/// front so an interrupted reduce can pick them up again, and `remove`
+/// deletes them on success, reporting any error.
+#[derive(Debug)]
+pub struct ShardDirGuard {
+    dir: PathBuf,
+    keep: bool,
+}
+
+impl ShardDirGuard {
+    /// Guard `dir`, which need not exist yet
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            keep: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Leave the directory in place when dropped
+    pub fn commit(&mut self) {
+        self.keep = true;
+    }
+
+    /// Remove the directory now, committed or not
+    pub fn remove(mut self) -> Result<()> {
+        self.keep = true;
+        if self.dir.exists() {
+            std::fs::remove_dir_all(&self.dir)
+                .with_context(|| format!("Failed to remove {}", self.dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ShardDirGuard {
+    fn drop(&mut self) {
+        if !self.keep && self.dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+                eprintln!("warning: failed to remove {}: {}", self.dir.display(), e);
+            }
+        }
+    }
+}
+
+/// Shard count that keeps an average shard's reduce within `max_ram`,
+/// estimated from the corpus file size. A tiny corpus gets one shard.
+pub fn auto_shard_count(corpus_path: &str, corpus_bytes: u64, max_ram: u64) -> usize {
+    let compressed = [".gz", ".zst", ".bz2"]
+        .iter()
+        .any(|ext| corpus_path.ends_with(ext));
+    let text_bytes = if compressed {
+        corpus_bytes as f64 * COMPRESSION_RATIO
+    } else {
+        corpus_bytes as f64
+    };
+    let reduce_bytes = text_bytes * PAIR_BYTES_PER_TEXT_BYTE * REDUCE_RAM_FACTOR as f64;
+    let shards = (reduce_bytes / max_ram.max(1) as f64).ceil() as usize;
+    shards.clamp(1, MAX_AUTO_SHARDS)
+}
+
+/// Parse a byte size such as `512M`, `4G` or a plain byte count
+pub fn parse_size(spec: &str) -> Result<u64> {
+    let (digits, multiplier) = match spec.chars().last() {
+        Some('K' | 'k') => (&spec[..spec.len() - 1], 1u64 << 10),
+        Some('M' | 'm') => (&spec[..spec.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&spec[..spec.len() - 1], 1 << 30),
+        _ => (spec, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .with_context(|| format!("bad size: {}", spec))?;
+    if value == 0 {
+        bail!("size must be positive: {}", spec);
+    }
+    Ok(value * multiplier)
+}
+
+/// Order-independent FNV-1a hash of a word -> id map
+pub fn map_hash<S: BuildHasher>(word_ids: &HashMap<String, u32, S>) -> u64 {
+    let mut entries: Vec<(&String, &u32)> = word_ids.iter().collect();
+    entries.sort();
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for (word, id) in entries {
+        for byte in word.bytes().chain([0xFF]).chain(id.to_le_bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Totals from `shard_bigrams`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShardStats {
+    pub bigrams: u64,
+    /// Lines decoded lossily (see `corpus::Utf8Policy`)
+    pub malformed_lines: u64,
+}
+
+/// How `shard_bigrams` reads the corpus
+#[derive(Clone, Copy)]
+pub struct ShardOptions<'a> {
+    /// Key on exact word_ids (see `builder`)
+    pub case_sensitive: bool,
+    pub normalizer: &'a dyn Normalizer,
+    pub utf8: Utf8Policy,
+    /// Print throughput and ETA reports (see `progress`)
+    pub progress: bool,
+}
+
+impl Default for ShardOptions<'_> {
+    fn default() -> Self {
+        Self {
+            case_sensitive: false,
+            normalizer: &LettersOnly,
+            utf8: Utf8Policy::Lossy,
+            progress: false,
+        }
+    }
+}
+
+/// Emit bigrams to shard files: shard[prev_id % S] gets (prev_id, next_id)
+pub fn shard_bigrams<S: BuildHasher>(
+    input_path: &str,
+    word_ids: &HashMap<String, u32, S>,
+    options: &ShardOptions,
+    shard_dir: &Path,
+    num_shards: usize,
+) -> Result<ShardStats> {
+    // Open shard files
+    let mut shards: Vec<BufWriter<File>> = (0..num_shards)
+        .map(|i| {
+            let path = shard_dir.join(format!("shard_{:03}.bin", i));
+            Ok(BufWriter::new(File::create(path)?))
+        })
+        .collect::<Result<_>>()?;
+
+    let (corpus, bytes_read) = open_corpus_counted(input_path)?;
+    let mut lines = CorpusLines::new(corpus, options.utf8);
+    let mut progress = options.progress.then(|| {
+        let mut progress = Progress::new("bigrams").with_total_bytes(file_size(input_path));
+        progress.track(bytes_read);
+        progress
+    });
+
+    let mut lines_processed = 0u64;
+    let mut bigrams_emitted = 0u64;
+    let mut prev_id: Option<u32> = None;
+
+    for line in &mut lines {
+        let line = line?;
+        lines_processed += 1;
+
+        if let Some(progress) = progress.as_mut() {
+            progress.tick(lines_processed, bigrams_emitted);
+        } else if lines_processed.is_multiple_of(5_000_000) {
+            println!(
+                "  {} M lines, {} M bigrams",
+                lines_processed / 1_000_000,
+                bigrams_emitted / 1_000_000
+            );
+        }
+
+        for word in line.split_whitespace() {
+            if let Some(word_id) =
+                token_id(word, word_ids, options.case_sensitive, options.normalizer)
+            {
+                if let Some(prev) = prev_id {
+                    // Emit to shard
+                    let shard_idx = (prev as usize) % num_shards;
+                    shards[shard_idx].write_all(&prev.to_le_bytes())?;
+                    shards[shard_idx].write_all(&word_id.to_le_bytes())?;
+                    bigrams_emitted += 1;
+                }
+                prev_id = Some(word_id);
+            } else {
+                prev_id = None;
+            }
+        }
+        prev_id = None; // End of line breaks chain
+    }
+
+    // Flush all shards
+    for mut shard in shards {
+        shard.flush()?;
+    }
+
+    Ok(ShardStats {
+        bigrams: bigrams_emitted,
+        malformed_lines: lines.malformed(),
+    })
+}
+
+/// Reduce shards: sort, count, top-N per prev.
+///
+/// A shard whose estimated reduce RAM exceeds `max_ram` is first split by
+/// prev id into sub-shards that each fit, so its counts are still exact.
+pub fn reduce_shards(
+    shard_dir: &Path,
+    num_shards: usize,
+    vocab_size: u32,
+    top_n: usize,
+    max_ram: u64,
+) -> Result<(Vec<IndexEntry>, Vec<EdgeEntry>)> {
+    let (index, counts) = reduce_shard_counts(shard_dir, num_shards, vocab_size, top_n, max_ram)?;
+    let edges = quantize_rows(&index, &counts);
+    Ok((index, edges))
+}
+
+/// Like `reduce_shards`, but keep each edge's count instead of its weight
+pub fn reduce_shard_counts(
+    shard_dir: &Path,
+    num_shards: usize,
+    vocab_size: u32,
+    top_n: usize,
+    max_ram: u64,
+) -> Result<(Vec<IndexEntry>, Vec<CountEntry>)> {
+    let mut all_edges: Vec<Vec<CountEntry>> = vec![Vec::new(); vocab_size as usize];
+    for_each_shard_row(
+        shard_dir,
+        num_shards,
+        vocab_size,
+        top_n,
+        max_ram,
+        |prev, row| {
+            all_edges[prev as usize] = row;
+            Ok(())
+        },
+    )?;
+
+    // Build index and edges arrays
+    let mut index: Vec<IndexEntry> = Vec::with_capacity(vocab_size as usize);
+    let mut edges: Vec<CountEntry> = Vec::new();
+
+    for row in all_edges {
+        let offset = (edges.len() * 8) as u32;
+        let log_max = row.first().map_or(0, |&(_, c)| encode_log_max(c));
+        index.push((offset, row.len() as u16, log_max));
+        edges.extend(row);
+    }
+
+    Ok((index, edges))
+}
+
+/// Reduce shards straight into `writer`, one shard's rows at a time, so
+/// only a shard's finalized rows are held at once instead of every edge.
+/// Rows are written in shard order (prev % num_shards), not prev order;
+/// the index records each row's offset, so readers see the same model as
+/// from `reduce_shards`, though the bytes differ.
+pub fn reduce_shards_into<W: Write + Seek>(
+    shard_dir: &Path,
+    num_shards: usize,
+    top_n: usize,
+    max_ram: u64,
+    writer: &mut BigramWriter<W>,
+) -> Result<()> {
+    let vocab_size = writer.vocab_size();
+    for_each_shard_row(
+        shard_dir,
+        num_shards,
+        vocab_size,
+        top_n,
+        max_ram,
+        |prev, row| writer.push_counts(prev, &row),
+    )
+}
+
+/// Count each shard and hand `emit` every prev's row, largest count first
+/// and cut to `top_n`, shard by shard and in prev order within a shard.
+/// Prevs at or past `vocab_size` are dropped.
+fn for_each_shard_row(
+    shard_dir: &Path,
+    num_shards: usize,
+    vocab_size: u32,
+    top_n: usize,
+    max_ram: u64,
+    mut emit: impl FnMut(u32, Vec<CountEntry>) -> Result<()>,
+) -> Result<()> {
+    for shard_idx in 0..num_shards {
+        let path = shard_dir.join(format!("shard_{:03}.bin", shard_idx));
+        let file_len = std::fs::metadata(&path)
+            .with_context(|| format!("Missing shard {}", path.display()))?
+            .len();
+
+        if file_len == 0 {
+            continue;
+        }
+
+        let needed = file_len.saturating_mul(REDUCE_RAM_FACTOR);
+        let parts = if needed > max_ram {
+            let parts = needed.div_ceil(max_ram.max(1)) as usize;
+            println!(
+                "  Shard {} needs ~{} MB to reduce; splitting into {} parts",
+                shard_idx,
+                needed >> 20,
+                parts
+            );
+            split_shard(&path, num_shards, parts)?
+        } else {
+            vec![path.clone()]
+        };
+
+        for part in &parts {
+            let counts = count_shard(part)?;
+            // A prev never spans two parts, so its row is complete here:
+            // size it once instead of growing it edge by edge
+            let mut row_lens: FastMap<u32, usize> = FastMap::default();
+            for &(prev, _) in counts.keys() {
+                if prev < vocab_size {
+                    *row_lens.entry(prev).or_insert(0) += 1;
+                }
+            }
+            let mut rows: FastMap<u32, Vec<CountEntry>> = row_lens
+                .into_iter()
+                .map(|(prev, len)| (prev, Vec::with_capacity(len)))
+                .collect();
+            for ((prev, next), count) in counts {
+                if let Some(row) = rows.get_mut(&prev) {
+                    row.push((next, count));
+                }
+            }
+            if *part != path {
+                std::fs::remove_file(part)?;
+            }
+
+            // Sort by count descending, take top-N
+            let mut rows: Vec<(u32, Vec<CountEntry>)> = rows.into_iter().collect();
+            rows.sort_unstable_by_key(|(prev, _)| *prev);
+            for (prev, mut row) in rows {
+                row.sort_by_key(|&(next_id, count)| (Reverse(count), next_id));
+                row.truncate(top_n);
+                emit(prev, row)?;
+            }
+        }
+
+        if (shard_idx + 1) % 32 == 0 {
+            println!("  Processed {}/{} shards", shard_idx + 1, num_shards);
+        }
+    }
+    Ok(())
+}
+
+/// Read a shard into memory and count its (prev, next) pairs
+fn count_shard(path: &Path) -> Result<FastMap<(u32, u32), u64>> {
+    let mut file = File::open(path).with_context(|| format!("Missing shard {}", path.display()))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    // One slot per pair is an upper bound on distinct pairs, and what
+    // REDUCE_RAM_FACTOR already budgets for; no rehashing as the map fills
+    let mut counts: FastMap<(u32, u32), u64> =
+        FastMap::with_capacity_and_hasher(buf.len() / 8, Default::default());
+    for chunk in buf.chunks_exact(8) {
+        let prev = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        let next = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        *counts.entry((prev, next)).or_insert(0) += 1;
+    }
+    Ok(counts)
+}
+
+/// Stream an oversized shard into `parts` sub-shards. Every prev in the
+/// shard is congruent mod `num_shards`, so the split keys on
+/// `prev / num_shards` to spread them, and a prev never spans two parts.
+fn split_shard(path: &Path, num_shards: usize, parts: usize) -> Result<Vec<PathBuf>> {
+    let part_paths: Vec<PathBuf> = (0..parts)
+        .map(|i| path.with_extension(format!("part{:03}", i)))
+        .collect();
+    let mut writers: Vec<BufWriter<File>> = part_paths
+        .iter()
+        .map(|p| Ok(BufWriter::new(File::create(p)?)))
+        .collect::<Result<_>>()?;
+
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(path)?);
+    let mut pair = [0u8; 8];
+    loop {
+        match reader.read_exact(&mut pair) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let prev = u32::from_le_bytes([pair[0], pair[1], pair[2], pair[3]]) as usize;
+        writers[(prev / num_shards) % parts].write_all(&pair)?;
+    }
+
+    for mut writer in writers {
+        writer.flush()?;
+    }
+    Ok(part_paths)
+}
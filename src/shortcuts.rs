@@ -0,0 +1,102 @@
+//! Shortcut expansions from the Android `.combined` dictionary
+//!
+//! A shortcut line follows the word it belongs to:
+//!
+//! ```text
+//!  word=omw,f=0,not_a_word=true
+//!   shortcut=on my way,f=14
+//! ```
+//!
+//! Saved as a side file of `typed<TAB>expansion` lines sorted by key.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// Frequency given to `f=whitelist` shortcuts, which always apply
+const WHITELIST_FREQ: u8 = u8::MAX;
+
+/// Parse a `shortcut=<target>,f=<freq>` line into (target, freq).
+/// Returns None for any other line.
+pub fn parse_shortcut(line: &str) -> Option<(&str, u8)> {
+    let rest = line.trim().strip_prefix("shortcut=")?;
+    // The target can't contain ',' in this format, so the first one ends it
+    let (target, attrs) = rest.split_once(',').unwrap_or((rest, ""));
+    if target.is_empty() {
+        return None;
+    }
+    let freq = attrs
+        .split(',')
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| *k == "f")
+        .map(|(_, v)| match v {
+            "whitelist" => WHITELIST_FREQ,
+            _ => v.parse::<u16>().unwrap_or(0).min(255) as u8,
+        })
+        .unwrap_or(0);
+    Some((target, freq))
+}
+
+/// Typed word -> expansion
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShortcutTable {
+    entries: BTreeMap<String, (String, u8)>, // typed -> (expansion, freq)
+}
+
+impl ShortcutTable {
+    /// Add a shortcut for `typed`; of several, the highest-frequency one wins
+    pub fn insert(&mut self, typed: &str, expansion: &str, freq: u8) {
+        self.entries
+            .entry(typed.to_string())
+            .and_modify(|(old, old_freq)| {
+                if freq > *old_freq {
+                    *old = expansion.to_string();
+                    *old_freq = freq;
+                }
+            })
+            .or_insert_with(|| (expansion.to_string(), freq));
+    }
+
+    /// Expansion for `typed`, trying the exact form and then lowercase
+    pub fn expand(&self, typed: &str) -> Option<&str> {
+        self.entries
+            .get(typed)
+            .or_else(|| self.entries.get(&typed.to_lowercase()))
+            .map(|(expansion, _)| expansion.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+        let mut writer = BufWriter::new(file);
+        for (typed, (expansion, _)) in &self.entries {
+            writeln!(writer, "{}\t{}", typed, expansion)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let mut table = Self::default();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let Some((typed, expansion)) = line.split_once('\t') else {
+                bail!("{}:{}: expected typed<TAB>expansion", path, i + 1);
+            };
+            table.insert(typed, expansion, 0);
+        }
+        Ok(table)
+    }
+}
@@ -3,15 +3,16 @@
 //! Usage: cargo run --release --bin suggest -- "i love"
 
 use anyhow::Result;
-use combined2fst::build_canonical_map;
-use memmap2::Mmap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use combined2fst::tokenize::{LettersOnly, Normalizer};
+use combined2fst::{truncate_display, ImeEngine, MAX_DISPLAY_CHARS};
 
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: {} \"sentence prefix\"", args[0]);
+        eprintln!(
+            "Usage: {} \"sentence prefix\" (\"\" for sentence starters)",
+            args[0]
+        );
         eprintln!("Example: {} \"i love\"", args[0]);
         std::process::exit(1);
     }
@@ -19,37 +20,20 @@ fn main() -> Result<()> {
     let sentence = args[1..].join(" ");
 
     // Load resources
-    let vocab: Vec<String> = BufReader::new(File::open("en.vocab.txt")?)
-        .lines()
-        .collect::<std::io::Result<_>>()?;
-    let (_, canonical_map) = build_canonical_map("en.lex.fst", "en.vocab.txt")?;
-
-    // Load bigram
-    let bigram_file = File::open("en.bigram.bin")?;
-    let bigram_mmap = unsafe { Mmap::map(&bigram_file)? };
-    let bigram_data = bigram_mmap.as_ref();
-
-    // Parse bigram header
-    let vocab_size = u32::from_le_bytes([
-        bigram_data[8],
-        bigram_data[9],
-        bigram_data[10],
-        bigram_data[11],
-    ]) as usize;
-    let header_size = 32;
-    let index_size = vocab_size * 8;
-    let edges_base = header_size + index_size;
+    let engine = ImeEngine::load("en.lex.fst", "en.vocab.txt", "en.bigram.bin", None)?;
 
     // Get last word of sentence
-    let words: Vec<&str> = sentence.split_whitespace().collect();
-    if words.is_empty() {
-        println!("Please enter a sentence prefix");
-        return Ok(());
-    }
-
-    let last_word = normalize_token(words.last().unwrap());
+    let last_word = sentence
+        .split_whitespace()
+        .last()
+        .map(|w| LettersOnly.normalize(w, false))
+        .unwrap_or_default();
     if last_word.is_empty() {
-        println!("Please enter a sentence prefix");
+        // Nothing typed yet, or the sentence just ended: likely first words
+        println!("Sentence starters:");
+        for (i, s) in engine.suggest_next("", 10).iter().enumerate() {
+            println!("  {}. {}", i + 1, s.word);
+        }
         return Ok(());
     }
 
@@ -57,19 +41,18 @@ fn main() -> Result<()> {
     println!("Last word: \"{}\"", last_word);
     println!();
 
-    // Look up bigram suggestions
-    if let Some(&word_id) = canonical_map.get(&last_word) {
-        let idx_offset = header_size + (word_id as usize) * 8;
-        let offset = u32::from_le_bytes([
-            bigram_data[idx_offset],
-            bigram_data[idx_offset + 1],
-            bigram_data[idx_offset + 2],
-            bigram_data[idx_offset + 3],
-        ]) as usize;
-        let len =
-            u16::from_le_bytes([bigram_data[idx_offset + 4], bigram_data[idx_offset + 5]]) as usize;
+    // Look up bigram suggestions (all stored edges)
+    if engine.word_id(&last_word).is_some() {
+        let suggestions = engine.suggest_next(&last_word, usize::MAX);
 
-        if len == 0 {
+        if engine.decode_errors() > 0 {
+            println!(
+                "warning: {} edges referenced ids past the vocab",
+                engine.decode_errors()
+            );
+        }
+
+        if suggestions.is_empty() {
             println!("No suggestions for \"{}\"", last_word);
             return Ok(());
         }
@@ -77,42 +60,18 @@ fn main() -> Result<()> {
         println!("Suggestions after \"{}\":", sentence);
         println!("─────────────────────────────");
 
-        for i in 0..len {
-            let e_off = edges_base + offset + i * 8;
-            let next_id = u32::from_le_bytes([
-                bigram_data[e_off],
-                bigram_data[e_off + 1],
-                bigram_data[e_off + 2],
-                bigram_data[e_off + 3],
-            ]) as usize;
-            let weight = u16::from_le_bytes([bigram_data[e_off + 4], bigram_data[e_off + 5]]);
-
-            if let Some(next_word) = vocab.get(next_id) {
-                let confidence = (weight as f64 / 65535.0 * 100.0) as u32;
-                println!(
-                    "  {}. {} ({}%)",
-                    i + 1,
-                    next_word.to_lowercase(),
-                    confidence
-                );
-            }
+        // Relative to the top continuation, not a share of all continuations
+        for (i, s) in suggestions.iter().enumerate() {
+            let confidence = (s.probability() * 100.0).round() as u32;
+            let word = truncate_display(&s.word.to_lowercase(), MAX_DISPLAY_CHARS);
+            println!("  {}. {} ({}%)", i + 1, word, confidence);
         }
 
-        // Show completed sentences
+        // Show completed sentences, two words ahead
         println!();
         println!("Complete sentences:");
-        for i in 0..len.min(5) {
-            let e_off = edges_base + offset + i * 8;
-            let next_id = u32::from_le_bytes([
-                bigram_data[e_off],
-                bigram_data[e_off + 1],
-                bigram_data[e_off + 2],
-                bigram_data[e_off + 3],
-            ]) as usize;
-
-            if let Some(next_word) = vocab.get(next_id) {
-                println!("  → {} {}", sentence, next_word.to_lowercase());
-            }
+        for (phrase, _) in engine.suggest_phrase(&last_word, 2, 5) {
+            println!("  → {} {}", sentence, phrase.to_lowercase());
         }
     } else {
         println!("Word \"{}\" not found in vocabulary", last_word);
@@ -120,10 +79,3 @@ fn main() -> Result<()> {
 
     Ok(())
 }
-
-fn normalize_token(word: &str) -> String {
-    word.to_lowercase()
-        .chars()
-        .filter(|c| c.is_alphabetic() || *c == '\'')
-        .collect()
-}
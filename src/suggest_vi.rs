@@ -1,12 +1,24 @@
 //! Vietnamese sentence suggestion demo
 //!
+//! Syllables typed without spaces ("tôiyêu") are split with
+//! `VietnameseEngine::segment` first. A last syllable that isn't complete
+//! ("tôi ngh") is finished with `VietnameseEngine::complete`, and the
+//! best completions are followed by their next-syllable predictions.
+//!
 //! Usage: cargo run --release --bin suggest_vi -- "tôi yêu"
 
-use anyhow::Result;
-use memmap2::Mmap;
+use anyhow::{bail, Context, Result};
+use combined2fst::bigram::VocabUnit;
+use combined2fst::{
+    dequantize_with, truncate_display, BigramModel, Edge, VietnameseEngine, Vocab,
+    MAX_DISPLAY_CHARS,
+};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+
+/// Completions shown for a partial last syllable
+const COMPLETIONS: usize = 5;
+/// Completions followed into next-syllable predictions
+const CHAINED: usize = 3;
 
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
@@ -16,92 +28,127 @@ fn main() -> Result<()> {
         std::process::exit(1);
     }
 
-    let sentence = args[1..].join(" ");
+    let typed = args[1..].join(" ");
+    let vi = VietnameseEngine::open("vi.syllable.fst")?;
+
+    // A last token that isn't a syllable but starts one is still being
+    // typed; segmenting it would split "ngh" into single letters
+    let last_token = typed.split_whitespace().last().unwrap_or("");
+    let partial = !typed.ends_with(char::is_whitespace)
+        && !vi.is_syllable(last_token)
+        && !vi.complete(last_token, 1).is_empty();
+    let (done, partial) = if partial {
+        (&typed[..typed.len() - last_token.len()], Some(last_token))
+    } else {
+        (typed.as_str(), None)
+    };
+    let split = vi.segment(done);
+    if !split.complete {
+        println!("(Không tách được hết âm tiết: {:?})", split.syllables);
+    }
+    let sentence = split.syllables.join(" ");
 
     // Load vocab
-    let vocab: Vec<String> = BufReader::new(File::open("vi.syllable.vocab.txt")?)
-        .lines()
-        .collect::<std::io::Result<_>>()?;
-
-    let mut word_to_id: HashMap<String, usize> = HashMap::new();
+    let vocab = Vocab::load("vi.syllable.vocab.txt")?;
+    let mut word_to_id: HashMap<String, u32> = HashMap::new();
     for (id, word) in vocab.iter().enumerate() {
-        word_to_id.insert(word.to_lowercase(), id);
+        word_to_id.insert(word.to_lowercase(), id as u32);
     }
 
     // Load bigram
-    let bigram_file = File::open("vi.bigram.bin")?;
-    let bigram_mmap = unsafe { Mmap::map(&bigram_file)? };
-    let data = bigram_mmap.as_ref();
+    let model = BigramModel::open("vi.bigram.bin")?;
+    model
+        .check_vocab(&vocab)
+        .context("vi.bigram.bin doesn't match vi.syllable.vocab.txt")?;
+    if model.unit() != VocabUnit::Syllable {
+        bail!("vi.bigram.bin was built with --unit phrase; this demo needs syllable ids");
+    }
 
-    let vocab_size = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
-    let header_size = 32;
-    let index_size = vocab_size * 8;
-    let edges_base = header_size + index_size;
+    if let Some(partial) = partial {
+        return complete_and_predict(&vi, &model, &vocab, &split.syllables, partial);
+    }
 
     // Get last syllable
-    let syllables: Vec<&str> = sentence.split_whitespace().collect();
-    if syllables.is_empty() {
+    let Some(last_syllable) = split.syllables.last().map(|s| s.to_lowercase()) else {
         println!("Vui lòng nhập câu tiếng Việt");
         return Ok(());
-    }
-
-    let last_syllable = syllables.last().unwrap().to_lowercase();
+    };
 
     println!("Input: \"{}\"", sentence);
     println!("Âm tiết cuối: \"{}\"", last_syllable);
     println!();
 
     if let Some(&syllable_id) = word_to_id.get(&last_syllable) {
-        let idx_offset = header_size + syllable_id * 8;
-        let offset = u32::from_le_bytes([
-            data[idx_offset],
-            data[idx_offset + 1],
-            data[idx_offset + 2],
-            data[idx_offset + 3],
-        ]) as usize;
-        let len = u16::from_le_bytes([data[idx_offset + 4], data[idx_offset + 5]]) as usize;
-
-        if len == 0 {
+        let edges = model.next(syllable_id, usize::MAX);
+        if edges.is_empty() {
             println!("Không có gợi ý cho \"{}\"", last_syllable);
             return Ok(());
         }
 
         println!("Gợi ý sau \"{}\":", sentence);
         println!("─────────────────────────────");
-
-        for i in 0..len {
-            let e_off = edges_base + offset + i * 8;
-            let next_id = u32::from_le_bytes([
-                data[e_off],
-                data[e_off + 1],
-                data[e_off + 2],
-                data[e_off + 3],
-            ]) as usize;
-            let weight = u16::from_le_bytes([data[e_off + 4], data[e_off + 5]]);
-
-            if let Some(next_word) = vocab.get(next_id) {
-                let confidence = (weight as f64 / 65535.0 * 100.0) as u32;
+        // Relative to the top continuation, not a share of all continuations
+        let log_max = model.log_max(syllable_id);
+        for (i, &Edge { next_id, weight }) in edges.iter().enumerate() {
+            if let Some(next_word) = vocab.word(next_id) {
+                let confidence = (dequantize_with(weight, log_max) * 100.0).round() as u32;
+                let next_word = truncate_display(next_word, MAX_DISPLAY_CHARS);
                 println!("  {}. {} ({}%)", i + 1, next_word, confidence);
             }
         }
 
         println!();
         println!("Câu hoàn chỉnh:");
-        for i in 0..len.min(5) {
-            let e_off = edges_base + offset + i * 8;
-            let next_id = u32::from_le_bytes([
-                data[e_off],
-                data[e_off + 1],
-                data[e_off + 2],
-                data[e_off + 3],
-            ]) as usize;
-
-            if let Some(next_word) = vocab.get(next_id) {
+        for &Edge { next_id, .. } in edges.iter().take(5) {
+            if let Some(next_word) = vocab.word(next_id) {
                 println!("  → {} {}", sentence, next_word);
             }
         }
-    } else {
-        println!("Âm tiết \"{}\" không có trong từ điển", last_syllable);
+        return Ok(());
+    }
+
+    println!("Âm tiết \"{}\" không có trong từ điển", last_syllable);
+    Ok(())
+}
+
+/// Complete the unfinished syllable `partial` typed after `head`, then
+/// predict what follows the best completions
+fn complete_and_predict(
+    vi: &VietnameseEngine,
+    model: &BigramModel,
+    vocab: &Vocab,
+    head: &[String],
+    partial: &str,
+) -> Result<()> {
+    let completions = vi.complete(partial, COMPLETIONS);
+    println!("Input: \"{} {}\"", head.join(" "), partial);
+    println!("Hoàn thành \"{}\":", partial);
+    println!("─────────────────────────────");
+    for (i, m) in completions.iter().enumerate() {
+        let marker = if m.exact { "" } else { " (không dấu)" };
+        println!("  {}. {}{}", i + 1, m.syllable, marker);
+    }
+
+    println!();
+    println!("Câu hoàn chỉnh:");
+    for m in completions.iter().take(CHAINED) {
+        let completed: Vec<&str> = head
+            .iter()
+            .map(String::as_str)
+            .chain([m.syllable.as_str()])
+            .collect();
+        let completed = completed.join(" ");
+        let next: Vec<&str> = model
+            .next(m.syllable_id, 3)
+            .into_iter()
+            .filter_map(|edge| vocab.word(edge.next_id))
+            .collect();
+        if next.is_empty() {
+            println!("  → {}", completed);
+        }
+        for next_word in next {
+            println!("  → {} {}", completed, next_word);
+        }
     }
 
     Ok(())
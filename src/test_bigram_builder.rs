@@ -0,0 +1,1548 @@
+use combined2fst::bigram::{
+    write_bigram, BigramModel, BigramStats, BigramWriter, EdgeValues, VocabUnit,
+    EDGE_FLAG_CROSS_SENTENCE, EDGE_FLAG_USER,
+};
+use combined2fst::builder::{
+    cap_total_edges, decode_log_max, encode_log_max, ends_sentence, quantize_rows, relative_count,
+    BigramCounter, EdgeEntry, FlaggedEntry, IndexEntry,
+};
+use combined2fst::corpus::{
+    weight_increments, CorpusLines, CorpusSource, LineSampler, Utf8Policy, DEFAULT_MAX_LINE_LEN,
+};
+use combined2fst::lexicon::write_lexicon;
+use combined2fst::progress::{format_duration, Progress, Snapshot};
+use combined2fst::shard::{
+    auto_shard_count, map_hash, parse_size, reduce_shard_counts, reduce_shards, reduce_shards_into,
+    shard_bigrams, ShardDirGuard, ShardManifest, ShardOptions, DEFAULT_MAX_RAM,
+};
+use combined2fst::sketch::CountMinSketch;
+use combined2fst::tokenize::{
+    from_flags, is_placeholder, LettersOnly, Locale, Localized, Normalizer, Pretokenized,
+    RichTokens, Script, ScriptLetters,
+};
+use combined2fst::validate_bigram;
+use combined2fst::{
+    open_corpus, open_corpus_counted, Count, Edge, TrigramCache, TrigramCounts, Vocab,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// The system allocator, noting the largest single allocation, so section
+/// 30 can check that a huge corpus line is never buffered whole
+struct TrackLargest;
+
+static LARGEST_ALLOC: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for TrackLargest {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LARGEST_ALLOC.fetch_max(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LARGEST_ALLOC.fetch_max(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackLargest = TrackLargest;
+
+fn main() {
+    println!("=== Test Bigram Builder ===");
+
+    let mut canonical = HashMap::new();
+    canonical.insert("i".to_string(), 0);
+    canonical.insert("love".to_string(), 1);
+    canonical.insert("you".to_string(), 2);
+    canonical.insert("pizza".to_string(), 3);
+    let vocab_size = 4;
+
+    let web: Vec<&str> = vec!["I love you"; 10];
+    let chat: Vec<&str> = vec!["i love pizza"; 2];
+
+    // 1. Corpus spec parsing
+    println!("\nParsing --corpus specs...");
+    let spec = CorpusSource::parse("chat.txt:20").unwrap();
+    assert_eq!(spec.path, "chat.txt");
+    assert_eq!(spec.weight, 20.0);
+    let bare = CorpusSource::parse("web.txt.gz").unwrap();
+    assert_eq!(bare.weight, 1.0);
+    assert!(CorpusSource::parse("bad.txt:0").is_err());
+    assert!(CorpusSource::parse("bad.txt:-2").is_err());
+
+    let sources = vec![
+        CorpusSource::parse("web.txt:0.5").unwrap(),
+        CorpusSource::parse("chat.txt:10").unwrap(),
+    ];
+    let increments = weight_increments(&sources);
+    println!("Increments: {:?}", increments);
+    assert_eq!(increments, vec![1, 20]);
+
+    // 2. Unweighted: "you" wins after "love"
+    println!("\nCounting unweighted...");
+    let top = top_after_love(&web, &chat, 1, 1, vocab_size, &canonical);
+    println!("Top after 'love': {}", top);
+    assert_eq!(top, 2, "'you' should win without weights");
+
+    // 3. Heavily weighted chat corpus flips the top suggestion
+    println!("\nCounting with chat weighted x20...");
+    let top = top_after_love(&web, &chat, 1, 20, vocab_size, &canonical);
+    println!("Top after 'love': {}", top);
+    assert_eq!(top, 3, "'pizza' should win with chat weight");
+
+    // 4. Case-sensitive mode keeps "Apple" and "apple" on separate rows
+    println!("\nCounting case-sensitive...");
+    let mut exact = HashMap::new();
+    exact.insert("apple".to_string(), 0);
+    exact.insert("Apple".to_string(), 1);
+    exact.insert("pie".to_string(), 2);
+    exact.insert("iphone".to_string(), 3);
+    let lines = ["Apple iPhone", "apple pie", "Apple iPhone"];
+
+    let mut counter = BigramCounter::new(10).case_sensitive(true);
+    for line in &lines {
+        counter.feed_line(line, &exact, 1);
+    }
+    let (index, edges) = counter.into_edges(4);
+    let row = |prev: usize| -> Vec<u32> {
+        let (offset, len, _) = index[prev];
+        let start = offset as usize / 8;
+        edges[start..start + len as usize]
+            .iter()
+            .map(|e| e.0)
+            .collect()
+    };
+    println!("After 'apple': {:?}, after 'Apple': {:?}", row(0), row(1));
+    assert_eq!(row(0), vec![2], "'apple' should be followed by 'pie'");
+    assert_eq!(row(1), vec![3], "'Apple' should be followed by 'iphone'");
+
+    // Canonical mode folds both variants onto one row
+    let mut folded = HashMap::new();
+    folded.insert("apple".to_string(), 0);
+    folded.insert("pie".to_string(), 2);
+    folded.insert("iphone".to_string(), 3);
+    let mut counter = BigramCounter::new(10);
+    for line in &lines {
+        counter.feed_line(line, &folded, 1);
+    }
+    let (index, _) = counter.into_edges(4);
+    assert_eq!(index[0].1, 2, "Canonical mode should merge case variants");
+    assert_eq!(index[1].1, 0);
+
+    // 5. Sketch-backed counting agrees with exact counting on the top-N
+    println!("\nComparing sketch vs exact top-N...");
+    assert!(CountMinSketch::parse("1024:4").is_ok());
+    assert!(CountMinSketch::parse("1024").is_err());
+    assert!(CountMinSketch::parse("0:4").is_err());
+    let huge = format!("{}:4", usize::MAX / 2);
+    assert!(
+        CountMinSketch::parse(&huge).is_err(),
+        "width * depth overflows"
+    );
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let overflow = std::panic::catch_unwind(|| CountMinSketch::new(usize::MAX, 2));
+    std::panic::set_hook(hook);
+    assert!(overflow.is_err(), "new panics rather than wrapping");
+
+    let (vocab, lines) = skewed_fixture(200, 20_000);
+    let top_n = 10;
+    // 160k pairs into a 65536-wide sketch: overestimates stay under ~7 counts
+    let mut exact = BigramCounter::new(top_n);
+    let mut sketched = BigramCounter::new(top_n).with_sketch(CountMinSketch::new(1 << 16, 4));
+    for line in &lines {
+        exact.feed_line(line, &vocab, 1);
+        sketched.feed_line(line, &vocab, 1);
+    }
+    let (exact_index, exact_edges) = exact.into_edges(vocab.len());
+    let (sketch_index, sketch_edges) = sketched.into_edges(vocab.len());
+
+    let ids = |index: &[(u32, u16, u16)], edges: &[(u32, u16)], prev: usize| -> Vec<u32> {
+        let (offset, len, _) = index[prev];
+        let start = offset as usize / 8;
+        edges[start..start + len as usize]
+            .iter()
+            .map(|e| e.0)
+            .collect()
+    };
+    let mut shared = 0;
+    let mut total = 0;
+    for prev in 0..vocab.len() {
+        let a = ids(&exact_index, &exact_edges, prev);
+        let b = ids(&sketch_index, &sketch_edges, prev);
+        shared += a.iter().filter(|id| b.contains(id)).count();
+        total += a.len();
+    }
+    let overlap = shared as f64 / total as f64;
+    println!(
+        "Top-{} overlap: {:.1}% of {} edges",
+        top_n,
+        overlap * 100.0,
+        total
+    );
+    assert!(overlap >= 0.9, "Sketch top-N should mostly match exact");
+
+    // 6. Stored ln(max_count) lets weights be turned back into count ratios
+    println!("\nInverting quantized weights...");
+    let mut counter = BigramCounter::new(10);
+    for (next, times) in [("you", 200), ("pizza", 50), ("i", 5)] {
+        for _ in 0..times {
+            counter.feed_line(&format!("love {}", next), &canonical, 1);
+        }
+    }
+    let (index, edges) = counter.into_edges(vocab_size);
+    let (offset, len, log_max) = index[1];
+    let log_max = decode_log_max(log_max).expect("builder stores ln(max)");
+    assert!((log_max - 200f64.ln()).abs() < 0.001);
+    let start = offset as usize / 8;
+    let ratios: Vec<f64> = edges[start..start + len as usize]
+        .iter()
+        .map(|&(_, weight)| relative_count(weight, log_max))
+        .collect();
+    println!("Relative counts: {:?}", ratios);
+    assert!((ratios[0] - 1.0).abs() < 0.001);
+    assert!((ratios[1] - 0.25).abs() < 0.01);
+    assert!((ratios[2] - 0.025).abs() < 0.001);
+    assert_eq!(decode_log_max(0), None, "0 marks files without ln(max)");
+
+    // 7. Compressed corpora count exactly like their plain equivalents
+    println!("\nCounting plain vs .zst vs .bz2 corpora...");
+    let (vocab, lines) = skewed_fixture(50, 500);
+    let text = lines.join("\n");
+    let dir = std::env::temp_dir();
+    let plain = dir.join(format!("{}_corpus.txt", std::process::id()));
+    let zst = dir.join(format!("{}_corpus.txt.zst", std::process::id()));
+    let bz2 = dir.join(format!("{}_corpus.txt.bz2", std::process::id()));
+    std::fs::write(&plain, &text).unwrap();
+    std::fs::write(&zst, zstd::encode_all(text.as_bytes(), 3).unwrap()).unwrap();
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    encoder.write_all(text.as_bytes()).unwrap();
+    std::fs::write(&bz2, encoder.finish().unwrap()).unwrap();
+
+    let expected = count_corpus(plain.to_str().unwrap(), &vocab);
+    assert!(expected.iter().any(|row| !row.is_empty()));
+    assert_eq!(count_corpus(zst.to_str().unwrap(), &vocab), expected);
+    assert_eq!(count_corpus(bz2.to_str().unwrap(), &vocab), expected);
+
+    // 8. Resuming after the shard phase matches a clean sharded run
+    println!("\nResuming a sharded build after the shard phase...");
+    let corpus = plain.to_str().unwrap();
+    let expected_manifest = ShardManifest {
+        corpus: corpus.to_string(),
+        map_hash: map_hash(&vocab),
+        num_shards: 4,
+        case_sensitive: false,
+        tokenizer: LettersOnly.name(),
+    };
+
+    let clean_dir = dir.join(format!("{}_clean_shards", std::process::id()));
+    std::fs::create_dir_all(&clean_dir).unwrap();
+    shard_bigrams(corpus, &vocab, &ShardOptions::default(), &clean_dir, 4).unwrap();
+    let clean = sorted_rows(
+        reduce_shards(&clean_dir, 4, vocab.len() as u32, 100, DEFAULT_MAX_RAM).unwrap(),
+    );
+
+    // First run: shards finish, manifest written, then the process "dies"
+    let resume_dir = dir.join(format!("{}_resume_shards", std::process::id()));
+    std::fs::create_dir_all(&resume_dir).unwrap();
+    shard_bigrams(corpus, &vocab, &ShardOptions::default(), &resume_dir, 4).unwrap();
+    expected_manifest.save(&resume_dir).unwrap();
+
+    // Second run: manifest matches, so go straight to reduce
+    let saved = ShardManifest::load(&resume_dir)
+        .unwrap()
+        .expect("manifest saved");
+    saved.ensure_matches(&expected_manifest).unwrap();
+    let resumed = sorted_rows(
+        reduce_shards(&resume_dir, 4, vocab.len() as u32, 100, DEFAULT_MAX_RAM).unwrap(),
+    );
+    assert_eq!(resumed, clean, "Resumed build should match a clean run");
+
+    // Mismatched inputs are refused
+    let other_shards = ShardManifest {
+        num_shards: 8,
+        ..expected_manifest.clone()
+    };
+    assert!(saved.ensure_matches(&other_shards).is_err());
+    let other_map = ShardManifest {
+        map_hash: map_hash(&folded),
+        ..expected_manifest.clone()
+    };
+    assert!(saved.ensure_matches(&other_map).is_err());
+    let other_tokens = ShardManifest {
+        tokenizer: RichTokens::default().name(),
+        ..expected_manifest.clone()
+    };
+    assert!(saved.ensure_matches(&other_tokens).is_err());
+    let other_corpus = ShardManifest {
+        corpus: "other.txt".to_string(),
+        ..expected_manifest
+    };
+    assert!(saved.ensure_matches(&other_corpus).is_err());
+    assert!(ShardManifest::load(&clean_dir).unwrap().is_none());
+
+    // 9. Shard count follows corpus size, and oversized shards are split
+    println!("\nSizing shards from the corpus...");
+    let corpus_bytes = std::fs::metadata(&plain).unwrap().len();
+    let small = auto_shard_count(corpus, corpus_bytes, DEFAULT_MAX_RAM);
+    println!("{} byte corpus -> {} shard(s)", corpus_bytes, small);
+    assert_eq!(small, 1, "A tiny corpus needs one shard");
+    let big = auto_shard_count("web.txt.gz", 10 << 30, DEFAULT_MAX_RAM);
+    assert!(
+        big > 64,
+        "10 GB compressed should need many shards, got {}",
+        big
+    );
+    assert!(auto_shard_count("web.txt", 10 << 30, DEFAULT_MAX_RAM) < big);
+    assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+    assert_eq!(parse_size("4G").unwrap(), 4 << 30);
+    assert!(parse_size("lots").is_err());
+
+    // A budget below every shard's size forces the split path
+    let shard_len = std::fs::metadata(clean_dir.join("shard_000.bin"))
+        .unwrap()
+        .len();
+    let split =
+        sorted_rows(reduce_shards(&clean_dir, 4, vocab.len() as u32, 100, shard_len / 2).unwrap());
+    assert_eq!(split, clean, "Split shards should count the same");
+
+    // 10. Equal counts break on next_id, so input order can't change the bytes
+    println!("\nBuilding from reordered input...");
+    let build = |lines: &[String]| {
+        let mut counter = BigramCounter::new(3);
+        for line in lines {
+            counter.feed_line(line, &vocab, 1);
+        }
+        counter.into_edges(vocab.len())
+    };
+    let mut reversed = lines.clone();
+    reversed.reverse();
+    assert_eq!(
+        build(&reversed),
+        build(&lines),
+        "Streaming build should not depend on line order"
+    );
+
+    let ties: Vec<String> = ["waa wad", "waa wab", "waa wae", "waa wac"]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+    let (index, edges) = build(&ties);
+    let row: Vec<u32> = edges[..index[0].1 as usize]
+        .iter()
+        .map(|&(id, _)| id)
+        .collect();
+    assert_eq!(row, [1, 2, 3], "Tied counts keep the lowest next_ids");
+
+    let reversed_path = dir.join(format!("{}_reversed.txt", std::process::id()));
+    std::fs::write(&reversed_path, reversed.join("\n")).unwrap();
+    let reversed_dir = dir.join(format!("{}_reversed_shards", std::process::id()));
+    std::fs::create_dir_all(&reversed_dir).unwrap();
+    shard_bigrams(
+        reversed_path.to_str().unwrap(),
+        &vocab,
+        &ShardOptions::default(),
+        &reversed_dir,
+        4,
+    )
+    .unwrap();
+    assert_eq!(
+        reduce_shards(&reversed_dir, 4, vocab.len() as u32, 3, DEFAULT_MAX_RAM).unwrap(),
+        reduce_shards(&clean_dir, 4, vocab.len() as u32, 3, DEFAULT_MAX_RAM).unwrap(),
+        "Sharded build should not depend on line order"
+    );
+
+    // 11. Raw-counts mode keeps each edge's count; weights match a normal build
+    println!("\nBuilding raw-counts and quantized models...");
+    let feed = || {
+        let mut counter = BigramCounter::new(10);
+        for line in &lines {
+            counter.feed_line(line, &vocab, 1);
+        }
+        counter
+    };
+    let (index, counts) = feed().into_counts(vocab.len());
+    let (quantized_index, weights) = feed().into_edges(vocab.len());
+    assert_eq!(index, quantized_index);
+
+    let write = |name: &str, edges: EdgeValues| {
+        let path = dir.join(format!("{}_{}.bin", std::process::id(), name));
+        let file = BufWriter::new(File::create(&path).unwrap());
+        write_bigram(file, vocab.len() as u32, 0, 10, &index, edges).unwrap();
+        path
+    };
+    let raw_path = write("raw", EdgeValues::RawCounts(&counts));
+    let quantized_path = write("quantized", EdgeValues::Weights(&weights));
+    assert!(validate_bigram(&std::fs::read(&raw_path).unwrap()).all_pass());
+    let raw = BigramModel::open(raw_path.to_str().unwrap()).unwrap();
+    let quantized = BigramModel::open(quantized_path.to_str().unwrap()).unwrap();
+    assert!(raw.has_raw_counts());
+    assert_eq!(raw.weight_bits(), 32);
+    assert!(!quantized.has_raw_counts());
+    assert!(quantized.raw_counts(0, 10).is_none());
+
+    // Every successor fits under the prune threshold, so counts are exact
+    let mut tally: HashMap<(u32, u32), u64> = HashMap::new();
+    for line in &lines {
+        let ids: Vec<u32> = line
+            .split_whitespace()
+            .map(|w| vocab[&LettersOnly.normalize(w, false)])
+            .collect();
+        for pair in ids.windows(2) {
+            *tally.entry((pair[0], pair[1])).or_insert(0) += 1;
+        }
+    }
+    let mut checked = 0;
+    for prev in 0..vocab.len() as u32 {
+        for (next, count) in raw.raw_counts(prev, usize::MAX).unwrap() {
+            assert_eq!(
+                count,
+                Count(tally[&(prev, next)]),
+                "count for ({}, {})",
+                prev,
+                next
+            );
+            checked += 1;
+        }
+        assert_eq!(
+            raw.next(prev, usize::MAX),
+            quantized.next(prev, usize::MAX),
+            "weights for prev {}",
+            prev
+        );
+        assert_eq!(raw.log_max(prev), quantized.log_max(prev));
+    }
+    println!("Checked {} raw counts", checked);
+    assert_eq!(checked, counts.len());
+    for (prev, row) in raw.iter() {
+        let counts: Vec<(u32, Count)> = row.iter().map(|e| (e.next_id(), e.count())).collect();
+        assert_eq!(Some(counts), raw.raw_counts(prev, usize::MAX));
+        let weights: Vec<Edge> = raw.weights(row).collect();
+        assert_eq!(weights, quantized.next(prev, usize::MAX));
+    }
+    std::fs::remove_file(raw_path).unwrap();
+    std::fs::remove_file(quantized_path).unwrap();
+
+    // 12. An invalid UTF-8 line is decoded lossily and counted, not fatal
+    println!("\nBuilding from a corpus with an invalid byte sequence...");
+    let bad_path = dir.join(format!("{}_bad_utf8.txt", std::process::id()));
+    std::fs::write(&bad_path, b"waa wab\nwaa \xFF\xFE wab wac\r\nwab wac\n").unwrap();
+    let mut bad_lines = CorpusLines::new(
+        open_corpus(bad_path.to_str().unwrap()).unwrap(),
+        Utf8Policy::Lossy,
+    );
+    let decoded: Vec<String> = bad_lines.by_ref().map(|l| l.unwrap()).collect();
+    assert_eq!(decoded.len(), 3);
+    assert_eq!(decoded[1], "waa \u{FFFD}\u{FFFD} wab wac");
+    assert_eq!(bad_lines.malformed(), 1);
+
+    let bad_dir = dir.join(format!("{}_bad_utf8_shards", std::process::id()));
+    std::fs::create_dir_all(&bad_dir).unwrap();
+    let bad = bad_path.to_str().unwrap();
+    let stats = shard_bigrams(bad, &vocab, &ShardOptions::default(), &bad_dir, 4).unwrap();
+    println!("Stats: {:?}", stats);
+    assert_eq!(stats.malformed_lines, 1);
+    // The bad token breaks the chain; the rest of its line still counts
+    assert_eq!(stats.bigrams, 3);
+
+    let strict = ShardOptions {
+        utf8: Utf8Policy::Strict,
+        ..Default::default()
+    };
+    let err = shard_bigrams(bad, &vocab, &strict, &bad_dir, 4).unwrap_err();
+    println!("Strict: {}", err);
+    assert!(err.to_string().contains("line 2"));
+    std::fs::remove_dir_all(&bad_dir).unwrap();
+    std::fs::remove_file(&bad_path).unwrap();
+
+    // 13. Rich tokens keep digits and hyphens and stand in for numbers and links
+    println!("\nNormalizing modern tokens...");
+    let rich = RichTokens::default();
+    let norm = |n: &dyn Normalizer, w: &str| n.normalize(w, false);
+    assert_eq!(
+        norm(&LettersOnly, "covid-19"),
+        "covid",
+        "The old rule splits it"
+    );
+    assert_eq!(norm(&LettersOnly, "3rd"), "rd");
+    assert_eq!(norm(&rich, "covid-19"), "covid-19");
+    assert_eq!(norm(&rich, "(COVID-19),"), "covid-19");
+    assert_eq!(norm(&rich, "3rd"), "3rd");
+    assert_eq!(norm(&rich, "http://x.com"), "<url>");
+    assert_eq!(rich.normalize("http://x.com", true), "<URL>");
+    assert_eq!(norm(&rich, "bob@example.com."), "<url>");
+    assert_eq!(norm(&rich, "42"), "<num>");
+    assert_eq!(norm(&rich, "3.14,"), "<num>");
+    assert_eq!(norm(&rich, "#rust"), "rust", "Hashtags count as the word");
+    assert_eq!(norm(&rich, "--"), "", "Bare punctuation breaks the chain");
+    assert!(is_placeholder("<NUM>") && is_placeholder("<url>"));
+    assert!(!is_placeholder("num"));
+
+    let no_digits = RichTokens {
+        digits: false,
+        ..rich
+    };
+    assert_eq!(norm(&no_digits, "covid-19"), "covid");
+    assert_eq!(
+        norm(&no_digits, "42"),
+        "<num>",
+        "Placeholders don't need digits kept"
+    );
+    let no_hyphens = RichTokens {
+        hyphens: false,
+        ..rich
+    };
+    assert_eq!(norm(&no_hyphens, "covid-19"), "covid19");
+    let no_placeholders = RichTokens {
+        placeholders: false,
+        ..rich
+    };
+    assert_eq!(norm(&no_placeholders, "42"), "42");
+    assert_ne!(rich.name(), no_digits.name());
+
+    // The chain runs through "covid-19" and <NUM> when the vocab has them
+    let mut modern = HashMap::new();
+    for (id, word) in ["cases", "of", "covid-19", "rose", "page", "<num>"]
+        .iter()
+        .enumerate()
+    {
+        modern.insert(word.to_string(), id as u32);
+    }
+    let text = "cases of covid-19 rose\nsee page 12 of the 3rd report";
+    let next_ids = |counter: BigramCounter| -> Vec<Vec<u32>> {
+        let mut counter = counter;
+        for line in text.lines() {
+            counter.feed_line(line, &modern, 1);
+        }
+        sorted_rows(counter.into_edges(modern.len()))
+            .into_iter()
+            .map(|row| row.into_iter().map(|(id, _)| id).collect())
+            .collect()
+    };
+    let rich_rows = next_ids(BigramCounter::new(10).normalizer(rich));
+    assert_eq!(rich_rows[1], [2], "of -> covid-19");
+    assert_eq!(rich_rows[2], [3], "covid-19 -> rose");
+    assert_eq!(rich_rows[4], [5], "page -> <NUM>");
+    assert_eq!(rich_rows[5], [1], "<NUM> -> of");
+    let plain_rows = next_ids(BigramCounter::new(10));
+    assert!(plain_rows[1].is_empty() && plain_rows[4].is_empty());
+
+    // 14. Progress reports rates and an ETA from bytes read
+    println!("\nComputing throughput...");
+    let snap = Snapshot::new(
+        Duration::from_secs(10),
+        1_000_000,
+        5_000_000,
+        Some((250, 1000)),
+    );
+    println!("{}", snap.describe("bigrams"));
+    assert!((snap.lines_per_sec - 100_000.0).abs() < 1e-6);
+    assert!((snap.items_per_sec - 500_000.0).abs() < 1e-6);
+    assert_eq!(snap.fraction, Some(0.25));
+    assert_eq!(
+        snap.eta,
+        Some(Duration::from_secs(30)),
+        "3x the time so far"
+    );
+    assert!(snap.describe("bigrams").contains("ETA 30s"));
+
+    let unknown = Snapshot::new(Duration::from_secs(4), 100, 0, None);
+    assert_eq!((unknown.fraction, unknown.eta), (None, None));
+    assert!(!unknown.describe("bigrams").contains("ETA"));
+    let instant = Snapshot::new(Duration::ZERO, 100, 50, Some((0, 1000)));
+    assert_eq!((instant.lines_per_sec, instant.eta), (0.0, None));
+    assert_eq!(format_duration(Duration::from_secs(3725)), "1h 02m");
+    assert_eq!(format_duration(Duration::from_secs(200)), "3m 20s");
+
+    // Bytes are counted on the compressed file, across inputs
+    let (reader, bytes_read) = open_corpus_counted(zst.to_str().unwrap()).unwrap();
+    assert_eq!(reader.lines().count(), lines.len());
+    let zst_size = std::fs::metadata(&zst).unwrap().len();
+    assert_eq!(bytes_read.get(), zst_size);
+    let mut progress = Progress::new("bigrams").with_total_bytes(Some(zst_size * 2));
+    progress.track(bytes_read.clone());
+    progress.track(bytes_read);
+    assert_eq!(progress.snapshot(0, 0).fraction, Some(1.0));
+
+    // 15. Edge flags: cross-sentence pairs are flagged and read back
+    println!("\nFlagging cross-sentence edges...");
+    assert!(ends_sentence("you.") && ends_sentence("you?\"") && ends_sentence("(end!)"));
+    assert!(!ends_sentence("you") && !ends_sentence("U.S"));
+    let mut counter = BigramCounter::new(10).mark_cross_sentence(true);
+    for _ in 0..5 {
+        counter.feed_line("I love you. I love pizza", &canonical, 1);
+    }
+    counter.feed_line("you I love", &canonical, 1);
+    let (index, counts, flags) = counter.into_flagged(vocab_size);
+    let weights = quantize_rows(&index, &counts);
+    let mut flagged: Vec<FlaggedEntry> = weights
+        .iter()
+        .zip(&flags)
+        .map(|(&(next_id, weight), &flags)| (next_id, weight, flags))
+        .collect();
+    // A merge tool would mark edges from user history like this
+    let love_pizza = flagged.iter().position(|e| e.0 == 3).unwrap();
+    flagged[love_pizza].2 |= EDGE_FLAG_USER;
+
+    let path = dir.join(format!("{}_flagged.bin", std::process::id()));
+    let file = BufWriter::new(File::create(&path).unwrap());
+    write_bigram(
+        file,
+        vocab_size as u32,
+        0,
+        10,
+        &index,
+        EdgeValues::Flagged(&flagged),
+    )
+    .unwrap();
+    assert!(validate_bigram(&std::fs::read(&path).unwrap()).all_pass());
+    let model = BigramModel::open(path.to_str().unwrap()).unwrap();
+    let flags_of = |prev: u32, next: u32| {
+        model
+            .next_flagged(prev, 10)
+            .into_iter()
+            .find(|(edge, _)| edge.next_id == next)
+            .map(|(_, flags)| flags)
+            .unwrap()
+    };
+    // you -> i crossed a sentence end 5 times out of 6
+    assert_eq!(flags_of(2, 0), EDGE_FLAG_CROSS_SENTENCE);
+    assert_eq!(flags_of(0, 1), 0);
+    assert_eq!(flags_of(1, 2), 0);
+    assert_eq!(flags_of(1, 3), EDGE_FLAG_USER);
+    let unflagged: Vec<Edge> = model
+        .next_flagged(1, 10)
+        .into_iter()
+        .map(|(edge, _)| edge)
+        .collect();
+    assert_eq!(unflagged, model.next(1, 10), "flags don't change weights");
+    let from_iter: Vec<u16> = model
+        .iter()
+        .flat_map(|(_, row)| row.iter().map(|e| e.flags()))
+        .collect();
+    assert_eq!(from_iter, flagged.iter().map(|e| e.2).collect::<Vec<_>>());
+
+    // Files written without flags read as unflagged
+    let path = dir.join(format!("{}_unflagged.bin", std::process::id()));
+    let file = BufWriter::new(File::create(&path).unwrap());
+    write_bigram(
+        file,
+        vocab_size as u32,
+        0,
+        10,
+        &index,
+        EdgeValues::Weights(&weights),
+    )
+    .unwrap();
+    let model = BigramModel::open(path.to_str().unwrap()).unwrap();
+    assert!((0..vocab_size as u32).all(|prev| model
+        .next_flagged(prev, 10)
+        .iter()
+        .all(|&(_, flags)| flags == 0)));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(dir.join(format!("{}_flagged.bin", std::process::id())));
+
+    // 16. Lowercasing under the root and Turkish locales
+    println!("\nLowercasing under locales...");
+    assert_eq!(Locale::Root.lowercase("I"), "i");
+    assert_eq!(
+        Locale::Root.lowercase("İ"),
+        "i\u{307}",
+        "Dot kept as a mark"
+    );
+    assert_eq!(Locale::Root.lowercase("ß"), "ß");
+    assert_eq!(Locale::Root.lowercase("ẞ"), "ß", "Not expanded to ss");
+    assert_eq!(Locale::Turkish.lowercase("I"), "ı");
+    assert_eq!(Locale::Turkish.lowercase("İ"), "i");
+    assert_eq!(Locale::Turkish.lowercase("ß"), "ß");
+    assert_eq!(Locale::Turkish.lowercase("DIŞ İzmir"), "dış izmir");
+
+    let turkish = Localized::new(LettersOnly, Locale::Turkish);
+    assert_eq!(LettersOnly.normalize("DIŞ", false), "diş");
+    assert_eq!(turkish.normalize("DIŞ", false), "dış");
+    assert_eq!(
+        LettersOnly.normalize("İzmir,", false),
+        "izmir",
+        "Mark dropped"
+    );
+    assert_eq!(turkish.normalize("İzmir,", false), "izmir");
+    assert_eq!(turkish.normalize("DIŞ", true), "DIŞ", "Case kept as is");
+    assert_eq!(turkish.normalize("Straße", false), "straße");
+    assert_eq!(turkish.name(), "letters+tr");
+    assert_eq!(
+        Localized::new(LettersOnly, Locale::Root).name(),
+        LettersOnly.name(),
+        "Root is the plain normalizer"
+    );
+    let rich = Localized::new(RichTokens::default(), Locale::Turkish);
+    assert_eq!(rich.normalize("(İSTANBUL'DA)", false), "istanbul'da");
+
+    // 17. A dry run prints the stats of the model it would build, and no file
+    println!("\nDry-running build_bigram_stream...");
+    let run_dir = dir.join(format!("{}_dry_run", std::process::id()));
+    std::fs::create_dir_all(&run_dir).unwrap();
+    let words: BTreeMap<String, u8> = ["i", "love", "you", "pizza"]
+        .into_iter()
+        .map(|w| (w.to_string(), 100))
+        .collect();
+    let fst = File::create(run_dir.join("en.lex.fst")).unwrap();
+    let vocab_file = File::create(run_dir.join("en.vocab.txt")).unwrap();
+    write_lexicon(
+        &words,
+        BufWriter::new(fst),
+        Some(BufWriter::new(vocab_file)),
+    )
+    .unwrap();
+    std::fs::write(
+        run_dir.join("corpus.txt"),
+        "I love you\nI love pizza\nyou love pizza\n",
+    )
+    .unwrap();
+    let output = run_bin_ok(
+        &run_dir,
+        "build_bigram_stream",
+        &["corpus.txt", "--dry-run", "--limit", "1"],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    println!(
+        "{}",
+        stdout
+            .lines()
+            .filter(|l| l.contains("edges"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    assert!(
+        stdout.contains("Total edges: 4"),
+        "i-love, love-you, love-pizza, you-love"
+    );
+    assert!(stdout.contains("Vocab entries with bigrams: 3/4"));
+    assert!(
+        !run_dir.join("en.bigram.bin").exists(),
+        "Dry run writes nothing"
+    );
+    let stats = BigramStats::from_index(&[(0, 2, 0), (16, 0, 0), (16, 1, 0)], 10);
+    assert_eq!((stats.edges_count, stats.non_empty_prevs), (3, 2));
+    assert_eq!(stats.file_size(), 40 + 3 * 8 + 3 * 8);
+    std::fs::remove_dir_all(&run_dir).unwrap();
+
+    // 18. Pretokenized corpora are looked up as split, not re-normalized
+    println!("\nCounting a pretokenized corpus...");
+    let mut segmented = HashMap::new();
+    for (id, word) in ["covid", "covid-19", "cases", "rd", "new_york", "newyork"]
+        .iter()
+        .enumerate()
+    {
+        segmented.insert(word.to_string(), id as u32);
+    }
+    // Normalizing merges "covid-19" into "covid", "3rd" into "rd" and
+    // "new_york" into "newyork"; it also rescues "cases," and "Cases"
+    let text = "covid-19 cases\ncovid cases\n3rd cases\nnew_york cases,\nnew_york Cases";
+    let count = |counter: BigramCounter| {
+        let mut counter = counter;
+        for line in text.lines() {
+            counter.feed_line(line, &segmented, 1);
+        }
+        // (prev, next, count) triples
+        let (index, counts) = counter.into_counts(segmented.len());
+        let mut triples = Vec::new();
+        for (prev, &(offset, len, _)) in index.iter().enumerate() {
+            let start = offset as usize / 8;
+            for &(next, count) in &counts[start..start + len as usize] {
+                triples.push((prev as u32, next, count));
+            }
+        }
+        triples.sort();
+        triples
+    };
+    let normalized = count(BigramCounter::new(10));
+    let pretokenized = count(BigramCounter::new(10).normalizer(Pretokenized));
+    println!("Normalized: {:?}", normalized);
+    println!("Pretokenized: {:?}", pretokenized);
+    assert_eq!(normalized, [(0, 2, 2), (3, 2, 1), (5, 2, 2)]);
+    assert_eq!(
+        pretokenized,
+        [(0, 2, 1), (1, 2, 1)],
+        "covid-19 keeps its own row; punctuated and capitalized tokens are OOV"
+    );
+    assert_eq!(Pretokenized.normalize("Cases,", false), "Cases,");
+    assert_eq!(Pretokenized.name(), "pretokenized");
+    assert_eq!(from_flags(false, true).unwrap().name(), "pretokenized");
+    assert_eq!(
+        from_flags(true, false).unwrap().name(),
+        RichTokens::default().name()
+    );
+    assert_eq!(from_flags(false, false).unwrap().name(), LettersOnly.name());
+    assert!(from_flags(true, true).is_err(), "Exclusive flags");
+
+    // 19. A phrase-unit Vietnamese bigram is keyed on phrase vocab ids
+    println!("\nBuilding a phrase-unit Vietnamese bigram...");
+    let run_dir = dir.join(format!("{}_vi_phrase", std::process::id()));
+    std::fs::create_dir_all(&run_dir).unwrap();
+    let phrases: BTreeMap<String, u8> = ["xin chào", "các bạn", "xin", "chào", "bạn", "tôi"]
+        .into_iter()
+        .map(|w| (w.to_string(), 128))
+        .collect();
+    let fst = File::create(run_dir.join("vi.phrase.fst")).unwrap();
+    let vocab_file = File::create(run_dir.join("vi.phrase.vocab.txt")).unwrap();
+    write_lexicon(
+        &phrases,
+        BufWriter::new(fst),
+        Some(BufWriter::new(vocab_file)),
+    )
+    .unwrap();
+    std::fs::write(
+        run_dir.join("corpus.txt"),
+        "Xin chào các bạn\nxin chào các bạn\ntôi chào bạn\ntôi xin\n",
+    )
+    .unwrap();
+    run_bin_ok(
+        &run_dir,
+        "build_vi_bigram",
+        &["corpus.txt", "--unit", "phrase"],
+    );
+    let model = BigramModel::open(run_dir.join("vi.bigram.bin").to_str().unwrap()).unwrap();
+    assert_eq!(model.unit(), VocabUnit::Phrase);
+    assert_eq!(model.unit().vi_paths().1, "vi.phrase.vocab.txt");
+    let vocab = Vocab::load(run_dir.join("vi.phrase.vocab.txt").to_str().unwrap()).unwrap();
+    assert_eq!(model.vocab_size(), vocab.len());
+    let id = |w: &str| vocab.iter().position(|v| v == w).unwrap() as u32;
+    let next = |w: &str| -> Vec<&str> {
+        model
+            .next(id(w), 10)
+            .into_iter()
+            .map(|edge| vocab.word(edge.next_id).unwrap())
+            .collect()
+    };
+    println!("After 'xin chào': {:?}", next("xin chào"));
+    assert_eq!(
+        next("xin chào"),
+        ["các bạn"],
+        "Longest match, not 'xin' 'chào'"
+    );
+    assert!(next("xin").is_empty());
+    assert_eq!(next("tôi"), ["chào", "xin"]);
+    assert_eq!(next("chào"), ["bạn"]);
+    let bad = run_bin(
+        &run_dir,
+        "build_vi_bigram",
+        &["corpus.txt", "--unit", "word"],
+    );
+    assert!(!bad.status.success(), "Unknown unit");
+
+    let mut out = Vec::new();
+    write_bigram(&mut out, 1, 0, 10, &[(0, 0, 0)], EdgeValues::Weights(&[])).unwrap();
+    let model = BigramModel::from_bytes(out).unwrap();
+    assert_eq!(model.unit(), VocabUnit::Syllable, "Default unit");
+    std::fs::remove_dir_all(&run_dir).unwrap();
+
+    // 20. Reduce output is byte-identical to a naive recount, split or not
+    println!("\nReducing shards against a naive recount...");
+    let (vocab, lines) = skewed_fixture(50, 500);
+    let mut tally: HashMap<(u32, u32), u64> = HashMap::new();
+    for line in &lines {
+        let ids: Vec<u32> = line
+            .split_whitespace()
+            .map(|w| vocab[&LettersOnly.normalize(w, false)])
+            .collect();
+        for pair in ids.windows(2) {
+            *tally.entry((pair[0], pair[1])).or_insert(0) += 1;
+        }
+    }
+    let mut index = Vec::new();
+    let mut counts = Vec::new();
+    for prev in 0..vocab.len() as u32 {
+        let mut row: Vec<(u32, u64)> = tally
+            .iter()
+            .filter(|&(&(p, _), _)| p == prev)
+            .map(|(&(_, next), &count)| (next, count))
+            .collect();
+        row.sort_by_key(|&(next, count)| (std::cmp::Reverse(count), next));
+        row.truncate(3);
+        let log_max = row.first().map_or(0, |&(_, c)| encode_log_max(c));
+        index.push(((counts.len() * 8) as u32, row.len() as u16, log_max));
+        counts.extend(row);
+    }
+    let to_bytes = |(index, counts): (Vec<IndexEntry>, Vec<(u32, u64)>)| {
+        let mut out = Vec::new();
+        write_bigram(
+            &mut out,
+            vocab.len() as u32,
+            0,
+            3,
+            &index,
+            EdgeValues::Weights(&quantize_rows(&index, &counts)),
+        )
+        .unwrap();
+        out
+    };
+    let expected = to_bytes((index, counts));
+    let reduce = |max_ram| {
+        to_bytes(reduce_shard_counts(&clean_dir, 4, vocab.len() as u32, 3, max_ram).unwrap())
+    };
+    assert!(reduce(DEFAULT_MAX_RAM) == expected, "Unsplit reduce");
+    assert!(reduce(shard_len / 4) == expected, "Split reduce");
+
+    // 21. Curly and straight apostrophes are one token in every normalizer
+    println!("\nFolding apostrophe variants...");
+    let normalizers: Vec<Box<dyn Normalizer>> = vec![
+        from_flags(false, false).unwrap(),
+        from_flags(true, false).unwrap(),
+        from_flags(false, true).unwrap(),
+        Box::new(Localized::new(LettersOnly, Locale::Turkish)),
+    ];
+    for normalizer in &normalizers {
+        for keep_case in [false, true] {
+            let straight = normalizer.normalize("don't", keep_case);
+            assert_eq!(straight, "don't", "{}", normalizer.name());
+            for variant in ["don’t", "don‘t"] {
+                assert_eq!(
+                    normalizer.normalize(variant, keep_case),
+                    straight,
+                    "{} on {}",
+                    normalizer.name(),
+                    variant
+                );
+            }
+        }
+    }
+    assert_eq!(LettersOnly.normalize("Can’t", false), "can't");
+    let mut ids = HashMap::new();
+    ids.insert("i".to_string(), 0);
+    ids.insert("don't".to_string(), 1);
+    ids.insert("know".to_string(), 2);
+    let count = |line: &str| {
+        let mut counter = BigramCounter::new(10);
+        counter.feed_line(line, &ids, 1);
+        counter.into_edges(3)
+    };
+    let straight = count("I don't know");
+    assert_eq!(straight.0[0].1, 1, "i -> don't");
+    assert_eq!(count("I don’t know"), straight);
+    assert_eq!(count("I DON‘T know"), straight);
+
+    // 22. Per-script normalizers keep Cyrillic, Arabic and decomposed Vietnamese
+    println!("\nNormalizing non-Latin tokens...");
+    let latin = ScriptLetters::default();
+    assert_eq!(latin.normalize("Москва,", false), "москва");
+    assert_eq!(LettersOnly.normalize("Москва,", false), "москва");
+    assert_eq!(
+        latin.normalize("Don’t", false),
+        LettersOnly.normalize("Don’t", false)
+    );
+
+    let arabic = ScriptLetters::new(Script::Arabic);
+    assert_eq!(arabic.normalize("الكِتَابُ", false), "الكتاب", "Harakat");
+    assert_eq!(arabic.normalize("كتـــاب.", false), "كتاب", "Tatweel");
+    assert_ne!(LettersOnly.normalize("الكِتَابُ", false), "الكتاب");
+
+    let decomposed = "Tie\u{302}\u{301}ng";
+    let vietnamese = ScriptLetters::new(Script::Vietnamese);
+    assert_eq!(vietnamese.normalize(decomposed, false), "tiếng");
+    assert_eq!(vietnamese.normalize(decomposed, true), "Tiếng");
+    assert_eq!(vietnamese.normalize("tiếng", false), "tiếng");
+    assert_eq!(
+        LettersOnly.normalize(decomposed, false),
+        "tieng",
+        "Loose tone marks are lost without composing"
+    );
+
+    let with_digits = ScriptLetters {
+        digits: true,
+        ..ScriptLetters::new(Script::Arabic)
+    };
+    assert_eq!(with_digits.normalize("٢٠٢٤", false), "٢٠٢٤");
+    assert_eq!(arabic.normalize("٢٠٢٤", false), "");
+    assert_eq!(Script::from_name("VI"), Some(Script::Vietnamese));
+    assert_eq!(Script::from_name("ru"), Some(Script::Latin));
+    assert_eq!(Script::from_name("klingon"), None);
+    assert_ne!(arabic.name(), with_digits.name());
+
+    let mut ids = HashMap::new();
+    ids.insert("ذهبت".to_string(), 0);
+    ids.insert("إلى".to_string(), 1);
+    ids.insert("المدرسة".to_string(), 2);
+    let mut counter = BigramCounter::new(10).normalizer(arabic);
+    counter.feed_line("ذَهَبْتُ إلى المدرسةِ", &ids, 1);
+    let (index, edges) = counter.into_edges(3);
+    let first = |prev: usize| edges[index[prev].0 as usize / 8].0;
+    assert_eq!((index[0].1, first(0)), (1, 1), "ذهبت -> إلى");
+    assert_eq!((index[1].1, first(1)), (1, 2), "إلى -> المدرسة");
+
+    // 23. --no-topn keeps the edges a top-N build prunes
+    println!("\nBuilding a full bigram matrix with --no-topn...");
+    let run_dir = dir.join(format!("{}_no_topn", std::process::id()));
+    std::fs::create_dir_all(&run_dir).unwrap();
+    let words: BTreeMap<String, u8> = ["the", "cat", "dog", "fox", "owl"]
+        .into_iter()
+        .map(|w| (w.to_string(), 128))
+        .collect();
+    write_lexicon(
+        &words,
+        BufWriter::new(File::create(run_dir.join("en.lex.fst")).unwrap()),
+        Some(BufWriter::new(
+            File::create(run_dir.join("en.vocab.txt")).unwrap(),
+        )),
+    )
+    .unwrap();
+    std::fs::write(
+        run_dir.join("corpus.txt"),
+        "the cat\nthe cat\nthe cat\nthe dog\nthe dog\nthe fox\nthe owl\n",
+    )
+    .unwrap();
+    let build_row = |flags: &[&str]| {
+        run_bin_ok(
+            &run_dir,
+            "build_bigram_stream",
+            &[&["corpus.txt"], flags].concat(),
+        );
+        let model = BigramModel::open(run_dir.join("en.bigram.bin").to_str().unwrap()).unwrap();
+        let vocab = Vocab::load(run_dir.join("en.vocab.txt").to_str().unwrap()).unwrap();
+        let the = vocab.iter().position(|w| w == "the").unwrap() as u32;
+        let row: Vec<String> = model
+            .next(the, usize::MAX)
+            .into_iter()
+            .map(|edge| vocab.word(edge.next_id).unwrap().to_string())
+            .collect();
+        (model.top_n(), row)
+    };
+    let (top_n, pruned) = build_row(&["--top", "2"]);
+    assert_eq!(top_n, 2);
+    assert_eq!(pruned, ["cat", "dog"]);
+    let (top_n, full) = build_row(&["--top", "2", "--no-topn"]);
+    println!("Full row after 'the': {:?}", full);
+    assert_eq!(top_n, 65535);
+    assert_eq!(full, ["cat", "dog", "fox", "owl"], "fox and owl survive");
+    let both = run_bin(
+        &run_dir,
+        "build_bigram_stream",
+        &["corpus.txt", "--no-topn", "--sketch", "64:2"],
+    );
+    assert!(!both.status.success(), "--no-topn with --sketch");
+    std::fs::remove_dir_all(&run_dir).unwrap();
+
+    // 24. Streamed writes match the buffered writer byte for byte
+    println!("\nStreaming rows through BigramWriter...");
+    let (index, counts) =
+        reduce_shard_counts(&clean_dir, 4, vocab.len() as u32, 3, DEFAULT_MAX_RAM).unwrap();
+    for raw in [false, true] {
+        let mut buffered = Vec::new();
+        let weights = quantize_rows(&index, &counts);
+        let values = if raw {
+            EdgeValues::RawCounts(&counts)
+        } else {
+            EdgeValues::Weights(&weights)
+        };
+        write_bigram(&mut buffered, vocab.len() as u32, 7, 3, &index, values).unwrap();
+
+        let cursor = Cursor::new(Vec::new());
+        let mut writer =
+            BigramWriter::new(cursor, VocabUnit::Syllable, vocab.len() as u32, 7, 3, raw).unwrap();
+        for (prev, &(offset, len, _)) in index.iter().enumerate() {
+            let row = &counts[offset as usize / 8..][..len as usize];
+            writer.push_counts(prev as u32, row).unwrap();
+        }
+        assert_eq!(writer.edges_count(), counts.len());
+        let streamed = writer.finish().unwrap().into_inner();
+        assert!(streamed == buffered, "Streamed bytes, raw counts {}", raw);
+    }
+    let mut writer =
+        BigramWriter::new(Cursor::new(Vec::new()), VocabUnit::Syllable, 2, 0, 3, false).unwrap();
+    assert!(
+        writer.push_counts(2, &[(0, 1)]).is_err(),
+        "prev past the vocab"
+    );
+    assert!(
+        writer.push_counts(0, &[(2, 1)]).is_err(),
+        "next past the vocab"
+    );
+
+    // Reduced in shard order the bytes differ, but not the model
+    let buffered = reduce_shards(&clean_dir, 4, vocab.len() as u32, 3, DEFAULT_MAX_RAM).unwrap();
+    for max_ram in [DEFAULT_MAX_RAM, shard_len / 4] {
+        let cursor = Cursor::new(Vec::new());
+        let mut writer =
+            BigramWriter::new(cursor, VocabUnit::Syllable, vocab.len() as u32, 0, 3, false)
+                .unwrap();
+        reduce_shards_into(&clean_dir, 4, 3, max_ram, &mut writer).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        assert!(
+            validate_bigram(&bytes).all_pass(),
+            "Streamed file validates"
+        );
+        let model = BigramModel::from_bytes(bytes).unwrap();
+        assert_eq!(model.edges_count(), buffered.1.len());
+        for (prev, &(offset, len, _)) in buffered.0.iter().enumerate() {
+            let row = &buffered.1[offset as usize / 8..][..len as usize];
+            let weights: Vec<(u32, u16)> = model
+                .next(prev as u32, 10)
+                .into_iter()
+                .map(|edge| (edge.next_id, edge.weight.0))
+                .collect();
+            assert_eq!(weights, row, "Row of prev {}", prev);
+        }
+    }
+
+    // 25. --max-edges trims the weakest edges but keeps each row's top one
+    println!("\nCapping the total edge count...");
+    let index: Vec<IndexEntry> = vec![(0, 3, 0), (24, 0, 0), (24, 2, 0), (40, 1, 0)];
+    let counts = vec![(1, 100), (2, 50), (3, 2), (0, 10), (1, 9), (2, 1)];
+    let capped_rows = |max_edges| {
+        let (capped, kept) = cap_total_edges(&index, &counts, max_edges);
+        capped
+            .iter()
+            .map(|&(offset, len, _)| {
+                let start = offset as usize / 8;
+                kept[start..start + len as usize]
+                    .iter()
+                    .map(|&i| counts[i])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        capped_rows(10),
+        [
+            vec![(1, 100), (2, 50), (3, 2)],
+            vec![],
+            vec![(0, 10), (1, 9)],
+            vec![(2, 1)]
+        ]
+    );
+    assert_eq!(
+        capped_rows(4),
+        [vec![(1, 100)], vec![], vec![(0, 10), (1, 9)], vec![(2, 1)]],
+        "The weakest edges relative to their row go first"
+    );
+    assert_eq!(
+        capped_rows(2),
+        [vec![(1, 100)], vec![], vec![(0, 10)], vec![]],
+        "Top edges go only once every other edge has"
+    );
+    let (capped, _) = cap_total_edges(&index, &counts, 2);
+    assert_eq!(capped[3], (16, 0, 0), "An emptied row has no log_max");
+
+    let run_dir = dir.join(format!("{}_max_edges", std::process::id()));
+    std::fs::create_dir_all(&run_dir).unwrap();
+    let words: BTreeMap<String, u8> = ["a", "b", "c", "d"]
+        .into_iter()
+        .map(|w| (w.to_string(), 128))
+        .collect();
+    write_lexicon(
+        &words,
+        BufWriter::new(File::create(run_dir.join("en.lex.fst")).unwrap()),
+        Some(BufWriter::new(
+            File::create(run_dir.join("en.vocab.txt")).unwrap(),
+        )),
+    )
+    .unwrap();
+    std::fs::write(
+        run_dir.join("corpus.txt"),
+        "a b\na b\na b\na c\na d\nb c\nb c\nb d\nc d\nd a\nd b\n",
+    )
+    .unwrap();
+    let output = run_bin_ok(
+        &run_dir,
+        "build_bigram_stream",
+        &["corpus.txt", "--max-edges", "5"],
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Trimmed 3 edges"));
+    let path = run_dir.join("en.bigram.bin");
+    let bytes = std::fs::read(&path).unwrap();
+    assert!(validate_bigram(&bytes).all_pass());
+    let model = BigramModel::from_bytes(bytes).unwrap();
+    assert_eq!(model.edges_count(), 5, "8 observed pairs, 5 kept");
+    for prev in 0..4 {
+        assert!(
+            !model.next(prev, 1).is_empty(),
+            "Prev {} keeps its top edge",
+            prev
+        );
+    }
+    std::fs::remove_dir_all(&run_dir).unwrap();
+
+    // 26. A reduce that fails still removes the shard directory
+    println!("\nFailing a reduce under a shard guard...");
+    let guarded_dir = dir.join(format!("{}_guarded_shards", std::process::id()));
+    let guarded_corpus = dir.join(format!("{}_guarded.txt", std::process::id()));
+    std::fs::write(&guarded_corpus, "the cat sat\nthe dog sat\nsat the cat\n").unwrap();
+    let guarded_corpus = guarded_corpus.to_str().unwrap();
+    let guarded_vocab: HashMap<String, u32> = ["the", "cat", "dog", "sat"]
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (w.to_string(), i as u32))
+        .collect();
+    let shard_then_fail = |commit: bool| -> anyhow::Result<()> {
+        let mut guard = ShardDirGuard::new(&guarded_dir);
+        if commit {
+            guard.commit();
+        }
+        std::fs::create_dir_all(guard.path())?;
+        shard_bigrams(
+            guarded_corpus,
+            &guarded_vocab,
+            &ShardOptions::default(),
+            guard.path(),
+            4,
+        )?;
+        // Injected fault: a shard goes missing between the two phases
+        std::fs::remove_file(guard.path().join("shard_001.bin"))?;
+        reduce_shard_counts(guard.path(), 4, 4, 100, DEFAULT_MAX_RAM)?;
+        guard.remove()
+    };
+    let err = shard_then_fail(false).unwrap_err();
+    println!("Reduce failed: {:#}", err);
+    assert!(format!("{:#}", err).contains("Missing shard"));
+    assert!(!guarded_dir.exists(), "The error path removed the shards");
+
+    assert!(shard_then_fail(true).is_err());
+    assert!(
+        guarded_dir.join("shard_000.bin").exists(),
+        "A committed guard (--resume) keeps them"
+    );
+    ShardDirGuard::new(&guarded_dir).remove().unwrap();
+    assert!(!guarded_dir.exists());
+    ShardDirGuard::new(&guarded_dir)
+        .remove()
+        .expect("Nothing to remove is fine");
+    std::fs::remove_file(guarded_corpus).unwrap();
+
+    // 27. --cross-sentence-weight chains across line ends and OOVs, flagged
+    println!("\nWeakening edges across line ends...");
+    let feed = |counter: &mut BigramCounter| {
+        for _ in 0..3 {
+            counter.feed_line("i love", &canonical, 1);
+            counter.feed_line("you", &canonical, 1);
+            counter.break_chain();
+        }
+        counter.feed_line("love xyzzy you", &canonical, 1);
+        counter.break_chain();
+        counter.feed_line("pizza", &canonical, 1);
+        counter.feed_line("love pizza", &canonical, 1);
+    };
+    // (next_id, count, flags) after "love"
+    let love_row = |counter: BigramCounter| -> Vec<(u32, u64, u16)> {
+        let (index, counts, flags) = counter.into_flagged(vocab_size);
+        let (offset, len, _) = index[1];
+        let start = offset as usize / 8;
+        (start..start + len as usize)
+            .map(|i| (counts[i].0, counts[i].1, flags[i]))
+            .collect()
+    };
+
+    let mut counter = BigramCounter::new(10);
+    feed(&mut counter);
+    assert_eq!(
+        love_row(counter),
+        vec![(3, 1, 0)],
+        "Without the option the chain breaks"
+    );
+
+    let mut counter = BigramCounter::new(10).cross_boundary_weight(0.25);
+    feed(&mut counter);
+    let row = love_row(counter);
+    println!("After love: {:?}", row);
+    assert_eq!(
+        row,
+        vec![(2, 1, EDGE_FLAG_CROSS_SENTENCE), (3, 1, 0)],
+        "Four cross edges count as one inline edge, and are flagged"
+    );
+
+    // Counts stay in corpus units, and the weight isn't rounded to 1/n:
+    // ten cross edges at 0.3 are 3, not 10 / 3
+    let mut counter = BigramCounter::new(10).cross_boundary_weight(0.3);
+    for _ in 0..10 {
+        counter.feed_line("i love", &canonical, 1);
+        counter.feed_line("you", &canonical, 1);
+        counter.break_chain();
+    }
+    counter.feed_line("love pizza", &canonical, 2);
+    assert_eq!(
+        love_row(counter),
+        vec![(2, 3, EDGE_FLAG_CROSS_SENTENCE), (3, 2, 0)]
+    );
+    // A pending fraction is rounded when the counter is finalized
+    let mut counter = BigramCounter::new(10).cross_boundary_weight(0.8);
+    counter.feed_line("love", &canonical, 1);
+    counter.feed_line("you", &canonical, 1);
+    assert_eq!(love_row(counter), vec![(2, 1, EDGE_FLAG_CROSS_SENTENCE)]);
+    let mut counter = BigramCounter::new(10).cross_boundary_weight(0.3);
+    counter.feed_line("love", &canonical, 1);
+    counter.feed_line("you", &canonical, 1);
+    assert!(
+        love_row(counter).is_empty(),
+        "0.3 of one edge rounds to none"
+    );
+    let mut counter = BigramCounter::new(10).cross_boundary_weight(0.25);
+    counter.feed_line("i love", &canonical, 1);
+    counter.break_chain();
+    counter.feed_line("you", &canonical, 1);
+    assert!(love_row(counter).is_empty(), "break_chain drops the carry");
+
+    // 28. An incremental trigram update matches a full rebuild on base + delta
+    println!("\nUpdating a trigram cache from a delta corpus...");
+    let base = "i love you\ni love pizza\ni love you too\nyou love pizza\n";
+    let delta = "i love pizza\ni love pizza\nyou love you\n";
+    let trigram_dir = |name: &str| {
+        let run_dir = dir.join(format!("{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&run_dir).unwrap();
+        let words: BTreeMap<String, u8> = ["i", "love", "you", "pizza", "too"]
+            .into_iter()
+            .map(|w| (w.to_string(), 100))
+            .collect();
+        write_lexicon(
+            &words,
+            BufWriter::new(File::create(run_dir.join("en.lex.fst")).unwrap()),
+            Some(BufWriter::new(
+                File::create(run_dir.join("en.vocab.txt")).unwrap(),
+            )),
+        )
+        .unwrap();
+        run_dir
+    };
+
+    let incremental = trigram_dir("trigram_incremental");
+    std::fs::write(incremental.join("base.txt"), base).unwrap();
+    std::fs::write(incremental.join("delta.txt"), delta).unwrap();
+    run_bin_ok(
+        &incremental,
+        "build_trigram",
+        &["base.txt", "--keep-counts"],
+    );
+    run_bin_ok(&incremental, "build_trigram", &["--update", "delta.txt"]);
+    let full = trigram_dir("trigram_full");
+    std::fs::write(full.join("all.txt"), format!("{}{}", base, delta)).unwrap();
+    run_bin_ok(&full, "build_trigram", &["all.txt"]);
+    assert!(!full.join("en.trigram.counts.bin").exists(), "Opt-in");
+
+    let open_cache = |dir: &std::path::Path| {
+        TrigramCache::open(dir.join("en.trigram.cache.bin").to_str().unwrap()).unwrap()
+    };
+    let (updated, rebuilt) = (open_cache(&incremental), open_cache(&full));
+    let vocab = Vocab::load(full.join("en.vocab.txt").to_str().unwrap()).unwrap();
+    let id = |w: &str| vocab.iter().position(|v| v == w).unwrap() as u32;
+    // The pairs the delta continues
+    for (w1, w2) in [("i", "love"), ("you", "love")] {
+        let (w1, w2) = (id(w1), id(w2));
+        assert_eq!(updated.lookup(w1, w2, 10), rebuilt.lookup(w1, w2, 10));
+        assert_eq!(updated.log_max(w1, w2), rebuilt.log_max(w1, w2));
+    }
+    assert_eq!(
+        updated.lookup(id("i"), id("love"), 1).unwrap()[0].next_id,
+        id("pizza"),
+        "The delta flips i love -> pizza"
+    );
+    let counts =
+        TrigramCounts::load(incremental.join("en.trigram.counts.bin").to_str().unwrap()).unwrap();
+    assert_eq!(counts.count(id("i"), id("love"), id("pizza")), 3);
+    assert_eq!(counts.vocab_hash(), vocab.fingerprint());
+
+    // The counts file round-trips, and a foreign file is refused
+    let copy = incremental.join("copy.counts.bin");
+    counts.save(copy.to_str().unwrap()).unwrap();
+    assert_eq!(TrigramCounts::load(copy.to_str().unwrap()).unwrap(), counts);
+    assert!(TrigramCounts::load(incremental.join("en.vocab.txt").to_str().unwrap()).is_err());
+    std::fs::remove_dir_all(&incremental).unwrap();
+    std::fs::remove_dir_all(&full).unwrap();
+
+    // 29. --sample-rate keeps a fixed, deterministic share of the lines
+    println!("\nSampling corpus lines...");
+    let half = LineSampler::new(0.5).unwrap();
+    let kept: Vec<u64> = (0..100_000).filter(|&n| half.keeps(n)).collect();
+    println!("Rate 0.5 kept {} of 100000", kept.len());
+    assert!((49_000..51_000).contains(&kept.len()), "About half");
+    let again: Vec<u64> = (0..100_000)
+        .filter(|&n| LineSampler::new(0.5).unwrap().keeps(n))
+        .collect();
+    assert_eq!(kept, again, "Same lines every run");
+    let tenth = LineSampler::new(0.1).unwrap();
+    let kept_tenth = (0..100_000).filter(|&n| tenth.keeps(n)).count();
+    assert!((9_500..10_500).contains(&kept_tenth));
+    assert!(
+        (0..100_000).all(|n| !tenth.keeps(n) || half.keeps(n)),
+        "A smaller sample is a subset of a larger one"
+    );
+    assert!((0..1000).all(|n| LineSampler::new(1.0).unwrap().keeps(n)));
+    for bad in [0.0, -0.5, 1.5, f64::NAN] {
+        assert!(LineSampler::new(bad).is_err(), "{} is out of range", bad);
+    }
+
+    // The builder counts only the sampled lines
+    let sample_dir = trigram_dir("sample");
+    let corpus: String = (0..2000)
+        .map(|i| format!("i love {}\n", ["you", "pizza"][i % 2]))
+        .collect();
+    std::fs::write(sample_dir.join("corpus.txt"), corpus).unwrap();
+    let sampled_run = || {
+        let output = run_bin_ok(
+            &sample_dir,
+            "build_bigram_stream",
+            &["corpus.txt", "--sample-rate", "0.5", "--dry-run"],
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let stdout = sampled_run();
+    let sampled_line = stdout
+        .lines()
+        .find(|l| l.trim_start().starts_with("Sampled:"))
+        .unwrap()
+        .to_string();
+    println!("{}", sampled_line.trim());
+    let sampled: u64 = sampled_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((900..1100).contains(&sampled), "Roughly half of 2000");
+    assert_eq!(sampled, (0..2000).filter(|&n| half.keeps(n)).count() as u64);
+    assert!(stdout.contains(&format!("Total: 2000 lines, {} bigrams", sampled * 2)));
+    assert_eq!(sampled_run(), stdout, "Deterministic");
+    std::fs::remove_dir_all(&sample_dir).unwrap();
+
+    // 30. A huge line is skipped without ever being buffered whole
+    println!("\nSkipping an overlong corpus line...");
+    let huge_line = 64 << 20;
+    let reader = std::io::BufReader::new(
+        Cursor::new("before\n")
+            .chain(std::io::repeat(b'x').take(huge_line))
+            .chain(Cursor::new("\nafter\r\n")),
+    );
+    let mut lines = CorpusLines::new(reader, Utf8Policy::Strict).with_max_line_len(64 << 10);
+    LARGEST_ALLOC.store(0, Ordering::Relaxed);
+    let kept: Vec<String> = lines.by_ref().map(|l| l.unwrap()).collect();
+    let largest = LARGEST_ALLOC.load(Ordering::Relaxed);
+    println!("Largest allocation for a 64 MiB line: {} bytes", largest);
+    assert_eq!(kept, ["before", "after"]);
+    assert_eq!(lines.overlong(), 1);
+    assert!(largest < 1 << 20, "Never more than the cap, give or take");
+
+    // The cap is inclusive, and counts the "\r" of a "\r\n"
+    let lines = |cap: usize| -> Vec<String> {
+        CorpusLines::new(Cursor::new("12345\r\n123456\nab"), Utf8Policy::Lossy)
+            .with_max_line_len(cap)
+            .map(|l| l.unwrap())
+            .collect()
+    };
+    assert_eq!(lines(5), ["ab"]);
+    assert_eq!(lines(6), ["12345", "123456", "ab"]);
+    let default = CorpusLines::new(Cursor::new("short\n"), Utf8Policy::Lossy);
+    assert_eq!(default.overlong(), 0);
+    assert_eq!(DEFAULT_MAX_LINE_LEN, 1 << 20);
+
+    println!("\nPASSED all tests!");
+}
+
+/// Run this package's `bin` in `dir` with `args`, through cargo
+fn run_bin(dir: &Path, bin: &str, args: &[&str]) -> Output {
+    Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args(["run", "-q", "--bin", bin, "--manifest-path"])
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        .arg("--")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+/// `run_bin`, failing with the binary's stderr if it fails
+fn run_bin_ok(dir: &Path, bin: &str, args: &[&str]) -> Output {
+    let output = run_bin(dir, bin, args);
+    assert!(
+        output.status.success(),
+        "{} failed: {}",
+        bin,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Stream `path` through `open_corpus` and return each prev's edges, sorted
+fn count_corpus(path: &str, vocab: &HashMap<String, u32>) -> Vec<Vec<(u32, u16)>> {
+    let mut counter = BigramCounter::new(100);
+    for line in open_corpus(path).unwrap().lines() {
+        counter.feed_line(&line.unwrap(), vocab, 1);
+    }
+    sorted_rows(counter.into_edges(vocab.len()))
+}
+
+/// Per-prev edges with each row sorted, so tie order doesn't matter
+fn sorted_rows((index, edges): (Vec<IndexEntry>, Vec<EdgeEntry>)) -> Vec<Vec<EdgeEntry>> {
+    index
+        .iter()
+        .map(|&(offset, len, _)| {
+            let start = offset as usize / 8;
+            let mut row = edges[start..start + len as usize].to_vec();
+            row.sort();
+            row
+        })
+        .collect()
+}
+
+/// Deterministic corpus where each word's successors follow a skewed
+/// distribution, so per-prev top-N is well defined
+fn skewed_fixture(vocab_size: u32, num_lines: usize) -> (HashMap<String, u32>, Vec<String>) {
+    // Tokens must survive LettersOnly, so spell ids with letters
+    let name = |i: u32| {
+        format!(
+            "w{}{}",
+            (b'a' + (i / 26) as u8) as char,
+            (b'a' + (i % 26) as u8) as char
+        )
+    };
+    let vocab: HashMap<String, u32> = (0..vocab_size).map(|i| (name(i), i)).collect();
+    let mut state: u64 = 42;
+    let mut next_rand = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 33) as u32
+    };
+
+    let mut lines = Vec::with_capacity(num_lines);
+    for _ in 0..num_lines {
+        let mut word = next_rand() % vocab_size;
+        let mut line = name(word);
+        for _ in 0..8 {
+            // Successor offset is the min of two uniforms: small offsets dominate
+            let offset = (next_rand() % 60).min(next_rand() % 60);
+            word = (word * 7 + offset) % vocab_size;
+            line.push(' ');
+            line.push_str(&name(word));
+        }
+        lines.push(line);
+    }
+    (vocab, lines)
+}
+
+fn top_after_love(
+    web: &[&str],
+    chat: &[&str],
+    web_inc: u64,
+    chat_inc: u64,
+    vocab_size: usize,
+    canonical: &HashMap<String, u32>,
+) -> u32 {
+    let mut counter = BigramCounter::new(10);
+    for line in web {
+        counter.feed_line(line, canonical, web_inc);
+    }
+    for line in chat {
+        counter.feed_line(line, canonical, chat_inc);
+    }
+    let (index, edges) = counter.into_edges(vocab_size);
+    let (offset, len, _) = index[1];
+    assert!(len > 0);
+    edges[offset as usize / 8].0
+}
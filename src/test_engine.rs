@@ -0,0 +1,2174 @@
+use combined2fst::bigram::{write_bigram, EdgeValues};
+use combined2fst::builder::{quantize_weight, DEFAULT_LOG_MAX};
+use combined2fst::lexicon::{write_lexicon, write_lexicon_with_ids};
+use combined2fst::reverse::ReverseBigrams;
+use combined2fst::shortcuts::parse_shortcut;
+use combined2fst::tokenize::RichTokens;
+use combined2fst::user_history::{HistoryConfig, UserHistory};
+use combined2fst::vietnamese::{clean_syllable, fold_marks};
+use combined2fst::{
+    apply_gating, build_canonical_display_map, build_canonical_map, default_rank, dequantize,
+    dequantize_with, finalize_suggestions, finalize_suggestions_with, source_label, top_unigrams,
+    truncate_display, BigramModel, Blocklist, BloomFilter, BoostTable, Candidate, CanonicalMap,
+    Count, DequantTable, Edge, EngineError, EngineRegistry, ImeEngine, Interpolation, ModelFiles,
+    ResolvedEdge, Score, ShortcutTable, Source, SourceStats, SuggestMode, Suggestion,
+    TaggedSuggestion, TrigramCache, VietnameseEngine, Vocab, Weight,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// Serialize a BGRM file from per-prev edge lists: rows[prev] = [(next_id, weight)]
+fn build_model(rows: &[Vec<(u32, u16)>]) -> Vec<u8> {
+    let edges_count: usize = rows.iter().map(|r| r.len()).sum();
+    let mut out = Vec::new();
+    out.extend_from_slice(&0x4247524Du32.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&(rows.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(edges_count as u32).to_le_bytes());
+    out.extend_from_slice(&10u32.to_le_bytes());
+    out.extend_from_slice(&[0u8; 12]);
+
+    let mut offset = 0u32;
+    for row in rows {
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&(row.len() as u16).to_le_bytes());
+        out.extend_from_slice(&[0u8; 2]);
+        offset += (row.len() * 8) as u32;
+    }
+    for row in rows {
+        for (next_id, weight) in row {
+            out.extend_from_slice(&next_id.to_le_bytes());
+            out.extend_from_slice(&weight.to_le_bytes());
+            out.extend_from_slice(&[0u8; 2]);
+        }
+    }
+    out
+}
+
+fn write_temp(name: &str, data: &[u8]) -> String {
+    let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+    std::fs::write(&path, data).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+/// Write a lexicon of `words` (word, prob) to temp files `{name}.lex.fst`
+/// and `{name}.vocab.txt`; ids follow key order. Returns (fst, vocab)
+/// paths.
+fn lexicon_files<S: ToString>(
+    name: &str,
+    words: impl IntoIterator<Item = (S, u8)>,
+) -> (String, String) {
+    let words: BTreeMap<String, u8> = words.into_iter().map(|(w, p)| (w.to_string(), p)).collect();
+    let (mut fst_bytes, mut vocab_bytes) = (Vec::new(), Vec::new());
+    write_lexicon(&words, &mut fst_bytes, Some(&mut vocab_bytes)).unwrap();
+    (
+        write_temp(&format!("{}.lex.fst", name), &fst_bytes),
+        write_temp(&format!("{}.vocab.txt", name), &vocab_bytes),
+    )
+}
+
+/// `ImeEngine::load` over `lexicon_files(name, words)` and a bigram model
+/// of `rows`
+fn lexicon_engine<S: ToString>(
+    name: &str,
+    words: impl IntoIterator<Item = (S, u8)>,
+    rows: &[Vec<(u32, u16)>],
+) -> ImeEngine {
+    let (fst_path, vocab_path) = lexicon_files(name, words);
+    let bigram_path = write_temp(&format!("{}.bigram.bin", name), &build_model(rows));
+    ImeEngine::load(&fst_path, &vocab_path, &bigram_path, None).unwrap()
+}
+
+/// A TRGC cache holding a single (w1, w2) pair
+fn build_trigram(w1: u32, w2: u32, edges: &[(u32, u16)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&0x54524743u32.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&10u32.to_le_bytes());
+    out.extend_from_slice(&[0u8; 16]);
+
+    out.extend_from_slice(&w1.to_le_bytes());
+    out.extend_from_slice(&w2.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(edges.len() as u16).to_le_bytes());
+    out.extend_from_slice(&[0u8; 2]);
+    for (next_id, weight) in edges {
+        out.extend_from_slice(&next_id.to_le_bytes());
+        out.extend_from_slice(&weight.to_le_bytes());
+        out.extend_from_slice(&[0u8; 2]);
+    }
+    out
+}
+
+/// `(next_id, weight)` rows as the readers return them
+fn edges(row: &[(u32, u16)]) -> Vec<Edge> {
+    row.iter()
+        .map(|&(next_id, weight)| Edge {
+            next_id,
+            weight: Weight(weight),
+        })
+        .collect()
+}
+
+fn engine_from_rows(name: &str, vocab: &[&str], rows: &[Vec<(u32, u16)>]) -> ImeEngine {
+    engine_from_files(vocab, &write_temp(name, &build_model(rows)), None)
+}
+
+/// An engine over `vocab` (ids in order) and the models at these paths,
+/// for tests that need the files themselves too
+fn engine_from_files(vocab: &[&str], bigram_path: &str, trigram_path: Option<&str>) -> ImeEngine {
+    let vocab: Vocab = vocab.iter().copied().collect();
+    let canonical: HashMap<String, u32> = vocab
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (w.to_string(), i as u32))
+        .collect();
+    ImeEngine::from_parts(
+        vocab,
+        canonical,
+        BigramModel::open(bigram_path).unwrap(),
+        trigram_path.map(|path| TrigramCache::open(path).unwrap()),
+    )
+}
+
+fn main() {
+    println!("=== Test ImeEngine ===");
+
+    // 1. Edges referencing ids past the vocab are reported, not swallowed
+    println!("\nLoading bigram with out-of-vocab edges...");
+    let engine = engine_from_rows(
+        "oob.bigram.bin",
+        &["i", "love", "you"],
+        &[
+            vec![(1, 65535), (7, 30000)], // 7 is past the 3-word vocab
+            vec![(2, 65535), (99, 100)],  // 99 too
+            vec![],
+        ],
+    );
+    println!("Unresolved at load: {}", engine.unresolved_at_load());
+    assert_eq!(engine.unresolved_at_load(), 2);
+
+    let after_i = engine.lookup_bigram(engine.word_id("i").unwrap());
+    println!("After 'i': {:?}", after_i);
+    assert_eq!(
+        after_i,
+        [ResolvedEdge {
+            word: "love",
+            weight: Weight::MAX
+        }]
+    );
+    assert_eq!(engine.decode_errors(), 1);
+
+    engine.lookup_bigram(engine.word_id("love").unwrap());
+    assert_eq!(engine.decode_errors(), 2);
+
+    // 2. A clean model reports nothing
+    let engine = engine_from_rows(
+        "clean.bigram.bin",
+        &["i", "love", "you"],
+        &[vec![(1, 65535)], vec![(2, 65535)], vec![]],
+    );
+    assert_eq!(engine.unresolved_at_load(), 0);
+    engine.lookup_bigram(0);
+    assert_eq!(engine.decode_errors(), 0);
+
+    // 3. Gating scales function words instead of hoisting them
+    println!("\nApplying gating...");
+    let boost = BoostTable::english();
+    let mut suggestions = vec![
+        ("store".to_string(), Score(60000)),
+        ("park".to_string(), Score(20000)),
+        ("the".to_string(), Score(18000)),
+        ("a".to_string(), Score(5000)),
+    ];
+    apply_gating(&mut suggestions, &boost);
+    println!("Gated: {:?}", suggestions);
+    let order: Vec<&str> = suggestions.iter().map(|(w, _)| w.as_str()).collect();
+    // "the" (18000 * 1.25) overtakes the close "park"; weak "a" stays below strong "store"
+    assert_eq!(order, vec!["store", "the", "park", "a"]);
+    assert_eq!(suggestions[3].1, Score(6250));
+
+    let custom = BoostTable::new(&["a"], 20.0);
+    let mut suggestions = vec![
+        ("store".to_string(), Score(60000)),
+        ("a".to_string(), Score(5000)),
+    ];
+    apply_gating(&mut suggestions, &custom);
+    assert_eq!(suggestions[0].0, "a", "Custom factor should be honored");
+    assert_eq!(suggestions[0].1, Score::MAX, "Boosted score saturates");
+
+    // 4. A short trigram list is padded with bigram results
+    println!("\nPadding trigram results with bigram...");
+    let vocab = ["i", "want", "to", "go", "eat", "see", "be"];
+    let bigram_path = write_temp(
+        "pad.bigram.bin",
+        &build_model(&[
+            vec![(1, 65535)],
+            vec![(2, 65535)],
+            // "go" is also the trigram result and must not repeat
+            vec![(4, 60000), (3, 50000), (5, 40000), (6, 30000), (1, 20000)],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        ]),
+    );
+    let trigram_path = write_temp("pad.trigram.bin", &build_trigram(1, 2, &[(3, 65535)]));
+    let engine = engine_from_files(&vocab, &bigram_path, Some(&trigram_path));
+
+    let suggestions = engine.suggest_next("I want to", 5);
+    println!("Suggestions: {:?}", suggestions);
+    assert_eq!(suggestions.len(), 5, "Should pad up to the limit");
+    assert_eq!(suggestions[0].word, "go");
+    assert_eq!(suggestions[0].source, Source::Trigram);
+    assert!(suggestions[1..].iter().all(|s| s.source == Source::Bigram));
+    let words: Vec<&str> = suggestions.iter().map(|s| s.word.as_str()).collect();
+    assert_eq!(words, vec!["go", "eat", "see", "be", "want"]);
+    assert_eq!(
+        suggestions[1].score,
+        Score(24000),
+        "Padded weights are backed off"
+    );
+    assert_eq!(source_label(&suggestions), "Trigram+Bigram");
+
+    // Without a trigram hit the bigram list is used alone
+    let suggestions = engine.suggest_next("want", 5);
+    assert_eq!(suggestions.len(), 1);
+    assert_eq!(suggestions[0].source, Source::Bigram);
+
+    // 5. JSONL export round-trips through a JSON parser
+    println!("\nExporting bigram as JSONL...");
+    let path = write_temp(
+        "export.bigram.bin",
+        &build_model(&[
+            vec![(1, 65535), (2, 1000)],
+            vec![(2, 40000), (9, 50000)], // 9 is past the vocab
+            vec![],
+        ]),
+    );
+    let model = BigramModel::open(&path).unwrap();
+    let vocab: Vocab = ["i", "love", "you"].into_iter().collect();
+
+    let mut out = Vec::new();
+    let rows = model.export_jsonl(&vocab, Weight(0), &mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    println!("{}", text.trim_end());
+    assert_eq!(rows, 2);
+    let lines: Vec<serde_json::Value> = text
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["prev"], "i");
+    assert_eq!(lines[0]["edges"][0]["next"], "love");
+    assert_eq!(lines[0]["edges"][0]["weight"], 65535);
+    assert_eq!(lines[0]["edges"][1]["next"], "you");
+    assert_eq!(lines[1]["prev"], "love");
+    assert_eq!(
+        lines[1]["edges"].as_array().unwrap().len(),
+        1,
+        "Unresolvable edge should be skipped"
+    );
+
+    let mut out = Vec::new();
+    model.export_jsonl(&vocab, Weight(30000), &mut out).unwrap();
+    let first: serde_json::Value =
+        serde_json::from_str(String::from_utf8(out).unwrap().lines().next().unwrap()).unwrap();
+    assert_eq!(
+        first["edges"].as_array().unwrap().len(),
+        1,
+        "--min-weight should drop the weak edge"
+    );
+
+    // 6. Probabilities are relative to the top edge and fall off down the list
+    println!("\nChecking suggestion probabilities...");
+    let engine = engine_from_rows(
+        "prob.bigram.bin",
+        &["to", "go", "eat", "see"],
+        &[
+            vec![(1, 65535), (2, 50000), (3, 20000)],
+            vec![],
+            vec![],
+            vec![],
+        ],
+    );
+    let suggestions = engine.suggest_next("to", 10);
+    let probs: Vec<f32> = suggestions.iter().map(|s| s.probability()).collect();
+    println!("Probabilities after 'to': {:?}", probs);
+    assert!((probs[0] - 1.0).abs() < 1e-6, "Top edge should map to 1.0");
+    assert!(
+        probs.windows(2).all(|w| w[0] > w[1]),
+        "Tail should decrease"
+    );
+    assert!(probs.iter().all(|&p| p > 0.0));
+
+    // 7. A saved canonical map loads back equal to a freshly built one
+    println!("\nChecking canonical map round-trip...");
+    // FST values pack word_id in bits 16..48 and the probability in the low byte
+    let words = [
+        ("Apple", 0u64, 200u64),
+        ("Berlin", 1, 90),
+        ("apple", 2, 120),
+        ("cat", 3, 50),
+    ];
+    let mut fst_builder = fst::MapBuilder::memory();
+    for (word, id, prob) in words {
+        fst_builder.insert(word, (id << 16) | prob).unwrap();
+    }
+    let fst_path = write_temp("canon.fst", &fst_builder.into_inner().unwrap());
+    let vocab_path = write_temp("canon.vocab.txt", b"Apple\nBerlin\napple\ncat\n");
+
+    let built = CanonicalMap::build(&fst_path, &vocab_path).unwrap();
+    assert_eq!(built.vocab_size(), 4);
+    assert_eq!(built.get("apple"), Some(2), "Exact lowercase form wins");
+    assert_eq!(built.get("berlin"), Some(1));
+
+    let map_path = write_temp("canon.map", &[]);
+    built.save(&map_path).unwrap();
+    let loaded = CanonicalMap::load(&map_path).unwrap();
+    assert_eq!(loaded, built, "Loaded map should equal the built one");
+
+    std::fs::write(&map_path, b"not a map").unwrap();
+    assert!(CanonicalMap::load(&map_path).is_err());
+
+    // 8. Merged trigram/bigram/user lists keep each word once, at its best score
+    println!("\nChecking suggestion dedup...");
+    let mut merged = vec![
+        Suggestion::new("go".into(), Score(500), Source::Trigram, Some(1)),
+        Suggestion::new("see".into(), Score(300), Source::Trigram, Some(3)),
+        Suggestion::new("go".into(), Score(200), Source::Bigram, Some(1)),
+        Suggestion::new("eat".into(), Score(300), Source::Bigram, Some(2)),
+        Suggestion::new("goooal".into(), Score(300), Source::User, None),
+        Suggestion::new("see".into(), Score(900), Source::User, Some(3)),
+        Suggestion::new("brb".into(), Score(300), Source::User, None),
+    ];
+    finalize_suggestions(&mut merged);
+    let ranked: Vec<(&str, u16)> = merged
+        .iter()
+        .map(|s| (s.word.as_str(), s.score.0))
+        .collect();
+    println!("Finalized: {:?}", ranked);
+    assert_eq!(
+        ranked,
+        vec![
+            ("see", 900),
+            ("go", 500),
+            ("eat", 300),
+            ("brb", 300),
+            ("goooal", 300)
+        ],
+        "Ties break on id, then on the word for user-only entries"
+    );
+    assert_eq!(
+        merged[0].source,
+        Source::User,
+        "The higher-scoring copy wins"
+    );
+
+    // 9. Readers return at most `limit` edges, in stored order
+    println!("\nChecking query-time limits...");
+    let row: Vec<(u32, u16)> = (0..10).map(|i| (i + 1, 60000 - i as u16 * 1000)).collect();
+    let mut rows = vec![row.clone()];
+    rows.extend(std::iter::repeat_n(Vec::new(), 10));
+    let model = BigramModel::open(&write_temp("limit.bigram.bin", &build_model(&rows))).unwrap();
+    assert_eq!(model.next(0, 2), edges(&row[..2]));
+    assert_eq!(
+        model.next(0, 50),
+        edges(&row),
+        "Stored top_n is the ceiling"
+    );
+    assert!(model.next(0, 0).is_empty());
+
+    let cache =
+        TrigramCache::open(&write_temp("limit.trigram.bin", &build_trigram(0, 1, &row))).unwrap();
+    assert_eq!(cache.lookup(0, 1, 2), Some(edges(&row[..2])));
+    assert_eq!(cache.lookup(0, 1, 50), Some(edges(&row)));
+
+    // 10. Shortcut lines from a .combined dictionary expand on commit
+    println!("\nChecking shortcut expansion...");
+    assert_eq!(
+        parse_shortcut("  shortcut=on my way,f=14"),
+        Some(("on my way", 14))
+    );
+    assert_eq!(
+        parse_shortcut(" shortcut=I'm,f=whitelist"),
+        Some(("I'm", 255))
+    );
+    assert_eq!(parse_shortcut(" word=omw,f=0,not_a_word=true"), None);
+
+    let mut shortcuts = ShortcutTable::default();
+    let (target, freq) = parse_shortcut("  shortcut=on my way,f=14").unwrap();
+    shortcuts.insert("omw", target, freq);
+    shortcuts.insert("omw", "oh my word", 3);
+    let tsv_path = write_temp("shortcuts.tsv", &[]);
+    shortcuts.save(&tsv_path).unwrap();
+    let shortcuts = ShortcutTable::load(&tsv_path).unwrap();
+
+    let mut engine = engine_from_rows(
+        "shortcut.bigram.bin",
+        &["omw", "home", "now"],
+        &[vec![(1, 40000), (2, 30000)], vec![], vec![]],
+    );
+    engine.set_shortcuts(shortcuts);
+    assert_eq!(engine.expand_shortcut("omw"), Some("on my way"));
+    assert_eq!(engine.expand_shortcut("OMW"), Some("on my way"));
+    assert_eq!(engine.expand_shortcut("home"), None);
+
+    let suggestions = engine.suggest_next("i am omw", 2);
+    println!(
+        "After 'omw': {:?}",
+        suggestions.iter().map(|s| &s.word).collect::<Vec<_>>()
+    );
+    assert_eq!(suggestions.len(), 2);
+    assert_eq!(suggestions[0].word, "on my way");
+    assert_eq!(suggestions[0].source, Source::Shortcut);
+    assert_eq!(suggestions[1].word, "home");
+
+    // 11. Capitalization follows sentence position, and "I" is always upper
+    println!("\nChecking contextual capitalization...");
+    let mut engine = engine_from_rows(
+        "caps.bigram.bin",
+        &["home", "then", "i", "we"],
+        &[
+            vec![(1, 50000), (2, 40000), (3, 30000)],
+            vec![],
+            vec![],
+            vec![],
+        ],
+    );
+    let plain: Vec<String> = engine
+        .suggest_next("home.", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    assert_eq!(plain, ["then", "i", "we"], "Off by default");
+
+    engine.set_capitalization(true);
+    let start: Vec<String> = engine
+        .suggest_next("going home.", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    let mid: Vec<String> = engine
+        .suggest_next("going home", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    println!("Sentence start: {:?}, mid-sentence: {:?}", start, mid);
+    assert_eq!(start, ["Then", "I", "We"]);
+    assert_eq!(mid, ["then", "I", "we"]);
+
+    // Two entries that capitalize alike count once, and the list refills
+    let mut engine = engine_from_rows(
+        "caps_collapse.bigram.bin",
+        &["home", "then", "Then", "we", "us"],
+        &[
+            vec![(1, 50000), (2, 45000), (3, 30000), (4, 20000)],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        ],
+    );
+    engine.set_capitalization(true);
+    let start: Vec<String> = engine
+        .suggest_next("home.", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    assert_eq!(start, ["Then", "We", "Us"]);
+    assert_eq!(engine.suggest_next("home.", 10).len(), 3, "Only 3 distinct");
+
+    // 12. v1 headers imply 32/16-bit widths; v2 headers record them
+    println!("\nChecking header versions...");
+    let rows = vec![vec![(1, 65535)], vec![]];
+    let v1 = BigramModel::open(&write_temp("v1.bigram.bin", &build_model(&rows))).unwrap();
+    assert_eq!(v1.version(), 1);
+    assert_eq!((v1.word_id_bits(), v1.weight_bits()), (32, 16));
+
+    let mut v2_bytes = build_model(&rows);
+    v2_bytes[4..8].copy_from_slice(&2u32.to_le_bytes());
+    v2_bytes[20..24].copy_from_slice(&24u32.to_le_bytes());
+    v2_bytes[24..28].copy_from_slice(&16u32.to_le_bytes());
+    let v2 = BigramModel::open(&write_temp("v2.bigram.bin", &v2_bytes)).unwrap();
+    assert_eq!(v2.version(), 2);
+    assert_eq!((v2.word_id_bits(), v2.weight_bits()), (24, 16));
+    assert_eq!(v2.next(0, 10), edges(&[(1, 65535)]));
+
+    v2_bytes[20..24].copy_from_slice(&40u32.to_le_bytes());
+    let wide = write_temp("wide.bigram.bin", &v2_bytes);
+    assert!(
+        matches!(
+            BigramModel::open(&wide),
+            Err(EngineError::UnsupportedField {
+                field: "word_id_bits",
+                value: 40
+            })
+        ),
+        "40-bit ids are unsupported"
+    );
+    v2_bytes[4..8].copy_from_slice(&4u32.to_le_bytes());
+    let future = write_temp("v4.bigram.bin", &v2_bytes);
+    assert!(
+        matches!(
+            BigramModel::open(&future),
+            Err(EngineError::UnsupportedVersion(4))
+        ),
+        "Unknown versions are refused"
+    );
+
+    let mut tri_bytes = build_trigram(0, 1, &[(2, 65535)]);
+    tri_bytes[4..8].copy_from_slice(&2u32.to_le_bytes());
+    tri_bytes[16..20].copy_from_slice(&32u32.to_le_bytes());
+    tri_bytes[20..24].copy_from_slice(&12u32.to_le_bytes());
+    let cache = TrigramCache::open(&write_temp("v2.trigram.bin", &tri_bytes)).unwrap();
+    assert_eq!((cache.word_id_bits(), cache.weight_bits()), (32, 12));
+    tri_bytes[20..24].copy_from_slice(&17u32.to_le_bytes());
+    let wide = write_temp("wide.trigram.bin", &tri_bytes);
+    assert!(matches!(
+        TrigramCache::open(&wide),
+        Err(EngineError::UnsupportedField {
+            field: "weight_bits",
+            value: 17
+        })
+    ));
+
+    // 13. A prev with no bigram edges falls back to the unigram prior
+    println!("\nChecking unigram prior fallback...");
+    let words = [
+        ("The", 0u64, 250u64),
+        ("a", 1, 200),
+        ("quark", 2, 5),
+        ("the", 3, 240),
+        ("zebra", 4, 20),
+    ];
+    let mut fst_builder = fst::MapBuilder::memory();
+    for (word, id, prob) in words {
+        fst_builder.insert(word, (id << 16) | prob).unwrap();
+    }
+    let fst_path = write_temp("prior.fst", &fst_builder.into_inner().unwrap());
+    let prior = top_unigrams(&fst_path, 3).unwrap();
+    assert_eq!(
+        prior,
+        vec![(3, 240), (1, 200), (4, 20)],
+        "The exact lowercase variant stands for its case variants"
+    );
+
+    let mut engine = engine_from_rows(
+        "prior.bigram.bin",
+        &["The", "a", "quark", "the", "zebra"],
+        &[vec![], vec![], vec![(4, 30000)], vec![], vec![]],
+    );
+    assert!(
+        engine.suggest_next("quark", 3).len() == 1,
+        "No prior by default"
+    );
+    engine.set_unigram_prior(prior);
+
+    let unseen: Vec<(String, Source)> = engine
+        .suggest_next("the", 2)
+        .into_iter()
+        .map(|s| (s.word, s.source))
+        .collect();
+    assert_eq!(
+        unseen,
+        [
+            ("the".to_string(), Source::Unigram),
+            ("a".to_string(), Source::Unigram)
+        ]
+    );
+
+    let padded = engine.suggest_next("quark", 3);
+    println!(
+        "After 'quark': {:?}",
+        padded
+            .iter()
+            .map(|s| (&s.word, s.score))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(padded[0].word, "zebra", "Real edges come first");
+    assert_eq!(padded[0].source, Source::Bigram);
+    assert!(padded[1..]
+        .iter()
+        .all(|s| s.source == Source::Unigram && s.score < padded[0].score));
+    assert_eq!(padded.len(), 3);
+
+    // 14. Prefetch covers the index (or the whole file) and pages end up resident
+    println!("\nChecking prefetch...");
+    let rows: Vec<Vec<(u32, u16)>> = (0..2000)
+        .map(|i| vec![(i, 65535), (i + 1, 30000)])
+        .collect();
+    let model_bytes = build_model(&rows);
+    let model = BigramModel::open(&write_temp("prefetch.bigram.bin", &model_bytes)).unwrap();
+    assert_eq!(model.prefetch(false), 32 + 2000 * 8);
+    assert_eq!(model.prefetch(true), model_bytes.len());
+    let resident = model.resident_estimate();
+    println!(
+        "Resident after prefetch: {:?} of {} bytes",
+        resident,
+        model_bytes.len()
+    );
+    if cfg!(unix) {
+        assert_eq!(resident, Some(model_bytes.len()));
+    }
+
+    let cache = TrigramCache::open(&write_temp(
+        "prefetch.trigram.bin",
+        &build_trigram(0, 1, &[(2, 65535)]),
+    ))
+    .unwrap();
+    assert_eq!(cache.prefetch(false), 32 + 16);
+    let engine = ImeEngine::from_parts(
+        (0..=2000).map(|i| format!("w{}", i)).collect(),
+        HashMap::new(),
+        model,
+        Some(cache),
+    );
+    assert_eq!(engine.prefetch(true), model_bytes.len() + 32 + 16 + 8);
+    assert_eq!(engine.resident_estimate().is_some(), cfg!(unix));
+
+    // 15. Two-step phrases are ranked by combined weight and skip cycles
+    println!("\nChecking phrase suggestions...");
+    let engine = engine_from_rows(
+        "phrase.bigram.bin",
+        &["i", "love", "you", "want", "to", "it"],
+        &[
+            vec![(1, 65535), (3, 60000)],
+            vec![(0, 65000), (2, 65535), (5, 40000)],
+            vec![],
+            vec![(4, 65535)],
+            vec![],
+            vec![],
+        ],
+    );
+    let phrases = engine.suggest_phrase("so i", 2, 10);
+    println!("Phrases after 'i': {:?}", phrases);
+    let texts: Vec<&str> = phrases.iter().map(|(p, _)| p.as_str()).collect();
+    assert_eq!(
+        texts,
+        ["love you", "want to", "love it"],
+        "'love i' would revisit 'i'"
+    );
+    assert_eq!(
+        phrases[0].1,
+        Score::MAX,
+        "Top edge twice keeps the full score"
+    );
+    assert!(phrases.windows(2).all(|w| w[0].1 >= w[1].1));
+    assert_eq!(engine.suggest_phrase("so i", 2, 1).len(), 1);
+    assert!(
+        engine.suggest_phrase("you", 2, 10).is_empty(),
+        "Dead ends yield no phrase"
+    );
+
+    // 16. Run-on Vietnamese splits into FST syllables, fewest pieces first
+    println!("\nSegmenting run-on Vietnamese...");
+    let mut syllables = ["chà", "chào", "nam", "o", "việt", "xin"];
+    syllables.sort();
+    let mut builder = fst::MapBuilder::memory();
+    for (id, syllable) in syllables.iter().enumerate() {
+        builder.insert(syllable, ((id as u64) << 16) | 128).unwrap();
+    }
+    let syllable_path = write_temp("vi.syllable.fst", &builder.into_inner().unwrap());
+    let vi = VietnameseEngine::open(&syllable_path).unwrap();
+
+    let split = vi.segment("xinchào");
+    println!("xinchào -> {:?}", split);
+    assert_eq!(
+        split.syllables,
+        ["xin", "chào"],
+        "Not chà + o: fewer pieces wins"
+    );
+    assert!(split.complete);
+    assert_eq!(
+        vi.segment("XinChào việtnam").syllables,
+        ["Xin", "Chào", "việt", "nam"],
+        "Case is kept and spaces stay boundaries"
+    );
+
+    let split = vi.segment("qqq");
+    println!("qqq -> {:?}", split);
+    assert_eq!(split.syllables, ["qqq"]);
+    assert!(!split.complete);
+    let split = vi.segment("xinzzchào");
+    assert_eq!(split.syllables, ["xin", "zz", "chào"]);
+    assert!(!split.complete);
+    assert!(vi.segment("").complete);
+
+    // 17. Load failures come back as typed errors, one variant per cause
+    println!("\nChecking typed load errors...");
+    let words: BTreeMap<String, u8> = [("a", 100), ("b", 200), ("c", 50)]
+        .iter()
+        .map(|&(w, p)| (w.to_string(), p))
+        .collect();
+    let (fst_path, vocab_path) = lexicon_files("errors", words.clone());
+    let rows = vec![vec![(1, 65535)], vec![(2, 65535)], vec![]];
+    let model_bytes = build_model(&rows);
+    let bigram_path = write_temp("errors.bigram.bin", &model_bytes);
+    let load = |bigram: &str, trigram: Option<&str>| {
+        ImeEngine::load(&fst_path, &vocab_path, bigram, trigram)
+    };
+    assert!(load(&bigram_path, None).is_ok());
+
+    let err = load("/nonexistent/en.bigram.bin", None).err().unwrap();
+    println!("Missing file: {}", err);
+    assert!(matches!(err, EngineError::Io { ref path, .. } if path.ends_with("en.bigram.bin")));
+
+    let short_vocab = write_temp("errors_short.bigram.bin", &build_model(&rows[..2]));
+    assert!(matches!(
+        load(&short_vocab, None),
+        Err(EngineError::VocabMismatch { model: 2, vocab: 3 })
+    ));
+
+    let mut foreign = model_bytes.clone();
+    foreign[0..4].copy_from_slice(b"XXXX");
+    let foreign_path = write_temp("errors_foreign.bigram.bin", &foreign);
+    assert!(matches!(
+        load(&foreign_path, None),
+        Err(EngineError::BadMagic {
+            found: 0x58585858,
+            expected: 0x4247524D
+        })
+    ));
+    assert!(
+        matches!(
+            load(&bigram_path, Some(&bigram_path)),
+            Err(EngineError::BadMagic {
+                expected: 0x54524743,
+                ..
+            })
+        ),
+        "A bigram file isn't a trigram cache"
+    );
+
+    let header_only = write_temp("errors_header.bigram.bin", &model_bytes[..4]);
+    assert!(matches!(
+        load(&header_only, None),
+        Err(EngineError::Truncated { need: 32, have: 4 })
+    ));
+    let index_cut = write_temp("errors_index.bigram.bin", &model_bytes[..40]);
+    assert!(matches!(
+        load(&index_cut, None),
+        Err(EngineError::Truncated { need: 56, have: 40 })
+    ));
+
+    let bad_fst = write_temp("errors_bad.lex.fst", b"not an fst");
+    assert!(matches!(
+        ImeEngine::load(&bad_fst, &vocab_path, &bigram_path, None),
+        Err(EngineError::Fst { .. })
+    ));
+
+    // A v3 model records its vocab's fingerprint: the same words in
+    // another order (ids shifted) are refused, though the size matches
+    let vocab = Vocab::load(&vocab_path).unwrap();
+    let index = [(0, 1, 0), (8, 1, 0), (16, 0, 0)];
+    let mut v3_bytes = Vec::new();
+    write_bigram(
+        &mut v3_bytes,
+        3,
+        vocab.fingerprint(),
+        10,
+        &index,
+        EdgeValues::Weights(&[(1, 65535), (2, 65535)]),
+    )
+    .unwrap();
+    let v3_path = write_temp("errors_v3.bigram.bin", &v3_bytes);
+    let v3 = load(&v3_path, None).unwrap();
+    assert_eq!(
+        v3.lookup_bigram(0),
+        [ResolvedEdge {
+            word: "b",
+            weight: Weight::MAX
+        }]
+    );
+
+    let reordered: Vocab = ["c", "b", "a"].into_iter().collect();
+    let (mut fst_bytes, mut vocab_bytes) = (Vec::new(), Vec::new());
+    write_lexicon_with_ids(&words, &reordered, &mut fst_bytes, Some(&mut vocab_bytes)).unwrap();
+    let reordered_fst = write_temp("errors_reordered.lex.fst", &fst_bytes);
+    let reordered_vocab = write_temp("errors_reordered.vocab.txt", &vocab_bytes);
+    let err = ImeEngine::load(&reordered_fst, &reordered_vocab, &v3_path, None)
+        .err()
+        .unwrap();
+    println!("Reordered vocab: {}", err);
+    assert!(matches!(
+        err,
+        EngineError::VocabHashMismatch { model, vocab: found }
+            if model == vocab.fingerprint() && found == reordered.fingerprint()
+    ));
+    assert!(
+        ImeEngine::load(&reordered_fst, &reordered_vocab, &bigram_path, None).is_ok(),
+        "Pre-v3 models carry no fingerprint and aren't checked"
+    );
+
+    // v3 trigram caches record it too; a stale cache is refused by
+    // `open_for` and dropped (not fatal) by `ImeEngine::load`
+    let mut trigram_bytes = build_trigram(0, 1, &[(2, 65535)]);
+    trigram_bytes[4..8].copy_from_slice(&3u32.to_le_bytes());
+    trigram_bytes[16..20].copy_from_slice(&32u32.to_le_bytes());
+    trigram_bytes[20..24].copy_from_slice(&16u32.to_le_bytes());
+    trigram_bytes[24..32].copy_from_slice(&vocab.fingerprint().to_le_bytes());
+    let trigram_path = write_temp("errors_v3.trigram.bin", &trigram_bytes);
+    let cache = TrigramCache::open_for(&trigram_path, &vocab).unwrap();
+    assert_eq!(cache.vocab_hash(), Some(vocab.fingerprint()));
+    assert!(load(&v3_path, Some(&trigram_path)).unwrap().has_trigram());
+    let err = TrigramCache::open_for(&trigram_path, &reordered)
+        .err()
+        .unwrap();
+    println!("Stale trigram cache: {}", err);
+    assert!(matches!(
+        err,
+        EngineError::TrigramVocabHashMismatch { model, vocab: found }
+            if model == vocab.fingerprint() && found == reordered.fingerprint()
+    ));
+    let engine = ImeEngine::load(
+        &reordered_fst,
+        &reordered_vocab,
+        &bigram_path,
+        Some(&trigram_path),
+    )
+    .unwrap();
+    assert!(!engine.has_trigram(), "Mismatched cache is disabled");
+    let v1_trigram = write_temp("errors_v1.trigram.bin", &build_trigram(0, 1, &[(2, 65535)]));
+    assert!(TrigramCache::open_for(&v1_trigram, &reordered).is_ok());
+
+    // Binaries keep using anyhow; the variant survives the conversion
+    let err: anyhow::Error = load(&short_vocab, None).err().unwrap().into();
+    assert!(matches!(
+        err.downcast_ref::<EngineError>(),
+        Some(EngineError::VocabMismatch { .. })
+    ));
+
+    // 18. Stats summarize coverage and row lengths from the index
+    println!("\nComputing model stats...");
+    let rows = vec![
+        vec![(1, 65535), (2, 100)],
+        vec![],
+        vec![(0, 65535)],
+        vec![(0, 65535), (1, 500), (2, 7)],
+        vec![],
+    ];
+    let model = BigramModel::open(&write_temp("stats.bigram.bin", &build_model(&rows))).unwrap();
+    let stats = model.stats();
+    println!("{:?}", stats);
+    assert_eq!((stats.vocab_size, stats.edges_count), (5, 6));
+    assert_eq!(stats.non_empty_prevs, 3);
+    assert!((stats.coverage_ratio - 0.6).abs() < 1e-9);
+    assert_eq!(
+        stats.len_histogram.len(),
+        11,
+        "One bucket per length up to top_n"
+    );
+    assert_eq!(stats.len_histogram[..4], [2, 1, 1, 1]);
+    assert_eq!(stats.len_histogram.iter().sum::<usize>(), 5);
+    assert_eq!((stats.p10_len, stats.median_len, stats.p90_len), (1, 2, 3));
+    let empty =
+        BigramModel::open(&write_temp("stats_empty.bigram.bin", &build_model(&[]))).unwrap();
+    assert_eq!(empty.stats().coverage_ratio, 0.0);
+    assert_eq!(empty.stats().median_len, 0);
+
+    // 19. iter walks the index once and yields every non-empty row in order
+    println!("\nIterating edges...");
+    let collected: Vec<(u32, Vec<Edge>)> = model
+        .iter()
+        .map(|(prev, row)| {
+            assert!(!row.is_empty(), "Empty prevs are skipped");
+            (
+                prev,
+                row.iter()
+                    .map(|e| Edge {
+                        next_id: e.next_id(),
+                        weight: e.weight(),
+                    })
+                    .collect(),
+            )
+        })
+        .collect();
+    let expected: Vec<(u32, Vec<Edge>)> = (0..rows.len() as u32)
+        .map(|prev| (prev, model.next(prev, usize::MAX)))
+        .filter(|(_, row)| !row.is_empty())
+        .collect();
+    assert_eq!(collected, expected);
+    assert_eq!(collected.len(), stats.non_empty_prevs);
+    assert_eq!(
+        model.iter().map(|(_, row)| row.len()).sum::<usize>(),
+        model.edges_count()
+    );
+    for (prev, row) in model.iter() {
+        let weights: Vec<Edge> = model.weights(row).collect();
+        assert_eq!(weights, model.next(prev, usize::MAX));
+    }
+    assert_eq!(empty.iter().count(), 0);
+
+    // A truncated file yields its rows cut short, not a panic
+    let bytes = build_model(&rows);
+    let truncated = write_temp("iter_truncated.bigram.bin", &bytes[..bytes.len() - 8]);
+    let truncated = BigramModel::open(&truncated).unwrap();
+    let lens: Vec<(u32, usize)> = truncated.iter().map(|(p, r)| (p, r.len())).collect();
+    assert_eq!(lens, vec![(0, 2), (2, 1), (3, 2)]);
+
+    // 20. Words that precede a given word come from the inverted bigram rows
+    println!("\nFilling in the blank before \"store\"...");
+    let engine = engine_from_rows(
+        "reverse.bigram.bin",
+        &["the", "store", "a", "go", "to", "grocery"],
+        &[
+            vec![(1, 60000), (3, 20000)],
+            vec![],
+            vec![(1, 40000)],
+            vec![(4, 65535)],
+            vec![(0, 65535), (1, 30000)],
+            vec![(1, 65535)],
+        ],
+    );
+    let before = engine.suggest_given_next("store", 3);
+    for s in &before {
+        println!("  {} {} {:.3}", s.word, s.score, s.probability());
+    }
+    let words: Vec<&str> = before.iter().map(|s| s.word.as_str()).collect();
+    assert_eq!(words, ["grocery", "the", "a"]);
+    assert_eq!(before[0].score, Score::MAX);
+    assert!((before[0].probability() - 1.0).abs() < 1e-6);
+    assert!(before.windows(2).all(|w| w[0].score >= w[1].score));
+    assert!(before.iter().all(|s| s.source == Source::Bigram));
+    assert_eq!(
+        engine.suggest_given_next("Store,", 10).len(),
+        4,
+        "Normalized like context"
+    );
+    assert_eq!(engine.suggest_given_next("the", 10)[0].word, "to");
+    assert!(engine.suggest_given_next("grocery", 10).is_empty());
+    assert!(engine.suggest_given_next("unknown", 10).is_empty());
+
+    // Preceder counts are the shared dequantization scaled by the row's max
+    let model = BigramModel::open(&write_temp(
+        "reverse_counts.bigram.bin",
+        &build_model(&[vec![(1, 65535), (2, 30000)], vec![], vec![]]),
+    ))
+    .unwrap();
+    let log_max = model.log_max(0).unwrap_or(DEFAULT_LOG_MAX).max(1.0);
+    let reverse = ReverseBigrams::build(&model);
+    let expected = Weight(30000).relative_count(log_max) * log_max.exp();
+    assert_eq!(reverse.preceders(2), [(0, expected as f32)]);
+
+    // 21. A half-typed syllable completes from the syllable FST
+    println!("\nCompleting partial syllables...");
+    let mut syllables = [
+        ("nghe", 200),
+        ("nghĩ", 180),
+        ("nghĩa", 150),
+        ("nghiêng", 40),
+        ("ngay", 220),
+        ("đi", 255),
+        ("xin", 90),
+    ];
+    syllables.sort();
+    let mut builder = fst::MapBuilder::memory();
+    for (id, (syllable, prob)) in syllables.iter().enumerate() {
+        builder
+            .insert(syllable, ((id as u64) << 16) | prob)
+            .unwrap();
+    }
+    let syllable_path = write_temp("complete.syllable.fst", &builder.into_inner().unwrap());
+    let vi = VietnameseEngine::open(&syllable_path).unwrap();
+    let completed = |prefix: &str| -> Vec<String> {
+        vi.complete(prefix, 10)
+            .into_iter()
+            .map(|m| m.syllable)
+            .collect()
+    };
+    println!("ngh -> {:?}", completed("ngh"));
+    assert_eq!(completed("ngh"), ["nghe", "nghĩ", "nghĩa", "nghiêng"]);
+    assert_eq!(completed("NGH"), completed("ngh"), "Case is ignored");
+    assert_eq!(vi.complete("ngh", 2).len(), 2);
+
+    // Unmarked input still reaches marked syllables, after exact matches
+    let nghi = vi.complete("nghi", 10);
+    println!("nghi -> {:?}", nghi);
+    let words: Vec<(&str, bool)> = nghi
+        .iter()
+        .map(|m| (m.syllable.as_str(), m.exact))
+        .collect();
+    assert_eq!(
+        words,
+        [("nghiêng", true), ("nghĩ", false), ("nghĩa", false)]
+    );
+    assert_eq!(completed("di"), ["đi"], "đ folds to d");
+    assert_eq!(
+        completed("nghĩ"),
+        ["nghĩ", "nghĩa"],
+        "Typed marks must match"
+    );
+    let id = syllables.iter().position(|&(s, _)| s == "nghe").unwrap() as u32;
+    assert_eq!(vi.complete("nghe", 1)[0].syllable_id, id);
+    assert_eq!(vi.complete("nghe", 1)[0].prob, 200);
+    assert!(completed("q").is_empty());
+    assert!(completed("").is_empty());
+    assert_eq!(fold_marks("Nghiêng Đường"), "Nghieng Duong");
+
+    // 22. Boost lists load from a per-language file, else the built-in list
+    println!("\nLoading a boost file...");
+    let boost_dir = std::env::temp_dir().join(format!("{}_boost", std::process::id()));
+    std::fs::create_dir_all(&boost_dir).unwrap();
+    let dir = boost_dir.to_str().unwrap();
+    std::fs::write(
+        boost_dir.join("boost.en.txt"),
+        "# tuned for chat\npark 2.0\nthe\n\nlol 0.5  # demoted\n",
+    )
+    .unwrap();
+    let boost = BoostTable::for_language("en", dir).unwrap();
+    assert_eq!(boost.len(), 3);
+    assert_eq!(boost.factor("park"), 2.0);
+    assert_eq!(boost.factor("the"), 1.25, "Factor defaults to 1.25");
+    assert_eq!(boost.factor("lol"), 0.5);
+    assert_eq!(
+        boost.factor("of"),
+        1.0,
+        "The file replaces the built-in list"
+    );
+    let mut suggestions = vec![
+        ("store".to_string(), Score(30000)),
+        ("park".to_string(), Score(20000)),
+        ("lol".to_string(), Score(25000)),
+    ];
+    apply_gating(&mut suggestions, &boost);
+    assert_eq!(
+        suggestions,
+        [
+            ("park".to_string(), Score(40000)),
+            ("store".to_string(), Score(30000)),
+            ("lol".to_string(), Score(12500))
+        ]
+    );
+
+    let vi = BoostTable::for_language("vi", dir).unwrap();
+    assert_eq!(vi.factor("của"), 1.25, "No file: built-in list");
+    assert!(BoostTable::for_language("fr", dir).unwrap().is_empty());
+
+    std::fs::write(
+        boost_dir.join("boost.xx.txt"),
+        "ok 1.5\nbad x\nneg -1\nthree fields here\nalso 0\n",
+    )
+    .unwrap();
+    let err = BoostTable::for_language("xx", dir).unwrap_err().to_string();
+    println!("{}", err);
+    assert!(err.starts_with("4 bad line(s)"));
+    for line in [":2:", ":3:", ":4:", ":5:"] {
+        assert!(err.contains(line), "{} is reported", line);
+    }
+    assert!(!err.contains(":1:"));
+    std::fs::remove_dir_all(&boost_dir).unwrap();
+
+    // 23. Models read from bytes in memory, e.g. embedded with include_bytes!
+    println!("\nReading embedded models...");
+    let embedded = BigramModel::from_bytes(&EMBEDDED_BIGRAM[..]).unwrap();
+    assert_eq!(embedded.vocab_size(), 2);
+    assert_eq!(embedded.next(0, 10), edges(&[(1, 65535)]));
+    assert!(embedded.next(1, 10).is_empty());
+    let rows: Vec<(u32, Vec<u32>)> = embedded
+        .iter()
+        .map(|(prev, row)| (prev, row.iter().map(|e| e.next_id()).collect()))
+        .collect();
+    assert_eq!(rows, [(0, vec![1])]);
+    assert!(matches!(
+        BigramModel::from_bytes(&EMBEDDED_BIGRAM[..40]),
+        Err(EngineError::Truncated { need: 48, have: 40 })
+    ));
+
+    let owned = BigramModel::from_bytes(build_model(&[vec![(1, 500), (0, 7)], vec![]])).unwrap();
+    assert_eq!(owned.next(0, 10), edges(&[(1, 500), (0, 7)]));
+    let cache = TrigramCache::from_bytes(build_trigram(0, 1, &[(2, 65535)])).unwrap();
+    assert_eq!(cache.lookup(0, 1, 10), Some(edges(&[(2, 65535)])));
+
+    // 24. Interpolation lets a strong bigram beat a one-off trigram
+    println!("\nInterpolating trigram and bigram scores...");
+    let words = ["a", "b", "noisy", "usual"];
+    // The trigram (a, b) saw "noisy" once and "usual" barely; after "b"
+    // the bigram model overwhelmingly predicts "usual"
+    let rows = vec![vec![(1, 65535)], vec![(3, 65535), (2, 0)], vec![], vec![]];
+    let trigram_path = write_temp(
+        "interpolation.trigram.bin",
+        &build_trigram(0, 1, &[(2, 65535), (3, 0)]),
+    );
+    let mut engine = engine_from_files(
+        &words,
+        &write_temp("interpolation.bigram.bin", &build_model(&rows)),
+        Some(&trigram_path),
+    );
+    let top = |engine: &ImeEngine| {
+        let suggestions = engine.suggest_next("a b", 5);
+        (
+            suggestions[0].word.clone(),
+            suggestions[0].source,
+            suggestions.len(),
+        )
+    };
+    assert_eq!(
+        top(&engine),
+        ("noisy".to_string(), Source::Trigram, 2),
+        "Trigram-first by default"
+    );
+    engine.set_interpolation(Interpolation::Linear { lambda: 0.3 });
+    let mixed = engine.suggest_next("a b", 5);
+    println!(
+        "Interpolated: {:?}",
+        mixed.iter().map(|s| (&s.word, s.score)).collect::<Vec<_>>()
+    );
+    assert_eq!(top(&engine), ("usual".to_string(), Source::Bigram, 2));
+    // 0.3 * 1.0 + 0.7 * exp(-6.9) for "noisy"
+    assert!((mixed[1].probability() - 0.3007).abs() < 1e-3);
+    engine.set_interpolation(Interpolation::Linear { lambda: 0.9 });
+    assert_eq!(top(&engine).0, "noisy", "A trusted trigram still wins");
+    engine.set_interpolation(Interpolation::Linear { lambda: 7.0 });
+    assert_eq!(top(&engine).0, "noisy", "Lambda is clamped to 1");
+    assert_eq!(
+        engine.suggest_next("b", 5)[0].word,
+        "usual",
+        "No trigram context: bigrams as before"
+    );
+
+    // 25. Blocklisted words never come back, even as the top edge
+    println!("\nFiltering suggestions through a blocklist...");
+    let mut engine = engine_from_rows(
+        "blocklist.bigram.bin",
+        &["the", "slur", "cat", "dog", "Slur"],
+        &[
+            vec![(1, 65535), (2, 40000), (4, 35000), (3, 30000)],
+            vec![(2, 65535)],
+            vec![(1, 65535), (3, 20000)],
+            vec![(1, 65535)],
+            vec![],
+        ],
+    );
+    let the = engine.word_id("the").unwrap();
+    assert_eq!(engine.suggest_next("the", 1)[0].word, "slur");
+    let blocklist = Blocklist::from_words(["SLUR"], engine.vocab());
+    assert_eq!(blocklist.len(), 2, "Case variants resolve too");
+    engine.set_blocklist(blocklist);
+    let mut shortcuts = ShortcutTable::default();
+    shortcuts.insert("sl", "slur", 0);
+    engine.set_shortcuts(shortcuts);
+    engine.set_unigram_prior(vec![(1, 255), (3, 200)]);
+    let blocked = |word: &str| word.split(' ').any(|w| w.eq_ignore_ascii_case("slur"));
+
+    let next = engine.suggest_next("the", 3);
+    println!(
+        "After 'the': {:?}",
+        next.iter().map(|s| &s.word).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        next.iter().map(|s| s.word.as_str()).collect::<Vec<_>>(),
+        ["cat", "dog"],
+        "Filled from the remaining edges"
+    );
+    assert!(engine.lookup_bigram(the).iter().all(|e| !blocked(e.word)));
+    for context in ["the cat", "cat", "dog", "sl", "the sl"] {
+        let next = engine.suggest_next(context, 5);
+        assert!(
+            !next.iter().any(|s| blocked(&s.word)),
+            "{}: {:?}",
+            context,
+            next
+        );
+    }
+    let phrases = engine.suggest_phrase("the", 2, 10);
+    println!("Phrases: {:?}", phrases);
+    assert!(!phrases.is_empty());
+    assert!(phrases.iter().all(|(p, _)| !blocked(p)));
+    let before_cat = engine.suggest_given_next("cat", 5);
+    assert!(!before_cat.is_empty() && before_cat.iter().all(|s| !blocked(&s.word)));
+
+    // 26. DOT export of a word's neighbourhood, one or two hops out
+    println!("\nExporting a neighbourhood as DOT...");
+    let model = BigramModel::open(&write_temp(
+        "dot.bigram.bin",
+        &build_model(&[
+            vec![(1, 65535), (3, 20000)],
+            vec![(2, 65535), (0, 30000), (9, 100)], // 9 is past the vocab
+            vec![(0, 65535)],
+            vec![],
+        ]),
+    ))
+    .unwrap();
+    let vocab: Vocab = ["i", "love", "you", "say \"hi\""].into_iter().collect();
+    let one_hop = model.to_dot("i", &vocab, 1);
+    println!("{}", one_hop.trim_end());
+    let lines: Vec<&str> = one_hop.lines().collect();
+    assert_eq!(lines[0], "digraph bigram {");
+    assert!(lines.contains(&"  \"i\" [shape=box];"));
+    assert!(lines.contains(&"  \"love\";"));
+    assert!(lines.contains(&"  \"i\" -> \"love\" [label=\"65535\"];"));
+    assert!(
+        lines.contains(&"  \"i\" -> \"say \\\"hi\\\"\" [label=\"20000\"];"),
+        "Quotes escaped"
+    );
+    assert!(!one_hop.contains("you"), "Second hop not expanded");
+    assert_eq!(lines.last(), Some(&"}"));
+
+    let two_hops = model.to_dot("i", &vocab, 2);
+    assert!(two_hops.contains("  \"love\" -> \"you\" [label=\"65535\"];"));
+    assert!(
+        two_hops.contains("  \"love\" -> \"i\" [label=\"30000\"];"),
+        "Edges back to a seen word are drawn"
+    );
+    assert_eq!(two_hops.matches("  \"i\" [shape=box];").count(), 1);
+    assert_eq!(
+        two_hops.matches("  \"love\";").count(),
+        1,
+        "Nodes listed once"
+    );
+    assert!(!two_hops.contains("\"you\" ->"), "Depth 2 stops there");
+    assert_eq!(
+        model.to_dot("nope", &vocab, 2),
+        "digraph bigram {\n  rankdir=LR;\n}\n"
+    );
+
+    // 27. Committing word by word learns the same bigrams as a batch learn
+    println!("\nLearning from a word-by-word session...");
+    let engine = engine_from_rows(
+        "session.bigram.bin",
+        &["my", "name", "is"],
+        &[vec![], vec![], vec![]],
+    );
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let text = "My name is Gox, and Gox is my name";
+    let mut batch = UserHistory::new();
+    batch.learn_at(text, |w| engine.word_id(w), now);
+    let mut streamed = UserHistory::new();
+    {
+        let mut session = engine.begin_session(&mut streamed);
+        for word in text.split_whitespace() {
+            session.commit_word_at(word, now);
+        }
+        assert_eq!(session.last_id(), Some(1), "Global id of \"name\"");
+        assert_eq!(session.commit_word_at("...", now), None, "Not a word");
+        assert_eq!(session.last_id(), Some(1), "Context kept");
+    }
+    let gox = streamed.get_user_word_id("gox").unwrap();
+    assert!(gox >= 0x8000_0000, "User word id");
+    let prevs = [0, 1, 2, gox, streamed.get_user_word_id("and").unwrap()];
+    // Sorted: equal scores come back in hash order
+    let predict = |history: &UserHistory, prev| {
+        let mut next = history.predict(prev);
+        next.sort();
+        next
+    };
+    for prev in prevs {
+        println!("After {}: {:?}", prev, predict(&streamed, prev));
+        assert_eq!(predict(&streamed, prev), predict(&batch, prev));
+    }
+    assert_eq!(streamed.prev_count(), batch.prev_count());
+    assert_eq!(
+        streamed.get_user_word_id("gox"),
+        batch.get_user_word_id("gox")
+    );
+
+    {
+        let mut session = engine.begin_session(&mut streamed);
+        session.commit_word_at("is", now);
+        session.reset();
+        session.commit_word_at("name", now);
+    }
+    assert!(
+        !streamed.predict(2).iter().any(|&(id, _)| id == 1),
+        "No edge across a reset"
+    );
+    engine.begin_session(&mut streamed).observe("is name");
+    assert!(streamed.predict(2).iter().any(|&(id, _)| id == 1));
+
+    // 28. A word committed moments ago outranks an equally weighted one
+    println!("\nBoosting recently committed words...");
+    let mut engine = engine_from_rows(
+        "recency.bigram.bin",
+        &["the", "cat", "dog", "end"],
+        &[vec![(1, 30000), (2, 30000)], vec![], vec![], vec![]],
+    );
+    let top = |engine: &ImeEngine| engine.suggest_next("the", 2)[0].word.clone();
+    assert_eq!(top(&engine), "cat", "Ties go to the lower id");
+    engine.record_commit("dog");
+    assert_eq!(top(&engine), "cat", "Off by default");
+    engine.set_recency(3, 1.5);
+    engine.record_commit("Dog");
+    let next = engine.suggest_next("the", 2);
+    println!(
+        "After committing 'dog': {:?}",
+        next.iter().map(|s| (&s.word, s.score)).collect::<Vec<_>>()
+    );
+    assert_eq!(next[0].word, "dog");
+    assert_eq!(
+        next[0].score,
+        Score(next[1].score.0 * 3 / 2),
+        "Full boost for the last commit"
+    );
+    engine.record_commit("cat");
+    assert_eq!(top(&engine), "cat", "The later commit gets more");
+    engine.record_commit("zebra");
+    assert_eq!(top(&engine), "cat", "Unknown words are skipped");
+    engine.record_commit("dog");
+    engine.record_commit("end");
+    engine.record_commit("end");
+    engine.record_commit("end");
+    let next = engine.suggest_next("the", 2);
+    assert_eq!(next[0].score, next[1].score, "Both out of the window");
+    engine.record_commit("dog");
+    assert_eq!(top(&engine), "dog");
+    engine.clear_recency();
+    assert_eq!(top(&engine), "cat");
+
+    // 29. Typed edges carry the stored ids and weights
+    println!("\nReading typed edges...");
+    let model = BigramModel::from_bytes(build_model(&[
+        vec![(1, 65535), (2, 20000), (9, 100)],
+        vec![],
+        vec![],
+    ]))
+    .unwrap();
+    let row = model.next(0, 10);
+    assert_eq!(
+        row,
+        [(1, Weight::MAX), (2, Weight(20000)), (9, Weight(100))].map(Edge::from)
+    );
+    assert_eq!(
+        row[0],
+        Edge {
+            next_id: 1,
+            weight: Weight::MAX
+        }
+    );
+    assert_eq!(model.next(0, 1).len(), 1);
+    assert!(model.next(7, 10).is_empty(), "Outside the index");
+    let vocab: Vocab = ["the", "cat", "dog"].into_iter().collect();
+    assert_eq!(
+        vocab.resolve(&row),
+        [
+            ResolvedEdge {
+                word: "cat",
+                weight: Weight::MAX
+            },
+            ResolvedEdge {
+                word: "dog",
+                weight: Weight(20000)
+            },
+        ],
+        "Id 9 is past the vocab"
+    );
+    let cache = TrigramCache::from_bytes(build_trigram(0, 1, &[(2, 65535), (1, 300)])).unwrap();
+    assert_eq!(
+        cache.lookup(0, 1, 10).unwrap(),
+        [
+            Edge {
+                next_id: 2,
+                weight: Weight::MAX
+            },
+            Edge {
+                next_id: 1,
+                weight: Weight(300)
+            }
+        ]
+    );
+    assert!(cache.lookup(1, 0, 10).is_none());
+
+    // 30. Mid-word text completes; text ending in a space predicts
+    println!("\nSuggesting by cursor position...");
+    let words = [
+        ("i", 200),
+        ("it", 150),
+        ("love", 100),
+        ("lovely", 250),
+        ("lover", 90),
+        ("you", 180),
+    ];
+    // Ids follow key order: i, it, love, lovely, lover, you
+    let rows = vec![
+        vec![(2, 65535), (1, 3000)],
+        vec![],
+        vec![(5, 65535), (1, 20000)],
+        vec![],
+        vec![],
+        vec![],
+    ];
+    let engine = lexicon_engine("suggest", words, &rows);
+    let entries = |text: &str, limit: usize| -> Vec<(SuggestMode, String)> {
+        engine
+            .suggest(text, limit)
+            .into_iter()
+            .map(|t| (t.mode, t.suggestion.word))
+            .collect()
+    };
+    use SuggestMode::{Completion, Prediction};
+    assert_eq!(
+        entries("i lov", 3),
+        [
+            (Completion, "love".to_string()),
+            (Completion, "lovely".to_string()),
+            (Completion, "lover".to_string()),
+        ],
+        "The bigram after \"i\" beats the more frequent \"lovely\""
+    );
+    assert_eq!(
+        entries("i lov", 5)[3..],
+        [
+            (Prediction, "you".to_string()),
+            (Prediction, "it".to_string())
+        ],
+        "Short completions bridge into what follows \"love\""
+    );
+    let predicted: Vec<String> = engine
+        .suggest_next("i love ", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    assert_eq!(predicted, ["you", "it"]);
+    assert_eq!(
+        entries("i love ", 3),
+        [
+            (Prediction, "you".to_string()),
+            (Prediction, "it".to_string())
+        ]
+    );
+    assert_eq!(
+        entries("lov", 1),
+        [(Completion, "lovely".to_string())],
+        "No previous word: ranked by prob"
+    );
+    assert!(entries("i xyz", 3).is_empty(), "Nothing to complete");
+
+    // 31. Dequantizing a weight recovers the count ratio it was quantized from
+    println!("\nDequantizing weights...");
+    // ln(992) is about DEFAULT_LOG_MAX, the top count `dequantize` assumes
+    for max in [992u64, 10, 1_000_000] {
+        let table = DequantTable::new((max as f64).ln());
+        for count in [1, 2, max / 3, max / 2, max - 1, max] {
+            let weight = Count(count).quantize(Count(max));
+            assert_eq!(weight, Weight(quantize_weight(count, max)));
+            let expected = count as f64 / max as f64;
+            let got = dequantize_with(weight, Some((max as f64).ln())) as f64;
+            assert!(
+                (got - expected).abs() / expected < 0.01,
+                "{}/{} came back as {}",
+                count,
+                max,
+                got
+            );
+            assert_eq!(table.get(weight), got as f32);
+            if max == 992 {
+                assert!((dequantize(weight) as f64 - expected).abs() / expected < 0.01);
+            }
+        }
+    }
+    assert_eq!(dequantize(Weight::MAX), 1.0);
+    assert_eq!(Weight::MAX.dequantize(), 1.0);
+    assert_eq!(dequantize_with(Weight::MAX, None), dequantize(Weight::MAX));
+    assert!(
+        (1..=65535).all(|w| dequantize(Weight(w)) >= dequantize(Weight(w - 1))),
+        "Monotonic"
+    );
+
+    // 32. The display form is the most probable spelling, not the id's
+    println!("\nPicking display forms...");
+    let (fst_path, vocab_path) = lexicon_files(
+        "display",
+        [
+            ("paris", 5),
+            ("Paris", 220),
+            ("PARIS", 40),
+            ("the", 250),
+            ("The", 250),
+            ("THE", 20),
+            ("NASA", 180),
+            ("Nasa", 30),
+        ],
+    );
+    let vocab = Vocab::load(&vocab_path).unwrap();
+    let (size, display) = build_canonical_display_map(&fst_path, &vocab_path).unwrap();
+    let (_, ids) = build_canonical_map(&fst_path, &vocab_path).unwrap();
+    assert_eq!(size as usize, vocab.len());
+    assert_eq!(display.len(), ids.len());
+    for (key, (id, _)) in &display {
+        assert_eq!(ids[key], *id, "Ids match build_canonical_map for {}", key);
+    }
+    let (paris_id, paris) = &display["paris"];
+    assert_eq!(
+        vocab.word(*paris_id),
+        Some("paris"),
+        "The lowercase entry keeps the id"
+    );
+    assert_eq!(paris, "Paris");
+    assert_eq!(display["the"].1, "the", "A tie goes to the lowercase form");
+    assert_eq!(display["nasa"].1, "NASA");
+
+    // 33. A header claiming more words than the vocab has is refused at load
+    println!("\nLoading a model against a stale vocab...");
+    let (fst_path, vocab_path) =
+        lexicon_files("stale", (0..90).map(|i| (format!("w{:02}", i), 100)));
+    let mut rows = vec![Vec::new(); 100];
+    rows[0] = vec![(95, 65535)];
+    let bigram_path = write_temp("stale.bigram.bin", &build_model(&rows));
+    let err = ImeEngine::load(&fst_path, &vocab_path, &bigram_path, None)
+        .err()
+        .unwrap();
+    println!("Stale vocab: {}", err);
+    assert!(matches!(
+        err,
+        EngineError::VocabMismatch {
+            model: 100,
+            vocab: 90
+        }
+    ));
+    let model = BigramModel::open(&bigram_path).unwrap();
+    let vocab = Vocab::load(&vocab_path).unwrap();
+    assert!(model.check_vocab(&vocab).is_err());
+    rows.truncate(90);
+    rows[0].clear();
+    let fitting = BigramModel::from_bytes(build_model(&rows)).unwrap();
+    assert!(fitting.check_vocab(&vocab).is_ok());
+
+    // 34. The background loader yields the same engine as the blocking one
+    println!("\nLoading in the background...");
+    let (fst_path, vocab_path) = lexicon_files(
+        "async",
+        [
+            ("i", 200),
+            ("It", 150),
+            ("it", 90),
+            ("love", 100),
+            ("lovely", 250),
+            ("you", 180),
+        ],
+    );
+    // Ids follow key order: It, i, it, love, lovely, you
+    let rows = vec![
+        vec![(3, 65535)],
+        vec![(3, 65535), (0, 4000)],
+        vec![],
+        vec![(5, 65535), (2, 30000)],
+        vec![],
+        vec![(4, 12000)],
+    ];
+    let bigram_path = write_temp("async.bigram.bin", &build_model(&rows));
+    let sync = ImeEngine::load(&fst_path, &vocab_path, &bigram_path, None).unwrap();
+    let loader = ImeEngine::load_async(&fst_path, &vocab_path, &bigram_path, None).unwrap();
+    assert_eq!(loader.get("It"), Some(0), "Exact lookups before the engine");
+    assert_eq!(loader.get("you"), Some(5));
+    assert_eq!(loader.get("IT"), None, "No case folding yet");
+    let loaded = loader.wait().unwrap();
+    assert_eq!(loaded.vocab(), sync.vocab());
+    for word in ["i", "it", "love", "lovely", "you", "nope"] {
+        assert_eq!(loaded.word_id(word), sync.word_id(word), "{}", word);
+    }
+    for text in ["i", "i love", "love you ", "i lov", "It", ""] {
+        assert_eq!(loaded.suggest_next(text, 5), sync.suggest_next(text, 5));
+        assert_eq!(loaded.suggest(text, 5), sync.suggest(text, 5));
+    }
+    assert!(ImeEngine::load_async("/nonexistent.fst", &vocab_path, &bigram_path, None).is_err());
+    let missing = ImeEngine::load_async(&fst_path, &vocab_path, "/nonexistent.bin", None).unwrap();
+    assert!(matches!(missing.wait(), Err(EngineError::Io { .. })));
+
+    // 35. A registry routes each language to its own engine, loaded on demand
+    println!("\nRouting queries by language...");
+    let (vi_fst, vi_vocab) = lexicon_files(
+        "registry.vi",
+        [
+            ("xin", 200),
+            ("chào", 220),
+            ("bạn", 180),
+            ("cảm", 150),
+            ("ơn", 150),
+        ],
+    );
+    // Ids follow byte order: bạn, chào, cảm, xin, ơn
+    let vi_rows = vec![
+        vec![],
+        vec![(0, 65535)],
+        vec![(4, 65535)],
+        vec![(1, 65535), (2, 20000)],
+        vec![],
+    ];
+    let vi_files = ModelFiles {
+        fst: vi_fst,
+        vocab: vi_vocab,
+        bigram: write_temp("registry.vi.bigram.bin", &build_model(&vi_rows)),
+        trigram: None,
+    };
+    let en_files = ModelFiles {
+        fst: fst_path.clone(),
+        vocab: vocab_path.clone(),
+        bigram: bigram_path.clone(),
+        trigram: None,
+    };
+    let mut registry = EngineRegistry::new();
+    registry.register("en", en_files.clone());
+    registry.register_with("en-US", en_files.clone(), |engine| {
+        let blocked = Blocklist::from_words(["you"], engine.vocab());
+        engine.set_blocklist(blocked);
+    });
+    registry.register("vi", vi_files.clone());
+    assert_eq!(registry.languages(), ["en", "en-US", "vi"]);
+    assert!(!registry.is_loaded("en"), "Nothing is loaded up front");
+
+    let words_of = |tagged: Vec<TaggedSuggestion>| -> Vec<String> {
+        tagged.into_iter().map(|t| t.suggestion.word).collect()
+    };
+    let vi_next = words_of(registry.suggest("vi", "xin ", 3).unwrap());
+    println!("vi after 'xin': {:?}", vi_next);
+    assert_eq!(vi_next, ["chào", "cảm"]);
+    assert!(registry.is_loaded("vi") && !registry.is_loaded("en"));
+    assert_eq!(words_of(registry.suggest("vi", "x", 3).unwrap())[0], "xin");
+    assert_eq!(
+        registry.suggest("en", "love ", 5).unwrap(),
+        sync.suggest("love ", 5),
+        "Same as a standalone engine"
+    );
+    assert!(registry.suggest("en", "xin ", 3).unwrap().is_empty());
+    assert!(registry.suggest("vi", "love ", 3).unwrap().is_empty());
+    assert_eq!(
+        words_of(registry.suggest("en", "i love ", 2).unwrap()),
+        ["you", "it"]
+    );
+    assert_eq!(
+        words_of(registry.suggest("en-US", "i love ", 2).unwrap()),
+        ["it"],
+        "Settings apply per language"
+    );
+    let en = registry.engine("en").unwrap();
+    let en_us = registry.engine("en-US").unwrap();
+    assert_eq!(
+        en.vocab().word(0).unwrap().as_ptr(),
+        en_us.vocab().word(0).unwrap().as_ptr(),
+        "One vocab buffer for both"
+    );
+    assert!(matches!(
+        registry.suggest("fr", "bonjour ", 3),
+        Err(EngineError::UnknownLanguage(lang)) if lang == "fr"
+    ));
+
+    registry.register(
+        "vi",
+        ModelFiles {
+            bigram: "/nonexistent.bigram.bin".to_string(),
+            ..vi_files
+        },
+    );
+    assert!(matches!(registry.engine("vi"), Err(EngineError::Io { .. })));
+    assert!(!registry.is_loaded("vi"), "A failed load isn't kept");
+    registry.insert(
+        "vi",
+        engine_from_rows("registry.vi", &["xin", "chào"], &[vec![(1, 65535)], vec![]]),
+    );
+    assert!(registry.is_loaded("vi"));
+    assert_eq!(
+        words_of(registry.suggest("vi", "xin ", 3).unwrap()),
+        ["chào"]
+    );
+
+    // 36. With nothing typed, the most frequent words start the sentence
+    println!("\nSuggesting sentence starters...");
+    let mut engine = ImeEngine::load(&fst_path, &vocab_path, &bigram_path, None).unwrap();
+    let starters = engine.suggest_next("", 4);
+    println!(
+        "Starters: {:?}",
+        starters
+            .iter()
+            .map(|s| (&s.word, s.score))
+            .collect::<Vec<_>>()
+    );
+    let words: Vec<&str> = starters.iter().map(|s| s.word.as_str()).collect();
+    // "it" stands for It/it, as in the canonical map
+    assert_eq!(words, ["lovely", "i", "you", "love"]);
+    assert!(starters.iter().all(|s| s.source == Source::Unigram));
+    assert!(starters.windows(2).all(|w| w[0].score > w[1].score));
+    assert_eq!(starters[0].score, Score(250 * 257));
+    for context in ["   ", "\n", "...", " , !? "] {
+        assert_eq!(engine.suggest_next(context, 4), starters, "{:?}", context);
+    }
+    assert_eq!(
+        engine.suggest("", 2),
+        engine
+            .suggest_next("", 2)
+            .into_iter()
+            .map(|s| TaggedSuggestion {
+                mode: SuggestMode::Prediction,
+                suggestion: s
+            })
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(engine.suggest_next("", 10).len(), 5, "Every word, once");
+
+    let mut history = UserHistory::new();
+    for _ in 0..3 {
+        history.learn("zed says love you", |w| engine.word_id(w));
+    }
+    history.learn("i love you", |w| engine.word_id(w));
+    let biased = engine.sentence_starters(Some(&history), 4);
+    println!(
+        "With history: {:?}",
+        biased
+            .iter()
+            .map(|s| (&s.word, s.source))
+            .collect::<Vec<_>>()
+    );
+    let words: Vec<&str> = biased.iter().map(|s| s.word.as_str()).collect();
+    assert_eq!(
+        words,
+        ["love", "you", "lovely", "i"],
+        "The user's words lead, up to half"
+    );
+    assert_eq!(biased[0].source, Source::User);
+    // "love" follows two words, so it outscores "you" though typed as often
+    assert_eq!(biased[0].id, engine.word_id("love"));
+    let wide = engine.sentence_starters(Some(&history), 10);
+    assert!(wide
+        .iter()
+        .any(|s| s.word == "zed" && s.id.is_none() && s.source == Source::User));
+    assert_eq!(
+        engine.sentence_starters(None, 4),
+        starters,
+        "No history, same as suggest_next"
+    );
+    engine.set_personalization(history);
+    assert_eq!(
+        engine.suggest_next("", 4),
+        biased,
+        "The overlay's history leads an empty context"
+    );
+    engine.clear_personalization();
+    assert_eq!(engine.suggest_next("", 4), starters);
+
+    engine.set_blocklist(Blocklist::from_words(["lovely"], engine.vocab()));
+    engine.set_capitalization(true);
+    let words: Vec<String> = engine
+        .suggest_next("", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    assert_eq!(words, ["I", "You", "Love"]);
+    engine.set_unigram_prior(vec![(5, 255), (3, 10)]);
+    let words: Vec<String> = engine
+        .suggest_next(". ", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    assert_eq!(
+        words,
+        ["You", "Love"],
+        "A prior replaces the lexicon's list"
+    );
+
+    // 37. Edges with equal weights go to the more frequent word first
+    println!("\nBreaking weight ties by unigram prob...");
+    let words = [("cat", 50), ("dog", 200), ("the", 100), ("a", 120)];
+    // Ids follow key order: a, cat, dog, the
+    let rows = vec![
+        vec![],
+        vec![],
+        vec![],
+        vec![(1, 30000), (2, 30000), (0, 20000)],
+    ];
+    let engine = lexicon_engine("ties", words, &rows);
+    let next = engine.suggest_next("the", 3);
+    println!(
+        "After 'the': {:?}",
+        next.iter().map(|s| (&s.word, s.score)).collect::<Vec<_>>()
+    );
+    let words: Vec<&str> = next.iter().map(|s| s.word.as_str()).collect();
+    assert_eq!(next[0].score, next[1].score);
+    assert_eq!(
+        words,
+        ["dog", "cat", "a"],
+        "dog (200) before cat (50); a has less weight"
+    );
+    let bare = engine_from_rows("ties", &["a", "cat", "dog", "the"], &rows);
+    let words: Vec<String> = bare
+        .suggest_next("the", 3)
+        .into_iter()
+        .map(|s| s.word)
+        .collect();
+    assert_eq!(words, ["cat", "dog", "a"], "No lexicon: ties by id");
+
+    let mut merged = vec![
+        Suggestion::new("x".into(), Score(10), Source::Bigram, Some(1)),
+        Suggestion::new("y".into(), Score(10), Source::Bigram, Some(2)),
+        Suggestion::new("z".into(), Score(10), Source::User, None),
+        Suggestion::new("w".into(), Score(10), Source::Bigram, Some(3)),
+        Suggestion::new("v".into(), Score(20), Source::Bigram, Some(4)),
+    ];
+    finalize_suggestions_with(&mut merged, |id| [0, 5, 9, 5, 0][id as usize]);
+    let ranked: Vec<&str> = merged.iter().map(|s| s.word.as_str()).collect();
+    assert_eq!(
+        ranked,
+        ["v", "y", "x", "w", "z"],
+        "Then by id, then id-less words"
+    );
+
+    // 38. Words without a row are listed as coverage gaps
+    println!("\nListing empty prevs...");
+    let rows = vec![vec![(1, 65535)], vec![], vec![(0, 65535), (1, 100)], vec![]];
+    let model = BigramModel::open(&write_temp("gaps.bigram.bin", &build_model(&rows))).unwrap();
+    let vocab = Vocab::from_text("the\nzebra\nof\nquokka\n");
+    let gaps: Vec<&str> = model.empty_prevs(&vocab).collect();
+    assert_eq!(gaps, vec!["zebra", "quokka"]);
+    assert_eq!(
+        gaps.len(),
+        model.stats().vocab_size - model.stats().non_empty_prevs
+    );
+    let short_vocab = Vocab::from_text("the\nzebra\n");
+    assert_eq!(
+        model.empty_prevs(&short_vocab).collect::<Vec<_>>(),
+        vec!["zebra"],
+        "Ids past the vocab are skipped"
+    );
+
+    // 39. Word-list syllables are NFC-normalized and checked letter by letter
+    println!("\nCleaning Vietnamese syllables...");
+    let decomposed = "vie\u{0323}\u{0302}t";
+    assert_ne!(decomposed, "việt");
+    assert_eq!(clean_syllable(decomposed).as_deref(), Some("việt"));
+    assert_eq!(clean_syllable("-đường-").as_deref(), Some("đường"));
+    assert_eq!(clean_syllable("a-lô").as_deref(), Some("a-lô"));
+    // No precomposed form, so the mark stays, on its letter
+    assert_eq!(clean_syllable("q\u{0309}").as_deref(), Some("q\u{0309}"));
+    assert_eq!(clean_syllable("\u{0301}a"), None, "Mark with no letter");
+    assert_eq!(clean_syllable("a-\u{0301}b"), None, "Mark after a hyphen");
+    assert_eq!(clean_syllable("a\u{0308}b"), None, "Not a Vietnamese mark");
+    assert_eq!(clean_syllable("straße"), None, "Not a Vietnamese letter");
+    assert_eq!(clean_syllable("中文"), None, "Not Latin");
+    assert_eq!(clean_syllable("abc1"), None);
+    assert_eq!(clean_syllable("--"), None);
+
+    // 40. contains_edge finds one edge without building the row
+    println!("\nChecking single edges...");
+    let rows = vec![
+        vec![(1, 65535), (2, 100)],
+        vec![],
+        vec![(0, 65535), (3, 40)],
+    ];
+    let model = BigramModel::open(&write_temp("contains.bigram.bin", &build_model(&rows))).unwrap();
+    assert_eq!(model.contains_edge(0, 2), Some(Weight(100)));
+    assert_eq!(model.contains_edge(2, 3), Some(Weight(40)));
+    assert_eq!(model.contains_edge(0, 3), None, "Absent edge");
+    assert_eq!(model.contains_edge(1, 0), None, "Empty row");
+    assert_eq!(model.contains_edge(9, 0), None, "Prev outside the index");
+    for (prev, row) in rows.iter().enumerate() {
+        for &(next, weight) in row {
+            assert_eq!(model.contains_edge(prev as u32, next), Some(Weight(weight)));
+        }
+    }
+
+    // 41. Every tier is cut only after gating, so `limit` entries come back
+    // in final ranked order even when the padding is long
+    println!("\nCutting gated padding to the limit...");
+    let vocab = [
+        "i", "want", "to", "go", "eat", "see", "be", "the", "run", "a", "of",
+    ];
+    let mut rows = vec![vec![]; vocab.len()];
+    rows[1] = vec![(2, 65535)];
+    // "the" is fifth, but its boost lifts it over the rest
+    rows[2] = vec![(4, 40000), (5, 39000), (6, 38000), (8, 37000), (7, 36000)];
+    let bigram_path = write_temp("cut.bigram.bin", &build_model(&rows));
+    let trigram_path = write_temp("cut.trigram.bin", &build_trigram(1, 2, &[(3, 65535)]));
+    let mut engine = engine_from_files(&vocab, &bigram_path, Some(&trigram_path));
+    engine.set_boost_table(BoostTable::english());
+    // Likewise "of", last in the prior
+    engine.set_unigram_prior(vec![(4, 200), (5, 190), (6, 180), (10, 170)]);
+
+    let words = |context: &str, limit: usize| -> Vec<String> {
+        engine
+            .suggest_next(context, limit)
+            .into_iter()
+            .map(|s| s.word)
+            .collect()
+    };
+    let top3 = words("want to", 3);
+    println!("Top 3 after \"want to\": {:?}", top3);
+    assert_eq!(top3, ["go", "the", "eat"]);
+    let top2 = words("run", 2);
+    println!("Top 2 after \"run\": {:?}", top2);
+    assert_eq!(top2, ["of", "eat"]);
+
+    // Any limit gives a prefix of the full list
+    for context in ["want to", "run", ""] {
+        let full = words(context, 100);
+        for limit in 0..=full.len() + 1 {
+            let cut = words(context, limit);
+            assert_eq!(cut.len(), limit.min(full.len()), "{:?} {}", context, limit);
+            assert_eq!(cut, full[..cut.len()], "{:?} {}", context, limit);
+        }
+    }
+
+    // 42. The canonical Bloom filter passes every vocab word and rejects
+    // most others before the map is consulted
+    println!("\nFiltering lookups through a Bloom filter...");
+    let words: Vec<String> = (0..2000).map(|i| format!("word{}", i)).collect();
+    let word_refs: Vec<&str> = words.iter().map(String::as_str).collect();
+    let mut engine = engine_from_rows("bloom.bigram.bin", &word_refs, &vec![vec![]; 2000]);
+    assert!(engine.canonical_bloom().is_none(), "Off by default");
+    assert!(engine.might_be_word("qwxzv"), "No filter, no verdict");
+    engine.enable_canonical_bloom(combined2fst::bloom::DEFAULT_FP_RATE);
+    let bloom = engine.canonical_bloom().unwrap();
+    println!(
+        "{} bits, {} hashes, {} bytes",
+        bloom.num_bits(),
+        bloom.num_hashes(),
+        bloom.size_bytes()
+    );
+    assert_eq!(bloom.num_hashes(), 7);
+    assert!(bloom.size_bytes() < 2000 * 2, "About 9.6 bits per key");
+    for (id, word) in words.iter().enumerate() {
+        assert!(engine.might_be_word(word), "{} is a word", word);
+        assert_eq!(engine.word_id(word), Some(id as u32));
+    }
+    let oov: Vec<String> = (0..10_000)
+        .map(|i| format!("unseen{}token{}", i, i * 7))
+        .collect();
+    let passed = oov.iter().filter(|w| engine.might_be_word(w)).count();
+    println!("False positives: {}/{}", passed, oov.len());
+    assert!(passed < 300, "Near the 1% it was sized for");
+    assert!(oov.iter().all(|w| engine.word_id(w).is_none()));
+
+    let mut filter = BloomFilter::new(0, 0);
+    assert_eq!((filter.num_bits(), filter.num_hashes()), (1, 1));
+    filter.insert("x");
+    assert!(
+        filter.may_contain("x") && filter.may_contain("y"),
+        "One bit"
+    );
+
+    // 43. A custom ranker reorders suggestions; the default keeps scores
+    println!("\nRanking with a custom scorer...");
+    let mut engine = engine_from_rows(
+        "ranker.bigram.bin",
+        &["i", "go", "understand", "eat", "appreciate"],
+        &[
+            vec![(2, 65535), (4, 60000), (1, 50000), (3, 40000)],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        ],
+    );
+    let words = |engine: &ImeEngine, limit: usize| -> Vec<String> {
+        engine
+            .suggest_next("i", limit)
+            .into_iter()
+            .map(|s| s.word)
+            .collect()
+    };
+    let by_score = words(&engine, 4);
+    assert_eq!(by_score, ["understand", "appreciate", "go", "eat"]);
+
+    // Long words pay 8000 per char
+    engine.set_ranker(Box::new(|c: &Candidate| {
+        default_rank(c) - 8000.0 * c.len as f32
+    }));
+    let short_first = words(&engine, 4);
+    println!("Length-penalized: {:?}", short_first);
+    assert_eq!(short_first, ["go", "eat", "understand", "appreciate"]);
+    assert_eq!(words(&engine, 2), ["go", "eat"], "Cut after ranking");
+    let scores: Vec<Score> = engine
+        .suggest_next("i", 4)
+        .iter()
+        .map(|s| s.score)
+        .collect();
+    assert_eq!(scores[0], Score(20000), "Scores are left as they were");
+
+    engine.set_ranker(Box::new(default_rank));
+    assert_eq!(words(&engine, 4), by_score);
+
+    let user = Suggestion::new("đi".to_string(), Score(10), Source::User, None);
+    let candidate = Candidate::of(&user);
+    assert_eq!((candidate.len, candidate.is_user), (2, true));
+    assert_eq!(default_rank(&candidate), 10.0);
+
+    // 44. Source stats tally which models answered over a set of queries
+    println!("\nTallying suggestion sources...");
+    let vocab = ["i", "want", "to", "go", "eat", "see", "be"];
+    let mut rows = vec![vec![]; vocab.len()];
+    rows[1] = vec![(2, 65535)];
+    rows[2] = vec![(4, 60000), (3, 50000), (5, 40000)];
+    let bigram_path = write_temp("sources.bigram.bin", &build_model(&rows));
+    let trigram_path = write_temp("sources.trigram.bin", &build_trigram(1, 2, &[(3, 65535)]));
+    let mut engine = engine_from_files(&vocab, &bigram_path, Some(&trigram_path));
+    assert_eq!(engine.source_stats(), SourceStats::default());
+
+    // Trigram + bigram padding, bigram only, nothing, then unigram fallback
+    let mut recorded = SourceStats::default();
+    for context in ["i want to", "want", "go"] {
+        recorded.record(&engine.suggest_next(context, 3));
+    }
+    engine.set_unigram_prior(vec![(4, 200)]);
+    recorded.record(&engine.suggest_next("zzz", 3));
+    println!("Sources: {}", recorded);
+    assert_eq!(
+        recorded,
+        SourceStats {
+            queries: 4,
+            empty: 1,
+            trigram: 1,
+            bigram: 2,
+            unigram: 1,
+            ..SourceStats::default()
+        }
+    );
+    assert_eq!(recorded.coverage(Source::Trigram), 0.25);
+    assert_eq!(recorded.coverage(Source::Bigram), 0.5);
+    assert_eq!(recorded.empty_rate(), 0.25);
+    assert_eq!(
+        recorded.to_string(),
+        "Trigram 25.0%, Bigram 50.0%, User 0.0%, Shortcut 0.0%, Unigram 25.0%, \
+         empty 25.0% of 4 queries"
+    );
+    assert_eq!(engine.source_stats(), recorded, "The engine tallies too");
+
+    // Structured, for CI: e.g. trigram coverage must not drop below 20%
+    let json = serde_json::to_string(&engine.source_stats()).unwrap();
+    let parsed: SourceStats = serde_json::from_str(&json).unwrap();
+    assert!(parsed.coverage(Source::Trigram) >= 0.2);
+
+    let mut merged = recorded;
+    merged.merge(&recorded);
+    assert_eq!((merged.queries, merged.bigram), (8, 4));
+    assert_eq!(merged.coverage(Source::Bigram), 0.5);
+    engine.reset_source_stats();
+    assert_eq!(engine.source_stats().queries, 0);
+    assert_eq!(SourceStats::default().coverage(Source::Trigram), 0.0);
+    // 45. Personalization overlays user edges on the global models without
+    // touching them, and clear_personalization goes back to global-only
+    println!("\nOverlaying personalization...");
+    let vocab = ["i", "love", "you", "pizza"];
+    let mut rows = vec![vec![]; vocab.len()];
+    rows[1] = vec![(2, 30000), (3, 20000)];
+    let model = build_model(&rows);
+    let bigram_path = write_temp("personal.bigram.bin", &model);
+    let mut engine = engine_from_files(&vocab, &bigram_path, None);
+    let words = |suggestions: Vec<Suggestion>| -> Vec<String> {
+        suggestions.into_iter().map(|s| s.word).collect()
+    };
+    let global_only = engine.suggest_next("i love", 3);
+    assert_eq!(words(global_only.clone()), ["you", "pizza"]);
+
+    engine.learn_personal("i love pizza");
+    assert_eq!(
+        engine.suggest_next("i love", 3),
+        global_only,
+        "Nothing is learned without an overlay"
+    );
+
+    // Memory-only: never saved anywhere
+    engine.set_personalization(UserHistory::new());
+    for _ in 0..30 {
+        engine.learn_personal("i love pizza");
+    }
+    engine.learn_personal("i love tacos");
+    let personal = engine.suggest_next("i love", 3);
+    println!("Personalized: {:?}", personal);
+    // User edges rank in the first tier; the bigram row pads after them
+    assert_eq!(words(personal.clone()), ["pizza", "tacos", "you"]);
+    assert_eq!(personal[0].source, Source::User);
+    assert_eq!(personal[0].id, Some(3), "Known words keep their global id");
+    assert_eq!(personal[1].id, None, "User-only words have no global id");
+    assert_eq!(
+        engine.with_personalization(|h| h.get_user_word_id("tacos").is_some()),
+        Some(true)
+    );
+    assert_eq!(
+        std::fs::read(&bigram_path).unwrap(),
+        model,
+        "The global model file is never written"
+    );
+
+    engine.clear_personalization();
+    assert_eq!(engine.suggest_next("i love", 3), global_only);
+    assert_eq!(
+        engine.with_personalization(|h| h.get_user_word_id("tacos")),
+        Some(None)
+    );
+    engine.learn_personal("i love tacos");
+    assert_eq!(
+        words(engine.suggest_next("i love", 3)),
+        ["tacos", "you", "pizza"],
+        "The overlay stays attached after clearing"
+    );
+
+    // With learn_trigrams, the last two words pick the overlay's words
+    engine.set_personalization(UserHistory::new_with_config(HistoryConfig {
+        learn_trigrams: true,
+        ..HistoryConfig::default()
+    }));
+    for _ in 0..5 {
+        engine.learn_personal("we love tacos");
+    }
+    engine.learn_personal("i love pizza");
+    assert_eq!(words(engine.suggest_next("we love", 2)), ["tacos", "pizza"]);
+    let after_pair = engine.suggest_next("i love", 2);
+    println!("After 'i love': {:?}", after_pair);
+    assert_eq!(
+        words(after_pair),
+        ["pizza", "tacos"],
+        "The pair's word leads the stronger bigram"
+    );
+    assert_eq!(
+        words(engine.suggest_next("love", 2)),
+        ["tacos", "pizza"],
+        "One word: the bigram alone"
+    );
+
+    // The overlay learns through the engine's normalizer
+    engine.set_normalizer(RichTokens::default());
+    let user_word = |engine: &ImeEngine, word: &str| {
+        engine
+            .with_personalization(|h| h.get_user_word_id(word))
+            .flatten()
+    };
+    engine.learn_personal("i love covid-19");
+    assert!(user_word(&engine, "covid-19").is_some(), "Kept its digits");
+    engine.set_personalization(UserHistory::new());
+    engine.learn_personal("we love covid-19");
+    assert!(user_word(&engine, "covid-19").is_some(), "A new overlay");
+    engine.clear_personalization();
+    engine.learn_personal("we love covid-19");
+    assert!(
+        user_word(&engine, "covid-19").is_some(),
+        "A cleared overlay"
+    );
+    assert!(user_word(&engine, "covid").is_none());
+    // 46. truncate_display cuts on char boundaries, never inside one
+    println!("\nTruncating suggestions for display...");
+    // "ê" takes bytes 4..6, so &word[..5] would panic
+    let word = "nghiêng";
+    assert_eq!(truncate_display(word, 7), word, "Fits: unchanged");
+    assert_eq!(truncate_display(word, 10), word);
+    assert_eq!(truncate_display(word, 5), "nghi…");
+    assert_eq!(truncate_display("đường", 3), "đư…");
+    assert_eq!(truncate_display("đường", 5), "đường");
+    assert_eq!(truncate_display("đường", 4).chars().count(), 4);
+    let emoji = "chúc mừng 🎉🎂🎁 sinh nhật";
+    assert_eq!(truncate_display(emoji, 13), "chúc mừng 🎉🎂…");
+    assert_eq!(truncate_display("🎉🎂🎁", 2), "🎉…");
+    assert_eq!(truncate_display("🎉🎂🎁", 1), "…");
+    assert_eq!(truncate_display("🎉", 0), "");
+    assert_eq!(truncate_display("", 3), "");
+    for max_chars in 0..=emoji.chars().count() + 1 {
+        let shown = truncate_display(emoji, max_chars);
+        assert!(shown.chars().count() <= max_chars);
+        assert!(emoji.starts_with(shown.trim_end_matches('…')));
+    }
+
+    println!("\nPASSED all tests!");
+}
+
+/// A version 1 model of two words with the single edge 0 -> 1, as a
+/// static array the way an app would embed it
+static EMBEDDED_BIGRAM: [u8; 56] = [
+    0x4D, 0x52, 0x47, 0x42, // magic "BGRM"
+    1, 0, 0, 0, // version
+    2, 0, 0, 0, // vocab_size
+    1, 0, 0, 0, // edges_count
+    10, 0, 0, 0, // top_n
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // unused in version 1
+    0, 0, 0, 0, 1, 0, 0, 0, // prev 0: offset 0, len 1
+    8, 0, 0, 0, 0, 0, 0, 0, // prev 1: no edges
+    1, 0, 0, 0, 0xFF, 0xFF, 0, 0, // 0 -> 1, weight 65535
+];
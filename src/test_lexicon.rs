@@ -0,0 +1,432 @@
+use combined2fst::corpus::DEFAULT_MAX_LINE_LEN;
+use combined2fst::lexicon::{
+    assign_ids, load_previous_vocab, pack_value, parse_frequency_line, quantize_prob,
+    search_fst_fuzzy_prefix, search_fst_fuzzy_prefix_with, unpack_value, write_lexicon,
+    write_lexicon_with_ids, FLAG_NOSUGGEST,
+};
+use combined2fst::tokenize::Locale;
+use combined2fst::{
+    build_canonical_map, build_exact_map, canonical_map_for, canonical_map_for_locale,
+    top_unigrams, top_unigrams_in_locale, EngineError, KeyboardLayout, Vocab,
+};
+use fst::Map;
+use std::collections::BTreeMap;
+
+fn main() {
+    println!("=== Test Lexicon FST ===");
+
+    // 1. Frequency list parsing
+    println!("\nParsing word<TAB>count lines...");
+    assert_eq!(parse_frequency_line("the\t5000"), Some(("the", 5000)));
+    assert_eq!(parse_frequency_line(" hello \t 12 "), Some(("hello", 12)));
+    assert_eq!(parse_frequency_line("no tab here"), None);
+    assert_eq!(parse_frequency_line("word\tmany"), None);
+    assert_eq!(parse_frequency_line("\t3"), None);
+
+    // 2. Value packing and prob quantization
+    println!("\nPacking values...");
+    assert_eq!(unpack_value(pack_value(200, 1, 123_456)), (200, 1, 123_456));
+    assert_eq!(quantize_prob(5000, 5000), 255);
+    assert_eq!(quantize_prob(1, 5000), 1, "Seen words stay suggestible");
+    assert_eq!(quantize_prob(0, 5000), 0);
+    assert!(quantize_prob(50, 5000) < quantize_prob(500, 5000));
+
+    // 3. Round-trip: get() returns each word's id and prob, ids index the vocab
+    println!("\nBuilding a small lexicon...");
+    let list = "the\t5000\nzebra\t3\nApple\t40\napple\t900\nof\t2500\nrare\t0\n";
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    for line in list.lines() {
+        let (word, count) = parse_frequency_line(line).unwrap();
+        *counts.entry(word.to_string()).or_insert(0) += count;
+    }
+    let max_count = *counts.values().max().unwrap();
+    let words: BTreeMap<String, u8> = counts
+        .iter()
+        .map(|(w, &c)| (w.clone(), quantize_prob(c, max_count)))
+        .collect();
+
+    let mut fst_bytes = Vec::new();
+    let mut vocab_bytes = Vec::new();
+    write_lexicon(&words, &mut fst_bytes, Some(&mut vocab_bytes)).unwrap();
+    let map = Map::new(fst_bytes.clone()).unwrap();
+    let vocab: Vec<String> = String::from_utf8(vocab_bytes.clone())
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    println!("Vocab: {:?}", vocab);
+    assert_eq!(map.len(), words.len());
+    assert_eq!(vocab.len(), words.len());
+
+    for (id, word) in vocab.iter().enumerate() {
+        let (prob, flags, word_id) = unpack_value(map.get(word).expect("vocab word in fst"));
+        assert_eq!(
+            word_id as usize, id,
+            "id of {} matches its vocab line",
+            word
+        );
+        assert_eq!(prob, words[word]);
+        assert_eq!(flags & FLAG_NOSUGGEST != 0, prob == 0);
+    }
+    assert_eq!(unpack_value(map.get("the").unwrap()).0, 255);
+    assert_eq!(unpack_value(map.get("rare").unwrap()).1, FLAG_NOSUGGEST);
+    assert!(map.get("missing").is_none());
+
+    // 4. The existing readers agree with the written files
+    println!("\nReading back through build_canonical_map...");
+    let dir = std::env::temp_dir();
+    let fst_path = dir.join(format!("{}_en.lex.fst", std::process::id()));
+    let vocab_path = dir.join(format!("{}_en.vocab.txt", std::process::id()));
+    std::fs::write(&fst_path, &fst_bytes).unwrap();
+    std::fs::write(&vocab_path, &vocab_bytes).unwrap();
+    let (fst_str, vocab_str) = (fst_path.to_str().unwrap(), vocab_path.to_str().unwrap());
+
+    let (vocab_size, canonical) = build_canonical_map(fst_str, vocab_str).unwrap();
+    assert_eq!(vocab_size as usize, vocab.len());
+    let apple = canonical["apple"] as usize;
+    assert_eq!(vocab[apple], "apple", "Exact lowercase wins over Apple");
+    let (exact_size, exact) = build_exact_map(fst_str, vocab_str).unwrap();
+    assert_eq!(exact_size, vocab_size);
+    assert_eq!(vocab[exact["Apple"] as usize], "Apple", "No case folding");
+    assert_eq!(exact["apple"] as usize, apple);
+    let top: Vec<&str> = top_unigrams(fst_str, 2)
+        .unwrap()
+        .iter()
+        .map(|&(id, _)| vocab[id as usize].as_str())
+        .collect();
+    assert_eq!(top, ["the", "of"]);
+
+    let interned = Vocab::load(vocab_str).unwrap();
+    assert_eq!(interned.len(), vocab.len());
+    for (id, word) in vocab.iter().enumerate() {
+        assert_eq!(interned.word(id as u32), Some(word.as_str()));
+    }
+    assert_eq!(interned.word(vocab.len() as u32), None);
+    assert!(interned.iter().eq(vocab.iter().map(String::as_str)));
+    assert_eq!(canonical_map_for(fst_str, &interned).unwrap(), canonical);
+
+    std::fs::remove_file(fst_path).unwrap();
+    std::fs::remove_file(vocab_path).unwrap();
+
+    // 5. Interned vocab splits lines like BufRead::lines
+    println!("\nInterning vocab text...");
+    let interned = Vocab::from_text("the\r\n\nÉcole\nof\n");
+    assert_eq!(interned.len(), 4);
+    assert_eq!(interned.word(0), Some("the"), "CRLF is stripped");
+    assert_eq!(interned.word(1), Some(""), "Blank lines keep their id");
+    assert_eq!(interned.word(2), Some("École"));
+    assert_eq!(interned.word(4), None, "No id for the trailing newline");
+    assert_eq!(interned, ["the", "", "École", "of"].into_iter().collect());
+    assert!(Vocab::from_text("").is_empty());
+
+    // 6. Rebuilding with the previous vocab keeps every known word's id
+    println!("\nRebuilding with kept ids...");
+    let previous = Vocab::from_text(std::str::from_utf8(&vocab_bytes).unwrap());
+    let mut updated = words.clone();
+    updated.remove("zebra");
+    updated.insert("aardvark".to_string(), 30);
+    updated.insert("xylophone".to_string(), 20);
+    let ids = assign_ids(&updated, Some(&previous));
+    assert!(ids.iter().take(previous.len()).eq(previous.iter()));
+    assert!(ids
+        .iter()
+        .skip(previous.len())
+        .eq(["aardvark", "xylophone"]));
+    assert_eq!(
+        assign_ids(&words, Some(&previous)),
+        previous,
+        "Same words, same ids"
+    );
+    assert!(assign_ids(&updated, None)
+        .iter()
+        .eq(updated.keys().map(String::as_str)));
+
+    let (mut kept_fst, mut kept_vocab) = (Vec::new(), Vec::new());
+    write_lexicon_with_ids(&updated, &ids, &mut kept_fst, Some(&mut kept_vocab)).unwrap();
+    let kept_map = Map::new(kept_fst).unwrap();
+    let kept_vocab = Vocab::from_text(std::str::from_utf8(&kept_vocab).unwrap());
+    assert_eq!(kept_vocab, ids);
+    for word in updated.keys() {
+        let id = unpack_value(kept_map.get(word).unwrap()).2;
+        assert_eq!(kept_vocab.word(id), Some(word.as_str()));
+        if let Some(old) = map.get(word) {
+            assert_eq!(id, unpack_value(old).2, "{} keeps its id", word);
+        }
+    }
+    assert!(
+        kept_map.get("zebra").is_none(),
+        "Dropped words leave the FST"
+    );
+    assert_eq!(kept_map.len(), updated.len());
+    let no_id: Vocab = ["the"].into_iter().collect();
+    assert!(write_lexicon_with_ids(&updated, &no_id, Vec::new(), None::<Vec<u8>>).is_err());
+
+    // The fingerprint follows words and order, not line endings
+    assert_eq!(
+        Vocab::from_text("a\r\nb\n").fingerprint(),
+        Vocab::from_text("a\nb").fingerprint()
+    );
+    assert_ne!(
+        Vocab::from_text("a\nb").fingerprint(),
+        Vocab::from_text("b\na").fingerprint()
+    );
+    assert_ne!(
+        Vocab::from_text("ab").fingerprint(),
+        Vocab::from_text("a\nb").fingerprint()
+    );
+    assert_ne!(ids.fingerprint(), assign_ids(&updated, None).fingerprint());
+
+    // 7. Fuzzy prefix completion tolerates one typo mid-word
+    println!("\nFuzzy prefix search...");
+    let words: BTreeMap<String, u8> = [
+        ("receive", 200),
+        ("received", 180),
+        ("recital", 90),
+        ("reciever", 0),
+        ("recieved", 40),
+        ("red", 220),
+        ("the", 255),
+    ]
+    .iter()
+    .map(|&(w, p)| (w.to_string(), p))
+    .collect();
+    let mut fst_bytes = Vec::new();
+    write_lexicon(&words, &mut fst_bytes, None::<Vec<u8>>).unwrap();
+    let map = Map::new(fst_bytes).unwrap();
+    let found = |prefix: &str, edits: u32, limit: usize| -> Vec<String> {
+        search_fst_fuzzy_prefix(&map, prefix, edits, limit)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.word)
+            .collect()
+    };
+
+    let recie = found("recie", 1, 10);
+    println!("recie~1: {:?}", recie);
+    assert!(
+        recie.contains(&"receive".to_string()),
+        "Typo still finds receive"
+    );
+    assert_eq!(recie[0], "recieved", "Exact prefix ranks first");
+    assert_eq!(recie[1..3], ["receive", "received"], "Then by prob");
+    assert!(
+        !recie.contains(&"reciever".to_string()),
+        "Nosuggest skipped"
+    );
+    assert!(!recie.contains(&"the".to_string()));
+    assert_eq!(found("recie", 1, 2).len(), 2, "Result count is capped");
+    assert_eq!(found("recie", 0, 10), ["recieved"], "No edits: exact only");
+    assert_eq!(
+        found("rx", 1, 10),
+        Vec::<String>::new(),
+        "Short prefix: exact only"
+    );
+    assert_eq!(
+        found("recie", 9, 10),
+        found("recie", 2, 10),
+        "Edit budget is capped"
+    );
+
+    // 8. The canonical map lowercases keys under the chosen locale
+    println!("\nBuilding a Turkish canonical map...");
+    let words: BTreeMap<String, u8> = [("DIŞ", 200), ("diş", 100), ("dış", 50), ("İzmir", 90)]
+        .into_iter()
+        .map(|(w, p)| (w.to_string(), p))
+        .collect();
+    let (mut fst_bytes, mut vocab_bytes) = (Vec::new(), Vec::new());
+    write_lexicon(&words, &mut fst_bytes, Some(&mut vocab_bytes)).unwrap();
+    let fst_path = dir.join(format!("{}_tr.lex.fst", std::process::id()));
+    std::fs::write(&fst_path, &fst_bytes).unwrap();
+    let fst_str = fst_path.to_str().unwrap();
+    let vocab = Vocab::from_text(std::str::from_utf8(&vocab_bytes).unwrap());
+    let word_of = |map: &std::collections::HashMap<String, u32>, key: &str| {
+        map.get(key)
+            .and_then(|&id| vocab.word(id))
+            .map(str::to_string)
+    };
+
+    let root = canonical_map_for(fst_str, &vocab).unwrap();
+    assert_eq!(word_of(&root, "diş").as_deref(), Some("diş"), "DIŞ merged");
+    assert_eq!(word_of(&root, "i\u{307}zmir").as_deref(), Some("İzmir"));
+    assert_eq!(root.get("izmir"), None);
+    let turkish = canonical_map_for_locale(fst_str, &vocab, Locale::Turkish).unwrap();
+    assert_eq!(
+        word_of(&turkish, "dış").as_deref(),
+        Some("dış"),
+        "DIŞ folds to dış, where the exact form wins"
+    );
+    assert_eq!(word_of(&turkish, "diş").as_deref(), Some("diş"));
+    assert_eq!(word_of(&turkish, "izmir").as_deref(), Some("İzmir"));
+    assert_eq!(
+        canonical_map_for_locale(fst_str, &vocab, Locale::Root).unwrap(),
+        root
+    );
+    std::fs::remove_file(fst_path).unwrap();
+
+    // Top unigrams fold case variants and apostrophes the same way
+    let words: BTreeMap<String, u8> = [("DIŞ", 200), ("dış", 50), ("don't", 30), ("don’t", 40)]
+        .into_iter()
+        .map(|(w, p)| (w.to_string(), p))
+        .collect();
+    let mut fst_bytes = Vec::new();
+    write_lexicon(&words, &mut fst_bytes, None::<Vec<u8>>).unwrap();
+    let fst = Map::new(fst_bytes).unwrap();
+    let top = |locale| -> Vec<String> {
+        top_unigrams_in_locale(&fst, 10, locale)
+            .into_iter()
+            .map(|(id, _)| words.keys().nth(id as usize).unwrap().clone())
+            .collect()
+    };
+    assert_eq!(top(Locale::Root), ["DIŞ", "dış", "don't"]);
+    assert_eq!(
+        top(Locale::Turkish),
+        ["dış", "don't"],
+        "DIŞ folds onto the exact dış"
+    );
+
+    // 9. Frequencies past 255 keep their order in the prob byte, and a line
+    // past the corpus length cap is skipped
+    println!("\nBuilding a lexicon from a combined word list...");
+    let overlong = format!(" word={},f=5", "x".repeat(DEFAULT_MAX_LINE_LEN));
+    let combined = [
+        "dictionary=main:en_us,locale=en_US",
+        " word=the,f=1200000",
+        " word=of,f=600000",
+        &overlong,
+        " word=apple,f=3000",
+        " word=zyzzyva,f=1",
+        " word=badword,f=0",
+    ]
+    .join("\n");
+    let input = dir.join(format!("{}_list.combined.gz", std::process::id()));
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut gz, combined.as_bytes()).unwrap();
+    std::fs::write(&input, gz.finish().unwrap()).unwrap();
+    let fst_path = dir.join(format!("{}_combined.lex.fst", std::process::id()));
+    let output =
+        std::process::Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+            .args(["run", "-q", "--bin", "combined2fst", "--manifest-path"])
+            .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+            .arg("--")
+            .args([&input, &fst_path])
+            .output()
+            .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let map = Map::new(std::fs::read(&fst_path).unwrap()).unwrap();
+    let prob = |word: &str| unpack_value(map.get(word).unwrap()).0;
+    println!(
+        "the={} of={} apple={} zyzzyva={}",
+        prob("the"),
+        prob("of"),
+        prob("apple"),
+        prob("zyzzyva")
+    );
+    assert_eq!(prob("the"), 255);
+    assert!(prob("the") > prob("of"), "Not both clamped to 255");
+    assert!(prob("of") > prob("apple"));
+    assert!(prob("apple") > prob("zyzzyva"));
+    assert_eq!(prob("zyzzyva"), 1, "Rare words stay suggestible");
+    assert_eq!(prob("badword"), 0, "f=0 is still nosuggest");
+    assert_eq!(map.len(), 5, "The overlong line is skipped");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 4 is longer than"));
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(fst_path).unwrap();
+
+    // 10. A vocab listing a word twice is refused, and deduplicated for rebuilds
+    println!("\nLoading a vocab with a repeated word...");
+    let path = std::env::temp_dir().join(format!("{}_dup.vocab.txt", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, "apple\nbanana\ncherry\nbanana\ndate\n").unwrap();
+    let err = Vocab::load(path).unwrap_err();
+    println!("Duplicate: {}", err);
+    assert!(matches!(
+        err,
+        EngineError::DuplicateWord { ref word, first: 1, id: 3, .. } if word == "banana"
+    ));
+    assert!(matches!(
+        build_exact_map("missing.lex.fst", path),
+        Err(EngineError::DuplicateWord { .. })
+    ));
+    let raw = Vocab::load_unchecked(path).unwrap();
+    assert_eq!(raw.len(), 5);
+    let (deduped, remap) = raw.dedup();
+    assert_eq!(
+        deduped.iter().collect::<Vec<_>>(),
+        ["apple", "banana", "cherry", "date"]
+    );
+    assert_eq!(remap, [0, 1, 2, 1, 3], "The repeat maps to the first line");
+    assert_eq!(deduped.first_duplicate(), None);
+    for (old, &new) in remap.iter().enumerate() {
+        assert_eq!(raw.word(old as u32), deduped.word(new));
+    }
+    let previous = load_previous_vocab(path).unwrap();
+    assert_eq!(previous, deduped);
+    let words: BTreeMap<String, u8> = ["banana", "elderberry"]
+        .into_iter()
+        .map(|w| (w.to_string(), 100))
+        .collect();
+    let ids = assign_ids(&words, Some(&previous));
+    assert_eq!(ids.first_duplicate(), None);
+    assert_eq!(ids.word(4), Some("elderberry"));
+    std::fs::remove_file(path).unwrap();
+
+    // 11. On a keyboard layout, an adjacent-key typo outranks a completion
+    // the same number of plain edits away
+    println!("\nRanking typos by keyboard distance...");
+    let words: BTreeMap<String, u8> = [("world", 100), ("wurld", 200), ("wprldly", 0)]
+        .iter()
+        .map(|&(w, p)| (w.to_string(), p))
+        .collect();
+    let mut fst_bytes = Vec::new();
+    write_lexicon(&words, &mut fst_bytes, None::<Vec<u8>>).unwrap();
+    let map = Map::new(fst_bytes).unwrap();
+    let qwerty = KeyboardLayout::qwerty();
+    let ranked = |layout: Option<&KeyboardLayout>| -> Vec<(String, f32)> {
+        search_fst_fuzzy_prefix_with(&map, "wprld", 1, 10, layout)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.word, m.cost))
+            .collect()
+    };
+    let plain = ranked(None);
+    println!("Plain: {:?}", plain);
+    assert_eq!(
+        plain,
+        [("wurld".to_string(), 1.0), ("world".to_string(), 1.0)]
+    );
+    let keyed = ranked(Some(&qwerty));
+    println!("QWERTY: {:?}", keyed);
+    assert_eq!(
+        keyed,
+        [("world".to_string(), 0.5), ("wurld".to_string(), 1.0)]
+    );
+    assert_eq!(
+        search_fst_fuzzy_prefix(&map, "wprld", 1, 10).unwrap(),
+        search_fst_fuzzy_prefix_with(&map, "wprld", 1, 10, None).unwrap()
+    );
+
+    assert!(qwerty.are_adjacent('o', 'p') && qwerty.are_adjacent('p', 'l'));
+    assert!(qwerty.are_adjacent('g', 'v') && qwerty.are_adjacent('g', 'b'));
+    assert!(!qwerty.are_adjacent('p', 'u') && !qwerty.are_adjacent('q', 'z'));
+    assert_eq!(qwerty.substitution_cost('a', 'a'), 0.0);
+    assert_eq!(qwerty.prefix_distance("wprld", "worldwide"), 0.5);
+    assert_eq!(qwerty.prefix_distance("wrld", "world"), 1.0, "A deletion");
+
+    // A custom table: AZERTY rows, plus one pair priced by hand
+    let mut azerty = KeyboardLayout::from_rows(&["azertyuiop", "qsdfghjklm", "wxcvbn"], 0.3);
+    azerty.set_cost('e', 'é', 0.1);
+    assert!(azerty.are_adjacent('a', 'q') && !azerty.are_adjacent('q', 'x'));
+    assert_eq!(azerty.substitution_cost('é', 'e'), 0.1);
+    let custom: Vec<String> = search_fst_fuzzy_prefix_with(&map, "wprld", 1, 10, Some(&azerty))
+        .unwrap()
+        .into_iter()
+        .map(|m| m.word)
+        .collect();
+    assert_eq!(custom, ["world", "wurld"], "o and p touch on AZERTY too");
+
+    println!("\nPASSED all tests!");
+}
@@ -1,6 +1,11 @@
-mod user_history;
+use combined2fst::tokenize::{Script, ScriptLetters};
+use combined2fst::user_history::{
+    EdgeStat, HistoryConfig, UserHistory, UserLexiconFst, WordStat, HISTORY_VERSION,
+};
+use combined2fst::{write_atomic, HistoryScore, Score};
 use std::collections::HashMap;
-use user_history::UserHistory;
+use std::io::Write;
+use unicode_normalization::UnicodeNormalization;
 
 fn main() {
     println!("=== Test User History (Robust) ===");
@@ -38,7 +43,7 @@ fn main() {
     let suggestions = history.predict(104);
     println!("Suggestions after 'is' (104): {:?}", suggestions);
 
-    let (gox_id, score1) = suggestions.get(0).expect("Should have suggestion");
+    let (gox_id, score1) = suggestions.first().expect("Should have suggestion");
     assert!(*gox_id >= 0x80000000, "Gox should have User ID");
 
     let word = history
@@ -68,7 +73,7 @@ fn main() {
         let after_i = history.predict(i_id);
         println!("Suggestions after 'i': {:?}", after_i);
         // Expect "don't" (normalized)
-        if let Some((dont_id, _)) = after_i.get(0) {
+        if let Some((dont_id, _)) = after_i.first() {
             let w = history.get_user_word(*dont_id).unwrap();
             println!("After 'i' -> '{}'", w);
             assert_eq!(w, "don't");
@@ -91,5 +96,560 @@ fn main() {
     println!("Found: '{}'", w);
     assert_eq!(w, "nhỉiii");
 
+    // 7. Test configurable half-life
+    println!("\nComparing short vs long lexicon half-life...");
+    let day = 24 * 3600;
+    let now = 100 * day;
+    let short = HistoryConfig {
+        hl_lexicon_sec: day as f64,
+        ..HistoryConfig::default()
+    };
+    let long = HistoryConfig::default(); // 14 days
+
+    // Same access pattern: used 5 times, last touched 3 days ago
+    let mut stat = WordStat::default();
+    for _ in 0..5 {
+        stat.touch_commit(now - 3 * day);
+    }
+    let short_score = stat.score(now, &short);
+    let long_score = stat.score(now, &long);
+    println!(
+        "Short HL score: {}, Long HL score: {}",
+        short_score, long_score
+    );
+    assert!(
+        short_score < long_score,
+        "Short half-life should forget faster"
+    );
+
+    let tuned = UserHistory::new_with_config(short);
+    assert_eq!(*tuned.config(), short);
+
+    // 8. Merge two device histories whose user ids collide
+    println!("\nMerging device + cloud histories...");
+    let no_global = |_: &str| None;
+    let mut device = UserHistory::new();
+    device.learn("gox bar", no_global);
+    let mut cloud = UserHistory::new();
+    cloud.learn("qux", no_global); // shifts cloud's ids so they clash with device's
+    cloud.learn("gox baz", no_global);
+
+    // Round-trip through disk like a real sync
+    let dir = std::env::temp_dir();
+    let device_path = dir.join(format!("{}_device.json", std::process::id()));
+    let cloud_path = dir.join(format!("{}_cloud.json", std::process::id()));
+    device.save(device_path.to_str().unwrap()).unwrap();
+    cloud.save(cloud_path.to_str().unwrap()).unwrap();
+    let mut merged = UserHistory::load(device_path.to_str().unwrap()).unwrap();
+    let cloud = UserHistory::load(cloud_path.to_str().unwrap()).unwrap();
+    merged.merge(&cloud, now);
+
+    let gox = merged.get_user_word_id("gox").expect("gox survives");
+    assert_eq!(
+        gox,
+        device.get_user_word_id("gox").unwrap(),
+        "Shared word keeps local id"
+    );
+    let after_gox: Vec<&str> = merged
+        .predict(gox)
+        .iter()
+        .map(|&(id, _)| merged.get_user_word(id).unwrap())
+        .collect();
+    println!("After 'gox': {:?}", after_gox);
+    assert!(after_gox.contains(&"bar"), "Device edge should survive");
+    assert!(after_gox.contains(&"baz"), "Cloud edge should survive");
+
+    let ids: Vec<u32> = ["gox", "bar", "baz", "qux"]
+        .iter()
+        .map(|w| merged.get_user_word_id(w).expect("every word survives"))
+        .collect();
+    let mut unique = ids.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), ids.len(), "Merged words need distinct ids");
+
+    // Shared word stats are summed
+    let gox_score = merged.lookup_prefix("gox", 1)[0].1;
+    let single_score = device.lookup_prefix("gox", 1)[0].1;
+    assert!(gox_score > single_score, "Merged freq should add up");
+
+    // 9. An accepted suggestion outranks a word typed the same number of times
+    println!("\nAccepting a suggestion...");
+    let mut history = UserHistory::new();
+    history.learn("zap zed", global_lookup);
+    let zap = history.get_user_word_id("zap").unwrap();
+    let zed = history.get_user_word_id("zed").unwrap();
+    history.learn("is zap", global_lookup);
+    history.accept_suggestion(104, zed);
+
+    let after_is = history.predict(104);
+    println!("After 'is': {:?}", after_is);
+    assert_eq!(after_is[0].0, zed, "Accepted edge should rank first");
+    assert!(after_is.iter().any(|&(id, _)| id == zap));
+    let by_prefix = history.lookup_prefix("z", 2);
+    println!("Prefix 'z': {:?}", by_prefix);
+    assert_eq!(by_prefix[0].0, zed, "Accepted word should rank first");
+
+    // Accepting a global word only touches the edge
+    history.accept_suggestion(zed, 101);
+    assert_eq!(history.predict(zed)[0].0, 101);
+    assert!(history.get_user_word(101).is_none());
+
+    // 10. Past max_prevs the least recently used prev is evicted
+    println!("\nCapping prev trackers...");
+    let capped = HistoryConfig {
+        max_prevs: 2,
+        ..HistoryConfig::default()
+    };
+    let mut history = UserHistory::new_with_config(capped);
+    history.learn_at("one two", no_global, 100);
+    history.learn_at("three four", no_global, 200);
+    history.learn_at("one two", no_global, 300); // "one" is recent again
+    assert_eq!(history.prev_count(), 2);
+    history.learn_at("five six", no_global, 400);
+    assert_eq!(history.prev_count(), 2, "Cap should hold");
+
+    let prev = |w: &str| history.get_user_word_id(w).unwrap();
+    assert!(
+        history.predict(prev("three")).is_empty(),
+        "Oldest prev should be evicted"
+    );
+    assert!(
+        !history.predict(prev("one")).is_empty(),
+        "Refreshed prev stays"
+    );
+    assert!(
+        !history.predict(prev("five")).is_empty(),
+        "Newest prev stays"
+    );
+    assert!(
+        history.get_user_word_id("four").is_some(),
+        "Only the edges go, not the word"
+    );
+
+    let old_file = r#"{"lexicon":{"word_to_id":{},"id_to_meta":{},"next_id":2147483648},"bigrams":{},"config":{"hl_lexicon_sec":60.0}}"#;
+    let loaded: UserHistory = serde_json::from_str(old_file).unwrap();
+    assert_eq!(
+        loaded.config().max_prevs,
+        HistoryConfig::default().max_prevs
+    );
+
+    // 11. Saves go through a temp file, so a failed write keeps the old file
+    println!("\nTesting atomic save...");
+    let dir = std::env::temp_dir().join(format!("{}_history_save", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("history.json");
+    let path = path.to_str().unwrap();
+    history.save(path).unwrap();
+    let saved = std::fs::read(path).unwrap();
+    let reloaded = UserHistory::load(path).unwrap();
+    assert_eq!(reloaded.prev_count(), history.prev_count());
+
+    let err = write_atomic(path, |writer| {
+        writer.write_all(b"{\"lexicon\":")?;
+        anyhow::bail!("serialization failed halfway")
+    });
+    assert!(err.is_err());
+    assert_eq!(std::fs::read(path).unwrap(), saved, "Original is intact");
+    let leftovers: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(leftovers.len(), 1, "Temp file is removed");
+
+    // A corrupt file loads as an empty history instead of failing
+    std::fs::write(path, &saved[..saved.len() / 2]).unwrap();
+    let recovered = UserHistory::load(path).unwrap();
+    assert_eq!(recovered.prev_count(), 0);
+    assert!(
+        UserHistory::load(dir.to_str().unwrap()).is_err(),
+        "Unreadable is still an error"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // 12. Forgetting a word removes it, its edges and frees its id
+    println!("\nForgetting a word...");
+    let mut history = UserHistory::new();
+    history.learn("my name is Zork and Zork likes hello", global_lookup);
+    let zork = history.get_user_word_id("zork").unwrap();
+    let likes = history.get_user_word_id("likes").unwrap();
+    assert!(history.predict(104).iter().any(|&(id, _)| id == zork));
+    assert!(!history.predict(zork).is_empty());
+
+    assert!(history.forget("Zork"), "Normalized like learned text");
+    assert_eq!(history.get_user_word_id("zork"), None);
+    assert_eq!(history.get_user_word(zork), None);
+    assert!(
+        history.predict(104).is_empty(),
+        "is -> zork was its only edge"
+    );
+    assert!(history.predict(zork).is_empty());
+    assert!(history.lookup_prefix("zo", 10).is_empty());
+    assert!(
+        history.predict(likes).iter().any(|&(id, _)| id == 100),
+        "Unrelated edges stay"
+    );
+
+    assert!(!history.forget("zork"), "Idempotent");
+    assert!(!history.forget("hello"), "Global words have no entry");
+    assert!(!history.forget("never-seen"));
+
+    // The freed id is handed out again, with none of the old edges
+    history.learn("quux", global_lookup);
+    assert_eq!(history.get_user_word_id("quux"), Some(zork));
+    assert!(history.predict(zork).is_empty());
+    assert_eq!(history.lookup_prefix("qu", 10).len(), 1);
+
+    // 13. Raw scores keep growing with accepts; the clamped score saturates
+    println!("\nComparing raw and clamped scores...");
+    let cfg = HistoryConfig::default();
+    let mut stat = WordStat::default();
+    let mut raw = Vec::new();
+    for _ in 0..40 {
+        stat.touch_accept(now);
+        raw.push(stat.score_raw(now, &cfg));
+    }
+    println!(
+        "Raw after 1, 20, 40 accepts: {:.0}, {:.0}, {:.0}",
+        raw[0], raw[19], raw[39]
+    );
+    assert!(
+        raw.windows(2).all(|w| w[1] > w[0]),
+        "Raw grows every accept"
+    );
+    assert!(raw[39] > 2.0 * 65535.0);
+    assert_eq!(
+        stat.score(now, &cfg),
+        HistoryScore(65535),
+        "Clamped to the global range"
+    );
+    let wide = HistoryConfig {
+        max_score: 1e9,
+        ..HistoryConfig::default()
+    };
+    assert_eq!(
+        stat.score(now, &wide),
+        HistoryScore(raw[39] as u32),
+        "Wider clamp"
+    );
+
+    let mut edge = EdgeStat::default();
+    edge.touch(now, u32::MAX / 2);
+    let tight = HistoryConfig {
+        max_score: 1000.0,
+        ..HistoryConfig::default()
+    };
+    assert!(edge.score_raw(now, &cfg) > 200_000.0);
+    assert_eq!(edge.score(now, &cfg), HistoryScore(65535));
+    assert_eq!(edge.score(now, &tight), HistoryScore(1000));
+
+    // 14. Prefix lookups through a compiled lexicon FST match the scan
+    println!("\nLooking up prefixes through a compiled lexicon...");
+    let no_global = |_: &str| None;
+    let mut scanned = UserHistory::new();
+    scanned.learn(
+        "zorb zorbing zorba zoo zooey zebu quux zorb zorbing zorb",
+        no_global,
+    );
+    let fst_path = std::env::temp_dir().join(format!("{}_user.lex.fst", std::process::id()));
+    let fst_path = fst_path.to_str().unwrap();
+    scanned.compile_lexicon(fst_path).unwrap();
+    let json_path = format!("{}.json", fst_path);
+    scanned.save(&json_path).unwrap();
+    let mut indexed = UserHistory::load(&json_path).unwrap();
+    let fst = UserLexiconFst::open(fst_path).unwrap();
+    assert_eq!(fst.len(), 7);
+    assert_eq!(fst.get("zebu"), scanned.get_user_word_id("zebu"));
+    indexed.attach_lexicon_fst(fst);
+    assert_eq!(indexed.unindexed_words(), 0);
+    let prefixes = ["z", "zo", "zor", "zorb", "zorbi", "q", "x", "Zoo"];
+    for prefix in prefixes {
+        assert_eq!(
+            indexed.lookup_prefix(prefix, 10),
+            scanned.lookup_prefix(prefix, 10),
+            "prefix {:?}",
+            prefix
+        );
+    }
+    println!("'zo' -> {:?}", indexed.lookup_prefix("zo", 10));
+
+    // Words learned or forgotten after the compile are still right
+    for history in [&mut scanned, &mut indexed] {
+        history.learn("zorro zorro", no_global);
+        history.forget("zoo");
+        history.learn("zonk", no_global);
+    }
+    assert_eq!(indexed.unindexed_words(), 2, "zorro and zonk");
+    assert_eq!(
+        indexed.get_user_word_id("zonk"),
+        scanned.get_user_word_id("zonk")
+    );
+    for prefix in prefixes {
+        assert_eq!(
+            indexed.lookup_prefix(prefix, 10),
+            scanned.lookup_prefix(prefix, 10),
+            "prefix {:?} after edits",
+            prefix
+        );
+    }
+    assert!(indexed
+        .lookup_prefix("zoo", 10)
+        .iter()
+        .all(|&(id, _)| indexed.get_user_word(id) != Some("zoo")));
+    std::fs::remove_file(fst_path).unwrap();
+    std::fs::remove_file(json_path).unwrap();
+
+    // 15. Importing a file learns its bigrams, sentence by sentence
+    println!("\nImporting a notes file...");
+    let notes_path = std::env::temp_dir().join(format!("{}_notes.txt", std::process::id()));
+    std::fs::write(
+        &notes_path,
+        "Hello world. My name is Zed\nzed likes kotlin! hello zed\r\nqux quux corge\n",
+    )
+    .unwrap();
+    let notes_path = notes_path.to_str().unwrap();
+    let mut imported = UserHistory::new();
+    let stats = imported
+        .learn_file_capped(notes_path, global_lookup, 3)
+        .unwrap();
+    println!("Import: {:?}", stats);
+    assert_eq!(stats.lines, 3);
+    assert_eq!(stats.new_words, 3, "zed, likes, kotlin; the cap stops qux");
+    assert_eq!(stats.skipped, 3);
+    assert_eq!(stats.words, 11, "Skipped words are not counted");
+    let zed = imported.get_user_word_id("zed").unwrap();
+    let likes = imported.get_user_word_id("likes").unwrap();
+    assert!(imported.predict(100).iter().any(|&(id, _)| id == 101));
+    assert!(imported.predict(100).iter().any(|&(id, _)| id == zed));
+    assert!(imported.predict(104).iter().any(|&(id, _)| id == zed));
+    assert!(imported.predict(zed).iter().any(|&(id, _)| id == likes));
+    assert!(
+        imported.predict(101).is_empty(),
+        "No edge across \"world.\""
+    );
+    let kotlin = imported.get_user_word_id("kotlin").unwrap();
+    assert!(
+        imported.predict(kotlin).is_empty(),
+        "Nor across \"kotlin!\""
+    );
+    assert_eq!(imported.get_user_word_id("qux"), None);
+
+    // The same text learned line by line gives the same history
+    let mut learned = UserHistory::new();
+    for sentence in [
+        "Hello world.",
+        "My name is Zed",
+        "zed likes kotlin!",
+        "hello zed",
+    ] {
+        learned.learn(sentence, global_lookup);
+    }
+    for id in [100, 101, 102, 103, 104, zed, likes, kotlin] {
+        let ids = |h: &UserHistory| {
+            h.predict(id)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<std::collections::BTreeSet<_>>()
+        };
+        // Scores may differ by recency; the edges may not
+        assert_eq!(ids(&imported), ids(&learned), "Edges after {}", id);
+    }
+    let default_cap = UserHistory::new()
+        .learn_file(notes_path, global_lookup)
+        .unwrap();
+    assert_eq!((default_cap.new_words, default_cap.skipped), (6, 0));
+    assert!(UserHistory::new()
+        .learn_file("/nonexistent/notes.txt", global_lookup)
+        .is_err());
+    std::fs::remove_file(notes_path).unwrap();
+
+    // 16. A personal trigram is predicted over its bigram backoff
+    println!("\nLearning trigrams...");
+    let trigrams = HistoryConfig {
+        learn_trigrams: true,
+        ..HistoryConfig::default()
+    };
+    let mut history = UserHistory::new_with_config(trigrams);
+    for _ in 0..3 {
+        history.learn("i want to go", no_global);
+    }
+    for _ in 0..5 {
+        history.learn("you need to eat", no_global);
+    }
+    let id = |h: &UserHistory, w: &str| h.get_user_word_id(w).unwrap();
+    let (want, to, go, eat) = (
+        id(&history, "want"),
+        id(&history, "to"),
+        id(&history, "go"),
+        id(&history, "eat"),
+    );
+    assert_eq!(history.predict(to)[0].0, eat, "The bigram prefers eat");
+    let after_want_to = history.predict_trigram(want, to);
+    println!("After \"want to\": {:?}", after_want_to);
+    assert_eq!(after_want_to[0].0, go, "The trigram prefers go");
+    assert_eq!(after_want_to[1].0, eat, "Padded with the bigram");
+    assert_eq!(after_want_to.len(), 2, "No duplicates");
+    let need = id(&history, "need");
+    assert_eq!(history.predict_trigram(need, to)[0].0, eat);
+    let i = id(&history, "i");
+    assert_eq!(
+        history.predict_trigram(i, to),
+        history.predict(to),
+        "An unseen pair backs off to the bigram"
+    );
+    assert_eq!(history.trigram_context_count(), 4);
+
+    // Sentence boundaries break trigram contexts too
+    let mut session = history.session(no_global);
+    session.commit_word("want");
+    session.reset();
+    session.commit_word("to");
+    session.commit_word("go");
+    assert_eq!(history.trigram_context_count(), 4);
+
+    let path = std::env::temp_dir().join(format!("{}_trigrams.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    history.save(path).unwrap();
+    let loaded = UserHistory::load(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.predict_trigram(want, to)[0].0, go);
+
+    // Forgetting a word drops the contexts it is part of
+    history.forget("want");
+    assert_eq!(
+        history.trigram_context_count(),
+        2,
+        "(i, want) and (want, to) go"
+    );
+    assert_eq!(history.predict_trigram(want, to), history.predict(to));
+
+    // Off by default
+    let mut plain = UserHistory::new();
+    plain.learn("i want to go", no_global);
+    assert_eq!(plain.trigram_context_count(), 0);
+
+    // Capped like the bigram trackers
+    let capped = HistoryConfig {
+        max_trigram_contexts: 2,
+        ..trigrams
+    };
+    let mut history = UserHistory::new_with_config(capped);
+    history.learn_at("a b c", no_global, 100);
+    history.learn_at("d e f", no_global, 200);
+    history.learn_at("g h i", no_global, 300);
+    assert_eq!(history.trigram_context_count(), 2, "Cap should hold");
+    let (a, b, c) = (id(&history, "a"), id(&history, "b"), id(&history, "c"));
+    assert_eq!(
+        history.predict_trigram(a, b),
+        history.predict(b),
+        "Oldest context should be evicted"
+    );
+    assert_eq!(history.predict(b)[0].0, c, "Its bigram stays");
+
+    // 17. A version 1 file (untagged, no config, free ids or trigrams)
+    // migrates on load instead of being discarded
+    println!("\nMigrating a version 1 history file...");
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let v1_file = format!(
+        r#"{{"lexicon":{{"word_to_id":{{"zed":2147483648,"kotlin":2147483649}},
+        "id_to_meta":{{"2147483648":["zed",{{"freq":4,"accept":1,"last_used":{now}}}],
+        "2147483649":["kotlin",{{"freq":2,"accept":0,"last_used":{now}}}]}},
+        "next_id":2147483650}},
+        "bigrams":{{"100":{{"counts":{{"2147483648":{{"count":3,"last_used":{now}}}}}}},
+        "2147483648":{{"counts":{{"2147483649":{{"count":2,"last_used":{now}}},
+        "101":{{"count":1,"last_used":{now}}}}}}}}}}}"#
+    );
+    let v1_path = std::env::temp_dir().join(format!("{}_v1.json", std::process::id()));
+    let v1_path = v1_path.to_str().unwrap();
+    std::fs::write(v1_path, &v1_file).unwrap();
+    let mut migrated = UserHistory::load(v1_path).unwrap();
+    let zed = migrated.get_user_word_id("zed").unwrap();
+    let kotlin = migrated.get_user_word_id("kotlin").unwrap();
+    assert_eq!((zed, kotlin), (2147483648, 2147483649), "Ids are kept");
+    assert_eq!(migrated.predict(100)[0].0, zed);
+    let after_zed: Vec<u32> = migrated.predict(zed).iter().map(|&(id, _)| id).collect();
+    assert_eq!(after_zed, [kotlin, 101]);
+    let zed_score = migrated.lookup_prefix("ze", 10)[0];
+    assert_eq!(zed_score.0, zed);
+    assert!(zed_score.1 > HistoryScore(0), "Word stats survive");
+    assert_eq!(
+        migrated.config().max_prevs,
+        HistoryConfig::default().max_prevs
+    );
+    assert_eq!(migrated.trigram_context_count(), 0);
+    assert_eq!(
+        migrated.session(no_global).commit_word("new"),
+        Some(2147483650),
+        "Allocation carries on after the old ids"
+    );
+
+    // Saving tags the file with the current version
+    migrated.save(v1_path).unwrap();
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(v1_path).unwrap()).unwrap();
+    assert_eq!(saved["version"], HISTORY_VERSION);
+    let reloaded = UserHistory::load(v1_path).unwrap();
+    assert_eq!(reloaded.get_user_word_id("new"), Some(2147483650));
+    assert_eq!(reloaded.predict(zed), migrated.predict(zed));
+
+    // A file from a newer version is an error, not an empty history
+    let future = v1_file.replacen('{', r#"{"version":99,"#, 1);
+    std::fs::write(v1_path, future).unwrap();
+    let err = UserHistory::load(v1_path)
+        .err()
+        .expect("Newer file should fail");
+    println!("Future file: {}", err);
+    assert!(err.to_string().contains("version 99"));
+    std::fs::remove_file(v1_path).unwrap();
+    // 18. A raised max_score keeps strong scores apart past the global range
+    println!("\nRanking wide scores...");
+    let mut history = UserHistory::new_with_config(HistoryConfig {
+        max_score: 1e9,
+        ..HistoryConfig::default()
+    });
+    history.learn("zap zed", global_lookup);
+    history.learn("zap zork", global_lookup);
+    let zap = history.get_user_word_id("zap").unwrap();
+    let zed = history.get_user_word_id("zed").unwrap();
+    let zork = history.get_user_word_id("zork").unwrap();
+    for accepts in 0..400 {
+        history.accept_suggestion(zap, zork);
+        if accepts < 300 {
+            history.accept_suggestion(zap, zed);
+        }
+    }
+    let after_zap = history.predict(zap);
+    println!("After 'zap': {:?}", after_zap);
+    let ids: Vec<u32> = after_zap.iter().map(|&(id, _)| id).collect();
+    assert_eq!(ids, [zork, zed], "Ranked on the wide scores, not on id");
+    assert!(
+        after_zap[1].1 > HistoryScore(65535),
+        "Past the global range"
+    );
+    assert!(after_zap[0].1 > after_zap[1].1, "The scores don't tie");
+    assert_eq!(after_zap[1].1.to_score(), Score::MAX, "Narrowed to blend");
+    let top = history.top_words(2);
+    println!("Top words: {:?}", top);
+    assert_eq!(top[0].0, zork);
+    assert!(top[0].1 > top[1].1 && top[1].1 > HistoryScore(65535));
+    // 19. Words are normalized by the configured normalizer, LettersOnly by default
+    println!("\nNormalizing with a configured normalizer...");
+    let decomposed: String = "tiếng việt".nfd().collect();
+    let mut plain = UserHistory::new();
+    plain.learn(&decomposed, global_lookup);
+    assert!(plain.get_user_word_id("tieng").is_some(), "Marks dropped");
+    let mut vietnamese = UserHistory::new();
+    vietnamese.set_normalizer(ScriptLetters::new(Script::Vietnamese));
+    vietnamese.learn(&decomposed, global_lookup);
+    let tieng = vietnamese
+        .get_user_word_id("tiếng")
+        .expect("Composed and kept");
+    assert_eq!(
+        vietnamese.lookup_prefix(&"tiế".nfd().collect::<String>(), 1)[0].0,
+        tieng
+    );
+    assert!(vietnamese.forget(&decomposed[..decomposed.find(' ').unwrap()]));
+    assert_eq!(vietnamese.get_user_word_id("tiếng"), None);
+
     println!("\nPASSED all tests!");
 }
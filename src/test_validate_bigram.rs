@@ -0,0 +1,345 @@
+use combined2fst::bigram::{write_bigram, EdgeValues, EDGE_FLAG_USER};
+use combined2fst::{
+    parse_header, read_headers, validate_bigram, validate_bigram_file, BigramModel, EngineError,
+    TrigramCache,
+};
+
+/// Serialize a BGRM file from per-prev edge lists: rows[prev] = [(next_id, weight)]
+fn build_model(rows: &[Vec<(u32, u16)>]) -> Vec<u8> {
+    let edges_count: usize = rows.iter().map(|r| r.len()).sum();
+    let mut out = Vec::new();
+    out.extend_from_slice(&0x4247524Du32.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&(rows.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(edges_count as u32).to_le_bytes());
+    out.extend_from_slice(&10u32.to_le_bytes());
+    out.extend_from_slice(&[0u8; 12]);
+
+    let mut offset = 0u32;
+    for row in rows {
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&(row.len() as u16).to_le_bytes());
+        out.extend_from_slice(&[0u8; 2]);
+        offset += (row.len() * 8) as u32;
+    }
+    for row in rows {
+        for (next_id, weight) in row {
+            out.extend_from_slice(&next_id.to_le_bytes());
+            out.extend_from_slice(&weight.to_le_bytes());
+            out.extend_from_slice(&[0u8; 2]);
+        }
+    }
+    out
+}
+
+fn main() {
+    println!("=== Test Bigram Validation ===");
+
+    let rows = vec![
+        vec![(1, 65535), (2, 40000)],
+        vec![],
+        vec![(0, 65535), (1, 100), (3, 50)],
+        vec![(2, 65535)],
+    ];
+
+    // 1. A well-formed model passes
+    println!("\nValidating well-formed model...");
+    let good = build_model(&rows);
+    let report = validate_bigram(&good);
+    println!("{:?}", report);
+    assert!(report.all_pass());
+    assert_eq!(report.vocab_size, 4);
+    assert_eq!(report.edges_count, 6);
+    assert_eq!(report.lens, vec![2, 0, 3, 1]);
+    assert_eq!((report.word_id_bits, report.weight_bits), (32, 16));
+
+    // A v2 header with recorded widths passes; an unsupported width fails
+    let mut v2 = good.clone();
+    v2[4..8].copy_from_slice(&2u32.to_le_bytes());
+    v2[20..24].copy_from_slice(&20u32.to_le_bytes());
+    v2[24..28].copy_from_slice(&16u32.to_le_bytes());
+    let report = validate_bigram(&v2);
+    assert!(report.all_pass());
+    assert_eq!(report.word_id_bits, 20);
+    v2[24..28].copy_from_slice(&32u32.to_le_bytes());
+    assert!(!validate_bigram(&v2).version_ok);
+
+    // 2. Wrong magic
+    println!("\nCorrupting magic...");
+    let mut bad = good.clone();
+    bad[0..4].copy_from_slice(b"XXXX");
+    let report = validate_bigram(&bad);
+    assert!(!report.magic_ok);
+    assert!(!report.all_pass());
+
+    // 3. Out-of-bounds offset for prev 3
+    println!("Corrupting offset...");
+    let mut bad = good.clone();
+    let idx = 32 + 3 * 8;
+    bad[idx..idx + 4].copy_from_slice(&1_000_000u32.to_le_bytes());
+    let report = validate_bigram(&bad);
+    assert_eq!(report.offset_errors, 1);
+    assert!(!report.all_pass());
+
+    // 4. Unsorted weights
+    println!("Unsorting weights...");
+    let bad = build_model(&[vec![(1, 100), (2, 65535)], vec![], vec![], vec![]]);
+    let report = validate_bigram(&bad);
+    assert_eq!(report.sorted_errors, 1);
+    assert!(!report.all_pass());
+
+    // 5. Duplicate next_id
+    println!("Duplicating next_id...");
+    let bad = build_model(&[vec![(1, 65535), (1, 100)], vec![]]);
+    let report = validate_bigram(&bad);
+    assert_eq!(report.duplicate_errors, 1);
+
+    // 6. Truncated file
+    println!("Truncating file...");
+    let report = validate_bigram(&good[..good.len() - 8]);
+    assert!(!report.size_ok);
+    assert!(report.offset_errors > 0);
+    let report = validate_bigram(&good[..4]);
+    assert!(!report.all_pass());
+
+    // 7. Header parsing errors out on short or foreign buffers instead of panicking
+    println!("Parsing short and foreign headers...");
+    let header = parse_header(&good).unwrap();
+    assert_eq!(
+        (header.version, header.vocab_size, header.edges_count),
+        (1, 4, 6)
+    );
+    let err = parse_header(&good[..4]).unwrap_err();
+    println!("4-byte buffer: {}", err);
+    assert!(err.to_string().contains("truncated"));
+    assert!(matches!(err, EngineError::Truncated { need: 32, have: 4 }));
+    assert!(matches!(
+        parse_header(&[]),
+        Err(EngineError::Truncated { have: 0, .. })
+    ));
+    let mut foreign = good.clone();
+    foreign[0..4].copy_from_slice(b"XXXX");
+    assert!(
+        matches!(parse_header(&foreign), Err(EngineError::BadMagic { .. })),
+        "Bad magic is rejected"
+    );
+
+    let path = std::env::temp_dir().join(format!("{}_short.bigram.bin", std::process::id()));
+    std::fs::write(&path, &good[..4]).unwrap();
+    assert!(BigramModel::open(path.to_str().unwrap()).is_err());
+
+    // 8. Edges past the vocab are caught when validating and when building
+    println!("\nChecking next_ids against the vocab size...");
+    let bad = build_model(&[vec![(1, 65535), (7, 100)], vec![(4, 65535)], vec![], vec![]]);
+    let report = validate_bigram(&bad);
+    assert_eq!(report.range_errors, 2, "7 and 4 are past a 4-word vocab");
+    assert!(report.offset_errors == 0 && report.size_ok);
+    assert!(!report.all_pass());
+    assert_eq!(validate_bigram(&good).range_errors, 0);
+
+    let index = [(0, 2, 0), (16, 1, 0), (24, 0, 0), (24, 0, 0)];
+    let mut out = Vec::new();
+    let err = write_bigram(
+        &mut out,
+        4,
+        0,
+        10,
+        &index,
+        EdgeValues::Weights(&[(1, 65535), (7, 100), (4, 65535)]),
+    )
+    .unwrap_err();
+    println!("Build: {}", err);
+    assert!(err.to_string().contains("2 of 3 edges"));
+    assert!(out.is_empty(), "Nothing written");
+    let err = write_bigram(
+        &mut out,
+        4,
+        0,
+        10,
+        &index,
+        EdgeValues::RawCounts(&[(1, 9), (2, 3), (4, 1)]),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("1 of 3 edges"));
+    write_bigram(
+        &mut out,
+        4,
+        0,
+        10,
+        &index,
+        EdgeValues::Weights(&[(1, 65535), (3, 100), (0, 65535)]),
+    )
+    .unwrap();
+    assert!(validate_bigram(&out).all_pass());
+
+    // 9. Nonzero reserved bytes are flagged: header bytes at open, edge bits when validating
+    println!("\nChecking reserved bytes...");
+    let mut v1 = good.clone();
+    v1[25] = 1;
+    match parse_header(&v1) {
+        Err(EngineError::ReservedNonZero { offset }) => assert_eq!(offset, 25),
+        other => panic!("expected ReservedNonZero, got {:?}", other),
+    }
+    assert!(BigramModel::from_bytes(v1.clone()).is_err());
+    let report = validate_bigram(&v1);
+    assert_eq!(report.reserved_errors, 1);
+    assert!(!report.all_pass());
+
+    let index = [(0, 2, 0), (16, 0, 0), (16, 0, 0), (16, 0, 0)];
+    let mut current = Vec::new();
+    write_bigram(
+        &mut current,
+        4,
+        0,
+        10,
+        &index,
+        EdgeValues::Flagged(&[(1, 65535, EDGE_FLAG_USER), (2, 100, 0)]),
+    )
+    .unwrap();
+    assert!(validate_bigram(&current).all_pass(), "Known flags");
+    let edges_base = 40 + 4 * 8;
+    current[edges_base + 8 + 7] = 0x80;
+    let report = validate_bigram(&current);
+    assert_eq!(report.reserved_errors, 1, "Unknown edge flag bit");
+    assert!(!report.all_pass());
+
+    let mut trigram = Vec::new();
+    for field in [0x54524743u32, 1, 1, 10] {
+        trigram.extend_from_slice(&field.to_le_bytes());
+    }
+    trigram.extend_from_slice(&[0u8; 16]);
+    for field in [0u32, 1, 0] {
+        trigram.extend_from_slice(&field.to_le_bytes());
+    }
+    trigram.extend_from_slice(&[1, 0, 0, 0]);
+    trigram.extend_from_slice(&[2, 0, 0, 0, 0xFF, 0xFF, 0, 0]);
+    let cache = TrigramCache::from_bytes(trigram.clone()).unwrap();
+    assert_eq!(cache.reserved_errors(), 0);
+    let mut bad_edge = trigram.clone();
+    bad_edge[32 + 16 + 6] = 1;
+    assert_eq!(
+        TrigramCache::from_bytes(bad_edge)
+            .unwrap()
+            .reserved_errors(),
+        1
+    );
+    trigram[20] = 1;
+    assert!(matches!(
+        TrigramCache::from_bytes(trigram),
+        Err(EngineError::ReservedNonZero { offset: 20 })
+    ));
+
+    // 10. A batch of headers reports each file on its own
+    println!("\nReading a directory of headers...");
+    let dir = std::env::temp_dir().join(format!("{}_headers", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path.to_string_lossy().into_owned()
+    };
+    let v1 = build_model(&[vec![(1, 65535)], vec![]]);
+    let mut v3 = Vec::new();
+    write_bigram(
+        &mut v3,
+        3,
+        0xABCD,
+        10,
+        &[(0, 2, 0), (16, 0, 0), (16, 0, 0)],
+        EdgeValues::Weights(&[(1, 65535), (2, 100)]),
+    )
+    .unwrap();
+    let paths = [
+        write("v1.bin", &v1),
+        write("v3.bin", &v3),
+        write("short_header.bin", &v1[..20]),
+        write("short_body.bin", &v3[..v3.len() - 4]),
+        write(
+            "foreign.bin",
+            b"PK\x03\x04 not a model, just long enough to parse",
+        ),
+        dir.join("missing.bin").to_string_lossy().into_owned(),
+    ];
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    let headers = read_headers(&paths);
+    assert_eq!(headers.len(), paths.len());
+    let v1_header = headers[0].as_ref().unwrap();
+    assert_eq!((v1_header.version, v1_header.vocab_size), (1, 2));
+    assert_eq!(v1_header, &parse_header(&v1).unwrap());
+    let v3_header = headers[1].as_ref().unwrap();
+    assert_eq!((v3_header.version, v3_header.edges_count), (3, 2));
+    assert_eq!(v3_header.vocab_hash, Some(0xABCD));
+    assert!(matches!(headers[2], Err(EngineError::Truncated { .. })));
+    assert!(matches!(
+        headers[3],
+        Err(EngineError::Truncated { need, have }) if need == v3.len() && have == v3.len() - 4
+    ));
+    assert!(matches!(headers[4], Err(EngineError::BadMagic { .. })));
+    assert!(matches!(headers[5], Err(EngineError::Io { .. })));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // 11. A build that writes a corrupt file fails its --validate step
+    println!("\nValidating a file written through a corrupting writer...");
+    /// Passes bytes through, overwriting the one at `at` with `byte`
+    struct Corrupting<W> {
+        inner: W,
+        written: usize,
+        at: usize,
+        byte: u8,
+    }
+    impl<W: std::io::Write> std::io::Write for Corrupting<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut buf = buf.to_vec();
+            if (self.written..self.written + buf.len()).contains(&self.at) {
+                buf[self.at - self.written] = self.byte;
+            }
+            self.inner.write_all(&buf)?;
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+    let write_model = |path: &std::path::Path, corrupt_at: usize| {
+        let file = std::fs::File::create(path).unwrap();
+        let writer = Corrupting {
+            inner: std::io::BufWriter::new(file),
+            written: 0,
+            at: corrupt_at,
+            byte: 0xFF,
+        };
+        write_bigram(
+            writer,
+            3,
+            0xABCD,
+            10,
+            &[(0, 2, 0), (16, 0, 0), (16, 0, 0)],
+            EdgeValues::Weights(&[(1, 65535), (2, 100)]),
+        )
+        .unwrap();
+    };
+    let path = std::env::temp_dir().join(format!("{}_validate.bin", std::process::id()));
+    write_model(&path, usize::MAX);
+    let report = validate_bigram_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(report.edges_count, 2);
+    assert!(report.failures().is_empty());
+
+    // The top byte of prev 0's edge offset, just past the 40-byte header
+    write_model(&path, 43);
+    let err = validate_bigram_file(path.to_str().unwrap()).unwrap_err();
+    println!("{}", err);
+    assert!(err.to_string().contains("offset bounds: 1 errors"));
+    std::fs::remove_file(&path).unwrap();
+    assert!(validate_bigram_file(path.to_str().unwrap()).is_err());
+    // 12. A header claiming a huge vocab doesn't size allocations from it
+    println!("\nValidating a header with an inflated vocab_size...");
+    let mut bad = good.clone();
+    bad[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+    let report = validate_bigram(&bad);
+    assert!(!report.all_pass());
+    assert!(report.lens.capacity() <= bad.len() / 8);
+    assert!(report.offset_errors > 0);
+
+    println!("\nPASSED all tests!");
+}
@@ -1,4 +1,5 @@
-use anyhow::Result;
+use anyhow::{ensure, Result};
+use combined2fst::VietnameseEngine;
 use fst::automaton::{Automaton, Str};
 use fst::{IntoStreamer, Map, Streamer};
 use memmap2::Mmap;
@@ -55,6 +56,25 @@ fn main() -> Result<()> {
         }
     }
 
+    println!("\n=== Completing 'ngh' in vi.syllable.fst ===\n");
+    {
+        let vi = VietnameseEngine::open("vi.syllable.fst")?;
+        let completions: Vec<String> = vi
+            .complete("ngh", 100)
+            .into_iter()
+            .map(|m| m.syllable)
+            .collect();
+        println!("  {}", completions[..completions.len().min(15)].join(" "));
+        for expected in ["nghe", "nghĩ"] {
+            ensure!(
+                completions.iter().any(|s| s == expected),
+                "'ngh' should complete to {}",
+                expected
+            );
+        }
+        ensure!(completions.iter().all(|s| s.starts_with("ngh")));
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,401 @@
+//! Token normalization shared by the corpus builders and the engine
+//!
+//! `LettersOnly` is the original rule and the default: lowercase, keep
+//! letters and apostrophes. It drops digits and hyphens inside words, so
+//! "covid-19" turns into "covid" and "3rd" into "rd".
+//!
+//! `RichTokens` keeps digits and intra-word hyphens (each can be turned
+//! off) and replaces whole numbers with `<NUM>` and links or email
+//! addresses with `<URL>`. Punctuation around a token is trimmed first, so
+//! "#rust" and "(covid-19)," count as "rust" and "covid-19".
+//!
+//! Placeholders are ordinary tokens to the builders, lowercased like the
+//! rest unless case is kept: they only join bigram chains if the lexicon
+//! has them as words (list `<NUM>` and `<URL>` in the frequency list given
+//! to `build_en_fst`). Otherwise they break the chain like any OOV token.
+//!
+//! A model built that way suggests placeholders like any other word, in
+//! their vocab spelling ("page" -> `<NUM>`); `is_placeholder` lets an app
+//! drop them or render them as a slot. Query such a model with the
+//! normalizer it was built with (`ImeEngine::set_normalizer`), or typed
+//! context like "covid-19" won't reach its rows.
+//!
+//! Lowercasing follows a `Locale`. `Locale::Root` is Rust's locale-free
+//! `to_lowercase`: 'I' becomes 'i' and 'İ' becomes "i\u{307}" (i plus a
+//! combining dot, which `LettersOnly` then drops as a non-letter). Turkish
+//! and Azeri pair the dotted and dotless letters instead ('I' -> 'ı',
+//! 'İ' -> 'i'), so root lowercasing merges "DIŞ" (outside) with "diş"
+//! (tooth); `Locale::Turkish` keeps them apart. Neither folds 'ß': it is
+//! already lowercase and stays one character ('ẞ' lowercases to it), so
+//! "straße" and "STRASSE" remain different keys. That is lowercasing, not
+//! full case folding, on purpose: folding 'ß' to "ss" would change word
+//! lengths and merge words the lexicon lists apart. Wrap a normalizer in
+//! `Localized` to lowercase under another locale, and build the canonical
+//! map to match (`canonical_map_for_locale`).
+//!
+//! Every normalizer folds the typographic apostrophes ’ and ‘ to ASCII '
+//! first (`fold_apostrophes`), so "don’t" and "don't" are one token in
+//! the corpus builders, the engine and user history alike. The canonical
+//! and exact word maps fold their keys the same way.
+//!
+//! `ScriptLetters` picks the characters a token keeps per script, for
+//! corpora outside Latin. Cyrillic, Greek and most others need nothing
+//! beyond `LettersOnly`: their letters are alphabetic and lowercase as
+//! usual. Vietnamese text is often stored decomposed (NFD), where each
+//! tone mark is a combining character that isn't alphabetic, so
+//! `LettersOnly` turns "tiếng" into "tieng"; `Script::Vietnamese`
+//! composes to NFC first and keeps any marks left over. Arabic has no
+//! case; `Script::Arabic` drops the optional short-vowel marks (harakat)
+//! and the tatweel stretch, so vocalized and stretched spellings meet the
+//! plain ones a lexicon lists. Digits can be kept as well, so number
+//! tokens don't come out empty and break the chain.
+//!
+//! `Pretokenized` is for corpora that are already segmented, one token per
+//! unit: it trusts the whitespace split and returns each token untouched,
+//! where `LettersOnly` could strip "covid-19" to "covid" or drop "3rd"'s
+//! digits and merge it with another word. Nothing is lowercased or trimmed,
+//! so tokens only join a chain if they match a vocab key exactly: the
+//! lowercase canonical keys by default, or the lexicon's own spelling with
+//! `--case-sensitive` (which also tries the lowercase form). "Cases," with
+//! its comma is OOV (apostrophes are still folded). The builders select
+//! it with `--pretokenized` (`from_flags`); the Vietnamese builders, which
+//! only lowercase, skip that too.
+
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Placeholder emitted for numbers ("42", "3.14", "10:30")
+pub const NUM_TOKEN: &str = "<NUM>";
+/// Placeholder emitted for links and email addresses
+pub const URL_TOKEN: &str = "<URL>";
+
+/// `word` with the typographic apostrophes ’ and ‘ replaced by ASCII '
+pub fn fold_apostrophes(word: &str) -> Cow<'_, str> {
+    if word.contains(['’', '‘']) {
+        Cow::Owned(word.replace(['’', '‘'], "'"))
+    } else {
+        Cow::Borrowed(word)
+    }
+}
+
+/// Whether `word` is one of the placeholder tokens (in either case)
+pub fn is_placeholder(word: &str) -> bool {
+    word.eq_ignore_ascii_case(NUM_TOKEN) || word.eq_ignore_ascii_case(URL_TOKEN)
+}
+
+/// Language rules for lowercasing (see the module docs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    /// Locale-independent Unicode lowercasing
+    #[default]
+    Root,
+    /// Turkish and Azeri: 'I' -> 'ı' and 'İ' -> 'i'
+    Turkish,
+}
+
+impl Locale {
+    /// `word` lowercased under this locale
+    pub fn lowercase(self, word: &str) -> String {
+        match self {
+            Locale::Turkish if word.contains(['I', 'İ']) => {
+                word.replace('I', "ı").replace('İ', "i").to_lowercase()
+            }
+            _ => word.to_lowercase(),
+        }
+    }
+
+    /// Short tag for normalizer names: "root" or "tr"
+    pub fn name(self) -> &'static str {
+        match self {
+            Locale::Root => "root",
+            Locale::Turkish => "tr",
+        }
+    }
+}
+
+/// Turns a whitespace-separated corpus or context token into its lookup form
+pub trait Normalizer: Send + Sync {
+    /// `word` reduced to the form looked up in the word map, lowercased
+    /// unless `keep_case`. Empty when nothing of it is a word; the token
+    /// then breaks the bigram chain.
+    fn normalize(&self, word: &str, keep_case: bool) -> String;
+
+    /// Short description recorded in shard manifests, so shards built with
+    /// one tokenizer aren't resumed with another
+    fn name(&self) -> String;
+}
+
+/// Letters and apostrophes only (the default)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LettersOnly;
+
+impl Normalizer for LettersOnly {
+    fn normalize(&self, word: &str, keep_case: bool) -> String {
+        let folded = fold_apostrophes(word);
+        let word = if keep_case {
+            folded
+        } else {
+            Cow::Owned(folded.to_lowercase())
+        };
+        word.chars()
+            .filter(|c| c.is_alphabetic() || *c == '\'')
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        "letters".to_string()
+    }
+}
+
+impl<N: Normalizer + ?Sized> Normalizer for Box<N> {
+    fn normalize(&self, word: &str, keep_case: bool) -> String {
+        (**self).normalize(word, keep_case)
+    }
+
+    fn name(&self) -> String {
+        (**self).name()
+    }
+}
+
+/// Tokens as they are, for pre-segmented corpora (see the module docs)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pretokenized;
+
+impl Normalizer for Pretokenized {
+    fn normalize(&self, word: &str, _keep_case: bool) -> String {
+        fold_apostrophes(word).into_owned()
+    }
+
+    fn name(&self) -> String {
+        "pretokenized".to_string()
+    }
+}
+
+/// The normalizer picked by the builders' `--rich-tokens` and
+/// `--pretokenized` flags, `LettersOnly` without either
+pub fn from_flags(rich_tokens: bool, pretokenized: bool) -> Result<Box<dyn Normalizer>> {
+    Ok(match (rich_tokens, pretokenized) {
+        (true, true) => bail!("--rich-tokens and --pretokenized are exclusive"),
+        (true, false) => Box::new(RichTokens::default()),
+        (false, true) => Box::new(Pretokenized),
+        (false, false) => Box::new(LettersOnly),
+    })
+}
+
+/// Script-specific character rules for `ScriptLetters`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Script {
+    /// Letters and apostrophes, as `LettersOnly`; also right for
+    /// Cyrillic, Greek and other cased alphabets
+    #[default]
+    Latin,
+    /// NFC-composed first; combining marks kept
+    Vietnamese,
+    /// Harakat and tatweel dropped
+    Arabic,
+}
+
+impl Script {
+    /// Parse a script or language name: "latin", "vi", "ar", ...
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "latin" | "en" | "cyrillic" | "ru" | "uk" | "greek" | "el" => Some(Script::Latin),
+            "vietnamese" | "vi" => Some(Script::Vietnamese),
+            "arabic" | "ar" => Some(Script::Arabic),
+            _ => None,
+        }
+    }
+
+    /// Short tag for normalizer names
+    pub fn name(self) -> &'static str {
+        match self {
+            Script::Latin => "latin",
+            Script::Vietnamese => "vi",
+            Script::Arabic => "ar",
+        }
+    }
+
+    fn keeps(self, c: char) -> bool {
+        match self {
+            Script::Latin => c.is_alphabetic() || c == '\'',
+            Script::Vietnamese => c.is_alphabetic() || c == '\'' || is_combining_mark(c),
+            Script::Arabic => c.is_alphabetic() && !is_arabic_optional(c),
+        }
+    }
+}
+
+/// Harakat, the superscript alef and tatweel: written or not at will
+fn is_arabic_optional(c: char) -> bool {
+    matches!(c, '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{0640}')
+}
+
+/// Letters by the rules of one script, optionally with digits (see the
+/// module docs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScriptLetters {
+    pub script: Script,
+    /// Keep digits of any script ("mp3", "٣")
+    pub digits: bool,
+}
+
+impl ScriptLetters {
+    pub fn new(script: Script) -> Self {
+        Self {
+            script,
+            digits: false,
+        }
+    }
+}
+
+impl Normalizer for ScriptLetters {
+    fn normalize(&self, word: &str, keep_case: bool) -> String {
+        let word = fold_apostrophes(word);
+        let word: Cow<str> = if self.script == Script::Vietnamese {
+            Cow::Owned(word.nfc().collect())
+        } else {
+            word
+        };
+        let word = if keep_case {
+            word
+        } else {
+            Cow::Owned(word.to_lowercase())
+        };
+        word.chars()
+            .filter(|&c| self.script.keeps(c) || (self.digits && c.is_numeric()))
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        format!("script({},digits={})", self.script.name(), self.digits)
+    }
+}
+
+/// Keeps the character classes modern text needs (see the module docs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RichTokens {
+    /// Keep digits inside words ("3rd", "mp3")
+    pub digits: bool,
+    /// Keep hyphens between letters or digits ("covid-19", "e-mail")
+    pub hyphens: bool,
+    /// Emit `NUM_TOKEN` and `URL_TOKEN`
+    pub placeholders: bool,
+}
+
+impl Default for RichTokens {
+    fn default() -> Self {
+        Self {
+            digits: true,
+            hyphens: true,
+            placeholders: true,
+        }
+    }
+}
+
+impl Normalizer for RichTokens {
+    fn normalize(&self, word: &str, keep_case: bool) -> String {
+        let word = fold_apostrophes(word);
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '\'');
+        if self.placeholders {
+            let placeholder = if is_url(word) {
+                Some(URL_TOKEN)
+            } else if is_number(word) {
+                Some(NUM_TOKEN)
+            } else {
+                None
+            };
+            if let Some(token) = placeholder {
+                // Lowercased like any token, to match the canonical map key
+                return if keep_case {
+                    token.to_string()
+                } else {
+                    token.to_lowercase()
+                };
+            }
+        }
+
+        let word = if keep_case {
+            Cow::Borrowed(word)
+        } else {
+            Cow::Owned(word.to_lowercase())
+        };
+        let chars: Vec<char> = word.chars().collect();
+        let mut out = String::with_capacity(word.len());
+        for (i, &c) in chars.iter().enumerate() {
+            let keep = c.is_alphabetic()
+                || c == '\''
+                || (self.digits && c.is_numeric())
+                || (self.hyphens
+                    && c == '-'
+                    && i > 0
+                    && chars[i - 1].is_alphanumeric()
+                    && chars.get(i + 1).is_some_and(|n| n.is_alphanumeric()));
+            if keep {
+                out.push(c);
+            }
+        }
+        // Digits dropped from "covid-19" leave a dangling hyphen
+        out.trim_matches('-').to_string()
+    }
+
+    fn name(&self) -> String {
+        format!(
+            "rich(digits={},hyphens={},placeholders={})",
+            self.digits, self.hyphens, self.placeholders
+        )
+    }
+}
+
+/// `inner` with its lowercasing done under `locale`. The word is lowercased
+/// before `inner` sees it, so `inner`'s own root lowercasing has nothing
+/// left to change; with `keep_case` it passes through untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Localized<N> {
+    pub inner: N,
+    pub locale: Locale,
+}
+
+impl<N: Normalizer> Localized<N> {
+    pub fn new(inner: N, locale: Locale) -> Self {
+        Self { inner, locale }
+    }
+}
+
+impl<N: Normalizer> Normalizer for Localized<N> {
+    fn normalize(&self, word: &str, keep_case: bool) -> String {
+        if keep_case || self.locale == Locale::Root {
+            return self.inner.normalize(word, keep_case);
+        }
+        self.inner.normalize(&self.locale.lowercase(word), false)
+    }
+
+    fn name(&self) -> String {
+        match self.locale {
+            Locale::Root => self.inner.name(),
+            locale => format!("{}+{}", self.inner.name(), locale.name()),
+        }
+    }
+}
+
+/// A link ("http://x.com", "www.x.com") or an email address
+fn is_url(word: &str) -> bool {
+    let lower = word.to_ascii_lowercase();
+    if lower.starts_with("www.") || lower.contains("://") {
+        return true;
+    }
+    match word.split_once('@') {
+        Some((user, domain)) => {
+            !user.is_empty() && domain.contains('.') && !domain.starts_with('.')
+        }
+        None => false,
+    }
+}
+
+/// Digits with optional separators: "42", "1,000", "3.14", "10:30", "2024/05/01"
+fn is_number(word: &str) -> bool {
+    word.chars().any(|c| c.is_ascii_digit())
+        && word
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | ',' | ':' | '/'))
+}
@@ -0,0 +1,205 @@
+//! Memory-mapped reader for TRGC trigram cache files
+//!
+//! Layout: header (32 bytes) + index sorted by (w1, w2) (16 bytes per pair)
+//! + edges (8 bytes each)
+//!
+//! The index entry's last u16 is the pair's encoded `ln(max_count)`, as in
+//! the bigram index. Version 2 headers record `word_id_bits` and
+//! `weight_bits` at offsets 16 and 20, as bigram files do at 20 and 24.
+//! Version 3 puts the `Vocab::fingerprint` of the build's vocab in the
+//! formerly reserved u64 at offset 24; `open_for` refuses a cache whose
+//! fingerprint differs from the vocab it will be used with. Older caches
+//! carry none and aren't checked.
+//!
+//! Reserved space follows the bigram policy (see `bigram`): the header bytes
+//! a version leaves unused (offsets 16-31 in version 1, 24-31 in version 2,
+//! none in version 3) must be zero and are checked at open, as is each
+//! edge's trailing u16, by `reserved_errors`, which has to scan them all.
+//!
+//! `prefetch` warms the page cache with the same trade-offs as
+//! `BigramModel::prefetch`; here the index is 16 bytes per pair.
+
+use crate::bigram::{
+    check_len, check_magic, check_reserved, map_file, read_bit_widths, read_u16, read_u32,
+    read_u64, resident_bytes, touch_pages, WEIGHT_BITS,
+};
+use crate::builder::decode_log_max;
+use crate::error::{EngineError, EngineResult};
+use crate::vocab::Vocab;
+use crate::{Edge, Weight};
+use memmap2::Mmap;
+
+pub const MAGIC: u32 = 0x54524743; // "TRGC"
+pub const VERSION: u32 = 3;
+const HEADER_SIZE: usize = 32;
+
+/// A TRGC cache over its bytes: a mapped file (`open`) or an in-memory
+/// store (`from_bytes`), as with `BigramModel`
+pub struct TrigramCache<D = Mmap> {
+    data: D,
+    num_pairs: usize,
+    word_id_bits: u32,
+    weight_bits: u32,
+    vocab_hash: Option<u64>,
+}
+
+/// Header bytes `version` reserves, as a (start, end) span
+pub fn reserved_header_span(version: u32) -> Option<(usize, usize)> {
+    match version {
+        1 => Some((16, 32)),
+        2 => Some((24, 32)),
+        _ => None,
+    }
+}
+
+impl TrigramCache {
+    pub fn open(path: &str) -> EngineResult<Self> {
+        Self::from_bytes(map_file(path)?)
+    }
+
+    /// `open`, then refuse the cache with `EngineError::TrigramVocabHashMismatch`
+    /// if it records a fingerprint other than `vocab`'s
+    pub fn open_for(path: &str, vocab: &Vocab) -> EngineResult<Self> {
+        let cache = Self::open(path)?;
+        cache.check_vocab(vocab)?;
+        Ok(cache)
+    }
+}
+
+impl<D: AsRef<[u8]>> TrigramCache<D> {
+    /// Read a cache from bytes already in memory, checked as `open` does
+    pub fn from_bytes(bytes: D) -> EngineResult<Self> {
+        let data = bytes.as_ref();
+
+        check_len(data, HEADER_SIZE)?;
+        check_magic(data, MAGIC)?;
+        let version = read_u32(data, 4);
+        let (word_id_bits, weight_bits) = read_bit_widths(data, version, VERSION, 16, WEIGHT_BITS)?;
+        let num_pairs = read_u32(data, 8) as usize;
+        check_len(data, HEADER_SIZE + num_pairs * 16)?;
+        check_reserved(data, reserved_header_span(version))?;
+        let vocab_hash = (version >= 3).then(|| read_u64(data, 24));
+
+        Ok(Self {
+            data: bytes,
+            num_pairs,
+            word_id_bits,
+            weight_bits,
+            vocab_hash,
+        })
+    }
+
+    /// Whether the cache's ids can be resolved in `vocab`: Ok unless it
+    /// records a different fingerprint
+    pub fn check_vocab(&self, vocab: &Vocab) -> EngineResult<()> {
+        match self.vocab_hash {
+            Some(model) if model != vocab.fingerprint() => {
+                Err(EngineError::TrigramVocabHashMismatch {
+                    model,
+                    vocab: vocab.fingerprint(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fingerprint of the vocab the cache was built against; None before
+    /// version 3
+    pub fn vocab_hash(&self) -> Option<u64> {
+        self.vocab_hash
+    }
+
+    pub fn num_pairs(&self) -> usize {
+        self.num_pairs
+    }
+
+    pub fn word_id_bits(&self) -> u32 {
+        self.word_id_bits
+    }
+
+    pub fn weight_bits(&self) -> u32 {
+        self.weight_bits
+    }
+
+    /// Fault in the header and index pages, plus the edges if `include_edges`.
+    /// Returns the number of bytes covered.
+    pub fn prefetch(&self, include_edges: bool) -> usize {
+        let data = self.data.as_ref();
+        let end = if include_edges {
+            data.len()
+        } else {
+            HEADER_SIZE + self.num_pairs * 16
+        };
+        touch_pages(&data[..end]);
+        end
+    }
+
+    /// Bytes of the file currently resident in memory, if the platform can tell
+    pub fn resident_estimate(&self) -> Option<usize> {
+        resident_bytes(self.data.as_ref())
+    }
+
+    /// Binary-search the (w1, w2) pair; returns up to `limit` of its edges
+    /// in stored order. As with bigrams, the build's `top_n` is a hard
+    /// ceiling.
+    pub fn lookup(&self, w1: u32, w2: u32, limit: usize) -> Option<Vec<Edge>> {
+        let data = self.data.as_ref();
+        let entry_offset = self.find(w1, w2)?;
+        let edges_start_offset = read_u32(data, entry_offset + 8) as usize;
+        let len = (read_u16(data, entry_offset + 12) as usize).min(limit);
+        let edges_base = HEADER_SIZE + self.num_pairs * 16;
+
+        let mut results = Vec::with_capacity(len);
+        for i in 0..len {
+            let off = edges_base + edges_start_offset + i * 8;
+            if off + 8 > data.len() {
+                break;
+            }
+            results.push(Edge {
+                next_id: read_u32(data, off),
+                weight: Weight(read_u16(data, off + 4)),
+            });
+        }
+        Some(results)
+    }
+
+    /// Edges whose reserved trailing u16 isn't zero. Reads every edge, so
+    /// it's for validation, not the open path.
+    pub fn reserved_errors(&self) -> usize {
+        let data = self.data.as_ref();
+        let edges_base = HEADER_SIZE + self.num_pairs * 16;
+        data.get(edges_base..)
+            .unwrap_or_default()
+            .chunks_exact(8)
+            .filter(|edge| edge[6..8] != [0, 0])
+            .count()
+    }
+
+    /// The pair's stored `ln(max_count)`, or None if absent or not stored
+    pub fn log_max(&self, w1: u32, w2: u32) -> Option<f64> {
+        let entry_offset = self.find(w1, w2)?;
+        decode_log_max(read_u16(self.data.as_ref(), entry_offset + 14))
+    }
+
+    /// Byte offset of the (w1, w2) index entry
+    fn find(&self, w1: u32, w2: u32) -> Option<usize> {
+        let data = self.data.as_ref();
+        let mut low = 0;
+        let mut high = self.num_pairs;
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let entry_offset = HEADER_SIZE + mid * 16;
+            let mw1 = read_u32(data, entry_offset);
+            let mw2 = read_u32(data, entry_offset + 4);
+
+            match (mw1, mw2).cmp(&(w1, w2)) {
+                std::cmp::Ordering::Equal => return Some(entry_offset),
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,212 @@
+//! Raw trigram counts behind a TRGC cache, for incremental rebuilds
+//!
+//! The cache stores each pair's top-N edges as quantized weights, which
+//! can't be added to. `build_trigram --keep-counts` also saves every
+//! (next_id, count) of the selected pairs, not just the top N, to a TRCN
+//! sidecar (`en.trigram.counts.bin`). `--update delta.txt` loads it, counts
+//! the delta corpus into the same pairs and rewrites both files, reading
+//! only the delta.
+//!
+//! Accuracy: each cached pair's edges come out exactly as a full rebuild
+//! over the base corpus plus the delta would write them. The pair
+//! selection is not revisited: pairs are the top K of the corpus the
+//! sidecar was first built from, so a pair the delta would push into the
+//! top K is not added and one it would push out is kept. Rebuild from
+//! scratch once the deltas add up to a sizable share of the corpus.
+//!
+//! Sidecar layout, little-endian: magic "TRCN" (4) version (4)
+//! num_pairs (4) reserved (4) vocab_hash (8), then per pair in (w1, w2)
+//! order: w1 (4) w2 (4) len (4) and `len` x [next_id (4) count (8)] by
+//! next_id.
+
+use crate::bigram::{WEIGHT_BITS, WORD_ID_BITS};
+use crate::builder::{encode_log_max, quantize_weight};
+use crate::trigram;
+use crate::{write_atomic, FastMap};
+use anyhow::{bail, Context, Result};
+use std::cmp::Reverse;
+use std::io::{BufWriter, Read, Write};
+
+pub const MAGIC: u32 = 0x5452434E; // "TRCN"
+pub const VERSION: u32 = 1;
+
+/// ((w1, w2), [(next_id, count)])
+pub type PairCounts = ((u32, u32), Vec<(u32, u64)>);
+
+/// Per-pair next-word counts for a fixed set of (w1, w2) pairs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrigramCounts {
+    vocab_hash: u64,
+    pairs: FastMap<(u32, u32), FastMap<u32, u64>>,
+}
+
+impl TrigramCounts {
+    /// Empty counts for `pairs`; trigrams after any other pair are ignored
+    pub fn new(pairs: impl IntoIterator<Item = (u32, u32)>, vocab_hash: u64) -> Self {
+        Self {
+            vocab_hash,
+            pairs: pairs
+                .into_iter()
+                .map(|pair| (pair, FastMap::default()))
+                .collect(),
+        }
+    }
+
+    pub fn vocab_hash(&self) -> u64 {
+        self.vocab_hash
+    }
+
+    pub fn num_pairs(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn contains_pair(&self, w1: u32, w2: u32) -> bool {
+        self.pairs.contains_key(&(w1, w2))
+    }
+
+    /// Count one `w1 w2 next` trigram. False if (w1, w2) isn't tracked.
+    pub fn add(&mut self, w1: u32, w2: u32, next_id: u32) -> bool {
+        match self.pairs.get_mut(&(w1, w2)) {
+            Some(counts) => {
+                *counts.entry(next_id).or_insert(0) += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How often `w1 w2 next` was counted
+    pub fn count(&self, w1: u32, w2: u32, next_id: u32) -> u64 {
+        self.pairs
+            .get(&(w1, w2))
+            .and_then(|counts| counts.get(&next_id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Each pair with counts, in (w1, w2) order, with its top `top_n`
+    /// (next_id, count) by count descending (ties: next_id ascending)
+    pub fn top_rows(&self, top_n: usize) -> Vec<PairCounts> {
+        let mut rows: Vec<PairCounts> = self
+            .pairs
+            .iter()
+            .filter(|(_, counts)| !counts.is_empty())
+            .map(|(&pair, counts)| {
+                let mut nexts: Vec<(u32, u64)> = counts.iter().map(|(&k, &v)| (k, v)).collect();
+                nexts.sort_by_key(|&(next_id, count)| (Reverse(count), next_id));
+                nexts.truncate(top_n);
+                (pair, nexts)
+            })
+            .collect();
+        rows.sort_by_key(|&(pair, _)| pair);
+        rows
+    }
+
+    /// Write the TRGC cache for these counts (see `trigram`): each pair's
+    /// top `top_n` edges, quantized against its largest count. Returns the
+    /// (pairs, edges) written.
+    pub fn write_cache<W: Write>(&self, out: W, top_n: usize) -> Result<(usize, usize)> {
+        let rows = self.top_rows(top_n);
+        let total_edges: usize = rows.iter().map(|(_, nexts)| nexts.len()).sum();
+        let mut out = BufWriter::new(out);
+
+        // Header: magic(4) version(4) num_pairs(4) top_n(4) word_id_bits(4)
+        // weight_bits(4) vocab_hash(8) = 32 bytes
+        out.write_all(&trigram::MAGIC.to_le_bytes())?;
+        out.write_all(&trigram::VERSION.to_le_bytes())?;
+        out.write_all(&(rows.len() as u32).to_le_bytes())?;
+        out.write_all(&(top_n as u32).to_le_bytes())?;
+        out.write_all(&WORD_ID_BITS.to_le_bytes())?;
+        out.write_all(&WEIGHT_BITS.to_le_bytes())?;
+        out.write_all(&self.vocab_hash.to_le_bytes())?;
+
+        // Index: [w1(4) w2(4) offset(4) len(2) log_max(2)] per pair
+        let mut edge_offset: u32 = 0;
+        for ((w1, w2), nexts) in &rows {
+            let max_count = nexts.first().map_or(1, |&(_, count)| count);
+            out.write_all(&w1.to_le_bytes())?;
+            out.write_all(&w2.to_le_bytes())?;
+            out.write_all(&edge_offset.to_le_bytes())?;
+            out.write_all(&(nexts.len() as u16).to_le_bytes())?;
+            out.write_all(&encode_log_max(max_count).to_le_bytes())?;
+            edge_offset += (nexts.len() * 8) as u32;
+        }
+
+        // Edges: [next_id(4) weight(2) reserved(2)]
+        for (_, nexts) in &rows {
+            let max_count = nexts.first().map_or(1, |&(_, count)| count);
+            for &(next_id, count) in nexts {
+                out.write_all(&next_id.to_le_bytes())?;
+                out.write_all(&quantize_weight(count, max_count).to_le_bytes())?;
+                out.write_all(&[0u8; 2])?;
+            }
+        }
+        out.flush()?;
+        Ok((rows.len(), total_edges))
+    }
+
+    /// Save as a TRCN sidecar, replacing `path` atomically
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut pairs: Vec<_> = self.pairs.iter().collect();
+        pairs.sort_by_key(|&(&pair, _)| pair);
+        write_atomic(path, |out| {
+            out.write_all(&MAGIC.to_le_bytes())?;
+            out.write_all(&VERSION.to_le_bytes())?;
+            out.write_all(&(pairs.len() as u32).to_le_bytes())?;
+            out.write_all(&0u32.to_le_bytes())?;
+            out.write_all(&self.vocab_hash.to_le_bytes())?;
+            for (&(w1, w2), counts) in &pairs {
+                let mut nexts: Vec<(u32, u64)> = counts.iter().map(|(&k, &v)| (k, v)).collect();
+                nexts.sort_unstable();
+                out.write_all(&w1.to_le_bytes())?;
+                out.write_all(&w2.to_le_bytes())?;
+                out.write_all(&(nexts.len() as u32).to_le_bytes())?;
+                for (next_id, count) in nexts {
+                    out.write_all(&next_id.to_le_bytes())?;
+                    out.write_all(&count.to_le_bytes())?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Read a sidecar written by `save`
+    pub fn load(path: &str) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+        let mut reader = data.as_slice();
+        let mut next_u32 = || -> Result<u32> {
+            let mut buf = [0u8; 4];
+            reader
+                .read_exact(&mut buf)
+                .with_context(|| format!("{} is truncated", path))?;
+            Ok(u32::from_le_bytes(buf))
+        };
+        if next_u32()? != MAGIC {
+            bail!("{} is not a trigram counts file", path);
+        }
+        let version = next_u32()?;
+        if version != VERSION {
+            bail!("{}: unsupported trigram counts version {}", path, version);
+        }
+        let num_pairs = next_u32()? as usize;
+        next_u32()?;
+        let vocab_hash = (next_u32()? as u64) | (next_u32()? as u64) << 32;
+
+        let mut pairs = FastMap::default();
+        for _ in 0..num_pairs {
+            let pair = (next_u32()?, next_u32()?);
+            let len = next_u32()? as usize;
+            let mut counts = FastMap::default();
+            for _ in 0..len {
+                let next_id = next_u32()?;
+                let count = (next_u32()? as u64) | (next_u32()? as u64) << 32;
+                counts.insert(next_id, count);
+            }
+            pairs.insert(pair, counts);
+        }
+        if !reader.is_empty() {
+            bail!("{} has trailing bytes", path);
+        }
+        Ok(Self { vocab_hash, pairs })
+    }
+}
@@ -1,17 +1,97 @@
-use anyhow::{Context, Result};
+use crate::builder::ends_sentence;
+use crate::corpus::{CorpusLines, Utf8Policy};
+use crate::open_corpus;
+use crate::tokenize::{LettersOnly, Normalizer};
+use crate::HistoryScore;
+use anyhow::{bail, Context, Result};
+use fst::{IntoStreamer, Map, MapBuilder, Streamer};
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use unicode_normalization::UnicodeNormalization;
 
 // --- Constants & Config ---
 const USER_ID_START: u32 = 0x80000000;
 const USER_ID_MAX: u32 = 0xFFFFFFF0; // Safety buffer
-const HL_LEXICON_SEC: f64 = 14.0 * 24.0 * 3600.0; // 14 days
-const HL_BIGRAM_SEC: f64 = 7.0 * 24.0 * 3600.0; // 7 days
-const SCORE_SCALE: f64 = 10000.0;
-const BONUS_ACCEPT: f64 = 3000.0;
-const MAX_SCORE: f64 = 65535.0;
+const DEFAULT_HL_LEXICON_SEC: f64 = 14.0 * 24.0 * 3600.0; // 14 days
+const DEFAULT_HL_BIGRAM_SEC: f64 = 7.0 * 24.0 * 3600.0; // 7 days
+const DEFAULT_SCORE_SCALE: f64 = 10000.0;
+const DEFAULT_BONUS_ACCEPT: f64 = 3000.0;
+const DEFAULT_MAX_PREVS: usize = 20_000;
+const DEFAULT_MAX_TRIGRAM_CONTEXTS: usize = 20_000;
+/// Edge count added per accepted suggestion (typing adds 1), matching the
+/// freq weight of `WordStat::touch_accept`
+const ACCEPT_EDGE_DELTA: u32 = 3;
+/// Matches the global 16-bit weight range
+const DEFAULT_MAX_SCORE: f64 = 65535.0;
+/// Layout version `UserHistory::save` tags files with. `load` migrates
+/// older files up to it; untagged files are version 1.
+///
+/// 1. `lexicon` and `bigrams` only
+/// 2. Adds the `version` tag, `config`, `lexicon.free_ids` and `trigrams`
+pub const HISTORY_VERSION: u32 = 2;
+/// New user words one `learn_file` creates at most, so importing a large
+/// document can't fill the user id space with one-off tokens
+pub const IMPORT_MAX_NEW_WORDS: usize = 5_000;
+
+/// What `UserHistory::learn_file` read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    pub lines: u64,
+    /// Words learned, global or user
+    pub words: u64,
+    /// User words created
+    pub new_words: usize,
+    /// Unknown words skipped once the new-word cap was reached
+    pub skipped: u64,
+}
+
+/// Tuning knobs for scoring and forgetting.
+///
+/// Stored inside the serialized history so a loaded file keeps its tuning.
+/// Histories saved before this existed load with the defaults.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Half-life of word frequency, in seconds.
+    pub hl_lexicon_sec: f64,
+    /// Half-life of bigram edge counts, in seconds.
+    pub hl_bigram_sec: f64,
+    /// Multiplier applied to `ln(1 + effective_count)`.
+    pub score_scale: f64,
+    /// Flat bonus per accepted suggestion.
+    pub bonus_accept: f64,
+    /// Upper bound of `score`, 65535 to match global weights. Raise it to
+    /// let strong personal signals outrank any global weight when blending
+    /// the readers' `HistoryScore`s (the engine's suggestions narrow them
+    /// to `Score`), or blend with `score_raw` and quantize once at the end.
+    pub max_score: f64,
+    /// Most prev words to keep bigram trackers for; past it the prev whose
+    /// edges were used least recently is evicted. 0 disables the cap.
+    pub max_prevs: usize,
+    /// Also learn which word follows each pair of words, for
+    /// `UserHistory::predict_trigram`. Off by default: it costs a tracker
+    /// per distinct pair typed, on top of the one per word.
+    pub learn_trigrams: bool,
+    /// `max_prevs` for the (prev_prev, prev) trackers
+    pub max_trigram_contexts: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            hl_lexicon_sec: DEFAULT_HL_LEXICON_SEC,
+            hl_bigram_sec: DEFAULT_HL_BIGRAM_SEC,
+            score_scale: DEFAULT_SCORE_SCALE,
+            bonus_accept: DEFAULT_BONUS_ACCEPT,
+            max_score: DEFAULT_MAX_SCORE,
+            max_prevs: DEFAULT_MAX_PREVS,
+            learn_trigrams: false,
+            max_trigram_contexts: DEFAULT_MAX_TRIGRAM_CONTEXTS,
+        }
+    }
+}
 
 fn now_sec() -> u32 {
     SystemTime::now()
@@ -37,6 +117,13 @@ pub struct WordStat {
 }
 
 impl WordStat {
+    /// Combine stats for the same word learned on two devices
+    pub fn merge(&mut self, other: &WordStat) {
+        self.freq = self.freq.saturating_add(other.freq);
+        self.accept = self.accept.saturating_add(other.accept);
+        self.last_used = self.last_used.max(other.last_used);
+    }
+
     pub fn touch_commit(&mut self, now: u32) {
         self.freq = self.freq.saturating_add(1);
         self.last_used = now;
@@ -47,13 +134,19 @@ impl WordStat {
         self.last_used = now;
     }
 
-    pub fn score(&self, now: u32) -> u16 {
+    /// Decayed score, clamped to `0..=cfg.max_score`
+    pub fn score(&self, now: u32, cfg: &HistoryConfig) -> HistoryScore {
+        HistoryScore(self.score_raw(now, cfg).clamp(0.0, cfg.max_score) as u32)
+    }
+
+    /// Decayed score before the clamp; grows without bound with accepts
+    pub fn score_raw(&self, now: u32, cfg: &HistoryConfig) -> f64 {
         let age = now.saturating_sub(self.last_used);
-        let decay = exp2_decay(age, HL_LEXICON_SEC);
+        let decay = exp2_decay(age, cfg.hl_lexicon_sec);
         let eff = (self.freq as f64) * decay;
-        let base = (1.0 + eff).ln() * SCORE_SCALE;
-        let accept = (self.accept as f64) * BONUS_ACCEPT;
-        (base + accept).clamp(0.0, MAX_SCORE) as u16
+        let base = (1.0 + eff).ln() * cfg.score_scale;
+        let accept = (self.accept as f64) * cfg.bonus_accept;
+        base + accept
     }
 }
 
@@ -64,17 +157,28 @@ pub struct EdgeStat {
 }
 
 impl EdgeStat {
+    /// Combine stats for the same edge learned on two devices
+    pub fn merge(&mut self, other: &EdgeStat) {
+        self.count = self.count.saturating_add(other.count);
+        self.last_used = self.last_used.max(other.last_used);
+    }
+
     pub fn touch(&mut self, now: u32, delta: u32) {
         self.count = self.count.saturating_add(delta);
         self.last_used = now;
     }
 
-    pub fn score(&self, now: u32) -> u16 {
+    /// Decayed score, clamped to `0..=cfg.max_score`
+    pub fn score(&self, now: u32, cfg: &HistoryConfig) -> HistoryScore {
+        HistoryScore(self.score_raw(now, cfg).clamp(0.0, cfg.max_score) as u32)
+    }
+
+    /// Decayed score before the clamp
+    pub fn score_raw(&self, now: u32, cfg: &HistoryConfig) -> f64 {
         let age = now.saturating_sub(self.last_used);
-        let decay = exp2_decay(age, HL_BIGRAM_SEC);
+        let decay = exp2_decay(age, cfg.hl_bigram_sec);
         let eff = (self.count as f64) * decay;
-        let val = (1.0 + eff).ln() * SCORE_SCALE;
-        val.clamp(0.0, MAX_SCORE) as u16
+        (1.0 + eff).ln() * cfg.score_scale
     }
 }
 
@@ -83,6 +187,15 @@ pub struct UserLexicon {
     word_to_id: HashMap<String, u32>,
     id_to_meta: HashMap<u32, (String, WordStat)>, // Store String here to easy reverse
     next_id: u32,
+    /// Ids of forgotten words, handed out again before `next_id`
+    #[serde(default)]
+    free_ids: Vec<u32>,
+    /// Compiled word index for prefix lookups, when one is attached
+    #[serde(skip)]
+    fst: Option<UserLexiconFst>,
+    /// Words the attached FST doesn't have: learned since it was compiled
+    #[serde(skip)]
+    unindexed: HashSet<u32>,
 }
 
 impl UserLexicon {
@@ -91,7 +204,45 @@ impl UserLexicon {
             word_to_id: HashMap::new(),
             id_to_meta: HashMap::new(),
             next_id: USER_ID_START,
+            free_ids: Vec::new(),
+            fst: None,
+            unindexed: HashSet::new(),
+        }
+    }
+
+    /// A fresh id: a freed one if any, else the next unused one. None once
+    /// the id space is exhausted.
+    fn alloc_id(&mut self) -> Option<u32> {
+        if let Some(id) = self.free_ids.pop() {
+            return Some(id);
+        }
+        if self.next_id >= USER_ID_MAX {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        Some(id)
+    }
+
+    /// Add a new word under a fresh id
+    fn insert(&mut self, word: &str, stat: WordStat) -> Option<u32> {
+        let id = self.alloc_id()?;
+        self.word_to_id.insert(word.to_string(), id);
+        self.id_to_meta.insert(id, (word.to_string(), stat));
+        if self.fst.is_some() {
+            self.unindexed.insert(id);
         }
+        Some(id)
+    }
+
+    /// Remove `word`, freeing its id. Returns the id it had, or None if
+    /// it wasn't here.
+    fn remove(&mut self, word: &str) -> Option<u32> {
+        let id = self.word_to_id.remove(word)?;
+        self.id_to_meta.remove(&id);
+        self.unindexed.remove(&id);
+        self.free_ids.push(id);
+        Some(id)
     }
 
     pub fn get_or_create(&mut self, word: &str, now: u32) -> Option<u32> {
@@ -102,33 +253,71 @@ impl UserLexicon {
             }
             Some(id)
         } else {
-            // Create new
-            if self.next_id >= USER_ID_MAX {
-                // Overflow protection: Refuse to add new words.
-                // In real app, we should prune the lexicon here.
-                return None;
-            }
-            let id = self.next_id;
-            self.next_id += 1;
-
+            // Create new. Overflow protection: refuse to add new words
+            // once the id space is used up.
+            // In real app, we should prune the lexicon here.
             let mut stat = WordStat::default();
             stat.touch_commit(now);
+            self.insert(word, stat)
+        }
+    }
 
-            self.word_to_id.insert(word.to_string(), id);
-            self.id_to_meta.insert(id, (word.to_string(), stat));
-            Some(id)
+    /// Record that the user picked `id` from the suggestions. Unknown ids
+    /// (e.g. global words) have no stat here and are ignored.
+    fn accept(&mut self, id: u32, now: u32) {
+        if let Some((_, stat)) = self.id_to_meta.get_mut(&id) {
+            stat.touch_accept(now);
         }
     }
 
+    /// Fold `stat` into `word`, creating it if needed. Returns its id here,
+    /// or None if the lexicon is full.
+    fn merge_word(&mut self, word: &str, stat: &WordStat) -> Option<u32> {
+        if let Some(&id) = self.word_to_id.get(word) {
+            if let Some((_, existing)) = self.id_to_meta.get_mut(&id) {
+                existing.merge(stat);
+            }
+            return Some(id);
+        }
+        self.insert(word, *stat)
+    }
+
     pub fn get_word(&self, id: u32) -> Option<&str> {
         self.id_to_meta.get(&id).map(|(s, _)| s.as_str())
     }
 
-    pub fn score(&self, id: u32, now: u32) -> u16 {
+    /// Ids and stats of the words starting with `prefix`: from the
+    /// attached FST plus the words it doesn't have yet, or a scan of every
+    /// word without one
+    fn with_prefix(&self, prefix: &str) -> Vec<(u32, &WordStat)> {
+        let Some(fst) = &self.fst else {
+            return self
+                .id_to_meta
+                .iter()
+                .filter(|(_, (word, _))| word.starts_with(prefix))
+                .map(|(&id, (_, stat))| (id, stat))
+                .collect();
+        };
+        // Forgotten words linger in the FST, their ids possibly reused
+        let indexed = fst
+            .prefix(prefix)
+            .into_iter()
+            .filter(|(word, id)| self.word_to_id.get(word) == Some(id));
+        let unindexed = self.unindexed.iter().filter_map(|&id| {
+            let (word, _) = self.id_to_meta.get(&id)?;
+            word.starts_with(prefix).then_some((word.clone(), id))
+        });
+        indexed
+            .chain(unindexed)
+            .filter_map(|(_, id)| Some((id, &self.id_to_meta.get(&id)?.1)))
+            .collect()
+    }
+
+    pub fn score(&self, id: u32, now: u32, cfg: &HistoryConfig) -> HistoryScore {
         self.id_to_meta
             .get(&id)
-            .map(|(_, s)| s.score(now))
-            .unwrap_or(0)
+            .map(|(_, s)| s.score(now, cfg))
+            .unwrap_or_default()
     }
 }
 
@@ -136,15 +325,24 @@ impl UserLexicon {
 pub struct TopNTracker {
     // next_id -> Stat
     counts: HashMap<u32, EdgeStat>,
-    #[serde(skip)]
+    // Not serialized; restored to the defaults so loaded trackers still prune sanely
+    #[serde(skip, default = "default_top_n")]
     top_n: usize,
-    #[serde(skip)]
+    #[serde(skip, default = "default_prune_threshold")]
     prune_threshold: usize,
 }
 
+fn default_top_n() -> usize {
+    20
+}
+
+fn default_prune_threshold() -> usize {
+    default_top_n() * 100
+}
+
 impl Default for TopNTracker {
     fn default() -> Self {
-        Self::new(20)
+        Self::new(default_top_n())
     }
 }
 
@@ -152,12 +350,16 @@ impl TopNTracker {
     pub fn new(top_n: usize) -> Self {
         Self {
             counts: HashMap::new(),
-            top_n: if top_n == 0 { 20 } else { top_n },
-            prune_threshold: if top_n == 0 { 2000 } else { top_n * 100 },
+            top_n: if top_n == 0 { default_top_n() } else { top_n },
+            prune_threshold: if top_n == 0 {
+                default_prune_threshold()
+            } else {
+                top_n * 100
+            },
         }
     }
 
-    pub fn increment(&mut self, next_id: u32, delta: u32, now: u32) {
+    pub fn increment(&mut self, next_id: u32, delta: u32, now: u32, cfg: &HistoryConfig) {
         self.counts
             .entry(next_id)
             .and_modify(|s| s.touch(now, delta))
@@ -168,11 +370,28 @@ impl TopNTracker {
             });
 
         if self.counts.len() > self.prune_threshold {
-            self.prune(now);
+            self.prune(now, cfg);
         }
     }
 
-    fn prune(&mut self, now: u32) {
+    /// Latest `last_used` among the edges (0 if there are none)
+    pub fn last_used(&self) -> u32 {
+        self.counts.values().map(|s| s.last_used).max().unwrap_or(0)
+    }
+
+    /// Fold an edge learned elsewhere into this tracker
+    fn merge_edge(&mut self, next_id: u32, stat: &EdgeStat, now: u32, cfg: &HistoryConfig) {
+        self.counts
+            .entry(next_id)
+            .and_modify(|s| s.merge(stat))
+            .or_insert(*stat);
+
+        if self.counts.len() > self.prune_threshold {
+            self.prune(now, cfg);
+        }
+    }
+
+    fn prune(&mut self, now: u32, cfg: &HistoryConfig) {
         let keep = self.top_n * 2;
         if self.counts.len() <= keep {
             return;
@@ -180,28 +399,23 @@ impl TopNTracker {
 
         let mut entries: Vec<(u32, EdgeStat)> = self.counts.drain().collect();
         // Sort by effective score
-        entries.sort_by(|a, b| b.1.score(now).cmp(&a.1.score(now)));
+        entries.sort_by_key(|e| std::cmp::Reverse(e.1.score(now, cfg)));
 
         entries.truncate(keep);
         self.counts = entries.into_iter().collect();
     }
 
-    pub fn get_top(&self, now: u32) -> Vec<(u32, u32)> {
-        // returns (id, score) like original requirement or (id, raw_count)?
-        // Requirement was "predict" returning suggestions.
-        // Let's return (id, score_u16)
-        let mut entries: Vec<(u32, u16)> = self
+    /// Top-N edges as (next_id, decayed score clamped to `max_score`)
+    pub fn get_top(&self, now: u32, cfg: &HistoryConfig) -> Vec<(u32, HistoryScore)> {
+        let mut entries: Vec<(u32, HistoryScore)> = self
             .counts
             .iter()
-            .map(|(&k, &v)| (k, v.score(now)))
+            .map(|(&k, &v)| (k, v.score(now, cfg)))
             .collect();
 
-        entries.sort_by(|a, b| b.1.cmp(&a.1));
+        entries.sort_by_key(|e| std::cmp::Reverse(e.1));
         entries.truncate(self.top_n);
         entries
-            .into_iter()
-            .map(|(id, score)| (id, score as u32))
-            .collect() // User wanted u32 score API?
     }
 }
 
@@ -210,82 +424,399 @@ pub struct UserHistory {
     lexicon: UserLexicon,
     // prev_id -> Tracker
     bigrams: HashMap<u32, TopNTracker>,
+    /// `trigram_key(prev_prev, prev)` -> Tracker, when `learn_trigrams` is on
+    #[serde(default)]
+    trigrams: HashMap<u64, TopNTracker>,
+    #[serde(default)]
+    config: HistoryConfig,
+    /// Turns typed words into lexicon keys; not saved, so set it again
+    /// after `load` (`set_normalizer`)
+    #[serde(skip, default = "default_normalizer")]
+    normalizer: Arc<dyn Normalizer>,
+}
+
+fn default_normalizer() -> Arc<dyn Normalizer> {
+    Arc::new(LettersOnly)
+}
+
+/// Key of a trigram context: a u64 rather than a tuple, so the map
+/// serializes as a JSON object like the bigram one
+fn trigram_key(prev_prev: u32, prev: u32) -> u64 {
+    (prev_prev as u64) << 32 | prev as u64
+}
+
+fn split_trigram_key(key: u64) -> (u32, u32) {
+    ((key >> 32) as u32, key as u32)
+}
+
+impl Default for UserHistory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl UserHistory {
     pub fn new() -> Self {
+        Self::new_with_config(HistoryConfig::default())
+    }
+
+    pub fn new_with_config(config: HistoryConfig) -> Self {
         Self {
             lexicon: UserLexicon::new(),
             bigrams: HashMap::new(),
+            trigrams: HashMap::new(),
+            config,
+            normalizer: default_normalizer(),
         }
     }
 
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    /// Normalize words for `learn`, `forget` and `lookup_prefix` with
+    /// `normalizer` rather than `LettersOnly`; use the one the engine
+    /// tokenizes with, or learned words won't meet its ids
+    pub fn set_normalizer(&mut self, normalizer: impl Normalizer + 'static) {
+        self.normalizer = Arc::new(normalizer);
+    }
+
+    /// `set_normalizer` with one shared with the engine
+    pub(crate) fn share_normalizer(&mut self, normalizer: Arc<dyn Normalizer>) {
+        self.normalizer = normalizer;
+    }
+
+    fn normalize(&self, word: &str) -> String {
+        self.normalizer.normalize(word, false)
+    }
+
     /// Learn from input text.
     /// `lookup_global`: Closure to resolve global IDs.
     pub fn learn<F>(&mut self, text: &str, lookup_global: F)
     where
         F: Fn(&str) -> Option<u32>,
     {
-        let now = now_sec();
-        let tokens = tokenize(text);
-        let mut prev_id: Option<u32> = None;
+        self.learn_at(text, lookup_global, now_sec());
+    }
 
-        for token in tokens {
-            let id = if let Some(gid) = lookup_global(&token) {
-                gid
-            } else {
-                if let Some(uid) = self.lexicon.get_or_create(&token, now) {
-                    uid
-                } else {
-                    // Lexicon full
-                    prev_id = None;
+    /// `learn` with an explicit timestamp (seconds since the epoch)
+    pub fn learn_at<F>(&mut self, text: &str, lookup_global: F, now: u32)
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        let mut session = self.session(lookup_global);
+        for word in text.split_whitespace() {
+            session.commit_word_at(word, now);
+        }
+    }
+
+    /// Seed the history from a text file of the user's own writing (notes,
+    /// sent messages), as `learn` would from each of its sentences. The
+    /// file is streamed through the corpus reader, so it may be compressed
+    /// and invalid UTF-8 is decoded lossily. Context resets at every line
+    /// end and after sentence-final punctuation. At most
+    /// `IMPORT_MAX_NEW_WORDS` user words are created; later unknown words
+    /// are skipped, breaking the context like a line end.
+    pub fn learn_file<F>(&mut self, path: &str, lookup_global: F) -> Result<ImportStats>
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        self.learn_file_capped(path, lookup_global, IMPORT_MAX_NEW_WORDS)
+    }
+
+    /// `learn_file` creating at most `max_new_words` user words
+    pub fn learn_file_capped<F>(
+        &mut self,
+        path: &str,
+        lookup_global: F,
+        max_new_words: usize,
+    ) -> Result<ImportStats>
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        let reader = open_corpus(path)?;
+        let now = now_sec();
+        let mut stats = ImportStats::default();
+        let mut session = self.session(lookup_global);
+        for line in CorpusLines::new(reader, Utf8Policy::Lossy) {
+            let line = line.with_context(|| format!("Failed to read {}", path))?;
+            stats.lines += 1;
+            for word in line.split_whitespace() {
+                if stats.new_words >= max_new_words && session.would_create(word) {
+                    stats.skipped += 1;
+                    session.reset();
                     continue;
                 }
-            };
-
-            if let Some(pid) = prev_id {
-                let tracker = self
-                    .bigrams
-                    .entry(pid)
-                    .or_insert_with(|| TopNTracker::new(20));
-                tracker.increment(id, 1, now);
+                let known = session.history.lexicon.word_to_id.len();
+                if session.commit_word_at(word, now).is_some() {
+                    stats.words += 1;
+                    if session.history.lexicon.word_to_id.len() > known {
+                        stats.new_words += 1;
+                    }
+                }
+                if ends_sentence(word) {
+                    session.reset();
+                }
             }
-            prev_id = Some(id);
+            session.reset();
+        }
+        Ok(stats)
+    }
+
+    /// Learn word by word, as a keyboard commits them: the session carries
+    /// the previous word across `HistorySession::commit_word` calls, so a
+    /// sentence committed one word at a time forms the same bigrams as
+    /// `learn` on the whole of it.
+    pub fn session<F>(&mut self, lookup_global: F) -> HistorySession<'_, F>
+    where
+        F: Fn(&str) -> Option<u32>,
+    {
+        HistorySession {
+            history: self,
+            lookup_global,
+            prev_id: None,
+            prev_prev_id: None,
         }
     }
 
-    pub fn predict(&self, prev_id: u32) -> Vec<(u32, u32)> {
-        // (id, score)
+    /// Learn that the user picked `accepted_id` from the suggestions shown
+    /// after `prev_id`.
+    ///
+    /// Call this when a suggestion is tapped, and keep `learn` for text the
+    /// user typed out. An accept counts for more than a typed word: a user
+    /// word gets `WordStat::touch_accept` (with its accept bonus) and the
+    /// edge gets `ACCEPT_EDGE_DELTA` instead of 1. Don't also pass the
+    /// accepted word through `learn`, or it is counted twice.
+    pub fn accept_suggestion(&mut self, prev_id: u32, accepted_id: u32) {
         let now = now_sec();
-        if let Some(tracker) = self.bigrams.get(&prev_id) {
-            tracker.get_top(now)
-        } else {
-            Vec::new()
+        self.lexicon.accept(accepted_id, now);
+        self.bigrams
+            .entry(prev_id)
+            .or_insert_with(|| TopNTracker::new(default_top_n()))
+            .increment(accepted_id, ACCEPT_EDGE_DELTA, now, &self.config);
+        self.enforce_prev_cap(Some(prev_id));
+    }
+
+    /// Forget a learned word ("delete from dictionary"): drop it from the
+    /// user lexicon, free its id for reuse and delete every edge it's the
+    /// prev or next of. `word` is normalized the way `learn` does. Returns
+    /// whether it was known; forgetting an unknown word, or a global one
+    /// (which has no entry here), changes nothing.
+    pub fn forget(&mut self, word: &str) -> bool {
+        let Some(id) = self.lexicon.remove(&self.normalize(word)) else {
+            return false;
+        };
+        self.bigrams.remove(&id);
+        self.bigrams.retain(|_, tracker| {
+            tracker.counts.remove(&id);
+            !tracker.counts.is_empty()
+        });
+        self.trigrams.retain(|&key, tracker| {
+            let (prev_prev, prev) = split_trigram_key(key);
+            tracker.counts.remove(&id);
+            prev_prev != id && prev != id && !tracker.counts.is_empty()
+        });
+        true
+    }
+
+    /// Number of prev words with a bigram tracker
+    pub fn prev_count(&self) -> usize {
+        self.bigrams.len()
+    }
+
+    /// Number of (prev_prev, prev) pairs with a trigram tracker
+    pub fn trigram_context_count(&self) -> usize {
+        self.trigrams.len()
+    }
+
+    /// Evict least-recently-used prevs (ties: lowest id) until at most
+    /// `max_prevs` remain, never evicting `keep`.
+    fn enforce_prev_cap(&mut self, keep: Option<u32>) {
+        evict_lru(&mut self.bigrams, self.config.max_prevs, keep);
+    }
+
+    /// `enforce_prev_cap` for the trigram contexts and `max_trigram_contexts`
+    fn enforce_trigram_cap(&mut self, keep: Option<u64>) {
+        evict_lru(&mut self.trigrams, self.config.max_trigram_contexts, keep);
+    }
+
+    /// What the user typed after `prev`, as (id, score) by score descending
+    pub fn predict(&self, prev_id: u32) -> Vec<(u32, HistoryScore)> {
+        let now = now_sec();
+        match self.bigrams.get(&prev_id) {
+            Some(tracker) => tracker.get_top(now, &self.config),
+            None => Vec::new(),
         }
     }
 
+    /// What the user typed after `prev_prev` `prev`, as (id, score) like
+    /// `predict`, padded with `predict(prev)` for the rest of the top-N.
+    /// A word seen after the pair comes first even when the bigram alone
+    /// scores it lower. Without `learn_trigrams` this is just `predict`.
+    pub fn predict_trigram(&self, prev_prev_id: u32, prev_id: u32) -> Vec<(u32, HistoryScore)> {
+        let now = now_sec();
+        let Some(tracker) = self.trigrams.get(&trigram_key(prev_prev_id, prev_id)) else {
+            return self.predict(prev_id);
+        };
+        let mut out = tracker.get_top(now, &self.config);
+        let limit = out.len().max(default_top_n());
+        let seen: HashSet<u32> = out.iter().map(|&(id, _)| id).collect();
+        out.extend(
+            self.predict(prev_id)
+                .into_iter()
+                .filter(|(id, _)| !seen.contains(id)),
+        );
+        out.truncate(limit);
+        out
+    }
+
+    /// The words the user typed most, as (id, score) by score descending
+    /// (ties: lower id first). User words score by their own stats, as in
+    /// `lookup_prefix`; global words have none here, so theirs is the sum
+    /// of the scores of the edges into them.
+    pub fn top_words(&self, limit: usize) -> Vec<(u32, HistoryScore)> {
+        let now = now_sec();
+        let mut scores: HashMap<u32, HistoryScore> = HashMap::new();
+        for tracker in self.bigrams.values() {
+            for (&next_id, stat) in &tracker.counts {
+                if next_id < USER_ID_START {
+                    let score = scores.entry(next_id).or_default();
+                    score.0 = score.0.saturating_add(stat.score(now, &self.config).0);
+                }
+            }
+        }
+        for (&id, (_, stat)) in &self.lexicon.id_to_meta {
+            scores.insert(id, stat.score(now, &self.config));
+        }
+        let mut top: Vec<(u32, HistoryScore)> = scores
+            .into_iter()
+            .filter(|&(_, s)| s > HistoryScore(0))
+            .collect();
+        top.sort_unstable_by_key(|&(id, score)| (std::cmp::Reverse(score), id));
+        top.truncate(limit);
+        top
+    }
+
     /// Find user words starting with `prefix`
-    pub fn lookup_prefix(&self, prefix: &str, limit: usize) -> Vec<(u32, u32)> {
+    pub fn lookup_prefix(&self, prefix: &str, limit: usize) -> Vec<(u32, HistoryScore)> {
         let now = now_sec();
-        // Since UserLexicon is relatively small (thousands), linear scan is acceptable for now.
-        // For larger lexicons, a Trie or FST should be used.
-        let norm_prefix = normalize_token(prefix);
+        // A linear scan over the user words unless a compiled FST is
+        // attached (`attach_lexicon_fst`)
+        let norm_prefix = self.normalize(prefix);
         if norm_prefix.is_empty() {
             return Vec::new();
         }
 
-        let mut matches: Vec<(u32, u16)> = self
+        let mut matches: Vec<(u32, HistoryScore)> = self
             .lexicon
-            .id_to_meta
-            .iter()
-            .filter(|(_, (word, _))| word.starts_with(&norm_prefix))
-            .map(|(&id, (_, stat))| (id, stat.score(now)))
+            .with_prefix(&norm_prefix)
+            .into_iter()
+            .map(|(id, stat)| (id, stat.score(now, &self.config)))
             .collect();
 
-        matches.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        matches.sort_unstable_by_key(|&(id, score)| (std::cmp::Reverse(score), id));
         matches.truncate(limit);
+        matches
+    }
+
+    /// Merge another history (e.g. from a second device) into this one.
+    ///
+    /// Both histories allocate user ids from `USER_ID_START`, so the other
+    /// side's user ids are remapped by word: shared words keep this history's
+    /// id, new words get fresh ids here. Word and edge stats are summed, with
+    /// `last_used` taken as the later of the two. Global ids pass through
+    /// unchanged. `now` is used to rank edges if a tracker needs pruning.
+    /// This history's config is kept, and its `max_prevs` cap applies to
+    /// the merged result.
+    pub fn merge(&mut self, other: &UserHistory, now: u32) {
+        let mut remap: HashMap<u32, u32> = HashMap::new();
+        for (&other_id, (word, stat)) in &other.lexicon.id_to_meta {
+            if let Some(id) = self.lexicon.merge_word(word, stat) {
+                remap.insert(other_id, id);
+            }
+        }
+        // Global ids map to themselves; user words we couldn't add are dropped
+        let map_id = |id: u32| -> Option<u32> {
+            if id < USER_ID_START {
+                Some(id)
+            } else {
+                remap.get(&id).copied()
+            }
+        };
 
-        matches.into_iter().map(|(id, s)| (id, s as u32)).collect()
+        for (&other_prev, tracker) in &other.bigrams {
+            let Some(prev) = map_id(other_prev) else {
+                continue;
+            };
+            let target = self.bigrams.entry(prev).or_default();
+            for (&other_next, stat) in &tracker.counts {
+                if let Some(next) = map_id(other_next) {
+                    target.merge_edge(next, stat, now, &self.config);
+                }
+            }
+        }
+        self.enforce_prev_cap(None);
+
+        for (&other_key, tracker) in &other.trigrams {
+            let (other_prev_prev, other_prev) = split_trigram_key(other_key);
+            let (Some(prev_prev), Some(prev)) = (map_id(other_prev_prev), map_id(other_prev))
+            else {
+                continue;
+            };
+            let target = self
+                .trigrams
+                .entry(trigram_key(prev_prev, prev))
+                .or_default();
+            for (&other_next, stat) in &tracker.counts {
+                if let Some(next) = map_id(other_next) {
+                    target.merge_edge(next, stat, now, &self.config);
+                }
+            }
+        }
+        self.enforce_trigram_cap(None);
+    }
+
+    /// Compile the user words into an FST at `path` (word -> user id) for
+    /// `attach_lexicon_fst`. Words learned after a compile are scanned
+    /// linearly until the next one, so recompile now and then, e.g. when
+    /// saving once `unindexed_words` grows.
+    pub fn compile_lexicon(&self, path: &str) -> Result<()> {
+        let mut words: Vec<(&str, u32)> = self
+            .lexicon
+            .word_to_id
+            .iter()
+            .map(|(word, &id)| (word.as_str(), id))
+            .collect();
+        words.sort_unstable();
+        crate::write_atomic(path, |writer| {
+            let mut builder = MapBuilder::new(writer).context("fst MapBuilder")?;
+            for (word, id) in words {
+                builder.insert(word, id as u64)?;
+            }
+            builder.finish()?;
+            Ok(())
+        })
+        .context("Failed to write user lexicon FST")
+    }
+
+    /// Serve `lookup_prefix` from a compiled FST instead of scanning every
+    /// user word. Stats stay in memory; only the word index moves to disk.
+    /// Words the FST is missing (learned since it was compiled) are found
+    /// here once and tracked until the next compile.
+    pub fn attach_lexicon_fst(&mut self, fst: UserLexiconFst) {
+        let lexicon = &mut self.lexicon;
+        lexicon.unindexed = lexicon
+            .word_to_id
+            .iter()
+            .filter(|&(word, &id)| fst.get(word) != Some(id))
+            .map(|(_, &id)| id)
+            .collect();
+        lexicon.fst = Some(fst);
+    }
+
+    /// User words the attached FST doesn't have yet (0 without one)
+    pub fn unindexed_words(&self) -> usize {
+        self.lexicon.unindexed.len()
     }
 
     pub fn get_user_word(&self, id: u32) -> Option<&str> {
@@ -296,39 +827,251 @@ impl UserHistory {
         self.lexicon.word_to_id.get(word).copied()
     }
 
-    /// Save UserHistory to a JSON file
+    /// Save UserHistory to a JSON file tagged with `HISTORY_VERSION`.
+    /// Written through `write_atomic`, so a crash mid-save leaves the
+    /// previous file intact rather than a half-written one.
     pub fn save(&self, path: &str) -> Result<()> {
-        let file = std::fs::File::create(path).context("Failed to create history file")?;
-        let writer = std::io::BufWriter::new(file);
-        serde_json::to_writer(writer, self).context("Failed to serialize history")?;
-        Ok(())
+        let tagged = Tagged {
+            version: HISTORY_VERSION,
+            history: self,
+        };
+        crate::write_atomic(path, |writer| {
+            serde_json::to_writer(writer, &tagged).context("Failed to serialize history")
+        })
+        .context("Failed to save history file")
     }
 
-    /// Load UserHistory from a JSON file. Returns empty if file doesn't exist or error.
+    /// Load UserHistory from a JSON file, migrating an older layout (see
+    /// `HISTORY_VERSION`). Returns empty if the file doesn't exist, or
+    /// doesn't parse (reported on stderr). Fails if it can't be read, or
+    /// was written by a newer version: starting empty would overwrite it
+    /// on the next save.
     pub fn load(path: &str) -> Result<Self> {
         if !std::path::Path::new(path).exists() {
             return Ok(Self::new());
         }
         let file = std::fs::File::open(path).context("Failed to open history file")?;
         let reader = std::io::BufReader::new(file);
-        let history = serde_json::from_reader(reader).context("Failed to deserialize history")?;
-        Ok(history)
+        let value: serde_json::Value = match serde_json::from_reader(reader) {
+            Ok(value) => value,
+            Err(e) if e.is_io() => return Err(e).context("Failed to read history file"),
+            Err(e) => return Ok(Self::corrupt(path, e)),
+        };
+        let version = history_version(&value);
+        if version > HISTORY_VERSION {
+            bail!(
+                "history file {} is version {}, newer than the supported {}",
+                path,
+                version,
+                HISTORY_VERSION
+            );
+        }
+        match serde_json::from_value(migrate(value, version)) {
+            Ok(history) => Ok(history),
+            Err(e) => Ok(Self::corrupt(path, e)),
+        }
+    }
+
+    fn corrupt(path: &str, e: serde_json::Error) -> Self {
+        eprintln!(
+            "warning: history file {} is corrupt ({}); starting empty",
+            path, e
+        );
+        Self::new()
+    }
+}
+
+/// A history as `save` writes it: the fields plus the layout version
+#[derive(Serialize)]
+struct Tagged<'a> {
+    version: u32,
+    #[serde(flatten)]
+    history: &'a UserHistory,
+}
+
+/// The layout version of a saved history: its tag, or 1 for untagged files
+fn history_version(value: &serde_json::Value) -> u32 {
+    value
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .map_or(1, |v| v.min(u32::MAX as u64) as u32)
+}
+
+/// Upgrade a saved history from layout `version` to `HISTORY_VERSION`,
+/// one step at a time. Each step fills what its version added with what a
+/// fresh history has, so old data is kept rather than discarded. Anything
+/// that isn't an object is left for deserialization to reject.
+fn migrate(mut value: serde_json::Value, version: u32) -> serde_json::Value {
+    let Some(fields) = value.as_object_mut() else {
+        return value;
+    };
+    if version < 2 {
+        fields
+            .entry("config")
+            .or_insert_with(|| serde_json::json!(HistoryConfig::default()));
+        fields
+            .entry("trigrams")
+            .or_insert_with(|| serde_json::json!({}));
+        if let Some(lexicon) = fields.get_mut("lexicon").and_then(|l| l.as_object_mut()) {
+            lexicon
+                .entry("free_ids")
+                .or_insert_with(|| serde_json::json!([]));
+        }
     }
+    fields.remove("version");
+    value
 }
 
-/// Robust normalization and tokenization
-fn normalize_token(raw: &str) -> String {
-    let s = raw.nfc().collect::<String>();
-    s.chars()
-        .map(|c| if c == '’' || c == '‘' { '\'' } else { c })
-        .flat_map(|c| c.to_lowercase())
-        .filter(|c| c.is_alphabetic() || *c == '\'')
-        .collect()
+/// The user words compiled by `UserHistory::compile_lexicon`, mapped
+/// from disk
+pub struct UserLexiconFst {
+    map: Map<Mmap>,
+}
+
+impl UserLexiconFst {
+    pub fn open(path: &str) -> Result<Self> {
+        let mmap = crate::bigram::map_file(path)?;
+        let map = Map::new(mmap).with_context(|| format!("Invalid user lexicon FST {}", path))?;
+        Ok(Self { map })
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The user id compiled for `word`
+    pub fn get(&self, word: &str) -> Option<u32> {
+        self.map.get(word).map(|v| v as u32)
+    }
+
+    /// (word, id) of every compiled word starting with `prefix`, in key order
+    pub fn prefix(&self, prefix: &str) -> Vec<(String, u32)> {
+        let mut stream = self.map.range().ge(prefix).into_stream();
+        let mut matches = Vec::new();
+        while let Some((key, v)) = stream.next() {
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            matches.push((String::from_utf8_lossy(key).into_owned(), v as u32));
+        }
+        matches
+    }
 }
 
-fn tokenize(text: &str) -> Vec<String> {
-    text.split_whitespace()
-        .map(normalize_token)
-        .filter(|s| !s.is_empty())
-        .collect()
+/// Evict least-recently-used trackers (ties: lowest key) until at most
+/// `cap` remain, never evicting `keep`. A cap of 0 disables it.
+///
+/// Each eviction scans every tracker, which is fine for a cap in the
+/// tens of thousands since it runs once per new context past the cap.
+fn evict_lru<K: Copy + Ord + std::hash::Hash>(
+    trackers: &mut HashMap<K, TopNTracker>,
+    cap: usize,
+    keep: Option<K>,
+) {
+    if cap == 0 {
+        return;
+    }
+    while trackers.len() > cap {
+        let oldest = trackers
+            .iter()
+            .filter(|(&key, _)| Some(key) != keep)
+            .map(|(&key, tracker)| (tracker.last_used(), key))
+            .min();
+        match oldest {
+            Some((_, key)) => trackers.remove(&key),
+            None => break,
+        };
+    }
+}
+
+/// Word-by-word learning into a `UserHistory` (see `UserHistory::session`)
+pub struct HistorySession<'a, F> {
+    history: &'a mut UserHistory,
+    lookup_global: F,
+    prev_id: Option<u32>,
+    /// The word before `prev_id`, for trigram learning
+    prev_prev_id: Option<u32>,
+}
+
+impl<F: Fn(&str) -> Option<u32>> HistorySession<'_, F> {
+    /// Learn one committed word, and the edge to it from the last one.
+    /// Returns its id; None if nothing of it is a word (the context is
+    /// kept) or the user lexicon is full (the context is reset).
+    pub fn commit_word(&mut self, word: &str) -> Option<u32> {
+        self.commit_word_at(word, now_sec())
+    }
+
+    /// `commit_word` with an explicit timestamp (seconds since the epoch)
+    pub fn commit_word_at(&mut self, word: &str, now: u32) -> Option<u32> {
+        let token = self.history.normalize(word);
+        if token.is_empty() {
+            return None;
+        }
+        let history = &mut *self.history;
+        let id = match (self.lookup_global)(&token) {
+            Some(gid) => gid,
+            None => match history.lexicon.get_or_create(&token, now) {
+                Some(uid) => uid,
+                None => {
+                    // Lexicon full
+                    self.reset();
+                    return None;
+                }
+            },
+        };
+
+        if let Some(pid) = self.prev_id {
+            history
+                .bigrams
+                .entry(pid)
+                .or_insert_with(|| TopNTracker::new(default_top_n()))
+                .increment(id, 1, now, &history.config);
+            history.enforce_prev_cap(Some(pid));
+            let learn_trigrams = history.config.learn_trigrams;
+            if let Some(ppid) = self.prev_prev_id.filter(|_| learn_trigrams) {
+                let key = trigram_key(ppid, pid);
+                history
+                    .trigrams
+                    .entry(key)
+                    .or_insert_with(|| TopNTracker::new(default_top_n()))
+                    .increment(id, 1, now, &history.config);
+                history.enforce_trigram_cap(Some(key));
+            }
+        }
+        self.prev_prev_id = self.prev_id;
+        self.prev_id = Some(id);
+        Some(id)
+    }
+
+    /// Commit each word of `text` in turn, continuing the current context
+    pub fn observe(&mut self, text: &str) {
+        let now = now_sec();
+        for word in text.split_whitespace() {
+            self.commit_word_at(word, now);
+        }
+    }
+
+    /// Forget the context at a sentence boundary, so the next word starts
+    /// no edge
+    pub fn reset(&mut self) {
+        self.prev_id = None;
+        self.prev_prev_id = None;
+    }
+
+    /// True when committing `word` would add a user word
+    fn would_create(&self, word: &str) -> bool {
+        let token = self.history.normalize(word);
+        !token.is_empty()
+            && (self.lookup_global)(&token).is_none()
+            && !self.history.lexicon.word_to_id.contains_key(&token)
+    }
+
+    /// Id of the last word committed since the last `reset`
+    pub fn last_id(&self) -> Option<u32> {
+        self.prev_id
+    }
 }
@@ -0,0 +1,225 @@
+//! Format invariant checks for BGRM bigram files
+
+use crate::bigram::{
+    header_size, read_bit_widths, read_flags, read_u64, reserved_header_span, EDGE_FLAGS_KNOWN,
+    FLAG_RAW_COUNTS, RAW_COUNT_BITS, VERSION, WEIGHT_BITS,
+};
+use anyhow::{bail, Context, Result};
+use memmap2::Mmap;
+use std::collections::HashSet;
+use std::fs::File;
+
+const MAGIC: u32 = 0x4247524D; // "BGRM"
+
+/// Result of `validate_bigram`: raw header fields plus per-invariant error counts
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub magic: u32,
+    pub version: u32,
+    pub vocab_size: usize,
+    pub edges_count: usize,
+    pub top_n: usize,
+    /// Declared widths (implicit 32/16 for version 1; 0 if unreadable)
+    pub word_id_bits: u32,
+    pub weight_bits: u32,
+    /// Header flags (0 for version 1 or if unreadable)
+    pub flags: u32,
+    /// Vocab fingerprint (version 3 and later)
+    pub vocab_hash: Option<u64>,
+
+    pub magic_ok: bool,
+    /// Version is known and its declared flags and bit widths are supported
+    pub version_ok: bool,
+    pub expected_size: usize,
+    pub actual_size: usize,
+    pub size_ok: bool,
+
+    pub offset_errors: usize,
+    pub sorted_errors: usize,
+    pub duplicate_errors: usize,
+    /// Edges whose next_id is not below `vocab_size`
+    pub range_errors: usize,
+    /// Nonzero reserved header bytes (1 if any), plus version 3 weight
+    /// edges with flag bits outside `EDGE_FLAGS_KNOWN`
+    pub reserved_errors: usize,
+
+    /// Edge count per prev_id (only for index entries that were readable)
+    pub lens: Vec<usize>,
+}
+
+impl ValidationReport {
+    pub fn all_pass(&self) -> bool {
+        self.magic_ok
+            && self.version_ok
+            && self.size_ok
+            && self.offset_errors == 0
+            && self.sorted_errors == 0
+            && self.duplicate_errors == 0
+            && self.range_errors == 0
+            && self.reserved_errors == 0
+    }
+
+    /// One line per failed invariant, e.g. "offset bounds: 3 errors";
+    /// empty when `all_pass`
+    pub fn failures(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        if !self.magic_ok {
+            out.push(format!("magic: 0x{:08X}", self.magic));
+        }
+        if !self.version_ok {
+            out.push(format!("version: {} unsupported", self.version));
+        }
+        if !self.size_ok {
+            out.push(format!(
+                "size: expected {} bytes, got {}",
+                self.expected_size, self.actual_size
+            ));
+        }
+        for (name, errors) in [
+            ("offset bounds", self.offset_errors),
+            ("weight order", self.sorted_errors),
+            ("duplicate next ids", self.duplicate_errors),
+            ("next ids past the vocab", self.range_errors),
+            ("reserved bits", self.reserved_errors),
+        ] {
+            if errors > 0 {
+                out.push(format!("{}: {} errors", name, errors));
+            }
+        }
+        out
+    }
+}
+
+/// Check magic, version, size formula, offset bounds, weight ordering,
+/// duplicate next_ids, next_ids past the vocab and reserved bits. Never
+/// panics on malformed input.
+pub fn validate_bigram(data: &[u8]) -> ValidationReport {
+    let mut report = ValidationReport {
+        actual_size: data.len(),
+        ..Default::default()
+    };
+    if data.len() < header_size(1) {
+        return report;
+    }
+
+    let read_u32 =
+        |off: usize| u32::from_le_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]]);
+    let read_u16 = |off: usize| u16::from_le_bytes([data[off], data[off + 1]]);
+
+    report.magic = read_u32(0);
+    report.version = read_u32(4);
+    report.vocab_size = read_u32(8) as usize;
+    report.edges_count = read_u32(12) as usize;
+    report.top_n = read_u32(16) as usize;
+
+    report.magic_ok = report.magic == MAGIC;
+    if let Ok(flags) = read_flags(data, report.version) {
+        let max_weight_bits = if flags & FLAG_RAW_COUNTS != 0 {
+            RAW_COUNT_BITS
+        } else {
+            WEIGHT_BITS
+        };
+        if let Ok((word_id_bits, weight_bits)) =
+            read_bit_widths(data, report.version, VERSION, 20, max_weight_bits)
+        {
+            report.flags = flags;
+            report.word_id_bits = word_id_bits;
+            report.weight_bits = weight_bits;
+            report.version_ok = true;
+        }
+    }
+    let raw_counts = report.flags & FLAG_RAW_COUNTS != 0;
+    let header_size = header_size(report.version);
+    if let Some((start, end)) = reserved_header_span(report.version) {
+        if data[start..end].iter().any(|&b| b != 0) {
+            report.reserved_errors += 1;
+        }
+    }
+    let check_edge_flags = report.version == VERSION && !raw_counts;
+    if report.version >= 3 && data.len() >= header_size {
+        report.vocab_hash = Some(read_u64(data, 32));
+    }
+
+    // Invariant A: Size formula
+    let index_size = report.vocab_size * 8;
+    report.expected_size = header_size + index_size + report.edges_count * 8;
+    report.size_ok = report.actual_size == report.expected_size;
+
+    // Invariant B: offsets in bounds, weights non-increasing, no duplicate
+    // next_ids, next_ids within the vocab
+    let edges_base = header_size + index_size;
+    // vocab_size comes from the header: reserve no more index entries
+    // than the file can actually hold
+    let readable_rows = data.len().saturating_sub(header_size) / 8;
+    report.lens.reserve(report.vocab_size.min(readable_rows));
+
+    for prev_id in 0..report.vocab_size {
+        let idx_offset = header_size + prev_id * 8;
+        if idx_offset + 8 > data.len() {
+            // Index itself is truncated; every remaining entry is unreadable
+            report.offset_errors += report.vocab_size - prev_id;
+            break;
+        }
+        let offset = read_u32(idx_offset) as usize;
+        let len = read_u16(idx_offset + 4) as usize;
+        report.lens.push(len);
+
+        if len == 0 {
+            continue;
+        }
+
+        let edge_start = edges_base + offset;
+        let edge_end = edge_start + len * 8;
+        if edge_end > data.len() {
+            report.offset_errors += 1;
+            continue;
+        }
+
+        let mut prev_weight = u32::MAX;
+        let mut seen_ids: HashSet<u32> = HashSet::new();
+
+        for i in 0..len {
+            let e_off = edge_start + i * 8;
+            let next_id = read_u32(e_off);
+            // Raw counts fill the weight and flags fields
+            let weight = if raw_counts {
+                read_u32(e_off + 4)
+            } else {
+                read_u16(e_off + 4) as u32
+            };
+
+            if weight > prev_weight {
+                report.sorted_errors += 1;
+            }
+            prev_weight = weight;
+
+            if !seen_ids.insert(next_id) {
+                report.duplicate_errors += 1;
+            }
+            if next_id as usize >= report.vocab_size {
+                report.range_errors += 1;
+            }
+            if check_edge_flags && read_u16(e_off + 6) & !EDGE_FLAGS_KNOWN != 0 {
+                report.reserved_errors += 1;
+            }
+        }
+    }
+
+    report
+}
+
+/// `validate_bigram` on the file at `path`, for builders checking their own
+/// output: an error listing the failed invariants unless all pass
+pub fn validate_bigram_file(path: &str) -> Result<ValidationReport> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    let report = validate_bigram(&mmap);
+    if !report.all_pass() {
+        bail!(
+            "{} failed validation: {}",
+            path,
+            report.failures().join("; ")
+        );
+    }
+    Ok(report)
+}
@@ -3,23 +3,21 @@
 //! Usage: cargo run --release --bin validate_vi_bigram
 
 use anyhow::Result;
+use combined2fst::bigram::header_size;
+use combined2fst::validate_bigram;
 use memmap2::Mmap;
-use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-const MAGIC: u32 = 0x4247524D;
-
 fn main() -> Result<()> {
     let file = File::open("vi.bigram.bin")?;
     let mmap = unsafe { Mmap::map(&file)? };
     let data = mmap.as_ref();
 
-    let magic = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-    let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
-    let vocab_size = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
-    let edges_count = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
-    let top_n = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+    let report = validate_bigram(data);
+    let vocab_size = report.vocab_size;
+    let top_n = report.top_n;
+    let lens = &report.lens;
 
     println!("═══════════════════════════════════════════════════════════════");
     println!("             VIETNAMESE BIGRAM VALIDATION TESTS                 ");
@@ -30,97 +28,68 @@ fn main() -> Result<()> {
     println!("│ 3.1 FORMAT INVARIANTS                                       │");
     println!("└─────────────────────────────────────────────────────────────┘\n");
 
-    let magic_ok = magic == MAGIC;
     println!(
         "  Magic: 0x{:08X} {}",
-        magic,
-        if magic_ok { "✓" } else { "✗" }
+        report.magic,
+        if report.magic_ok { "✓" } else { "✗" }
     );
-
-    let version_ok = version == 1;
     println!(
-        "  Version: {} {}",
-        version,
-        if version_ok { "✓" } else { "✗" }
+        "  Version: {} (word_id_bits={}, weight_bits={}) {}",
+        report.version,
+        report.word_id_bits,
+        report.weight_bits,
+        if report.version_ok { "✓" } else { "✗" }
     );
-
-    let header_size = 32;
-    let index_size = vocab_size * 8;
-    let edges_size = edges_count * 8;
-    let expected_size = header_size + index_size + edges_size;
-    let actual_size = data.len();
-    let size_ok = actual_size == expected_size;
     println!(
         "  Size: expected={}, actual={} {}",
-        expected_size,
-        actual_size,
-        if size_ok { "✓" } else { "✗" }
+        report.expected_size,
+        report.actual_size,
+        if report.size_ok { "✓" } else { "✗" }
     );
-
-    let edges_base = header_size + index_size;
-    let mut offset_errors = 0;
-    let mut sorted_errors = 0;
-    let mut duplicate_errors = 0;
-    let mut lens: Vec<usize> = Vec::with_capacity(vocab_size);
-
-    for prev_id in 0..vocab_size {
-        let idx_offset = header_size + prev_id * 8;
-        let offset = u32::from_le_bytes([
-            data[idx_offset],
-            data[idx_offset + 1],
-            data[idx_offset + 2],
-            data[idx_offset + 3],
-        ]) as usize;
-        let len = u16::from_le_bytes([data[idx_offset + 4], data[idx_offset + 5]]) as usize;
-        lens.push(len);
-
-        if len == 0 {
-            continue;
-        }
-
-        let edge_start = edges_base + offset;
-        let edge_end = edge_start + len * 8;
-        if edge_end > actual_size {
-            offset_errors += 1;
-            continue;
-        }
-
-        let mut prev_weight = u16::MAX;
-        let mut seen: HashSet<u32> = HashSet::new();
-
-        for i in 0..len {
-            let e_off = edge_start + i * 8;
-            let next_id = u32::from_le_bytes([
-                data[e_off],
-                data[e_off + 1],
-                data[e_off + 2],
-                data[e_off + 3],
-            ]);
-            let weight = u16::from_le_bytes([data[e_off + 4], data[e_off + 5]]);
-            if weight > prev_weight {
-                sorted_errors += 1;
-            }
-            prev_weight = weight;
-            if !seen.insert(next_id) {
-                duplicate_errors += 1;
-            }
-        }
-    }
-
     println!(
         "  Offset bounds: {} errors {}",
-        offset_errors,
-        if offset_errors == 0 { "✓" } else { "✗" }
+        report.offset_errors,
+        if report.offset_errors == 0 {
+            "✓"
+        } else {
+            "✗"
+        }
     );
     println!(
         "  Weight sorted: {} errors {}",
-        sorted_errors,
-        if sorted_errors == 0 { "✓" } else { "✗" }
+        report.sorted_errors,
+        if report.sorted_errors == 0 {
+            "✓"
+        } else {
+            "✗"
+        }
     );
     println!(
         "  No duplicates: {} errors {}",
-        duplicate_errors,
-        if duplicate_errors == 0 { "✓" } else { "✗" }
+        report.duplicate_errors,
+        if report.duplicate_errors == 0 {
+            "✓"
+        } else {
+            "✗"
+        }
+    );
+    println!(
+        "  Next ids in vocab: {} errors {}",
+        report.range_errors,
+        if report.range_errors == 0 {
+            "✓"
+        } else {
+            "✗"
+        }
+    );
+    println!(
+        "  Reserved bits zero: {} errors {}",
+        report.reserved_errors,
+        if report.reserved_errors == 0 {
+            "✓"
+        } else {
+            "✗"
+        }
     );
 
     // 3.2 COVERAGE
@@ -136,7 +105,7 @@ fn main() -> Result<()> {
     );
 
     let mut histogram = vec![0usize; top_n + 1];
-    for &len in &lens {
+    for &len in lens {
         histogram[len.min(top_n)] += 1;
     }
     println!("\n  Histogram:");
@@ -152,6 +121,9 @@ fn main() -> Result<()> {
     println!("│ 3.3 PROBE SANITY CHECK                                      │");
     println!("└─────────────────────────────────────────────────────────────┘\n");
 
+    let header_size = header_size(report.version);
+    let edges_base = header_size + vocab_size * 8;
+
     let vocab: Vec<String> = BufReader::new(File::open("vi.syllable.vocab.txt")?)
         .lines()
         .collect::<std::io::Result<_>>()?;
@@ -199,12 +171,7 @@ fn main() -> Result<()> {
     }
 
     println!("\n═══════════════════════════════════════════════════════════════");
-    let all_pass = magic_ok
-        && version_ok
-        && size_ok
-        && offset_errors == 0
-        && sorted_errors == 0
-        && duplicate_errors == 0;
+    let all_pass = report.all_pass();
     println!(
         "  {} ALL FORMAT TESTS {}",
         if all_pass { "✅" } else { "❌" },
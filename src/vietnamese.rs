@@ -0,0 +1,239 @@
+//! Vietnamese syllable segmentation over `vi.syllable.fst`
+//!
+//! Users often type syllables run together ("xinchào"), while the bigram
+//! and trigram lookups need one syllable per token. `segment` splits each
+//! whitespace-separated chunk into syllables from the FST, walking the FST
+//! from every position to find the syllables that start there.
+//!
+//! The syllable FST carries no useful frequencies (every entry has the
+//! same prob), so the best split is the one that leaves the fewest
+//! characters uncovered, then uses the fewest syllables. Remaining ties go
+//! to the longest leading syllable. Uncovered characters are kept as
+//! chunks of their own and mark the result incomplete.
+//!
+//! `complete` finishes a half-typed syllable ("ngh" -> "nghe", "nghĩ").
+//! Input typed without any tone or vowel marks is also matched against the
+//! syllables with their marks folded away, so "nghi" offers "nghĩ" too;
+//! completions that match the typed letters exactly rank first.
+
+use anyhow::{Context, Result};
+use fst::automaton::{Automaton, Str};
+use fst::{IntoStreamer, Map, Streamer};
+use memmap2::Mmap;
+use std::fs::File;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Result of `VietnameseEngine::segment`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segmentation {
+    /// Syllables in input order, with the input's casing; uncovered runs of
+    /// characters appear as they were typed
+    pub syllables: Vec<String>,
+    /// Every character was covered by a known syllable
+    pub complete: bool,
+}
+
+/// One completion from `VietnameseEngine::complete`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyllableMatch {
+    pub syllable: String,
+    pub syllable_id: u32,
+    pub prob: u8,
+    /// The syllable starts with the typed prefix, marks included
+    pub exact: bool,
+}
+
+pub struct VietnameseEngine {
+    syllables: Map<Mmap>,
+}
+
+impl VietnameseEngine {
+    /// Open a syllable FST keyed on lowercase syllables (`vi.syllable.fst`)
+    pub fn open(syllable_fst: &str) -> Result<Self> {
+        let file =
+            File::open(syllable_fst).with_context(|| format!("Failed to open {}", syllable_fst))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let syllables = Map::new(mmap).with_context(|| format!("Invalid {}", syllable_fst))?;
+        Ok(Self { syllables })
+    }
+
+    pub fn is_syllable(&self, word: &str) -> bool {
+        self.syllables.contains_key(word.to_lowercase())
+    }
+
+    /// Syllables that start with `prefix`, ignoring case. A prefix typed
+    /// without any marks also matches syllables whose unmarked form starts
+    /// with it; a marked one is taken as meant. Exact matches come first,
+    /// then each group by stored prob, then shorter syllables (the closest
+    /// completions), then alphabetically. At most `limit`.
+    ///
+    /// The unmarked pass scans every syllable; the syllable list is small
+    /// (around ten thousand entries).
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<SyllableMatch> {
+        let prefix = prefix.trim().to_lowercase();
+        if prefix.is_empty() || limit == 0 {
+            return Vec::new();
+        }
+        let unmarked = fold_marks(&prefix) == prefix;
+
+        let mut matches = Vec::new();
+        let mut push = |key: &[u8], value: u64, exact: bool| {
+            matches.push(SyllableMatch {
+                syllable: String::from_utf8_lossy(key).into_owned(),
+                syllable_id: ((value >> 16) & 0xFFFF_FFFF) as u32,
+                prob: (value & 0xFF) as u8,
+                exact,
+            });
+        };
+        let mut stream = self
+            .syllables
+            .search(Str::new(&prefix).starts_with())
+            .into_stream();
+        while let Some((key, value)) = stream.next() {
+            push(key, value, true);
+        }
+        if unmarked {
+            let mut stream = self.syllables.stream();
+            while let Some((key, value)) = stream.next() {
+                let Ok(key_str) = std::str::from_utf8(key) else {
+                    continue;
+                };
+                if !key_str.starts_with(&prefix) && fold_marks(key_str).starts_with(&prefix) {
+                    push(key, value, false);
+                }
+            }
+        }
+
+        matches.sort_by(|a, b| {
+            b.exact
+                .cmp(&a.exact)
+                .then(b.prob.cmp(&a.prob))
+                .then(a.syllable.chars().count().cmp(&b.syllable.chars().count()))
+                .then_with(|| a.syllable.cmp(&b.syllable))
+        });
+        matches.truncate(limit);
+        matches
+    }
+
+    /// Split `run_on` into syllables (see the module docs for how the
+    /// split is chosen). Whitespace is kept as a hard boundary.
+    pub fn segment(&self, run_on: &str) -> Segmentation {
+        let mut result = Segmentation {
+            syllables: Vec::new(),
+            complete: true,
+        };
+        for chunk in run_on.split_whitespace() {
+            self.segment_chunk(chunk, &mut result);
+        }
+        result
+    }
+
+    fn segment_chunk(&self, chunk: &str, out: &mut Segmentation) {
+        let bounds: Vec<usize> = chunk
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([chunk.len()])
+            .collect();
+        let n = bounds.len() - 1;
+
+        // best[i] = (uncovered chars, pieces) for chunk[bounds[i]..];
+        // step[i] = where its first piece ends and whether it's a syllable
+        let mut best: Vec<(usize, usize)> = vec![(0, 0); n + 1];
+        let mut step: Vec<(usize, bool)> = vec![(n, false); n + 1];
+        for i in (0..n).rev() {
+            let (uncovered, pieces) = best[i + 1];
+            best[i] = (uncovered + 1, pieces + 1);
+            step[i] = (i + 1, false);
+            for j in self.syllable_ends(chunk, &bounds, i) {
+                let (uncovered, pieces) = best[j];
+                // `<=` with ascending j: ties go to the longest syllable
+                if (uncovered, pieces + 1) <= best[i] {
+                    best[i] = (uncovered, pieces + 1);
+                    step[i] = (j, true);
+                }
+            }
+        }
+
+        let mut i = 0;
+        let mut pending_uncovered: Option<usize> = None;
+        while i < n {
+            let (j, known) = step[i];
+            if known {
+                if let Some(start) = pending_uncovered.take() {
+                    out.syllables
+                        .push(chunk[bounds[start]..bounds[i]].to_string());
+                }
+                out.syllables.push(chunk[bounds[i]..bounds[j]].to_string());
+            } else {
+                out.complete = false;
+                pending_uncovered.get_or_insert(i);
+            }
+            i = j;
+        }
+        if let Some(start) = pending_uncovered {
+            out.syllables.push(chunk[bounds[start]..].to_string());
+        }
+    }
+
+    /// Char positions j > i such that chunk[i..j], lowercased, is a syllable
+    fn syllable_ends(&self, chunk: &str, bounds: &[usize], i: usize) -> Vec<usize> {
+        let fst = self.syllables.as_fst();
+        let mut node = fst.root();
+        let mut ends = Vec::new();
+        let mut buf = [0u8; 4];
+        for (k, c) in chunk[bounds[i]..].chars().enumerate() {
+            for lower in c.to_lowercase() {
+                for &byte in lower.encode_utf8(&mut buf).as_bytes() {
+                    match node.find_input(byte) {
+                        Some(t) => node = fst.node(node.transition(t).addr),
+                        None => return ends,
+                    }
+                }
+            }
+            if node.is_final() {
+                ends.push(i + k + 1);
+            }
+        }
+        ends
+    }
+}
+
+/// `word` without tone and vowel marks, with "đ" as "d": what a user
+/// typing without an input method would enter
+pub fn fold_marks(word: &str) -> String {
+    word.nfd()
+        .filter(|&c| !is_combining_mark(c))
+        .map(|c| match c {
+            'đ' => 'd',
+            'Đ' => 'D',
+            c => c,
+        })
+        .collect()
+}
+
+/// Combining marks Vietnamese spells with: the five tone marks (grave,
+/// acute, tilde, hook above, dot below) and the circumflex, breve and horn
+const VI_COMBINING_MARKS: [char; 8] = [
+    '\u{0300}', '\u{0301}', '\u{0303}', '\u{0309}', '\u{0323}', '\u{0302}', '\u{0306}', '\u{031B}',
+];
+
+/// A raw token from a word list as a syllable FST key: NFC-normalized and
+/// with hyphens trimmed from the ends. None unless, once decomposed, every
+/// character is an ASCII letter, "đ", an inner hyphen, or a Vietnamese mark
+/// on a letter. So a decomposed "việt" is accepted as the precomposed
+/// one, while a mark with no letter under it, or a letter Vietnamese
+/// doesn't use ("ä", "ß"), rejects the token.
+pub fn clean_syllable(raw: &str) -> Option<String> {
+    let clean = raw.trim_matches('-');
+    let mut after_letter = false;
+    for c in clean.nfd() {
+        after_letter = match c {
+            'a'..='z' | 'A'..='Z' | 'đ' | 'Đ' => true,
+            '-' => false,
+            c if VI_COMBINING_MARKS.contains(&c) && after_letter => true,
+            _ => return None,
+        };
+    }
+    (!clean.is_empty()).then(|| clean.nfc().collect())
+}
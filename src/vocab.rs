@@ -0,0 +1,194 @@
+//! Vocab held as one contiguous string plus line offsets
+//!
+//! A `Vec<String>` costs a heap allocation and 24 bytes of header per
+//! word; here every word lives in a single buffer and `word(id)` slices it
+//! without allocating. Line `i` of the vocab file is word id `i`, as with
+//! the FST ids.
+//!
+//! That makes the file a bijection with ids, so `load` refuses a vocab
+//! that lists a word twice: lookups find the first line, and every edge
+//! to the later id would resolve to the wrong word. Builders that take a
+//! previous vocab read it with `load_unchecked` and `dedup` it instead.
+//!
+//! The buffer and offsets are reference-counted and never change after
+//! construction, so cloning a `Vocab` shares them: engines loaded from the
+//! same vocab file (see `registry`) hold one copy between them.
+
+use crate::error::{EngineError, EngineResult};
+use crate::{Edge, ResolvedEdge};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vocab {
+    text: Arc<str>,
+    /// Start of each word in `text`, plus the end of the last one
+    offsets: Arc<[usize]>,
+}
+
+/// A `Vocab` under construction, appended to word by word
+struct VocabBuilder {
+    text: String,
+    offsets: Vec<usize>,
+}
+
+impl VocabBuilder {
+    fn with_capacity(bytes: usize) -> Self {
+        Self {
+            text: String::with_capacity(bytes),
+            offsets: vec![0],
+        }
+    }
+
+    fn push(&mut self, word: &str) {
+        self.text.push_str(word);
+        self.offsets.push(self.text.len());
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    fn finish(self) -> Vocab {
+        Vocab {
+            text: self.text.into(),
+            offsets: self.offsets.into(),
+        }
+    }
+}
+
+impl Vocab {
+    /// Read a vocab file, one word per line (`\n` or `\r\n` endings).
+    /// A word on two lines is an error (`EngineError::DuplicateWord`).
+    pub fn load(path: &str) -> EngineResult<Self> {
+        let vocab = Self::load_unchecked(path)?;
+        if let Some((first, id)) = vocab.first_duplicate() {
+            return Err(EngineError::DuplicateWord {
+                path: path.to_string(),
+                word: vocab.word(id).unwrap_or_default().to_string(),
+                first,
+                id,
+            });
+        }
+        Ok(vocab)
+    }
+
+    /// `load` without the duplicate check
+    pub fn load_unchecked(path: &str) -> EngineResult<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| EngineError::io(path, e))?;
+        Ok(Self::from_text(&text))
+    }
+
+    /// Split `text` into words the way `BufRead::lines` would
+    pub fn from_text(text: &str) -> Self {
+        let mut vocab = VocabBuilder::with_capacity(text.len());
+        for line in text.lines() {
+            vocab.push(line);
+        }
+        vocab.finish()
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The word with id `id`, or None past the end of the vocab
+    pub fn word(&self, id: u32) -> Option<&str> {
+        let id = id as usize;
+        let (&start, &end) = (self.offsets.get(id)?, self.offsets.get(id + 1)?);
+        Some(&self.text[start..end])
+    }
+
+    /// Resolve `edges` to their words, dropping any whose id is past the
+    /// end of the vocab
+    pub fn resolve<'a>(&'a self, edges: &[Edge]) -> Vec<ResolvedEdge<'a>> {
+        edges
+            .iter()
+            .filter_map(|e| {
+                Some(ResolvedEdge {
+                    word: self.word(e.next_id)?,
+                    weight: e.weight,
+                })
+            })
+            .collect()
+    }
+
+    /// 64-bit FNV-1a hash of the words in id order, recorded in models so
+    /// a vocab that was regenerated or reordered is caught at load. Equal
+    /// for equal word lists whatever the line endings of the file.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for word in self.iter() {
+            for &b in word.as_bytes().iter().chain(b"\n") {
+                hash ^= b as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+
+    /// Words in id order
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.offsets.windows(2).map(|w| &self.text[w[0]..w[1]])
+    }
+
+    /// The first word listed twice, as (id of its first line, id of the repeat)
+    pub fn first_duplicate(&self) -> Option<(u32, u32)> {
+        let mut seen: HashMap<&str, u32> = HashMap::with_capacity(self.len());
+        for (id, word) in self.iter().enumerate() {
+            if let Some(&first) = seen.get(word) {
+                return Some((first, id as u32));
+            }
+            seen.insert(word, id as u32);
+        }
+        None
+    }
+
+    /// Drop repeated words, keeping each at its first line, and return the
+    /// deduplicated vocab with the new id of every old one (a repeat maps
+    /// to its first line's). Ids before the first repeat don't move; later
+    /// ones shift down by the repeats before them.
+    pub fn dedup(&self) -> (Vocab, Vec<u32>) {
+        let mut new_id: HashMap<&str, u32> = HashMap::with_capacity(self.len());
+        let mut deduped = VocabBuilder::with_capacity(self.text.len());
+        let mut remap = Vec::with_capacity(self.len());
+        for word in self.iter() {
+            let id = *new_id.entry(word).or_insert_with(|| {
+                deduped.push(word);
+                deduped.len() as u32 - 1
+            });
+            remap.push(id);
+        }
+        (deduped.finish(), remap)
+    }
+}
+
+impl Default for Vocab {
+    fn default() -> Self {
+        VocabBuilder::with_capacity(0).finish()
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Vocab {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(words: I) -> Self {
+        let mut vocab = VocabBuilder::with_capacity(0);
+        for word in words {
+            vocab.push(word);
+        }
+        vocab.finish()
+    }
+}
+
+impl FromIterator<String> for Vocab {
+    fn from_iter<I: IntoIterator<Item = String>>(words: I) -> Self {
+        let mut vocab = VocabBuilder::with_capacity(0);
+        for word in words {
+            vocab.push(&word);
+        }
+        vocab.finish()
+    }
+}